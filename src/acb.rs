@@ -0,0 +1,236 @@
+use crate::error::{CpkError, Result};
+use crate::utf::Utf;
+use log::debug;
+
+/// ReferenceType / ReferenceItems type codes.
+const REF_WAVEFORM: u16 = 1;
+const REF_SYNTH: u16 = 2;
+const REF_SEQUENCE: u16 = 3;
+
+/// Track event commands that trigger a synth or sequence.
+const CMD_NOTE_ON: u16 = 2000;
+const CMD_NOTE_ON_WITH_ID: u16 = 2003;
+
+/// Synths and sequences may nest; real banks stay far below this.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct Cue {
+    pub id: u32,
+    pub name: Option<String>,
+    pub waveforms: Vec<WaveformRef>,
+}
+
+/// A waveform played by a cue and where its audio lives.
+#[derive(Debug, Clone)]
+pub struct WaveformRef {
+    /// Row in the ACB's Waveform table.
+    pub index: u16,
+    /// Slot in the AWB (memory or streaming, see `streaming`).
+    pub awb_id: Option<u16>,
+    pub streaming: bool,
+    pub encode_type: Option<u8>,
+}
+
+/// Parses an ACB file (a nested @UTF table) and resolves each cue to its waveforms.
+pub fn read_cues(data: &[u8]) -> Result<Vec<Cue>> {
+    let header = parse_table(data)?;
+    let acb = Acb {
+        cues: nested_table(&header, "CueTable")?
+            .ok_or_else(|| CpkError::InvalidFormat("ACB has no CueTable".to_string()))?,
+        cue_names: nested_table(&header, "CueNameTable")?,
+        waveforms: nested_table(&header, "WaveformTable")?,
+        synths: nested_table(&header, "SynthTable")?,
+        sequences: nested_table(&header, "SequenceTable")?,
+        tracks: nested_table(&header, "TrackTable")?,
+        // Older ACBs keep track events in the command table
+        track_events: match nested_table(&header, "TrackEventTable")? {
+            Some(table) => Some(table),
+            None => nested_table(&header, "CommandTable")?,
+        },
+    };
+
+    let mut names = vec![None; acb.cues.rows.len()];
+    if let Some(cue_names) = &acb.cue_names {
+        for row in 0..cue_names.rows.len() {
+            let name = cue_names
+                .get_column_data(row, "CueName")
+                .and_then(|v| v.as_string());
+            let index = cue_names
+                .get_column_data(row, "CueIndex")
+                .and_then(|v| v.as_u16());
+            if let (Some(name), Some(index)) = (name, index)
+                && let Some(slot) = names.get_mut(index as usize)
+            {
+                *slot = Some(name.to_string());
+            }
+        }
+    }
+
+    let mut cues = Vec::with_capacity(acb.cues.rows.len());
+    for (row, name) in names.into_iter().enumerate() {
+        let id = acb
+            .cues
+            .get_column_data(row, "CueId")
+            .and_then(|v| v.as_u32())
+            .unwrap_or(row as u32);
+        let reference_type = acb
+            .cues
+            .get_column_data(row, "ReferenceType")
+            .and_then(|v| v.as_u8())
+            .unwrap_or(0) as u16;
+        let reference_index = acb
+            .cues
+            .get_column_data(row, "ReferenceIndex")
+            .and_then(|v| v.as_u16())
+            .unwrap_or(0);
+
+        let mut indices = Vec::new();
+        acb.collect(reference_type, reference_index, 0, &mut indices);
+        indices.dedup();
+
+        cues.push(Cue {
+            id,
+            name,
+            waveforms: indices.into_iter().map(|i| acb.waveform(i)).collect(),
+        });
+    }
+
+    Ok(cues)
+}
+
+struct Acb {
+    cues: Utf,
+    cue_names: Option<Utf>,
+    waveforms: Option<Utf>,
+    synths: Option<Utf>,
+    sequences: Option<Utf>,
+    tracks: Option<Utf>,
+    track_events: Option<Utf>,
+}
+
+impl Acb {
+    /// Follows a reference down to the waveform indices it ends up playing.
+    fn collect(&self, reference_type: u16, index: u16, depth: usize, out: &mut Vec<u16>) {
+        if depth > MAX_DEPTH {
+            debug!("ACB: reference chain deeper than {}, stopping", MAX_DEPTH);
+            return;
+        }
+
+        match reference_type {
+            REF_WAVEFORM => out.push(index),
+            REF_SYNTH => {
+                let Some(items) = self
+                    .synths
+                    .as_ref()
+                    .and_then(|t| t.get_column_data(index as usize, "ReferenceItems"))
+                    .and_then(|v| v.as_data())
+                else {
+                    return;
+                };
+                for pair in items.chunks_exact(4) {
+                    let item_type = u16::from_be_bytes([pair[0], pair[1]]);
+                    let item_index = u16::from_be_bytes([pair[2], pair[3]]);
+                    self.collect(item_type, item_index, depth + 1, out);
+                }
+            }
+            REF_SEQUENCE => {
+                let Some(sequences) = &self.sequences else {
+                    return;
+                };
+                let row = index as usize;
+                let num_tracks = sequences
+                    .get_column_data(row, "NumTracks")
+                    .and_then(|v| v.as_u16())
+                    .unwrap_or(0) as usize;
+                let Some(track_indices) = sequences
+                    .get_column_data(row, "TrackIndex")
+                    .and_then(|v| v.as_data())
+                else {
+                    return;
+                };
+                for track in track_indices.chunks_exact(2).take(num_tracks) {
+                    let track = u16::from_be_bytes([track[0], track[1]]);
+                    self.collect_track(track, depth + 1, out);
+                }
+            }
+            _ => debug!(
+                "ACB: unsupported reference type {} (index {})",
+                reference_type, index
+            ),
+        }
+    }
+
+    fn collect_track(&self, track: u16, depth: usize, out: &mut Vec<u16>) {
+        let Some(event) = self
+            .tracks
+            .as_ref()
+            .and_then(|t| t.get_column_data(track as usize, "EventIndex"))
+            .and_then(|v| v.as_u16())
+        else {
+            return;
+        };
+        let Some(commands) = self
+            .track_events
+            .as_ref()
+            .and_then(|t| t.get_column_data(event as usize, "Command"))
+            .and_then(|v| v.as_data())
+        else {
+            return;
+        };
+
+        // Commands are (u16 opcode, u8 size, payload) records
+        let mut pos = 0;
+        while pos + 3 <= commands.len() {
+            let opcode = u16::from_be_bytes([commands[pos], commands[pos + 1]]);
+            let size = commands[pos + 2] as usize;
+            let Some(payload) = commands.get(pos + 3..pos + 3 + size) else {
+                break;
+            };
+            if matches!(opcode, CMD_NOTE_ON | CMD_NOTE_ON_WITH_ID) && payload.len() >= 4 {
+                let target_type = u16::from_be_bytes([payload[0], payload[1]]);
+                let target_index = u16::from_be_bytes([payload[2], payload[3]]);
+                self.collect(target_type, target_index, depth + 1, out);
+            }
+            pos += 3 + size;
+        }
+    }
+
+    fn waveform(&self, index: u16) -> WaveformRef {
+        let row = index as usize;
+        let get = |column: &str| {
+            self.waveforms
+                .as_ref()
+                .and_then(|t| t.get_column_data(row, column))
+        };
+
+        let streaming = get("Streaming").and_then(|v| v.as_u8()).unwrap_or(0) != 0;
+        // Newer ACBs split the AWB ID by storage; older ones have a single Id
+        let awb_id = match streaming {
+            true => get("StreamAwbId").and_then(|v| v.as_u16()),
+            false => get("MemoryAwbId").and_then(|v| v.as_u16()),
+        }
+        .filter(|&id| id != 0xFFFF)
+        .or_else(|| get("Id").and_then(|v| v.as_u16()));
+
+        WaveformRef {
+            index,
+            awb_id,
+            streaming,
+            encode_type: get("EncodeType").and_then(|v| v.as_u8()),
+        }
+    }
+}
+
+fn parse_table(data: &[u8]) -> Result<Utf> {
+    let mut utf = Utf::new();
+    utf.read_utf(data)?;
+    Ok(utf)
+}
+
+fn nested_table(header: &Utf, column: &str) -> Result<Option<Utf>> {
+    match header.get_column_data(0, column).and_then(|v| v.as_data()) {
+        Some(data) if !data.is_empty() => header.read_nested(data).map(Some),
+        _ => Ok(None),
+    }
+}
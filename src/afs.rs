@@ -0,0 +1,138 @@
+use crate::endian::EndianReader;
+use crate::error::{CpkError, Result};
+use crate::pread;
+use crate::sparse;
+use log::{debug, info, warn};
+use std::fs::File;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+
+/// Size of one record in the optional filename directory.
+const DIRECTORY_RECORD_SIZE: u64 = 0x30;
+
+#[derive(Debug, Clone)]
+pub struct AfsEntry {
+    pub index: u32,
+    /// Name from the filename directory, or the zero-padded index when there is none.
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A legacy CRI AFS container: a flat list of files with an optional name directory.
+#[derive(Debug, Default)]
+pub struct Afs {
+    pub entries: Vec<AfsEntry>,
+}
+
+impl Afs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read_afs<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let file = File::open(&path)?;
+        let file_size = file.metadata()?.len();
+        let mut reader = EndianReader::new(BufReader::new(file), true);
+
+        let signature = reader.read_bytes(4)?;
+        if &signature[..3] != b"AFS" {
+            return Err(CpkError::InvalidSignature);
+        }
+
+        let count = reader.read_u32()?;
+        if 8 + count as u64 * 8 > file_size {
+            return Err(CpkError::InvalidFormat(format!(
+                "AFS declares {} files, more than fit in {} bytes",
+                count, file_size
+            )));
+        }
+
+        let mut entries = Vec::with_capacity(count as usize);
+        for index in 0..count {
+            let offset = reader.read_u32()? as u64;
+            let size = reader.read_u32()? as u64;
+            entries.push(AfsEntry {
+                index,
+                name: format!("{:04}", index),
+                offset,
+                size,
+            });
+        }
+        debug!("AFS: {} files", count);
+
+        // The directory pointer follows the table, or sits just before the first file
+        let table_end = 8 + count as u64 * 8;
+        let first_data = entries
+            .iter()
+            .map(|e| e.offset)
+            .filter(|&o| o != 0)
+            .min()
+            .unwrap_or(table_end);
+        for pointer_pos in [table_end, first_data.saturating_sub(8)] {
+            if pointer_pos < table_end || pointer_pos + 8 > file_size {
+                continue;
+            }
+            reader.seek(SeekFrom::Start(pointer_pos))?;
+            let dir_offset = reader.read_u32()? as u64;
+            let dir_size = reader.read_u32()? as u64;
+            let expected = count as u64 * DIRECTORY_RECORD_SIZE;
+            if dir_offset != 0 && dir_size >= expected && dir_offset + expected <= file_size {
+                debug!("AFS: name directory at 0x{:X}", dir_offset);
+                Self::read_names(&mut reader, dir_offset, &mut entries)?;
+                break;
+            }
+        }
+
+        self.entries = entries;
+        Ok(())
+    }
+
+    fn read_names(
+        reader: &mut EndianReader<BufReader<File>>,
+        dir_offset: u64,
+        entries: &mut [AfsEntry],
+    ) -> Result<()> {
+        for entry in entries.iter_mut() {
+            reader.seek(SeekFrom::Start(
+                dir_offset + entry.index as u64 * DIRECTORY_RECORD_SIZE,
+            ))?;
+            let name = reader.read_cstring(Some(32))?;
+            // Names are plain file names; don't let one escape the output directory
+            let name = name.replace(['/', '\\'], "_");
+            if !name.is_empty() && name != "." && name != ".." {
+                entry.name = name;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the entries whose name matches `target` (or all of them for "all")
+    /// to the current directory.
+    pub fn extract<P: AsRef<Path>>(&self, path: P, target: &str) -> Result<()> {
+        let file = File::open(path)?;
+        let all = target.eq_ignore_ascii_case("all");
+
+        let mut extracted = 0;
+        for entry in &self.entries {
+            if !all && !entry.name.eq_ignore_ascii_case(target) {
+                continue;
+            }
+            if entry.size == 0 {
+                warn!("File {} has zero size, skipping", entry.name);
+                continue;
+            }
+
+            let mut data = vec![0u8; entry.size as usize];
+            pread::read_exact_at(&file, &mut data, entry.offset)?;
+            info!("Extracting: {} ({} bytes)", entry.name, data.len());
+            sparse::write_file(&entry.name, &data)?;
+            extracted += 1;
+        }
+
+        if extracted == 0 && !all {
+            return Err(CpkError::FileNotFound(target.to_string()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use crate::cancel::CancellationToken;
+use crate::compression::{CrilaylaDecoder, decompress_crilayla};
+use crate::cpk::{Cpk, FileEntry};
+use crate::error::Result;
+use log::debug;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// Decodes one stored entry, returning the number of bytes produced.
+type Decoder = fn(&[u8]) -> Result<u64>;
+
+/// Throughput of one decompression path over the sampled entries.
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub codec: &'static str,
+    pub entries: usize,
+    /// Stored bytes decoded per iteration.
+    pub input_bytes: u64,
+    /// Decompressed bytes produced per iteration.
+    pub output_bytes: u64,
+    pub iterations: usize,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    /// Decompressed megabytes (10^6 bytes) produced per second.
+    pub fn megabytes_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        (self.output_bytes * self.iterations as u64) as f64 / seconds / 1_000_000.0
+    }
+}
+
+/// Decompresses up to `sample` CRILAYLA entries, spread evenly across the
+/// archive, `iterations` times with each decoder.
+///
+/// Entries are read into memory first so disk speed doesn't enter the figures.
+/// Returns no results when the archive holds no compressed entries.
+pub fn run(
+    cpk: &Cpk,
+    sample: usize,
+    iterations: usize,
+    cancel: &CancellationToken,
+) -> Result<Vec<BenchResult>> {
+    let compressed: Vec<&FileEntry> = cpk
+        .unique_files()
+        .into_iter()
+        .filter(|e| e.extract_size.is_some_and(|size| size > e.file_size))
+        .collect();
+    let step = compressed.len().div_ceil(sample.max(1)).max(1);
+
+    let mut inputs = Vec::new();
+    for entry in compressed.into_iter().step_by(step) {
+        let data = cpk.read_entry_raw(entry)?;
+        if data.starts_with(b"CRILAYLA") {
+            inputs.push(data);
+        }
+    }
+    if inputs.is_empty() {
+        return Ok(Vec::new());
+    }
+    debug!("bench: {} entries, {} iterations", inputs.len(), iterations);
+
+    let input_bytes = inputs.iter().map(|data| data.len() as u64).sum();
+    let mut results = Vec::new();
+    let decoders: [(&'static str, Decoder); 2] = [
+        ("crilayla", |data| {
+            Ok(decompress_crilayla(data)?.len() as u64)
+        }),
+        ("crilayla-stream", |data| {
+            let mut decoder = CrilaylaDecoder::new(Cursor::new(data))?;
+            Ok(std::io::copy(&mut decoder, &mut std::io::sink())?)
+        }),
+    ];
+    for (codec, decode) in decoders {
+        let mut output_bytes = 0;
+        let start = Instant::now();
+        for _ in 0..iterations {
+            cancel.check()?;
+            output_bytes = 0;
+            for data in &inputs {
+                output_bytes += decode(data)?;
+            }
+        }
+        results.push(BenchResult {
+            codec,
+            entries: inputs.len(),
+            input_bytes,
+            output_bytes,
+            iterations,
+            elapsed: start.elapsed(),
+        });
+    }
+    Ok(results)
+}
@@ -0,0 +1,1002 @@
+use crate::cancel::{CancellationToken, StagedFile};
+use crate::codec::{Codec, Crilayla};
+use crate::compression::{CompressionPolicy, StoredData, crc32, decompress_crilayla};
+use crate::cpk::{CpkMode, FileEntry};
+use crate::cpk::{align_up, encode_table, toc_base_offset, write_padding};
+use crate::crypt::{Cipher, XorStream};
+use crate::error::{CpkError, Result};
+use crate::escape;
+use crate::group::Group;
+use crate::ignore::{IGNORE_FILE, IgnoreRules};
+use crate::journal::PackJournal;
+use crate::utf::{Cell, CellValue, Column, Utf};
+use log::{debug, info};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Each table starts on the next 0x800 boundary after the CPK header.
+const HEADER_ALIGN: u64 = 0x800;
+
+/// Sector size of CD/DVD/Blu-ray images.
+pub const DISC_SECTOR: u16 = 0x800;
+
+/// Bytes of a TOC row with a CRC column; the names it points to are stored after the rows.
+const TOC_ROW_LEN: u64 = 36;
+
+/// Generous bound on the header block plus the TOC's table header, columns and
+/// fixed strings.
+pub(crate) const ARCHIVE_OVERHEAD: u64 = HEADER_ALIGN + 0x200;
+
+/// Generous bound on the ITOC's own block, for archives that carry one.
+pub(crate) const ITOC_OVERHEAD: u64 = HEADER_ALIGN + 0x200;
+
+/// Bytes of the widest ITOC row (16-bit ID, 32-bit sizes).
+pub(crate) const ITOC_ROW_LEN: u64 = 10;
+
+/// Upper bound on what an entry at `path` adds to the TOC; shared strings only
+/// make it smaller.
+pub(crate) fn toc_entry_bound(path: &str) -> u64 {
+    TOC_ROW_LEN + path.len() as u64 + 2
+}
+
+/// Every file below `dir` with its path relative to `dir`, in path order.
+///
+/// Whatever the directory's `.cpkignore` matches is skipped. Names extraction
+/// escaped get back the originals recorded in the directory's names file.
+/// Neither file is listed itself.
+pub(crate) fn files_below(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let names = escape::read_names(dir)?;
+    let ignore = IgnoreRules::load(dir)?;
+    let own_files = [dir.join(escape::NAMES_FILE), dir.join(IGNORE_FILE)];
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let path = entry?.path();
+            let is_dir = path.is_dir();
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            if ignore.is_ignored(&relative.to_string_lossy().replace('\\', "/"), is_dir) {
+                debug!("{}: ignored", path.display());
+            } else if is_dir {
+                pending.push(path);
+            } else if !own_files.contains(&path) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+
+    Ok(found
+        .into_iter()
+        .map(|path| {
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            match names.get(&relative) {
+                Some(original) => (original.clone(), path.clone()),
+                None => (relative, path.clone()),
+            }
+        })
+        .collect())
+}
+
+/// Where a path is cut into the TOC's DirName and FileName.
+///
+/// Readers join the two with `/` either way; which one an engine expects
+/// depends on how it looks files up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathSplit {
+    /// At the last `/`: `a/b/c.bin` is `a/b` and `c.bin`.
+    #[default]
+    Parent,
+    /// At the first `/`: `a/b/c.bin` is `a` and `b/c.bin`.
+    FirstComponent,
+    /// Nowhere: DirName is empty and FileName holds the whole path.
+    None,
+}
+
+impl PathSplit {
+    /// `path` as DirName and FileName.
+    pub fn split<'a>(&self, path: &'a str) -> (&'a str, &'a str) {
+        let cut = match self {
+            PathSplit::Parent => path.rsplit_once('/'),
+            PathSplit::FirstComponent => path.split_once('/'),
+            PathSplit::None => None,
+        };
+        cut.unwrap_or(("", path))
+    }
+}
+
+/// How files added without an explicit ID are numbered.
+#[derive(Debug, Clone, Default)]
+pub enum IdStrategy {
+    /// The lowest IDs left free, in the order files were added.
+    #[default]
+    Sequential,
+    /// The ID each path had before (matched case-insensitively), as listed by
+    /// `ids --csv`; paths not listed or whose ID is taken are numbered
+    /// sequentially.
+    Mapped(HashMap<String, u32>),
+    /// A CRC-32 of the lowercase path, cut to 16 bits when there's an ITOC, so
+    /// a file keeps its ID across builds whatever else is added. Clashes move
+    /// to the next free ID, in path order.
+    Hash,
+}
+
+/// Authors a CPK archive from in-memory files, addressed by path (TOC), by ID
+/// (ITOC) or both.
+///
+/// ```no_run
+/// # use cpk_tool_rs::CpkBuilder;
+/// CpkBuilder::new()
+///     .align(0x800)
+///     .encrypt_tables(true)
+///     .add_file("a/b.bin", vec![0u8; 16])
+///     .write("out.cpk")?;
+/// # Ok::<(), cpk_tool_rs::CpkError>(())
+/// ```
+#[derive(Debug)]
+pub struct CpkBuilder {
+    align: u16,
+    mode: CpkMode,
+    groups: Vec<Group>,
+    /// GTOC packet carried over as is from another archive.
+    raw_gtoc: Option<Vec<u8>>,
+    /// Pads the end of the archive to a whole disc sector.
+    sector_padding: bool,
+    encrypt_tables: bool,
+    /// Adds a CRC column to the TOC.
+    crc: bool,
+    path_split: PathSplit,
+    ids: IdStrategy,
+    compression: CompressionPolicy,
+    codec: Arc<dyn Codec>,
+    cipher: Arc<dyn Cipher>,
+    cancel: CancellationToken,
+    /// Journals compressed entries next to the output so an interrupted write can resume.
+    resumable: bool,
+    files: Vec<PendingFile>,
+}
+
+#[derive(Debug)]
+struct PendingFile {
+    path: String,
+    /// Explicit ID; the others get the lowest IDs left free, in order.
+    id: Option<u32>,
+    source: Source,
+}
+
+#[derive(Debug)]
+enum Source {
+    /// Plain data, compressed according to the policy on write.
+    Data(Vec<u8>),
+    /// A local file, read and compressed on write.
+    File(PathBuf),
+    Content(Content),
+}
+
+/// Entry data in the form it is written to the archive.
+#[derive(Debug)]
+enum Content {
+    Stored(StoredData),
+    /// Already stored data copied as is from another archive.
+    Copied {
+        archive: PathBuf,
+        offset: u64,
+        size: u64,
+        extract_size: u64,
+        crc: Option<u32>,
+    },
+}
+
+impl Content {
+    fn stored_size(&self) -> u64 {
+        match self {
+            Content::Stored(stored) => stored.data.len() as u64,
+            Content::Copied { size, .. } => *size,
+        }
+    }
+
+    fn extract_size(&self) -> u64 {
+        match self {
+            Content::Stored(stored) => stored.extract_size,
+            Content::Copied { extract_size, .. } => *extract_size,
+        }
+    }
+
+    /// CRC-32 of the extracted content, decoding copied data whose archive had none.
+    fn crc(&self) -> Result<u32> {
+        match self {
+            Content::Stored(stored) => Ok(stored.crc),
+            Content::Copied { crc: Some(crc), .. } => Ok(*crc),
+            Content::Copied {
+                archive,
+                offset,
+                size,
+                extract_size,
+                crc: None,
+            } => {
+                let mut data = vec![0u8; *size as usize];
+                let mut file = File::open(archive)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                file.read_exact(&mut data)?;
+                if extract_size != size && data.starts_with(b"CRILAYLA") {
+                    data = decompress_crilayla(&data)?;
+                }
+                Ok(crc32(&data))
+            }
+        }
+    }
+}
+
+impl Default for CpkBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpkBuilder {
+    pub fn new() -> Self {
+        Self {
+            align: 0x800,
+            mode: CpkMode::FileName,
+            groups: Vec::new(),
+            raw_gtoc: None,
+            sector_padding: false,
+            encrypt_tables: false,
+            crc: false,
+            path_split: PathSplit::default(),
+            ids: IdStrategy::default(),
+            compression: CompressionPolicy::default(),
+            codec: Arc::new(Crilayla),
+            cipher: Arc::new(XorStream),
+            resumable: false,
+            cancel: CancellationToken::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Boundary every entry starts on (the header's `Align`).
+    pub fn align(mut self, align: u16) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Tables the archive carries; defaults to [`CpkMode::FileName`]. With an
+    /// ITOC, entries are stored in ID order and IDs must fit in 16 bits.
+    pub fn mode(mut self, mode: CpkMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Lists `groups` in a GTOC, adding one to the mode. Members are given by path.
+    pub fn groups(mut self, groups: Vec<Group>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Writes `packet` as the GTOC unchanged; the entries it refers to must keep their IDs.
+    pub(crate) fn raw_gtoc(mut self, packet: Vec<u8>) -> Self {
+        self.raw_gtoc = Some(packet);
+        self
+    }
+
+    /// Preset for archives streamed straight off optical media images: every
+    /// entry starts on a [`DISC_SECTOR`] boundary and the archive is padded to a
+    /// whole number of sectors.
+    pub fn disc_image(mut self) -> Self {
+        self.align = DISC_SECTOR;
+        self.sector_padding = true;
+        self
+    }
+
+    /// XOR-encrypts the header and TOC packets like most retail archives.
+    pub fn encrypt_tables(mut self, encrypt: bool) -> Self {
+        self.encrypt_tables = encrypt;
+        self
+    }
+
+    /// Adds a CRC column with the CRC-32 of each entry's extracted content, for
+    /// engines that check it.
+    pub fn crc(mut self, enabled: bool) -> Self {
+        self.crc = enabled;
+        self
+    }
+
+    /// Where paths are cut into DirName and FileName, at the last `/` by default.
+    pub fn path_split(mut self, split: PathSplit) -> Self {
+        self.path_split = split;
+        self
+    }
+
+    /// How files added without an explicit ID are numbered, sequentially by default.
+    pub fn ids(mut self, strategy: IdStrategy) -> Self {
+        self.ids = strategy;
+        self
+    }
+
+    pub fn compression(mut self, policy: CompressionPolicy) -> Self {
+        self.compression = policy;
+        self
+    }
+
+    /// The cipher tables are encrypted with when `encrypt_tables` is on, the
+    /// XOR stream by default.
+    pub fn cipher(mut self, cipher: Arc<dyn Cipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// The codec new entries are compressed with when the compression policy
+    /// allows it, CRILAYLA by default.
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Keeps each local file's compressed form in `<output>.pack/` as it's
+    /// produced, so that writing to the same output again after an interruption
+    /// only compresses the files that weren't done or changed since. The
+    /// directory is removed once the archive is written.
+    pub fn resumable(mut self, resumable: bool) -> Self {
+        self.resumable = resumable;
+        self
+    }
+
+    /// Stops `write` between entries once `token` is cancelled, leaving no output behind.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Adds a file under `path` (`/`-separated; backslashes are accepted too).
+    /// IDs follow the order in which files are added.
+    pub fn add_file<P: Into<String>, D: Into<Vec<u8>>>(self, path: P, data: D) -> Self {
+        self.push(path.into(), None, Source::Data(data.into()))
+    }
+
+    /// Adds every file below `dir`, in path order, under its path relative to `dir`,
+    /// except those its `.cpkignore` leaves out. The files are read when the
+    /// archive is written.
+    pub fn add_dir<P: AsRef<Path>>(mut self, dir: P) -> Result<Self> {
+        for (name, path) in files_below(dir.as_ref())? {
+            self = self.push(name, None, Source::File(path));
+        }
+        Ok(self)
+    }
+
+    /// Adds an entry of another archive under `path` and `id`, copying its
+    /// stored (possibly compressed) data without decoding it.
+    pub(crate) fn add_copied<P: Into<String>>(
+        self,
+        path: P,
+        id: Option<u32>,
+        archive: &Path,
+        entry: &FileEntry,
+    ) -> Self {
+        let content = Content::Copied {
+            archive: archive.to_path_buf(),
+            offset: entry.file_offset,
+            size: entry.file_size,
+            extract_size: entry.extract_size.unwrap_or(entry.file_size),
+            crc: entry.crc,
+        };
+        self.push(path.into(), id, Source::Content(content))
+    }
+
+    /// Adds in-memory data under `path` with an explicit `id`.
+    pub(crate) fn add_data_with_id<P: Into<String>>(
+        self,
+        path: P,
+        id: Option<u32>,
+        data: Vec<u8>,
+    ) -> Self {
+        self.push(path.into(), id, Source::Data(data))
+    }
+
+    /// Number of files added so far.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    fn push(mut self, path: String, id: Option<u32>, source: Source) -> Self {
+        let path = path.replace('\\', "/");
+        self.files.push(PendingFile {
+            path: path.trim_start_matches('/').to_string(),
+            id,
+            source,
+        });
+        self
+    }
+
+    pub fn write<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut ids = HashSet::new();
+        for file in &self.files {
+            if file.path.is_empty() || file.path.ends_with('/') {
+                return Err(CpkError::InvalidFormat(format!(
+                    "'{}' is not a file path",
+                    file.path
+                )));
+            }
+            if !seen.insert(file.path.to_lowercase()) {
+                return Err(CpkError::InvalidFormat(format!(
+                    "'{}' was added more than once",
+                    file.path
+                )));
+            }
+            if let Some(id) = file.id
+                && !ids.insert(id)
+            {
+                return Err(CpkError::InvalidFormat(format!(
+                    "ID {} is used by more than one file",
+                    id
+                )));
+            }
+        }
+
+        let mode = match self.groups.is_empty() && self.raw_gtoc.is_none() {
+            true => self.mode,
+            false => self.mode.with_groups(),
+        };
+
+        let path = path.as_ref();
+        let mut journal = match self.resumable {
+            true => {
+                let settings = format!(
+                    "{}\t{}\t{}",
+                    self.compression.enabled,
+                    self.compression.min_saving,
+                    self.codec.name()
+                );
+                Some(PackJournal::open(path, &settings)?)
+            }
+            false => None,
+        };
+
+        let align = self.align.max(1) as u64;
+        let assigned = assign_ids(&self.files, &self.ids, ids, mode.has_itoc());
+        let mut entries = Vec::with_capacity(self.files.len());
+        for (file, id) in self.files.into_iter().zip(assigned) {
+            self.cancel.check()?;
+            let content = match (file.source, journal.as_mut()) {
+                (Source::Data(data), _) => {
+                    Content::Stored(self.compression.store(&*self.codec, data)?)
+                }
+                (Source::File(source), None) => Content::Stored(
+                    self.compression
+                        .store(&*self.codec, std::fs::read(source)?)?,
+                ),
+                (Source::File(source), Some(journal)) => {
+                    let spooled = match journal.stored(&source)? {
+                        Some(spooled) => {
+                            debug!("{}: stored by an earlier run", source.display());
+                            spooled
+                        }
+                        None => {
+                            let stored = self
+                                .compression
+                                .store(&*self.codec, std::fs::read(&source)?)?;
+                            journal.record(&source, &stored)?
+                        }
+                    };
+                    Content::Copied {
+                        archive: match spooled.offset {
+                            Some(_) => journal.spool_path().to_path_buf(),
+                            None => source,
+                        },
+                        offset: spooled.offset.unwrap_or(0),
+                        size: spooled.size,
+                        extract_size: spooled.extract_size,
+                        crc: Some(spooled.crc),
+                    }
+                }
+                (Source::Content(content), _) => content,
+            };
+            let crc = match self.crc {
+                true => Some(content.crc()?),
+                false => None,
+            };
+            entries.push(BuiltEntry {
+                name: file.path,
+                crc,
+                id,
+                content,
+                offset: 0,
+            });
+        }
+
+        // The ITOC implies offsets from the ID order
+        if mode.has_itoc() {
+            if let Some(entry) = entries.iter().find(|e| e.id > u16::MAX as u32) {
+                return Err(CpkError::Unsupported(format!(
+                    "ID {} of '{}' does not fit in an ITOC",
+                    entry.id, entry.name
+                )));
+            }
+            entries.sort_by_key(|e| e.id);
+        }
+
+        // Cell widths are fixed, so the packets can be sized before the offsets are known
+        let mut layout = Layout {
+            mode,
+            ..Layout::default()
+        };
+        let mut table_end = encoded_len(&header_table(&layout)?)?;
+        if mode.has_toc() {
+            layout.toc_offset = align_up(table_end, HEADER_ALIGN);
+            layout.toc_len = encoded_len(&toc_table(&entries, 0, self.path_split)?)?;
+            table_end = layout.toc_offset + layout.toc_len;
+        }
+        let itoc = match mode.has_itoc() {
+            true => Some(itoc_table(&entries)?.to_bytes()?),
+            false => None,
+        };
+        if let Some(itoc) = &itoc {
+            layout.itoc_offset = align_up(table_end, HEADER_ALIGN);
+            layout.itoc_len = itoc.len() as u64 + 0x10;
+            table_end = layout.itoc_offset + layout.itoc_len;
+        }
+        let gtoc = match (mode.has_gtoc(), self.raw_gtoc) {
+            (true, Some(packet)) => Some(packet),
+            (true, None) => Some(gtoc_table(&self.groups, &entries)?.to_bytes()?),
+            (false, _) => None,
+        };
+        if let Some(gtoc) = &gtoc {
+            layout.gtoc_offset = align_up(table_end, HEADER_ALIGN);
+            layout.gtoc_len = gtoc.len() as u64 + 0x10;
+            table_end = layout.gtoc_offset + layout.gtoc_len;
+        }
+        let content_offset = align_up(table_end, align);
+
+        let mut pos = content_offset;
+        for entry in &mut entries {
+            entry.offset = pos;
+            pos += align_up(entry.content.stored_size(), align);
+        }
+        if self.sector_padding {
+            pos = align_up(pos, DISC_SECTOR as u64);
+        }
+        layout.content_offset = content_offset;
+        layout.content_end = pos;
+        layout.files = entries.len() as u32;
+        layout.packed_size = entries.iter().map(|e| e.content.stored_size()).sum();
+        layout.data_size = entries.iter().map(|e| e.content.extract_size()).sum();
+        layout.align = self.align;
+
+        let header = header_table(&layout)?.to_bytes()?;
+
+        let staged = StagedFile::new(path);
+        let mut out = BufWriter::new(File::create(staged.path())?);
+        let cipher = self.encrypt_tables.then_some(&*self.cipher);
+        let header = encode_table(b"CPK ", &header, cipher);
+        out.write_all(&header)?;
+        let mut written = header.len() as u64;
+        if mode.has_toc() {
+            let toc_base = toc_base_offset(layout.toc_offset, content_offset);
+            let toc = toc_table(&entries, toc_base, self.path_split)?.to_bytes()?;
+            write_padding(&mut out, layout.toc_offset - written)?;
+            let toc = encode_table(b"TOC ", &toc, cipher);
+            out.write_all(&toc)?;
+            written = layout.toc_offset + toc.len() as u64;
+        }
+        if let Some(itoc) = &itoc {
+            write_padding(&mut out, layout.itoc_offset - written)?;
+            let itoc = encode_table(b"ITOC", itoc, cipher);
+            out.write_all(&itoc)?;
+            written = layout.itoc_offset + itoc.len() as u64;
+        }
+        if let Some(gtoc) = &gtoc {
+            write_padding(&mut out, layout.gtoc_offset - written)?;
+            let gtoc = encode_table(b"GTOC", gtoc, cipher);
+            out.write_all(&gtoc)?;
+            written = layout.gtoc_offset + gtoc.len() as u64;
+        }
+
+        let mut sources: HashMap<PathBuf, File> = HashMap::new();
+        for entry in &entries {
+            self.cancel.check()?;
+            write_padding(&mut out, entry.offset - written)?;
+            match &entry.content {
+                Content::Stored(stored) => out.write_all(&stored.data)?,
+                Content::Copied {
+                    archive,
+                    offset,
+                    size,
+                    ..
+                } => {
+                    let source = match sources.entry(archive.clone()) {
+                        Entry::Occupied(file) => file.into_mut(),
+                        Entry::Vacant(slot) => slot.insert(File::open(archive)?),
+                    };
+                    source.seek(SeekFrom::Start(*offset))?;
+                    if std::io::copy(&mut source.take(*size), &mut out)? != *size {
+                        return Err(CpkError::InvalidFormat(format!(
+                            "Entry '{}' is truncated in {}",
+                            entry.name,
+                            archive.display()
+                        )));
+                    }
+                }
+            }
+            written = entry.offset + entry.content.stored_size();
+        }
+        write_padding(&mut out, layout.content_end - written)?;
+        out.flush()?;
+        drop(out);
+        staged.commit()?;
+        if let Some(journal) = journal {
+            journal.finish()?;
+        }
+
+        info!(
+            "Wrote {} ({} files, {} bytes)",
+            path.display(),
+            layout.files,
+            layout.content_end
+        );
+        Ok(())
+    }
+}
+
+struct BuiltEntry {
+    name: String,
+    crc: Option<u32>,
+    id: u32,
+    content: Content,
+    offset: u64,
+}
+
+#[derive(Default)]
+struct Layout {
+    mode: CpkMode,
+    toc_offset: u64,
+    toc_len: u64,
+    itoc_offset: u64,
+    itoc_len: u64,
+    gtoc_offset: u64,
+    gtoc_len: u64,
+    content_offset: u64,
+    content_end: u64,
+    files: u32,
+    packed_size: u64,
+    data_size: u64,
+    align: u16,
+}
+
+fn header_table(layout: &Layout) -> Result<Utf> {
+    use CellValue::*;
+    let mut row = vec![
+        ("UpdateDateTime", UInt64(0)),
+        ("FileSize", UInt64(layout.content_end)),
+        ("ContentOffset", UInt64(layout.content_offset)),
+        (
+            "ContentSize",
+            UInt64(layout.content_end - layout.content_offset),
+        ),
+    ];
+    // Readers treat a missing offset column as a missing table
+    if layout.mode.has_toc() {
+        row.push(("TocOffset", UInt64(layout.toc_offset)));
+        row.push(("TocSize", UInt64(layout.toc_len)));
+    }
+    if layout.mode.has_itoc() {
+        row.push(("ItocOffset", UInt64(layout.itoc_offset)));
+        row.push(("ItocSize", UInt64(layout.itoc_len)));
+    }
+    if layout.mode.has_gtoc() {
+        row.push(("GtocOffset", UInt64(layout.gtoc_offset)));
+        row.push(("GtocSize", UInt64(layout.gtoc_len)));
+    }
+    row.extend([
+        ("EnabledPackedSize", UInt64(layout.packed_size)),
+        ("EnabledDataSize", UInt64(layout.data_size)),
+        ("Files", UInt32(layout.files)),
+        ("Version", UInt16(7)),
+        ("Revision", UInt16(2)),
+        ("Align", UInt16(layout.align)),
+        ("Sorted", UInt16(0)),
+        ("CpkMode", UInt32(layout.mode.value())),
+        (
+            "Tvers",
+            String(format!("cpk-tool-rs {}", env!("CARGO_PKG_VERSION"))),
+        ),
+    ]);
+    new_table("CpkHeader", vec![row])
+}
+
+/// The ITOC: IDs whose sizes fit in 16 bits go in DataL, the rest in DataH.
+fn itoc_table(entries: &[BuiltEntry]) -> Result<Utf> {
+    let (low, high): (Vec<&BuiltEntry>, Vec<&BuiltEntry>) = entries
+        .iter()
+        .partition(|e| e.content.stored_size() <= 0xFFFF && e.content.extract_size() <= 0xFFFF);
+
+    new_table(
+        "CpkItocInfo",
+        vec![vec![
+            ("FilesL", CellValue::UInt32(low.len() as u32)),
+            ("FilesH", CellValue::UInt32(high.len() as u32)),
+            (
+                "DataL",
+                CellValue::Data(size_table("CpkItocL", &low, false)?),
+            ),
+            (
+                "DataH",
+                CellValue::Data(size_table("CpkItocH", &high, true)?),
+            ),
+        ]],
+    )
+}
+
+/// An ITOC size table of ID, FileSize and ExtractSize, with 32-bit sizes if `wide`.
+fn size_table(name: &str, entries: &[&BuiltEntry], wide: bool) -> Result<Vec<u8>> {
+    let size_type = if wide { 0x04 } else { 0x02 };
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let size = |value: u64| match wide {
+                true => u32::try_from(value).map(CellValue::UInt32).map_err(|_| {
+                    CpkError::Unsupported(format!("'{}' is larger than 4 GiB", entry.name))
+                }),
+                false => Ok(CellValue::UInt16(value as u16)),
+            };
+            Ok(vec![
+                CellValue::UInt16(entry.id as u16),
+                size(entry.content.stored_size())?,
+                size(entry.content.extract_size())?,
+            ])
+        })
+        .collect::<Result<Vec<_>>>()?;
+    typed_table(
+        name,
+        &[
+            ("ID", 0x02),
+            ("FileSize", size_type),
+            ("ExtractSize", size_type),
+        ],
+        rows,
+    )
+    .to_bytes()
+}
+
+/// The ID of each of `files`, in order: their own, or one `strategy` picks
+/// that isn't in `taken`.
+fn assign_ids(
+    files: &[PendingFile],
+    strategy: &IdStrategy,
+    mut taken: HashSet<u32>,
+    itoc: bool,
+) -> Vec<u32> {
+    let mut assigned: Vec<Option<u32>> = files.iter().map(|file| file.id).collect();
+    match strategy {
+        IdStrategy::Sequential => {}
+        IdStrategy::Mapped(map) => {
+            let map: HashMap<String, u32> = map
+                .iter()
+                .map(|(path, id)| (path.replace('\\', "/").to_lowercase(), *id))
+                .collect();
+            for (file, id) in files.iter().zip(assigned.iter_mut()) {
+                if id.is_none()
+                    && let Some(&previous) = map.get(&file.path.to_lowercase())
+                    && taken.insert(previous)
+                {
+                    *id = Some(previous);
+                }
+            }
+        }
+        IdStrategy::Hash => {
+            let space = match itoc {
+                true => u16::MAX as u64 + 1,
+                false => u32::MAX as u64 + 1,
+            };
+            let mut order: Vec<usize> = (0..files.len())
+                .filter(|&i| assigned[i].is_none())
+                .collect();
+            order.sort_by_key(|&i| files[i].path.to_lowercase());
+            for i in order {
+                let start = crc32(files[i].path.to_lowercase().as_bytes()) as u64 % space;
+                let free = (0..space)
+                    .map(|step| ((start + step) % space) as u32)
+                    .find(|id| !taken.contains(id));
+                if let Some(id) = free {
+                    taken.insert(id);
+                    assigned[i] = Some(id);
+                }
+            }
+        }
+    }
+
+    let mut free_ids = (0u32..).filter(|id| !taken.contains(id));
+    assigned
+        .into_iter()
+        .map(|id| id.unwrap_or_else(|| free_ids.next().unwrap_or_default()))
+        .collect()
+}
+
+/// The GTOC: each group (Gdata) points at a run of entry IDs (Fdata) and an
+/// attribute (Attrdata).
+fn gtoc_table(groups: &[Group], entries: &[BuiltEntry]) -> Result<Utf> {
+    let ids: HashMap<String, u32> = entries
+        .iter()
+        .map(|entry| (entry.name.to_lowercase(), entry.id))
+        .collect();
+
+    let mut attributes: Vec<(&str, u32)> = Vec::new();
+    let mut group_rows = Vec::with_capacity(groups.len());
+    let mut link_rows = Vec::new();
+    for group in groups {
+        let attribute = group.attribute.as_deref().unwrap_or_default();
+        let attribute_index = match attributes.iter().position(|(name, _)| *name == attribute) {
+            Some(index) => index,
+            None => {
+                attributes.push((attribute, 0));
+                attributes.len() - 1
+            }
+        };
+        attributes[attribute_index].1 += group.files.len() as u32;
+
+        group_rows.push(vec![
+            CellValue::String(group.name.clone()),
+            CellValue::UInt16(attribute_index as u16),
+            CellValue::UInt32(link_rows.len() as u32),
+            CellValue::UInt32(group.files.len() as u32),
+        ]);
+        for file in &group.files {
+            let id = ids.get(&file.to_lowercase()).ok_or_else(|| {
+                CpkError::InvalidFormat(format!(
+                    "Group '{}' lists '{}', which is not in the archive",
+                    group.name, file
+                ))
+            })?;
+            link_rows.push(vec![CellValue::UInt32(*id)]);
+        }
+    }
+    let attribute_rows: Vec<Vec<CellValue>> = attributes
+        .iter()
+        .map(|(name, files)| {
+            vec![
+                CellValue::String(name.to_string()),
+                CellValue::UInt32(*files),
+            ]
+        })
+        .collect();
+
+    let gdata = typed_table(
+        "CpkGtocGlink",
+        &[
+            ("Gname", 0x0A),
+            ("Aindex", 0x02),
+            ("Child", 0x04),
+            ("Files", 0x04),
+        ],
+        group_rows,
+    );
+    let fdata = typed_table("CpkGtocFlink", &[("ID", 0x04)], link_rows);
+    let attrdata = typed_table(
+        "CpkGtocAttr",
+        &[("Aname", 0x0A), ("Files", 0x04)],
+        attribute_rows,
+    );
+    new_table(
+        "CpkGtocInfo",
+        vec![vec![
+            ("Glink", CellValue::UInt32(gdata.rows.len() as u32)),
+            ("Flink", CellValue::UInt32(fdata.rows.len() as u32)),
+            ("Attr", CellValue::UInt32(attrdata.rows.len() as u32)),
+            ("Gdata", CellValue::Data(gdata.to_bytes()?)),
+            ("Fdata", CellValue::Data(fdata.to_bytes()?)),
+            ("Attrdata", CellValue::Data(attrdata.to_bytes()?)),
+        ]],
+    )
+}
+
+fn toc_table(entries: &[BuiltEntry], toc_base: u64, split: PathSplit) -> Result<Utf> {
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let (dir, file) = split.split(&entry.name);
+            let size = |value: u64| {
+                u32::try_from(value).map_err(|_| {
+                    CpkError::Unsupported(format!("'{}' is larger than 4 GiB", entry.name))
+                })
+            };
+            let mut row = vec![
+                ("DirName", CellValue::String(dir.to_string())),
+                ("FileName", CellValue::String(file.to_string())),
+                (
+                    "FileSize",
+                    CellValue::UInt32(size(entry.content.stored_size())?),
+                ),
+                (
+                    "ExtractSize",
+                    CellValue::UInt32(size(entry.content.extract_size())?),
+                ),
+                (
+                    "FileOffset",
+                    CellValue::UInt64(entry.offset.saturating_sub(toc_base)),
+                ),
+                ("ID", CellValue::UInt32(entry.id)),
+                ("UserString", CellValue::String("<NULL>".to_string())),
+            ];
+            if let Some(crc) = entry.crc {
+                row.push(("CRC", CellValue::UInt32(crc)));
+            }
+            Ok(row)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    new_table("CpkTocInfo", rows)
+}
+
+/// Builds a table whose columns are all stored per row, typed after the first row.
+fn new_table(name: &str, rows: Vec<Vec<(&str, CellValue)>>) -> Result<Utf> {
+    let mut table = Utf::new();
+    table.name = name.to_string();
+
+    let Some(first) = rows.first() else {
+        return Ok(table);
+    };
+    for (column, value) in first {
+        let column_type = match value {
+            CellValue::UInt8(_) => 0x00,
+            CellValue::UInt16(_) => 0x02,
+            CellValue::UInt32(_) => 0x04,
+            CellValue::UInt64(_) => 0x06,
+            CellValue::String(_) => 0x0A,
+            CellValue::Data(_) => 0x0B,
+            other => {
+                return Err(CpkError::Unsupported(format!(
+                    "Column '{}' has unsupported value {:?}",
+                    column, other
+                )));
+            }
+        };
+        table.columns.push(Column {
+            flags: 0x50 | column_type,
+            name: column.to_string(),
+            constant: None,
+        });
+    }
+
+    for row in rows {
+        table.rows.push(
+            row.into_iter()
+                .map(|(_, value)| Cell { value, position: 0 })
+                .collect(),
+        );
+    }
+    Ok(table)
+}
+
+/// Builds a table with per-row columns of the given types, which are kept even
+/// when there are no rows.
+fn typed_table(name: &str, columns: &[(&str, u8)], rows: Vec<Vec<CellValue>>) -> Utf {
+    let mut table = Utf::new();
+    table.name = name.to_string();
+    for (column, column_type) in columns {
+        table.columns.push(Column {
+            flags: 0x50 | column_type,
+            name: column.to_string(),
+            constant: None,
+        });
+    }
+    for row in rows {
+        table.rows.push(
+            row.into_iter()
+                .map(|value| Cell { value, position: 0 })
+                .collect(),
+        );
+    }
+    table
+}
+
+/// Size of a table on disk, including the 16-byte table header.
+fn encoded_len(table: &Utf) -> Result<u64> {
+    Ok(table.to_bytes()?.len() as u64 + 0x10)
+}
@@ -0,0 +1,222 @@
+use crate::cpk::FileEntry;
+use crate::error::Result;
+#[cfg(feature = "sqlite")]
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use std::collections::HashMap;
+use std::path::Path;
+#[cfg(feature = "sqlite")]
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+#[cfg(feature = "sqlite")]
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS archives (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS checks (
+        archive_id INTEGER NOT NULL REFERENCES archives(id) ON DELETE CASCADE,
+        path TEXT NOT NULL,
+        offset INTEGER NOT NULL,
+        stored_size INTEGER NOT NULL,
+        sha256 TEXT,
+        problem TEXT
+    );
+    CREATE INDEX IF NOT EXISTS checks_archive ON checks(archive_id);
+    CREATE TABLE IF NOT EXISTS contents (
+        archive_id INTEGER NOT NULL REFERENCES archives(id) ON DELETE CASCADE,
+        path TEXT NOT NULL,
+        offset INTEGER NOT NULL,
+        stored_size INTEGER NOT NULL,
+        sha256 TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS contents_archive ON contents(archive_id);
+";
+
+/// What a deep check found for one entry, as remembered by the cache.
+#[derive(Debug, Clone, Default)]
+pub struct CachedCheck {
+    /// SHA-256 of the stored (possibly compressed) bytes.
+    pub sha256: Option<String>,
+    pub problem: Option<String>,
+}
+
+/// Entries are told apart by path, offset and stored size.
+pub type CheckKey = (String, u64, u64);
+
+/// SHA-256 of entries' extracted content, by entry, as comparisons against
+/// local files use it.
+pub type ContentDigests = HashMap<CheckKey, String>;
+
+/// The key an entry's results are remembered under.
+pub fn check_key(entry: &FileEntry) -> CheckKey {
+    (entry.full_path(), entry.file_offset, entry.file_size)
+}
+
+/// Deep-check results and content digests kept on disk per archive, keyed by
+/// its path, size and modification time, so checking or comparing an
+/// unchanged archive again needs no decompression.
+#[cfg(feature = "sqlite")]
+pub struct VerifyCache {
+    db: Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl VerifyCache {
+    /// Opens the cache at `path`, creating it and its directory if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let db = Connection::open(path)?;
+        db.execute_batch("PRAGMA foreign_keys = ON;")?;
+        db.execute_batch(SCHEMA)?;
+        Ok(Self { db })
+    }
+
+    /// `$XDG_CACHE_HOME/cpk-tools/verify.sqlite`, falling back to `~/.cache`.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("cpk-tools").join("verify.sqlite"))
+    }
+
+    /// The check results stored for `archive`, or nothing if it changed since.
+    pub fn lookup(&self, archive: &Path) -> Result<HashMap<CheckKey, CachedCheck>> {
+        let (path, size, mtime) = archive_key(archive)?;
+        let mut statement = self.db.prepare(
+            "SELECT c.path, c.offset, c.stored_size, c.sha256, c.problem
+             FROM checks c JOIN archives a ON a.id = c.archive_id
+             WHERE a.path = ?1 AND a.size = ?2 AND a.mtime = ?3",
+        )?;
+        let rows = statement.query_map(params![path, size, mtime], |row| {
+            let key = (
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)? as u64,
+                row.get::<_, i64>(2)? as u64,
+            );
+            let check = CachedCheck {
+                sha256: row.get(3)?,
+                problem: row.get(4)?,
+            };
+            Ok((key, check))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Replaces the check results stored for `archive` with `checks`.
+    pub fn store(&mut self, archive: &Path, checks: &HashMap<CheckKey, CachedCheck>) -> Result<()> {
+        let tx = self.db.transaction()?;
+        let archive_id = current_archive(&tx, archive)?;
+        tx.execute(
+            "DELETE FROM checks WHERE archive_id = ?1",
+            params![archive_id],
+        )?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO checks (archive_id, path, offset, stored_size, sha256, problem)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for ((entry, offset, stored_size), check) in checks {
+                insert.execute(params![
+                    archive_id,
+                    entry,
+                    *offset as i64,
+                    *stored_size as i64,
+                    check.sha256,
+                    check.problem
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The content digests stored for `archive`, or nothing if it changed since.
+    pub fn contents(&self, archive: &Path) -> Result<ContentDigests> {
+        let (path, size, mtime) = archive_key(archive)?;
+        let mut statement = self.db.prepare(
+            "SELECT c.path, c.offset, c.stored_size, c.sha256
+             FROM contents c JOIN archives a ON a.id = c.archive_id
+             WHERE a.path = ?1 AND a.size = ?2 AND a.mtime = ?3",
+        )?;
+        let rows = statement.query_map(params![path, size, mtime], |row| {
+            let key = (
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)? as u64,
+                row.get::<_, i64>(2)? as u64,
+            );
+            Ok((key, row.get(3)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Replaces the content digests stored for `archive` with `digests`.
+    pub fn store_contents(&mut self, archive: &Path, digests: &ContentDigests) -> Result<()> {
+        let tx = self.db.transaction()?;
+        let archive_id = current_archive(&tx, archive)?;
+        tx.execute(
+            "DELETE FROM contents WHERE archive_id = ?1",
+            params![archive_id],
+        )?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO contents (archive_id, path, offset, stored_size, sha256)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for ((entry, offset, stored_size), sha256) in digests {
+                insert.execute(params![
+                    archive_id,
+                    entry,
+                    *offset as i64,
+                    *stored_size as i64,
+                    sha256
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// The row of `archive` as it is now. A row recorded for an earlier size or
+/// modification time is replaced, dropping everything stored for it.
+#[cfg(feature = "sqlite")]
+fn current_archive(tx: &Transaction, archive: &Path) -> Result<i64> {
+    let (path, size, mtime) = archive_key(archive)?;
+    let known: Option<i64> = tx
+        .query_row(
+            "SELECT id FROM archives WHERE path = ?1 AND size = ?2 AND mtime = ?3",
+            params![path, size, mtime],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(id) = known {
+        return Ok(id);
+    }
+    tx.execute("DELETE FROM archives WHERE path = ?1", params![path])?;
+    tx.execute(
+        "INSERT INTO archives (path, size, mtime) VALUES (?1, ?2, ?3)",
+        params![path, size, mtime],
+    )?;
+    Ok(tx.last_insert_rowid())
+}
+
+/// Canonical path, size and modification time (ns since the epoch) of an archive.
+pub(crate) fn archive_key(archive: &Path) -> Result<(String, i64, i64)> {
+    let path = std::fs::canonicalize(archive)?;
+    let metadata = std::fs::metadata(&path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |age| age.as_nanos() as i64);
+    Ok((
+        path.to_string_lossy().into_owned(),
+        metadata.len() as i64,
+        mtime,
+    ))
+}
@@ -0,0 +1,78 @@
+use crate::error::{CpkError, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Shared flag asking long-running operations to stop.
+///
+/// Extraction, replacement and packing check it between entries, so they stop
+/// at an entry boundary and never leave a half-written file behind.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Returns `CpkError::Cancelled` once cancellation has been requested.
+    pub fn check(&self) -> Result<()> {
+        match self.is_cancelled() {
+            true => Err(CpkError::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Wraps an existing flag, e.g. one set from a signal handler.
+impl From<Arc<AtomicBool>> for CancellationToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        Self { flag }
+    }
+}
+
+/// A `<path>.tmp` file that replaces `path` on `commit` and is removed otherwise.
+pub(crate) struct StagedFile {
+    temp_path: PathBuf,
+    final_path: PathBuf,
+    committed: bool,
+}
+
+impl StagedFile {
+    pub(crate) fn new(final_path: &Path) -> Self {
+        Self {
+            temp_path: PathBuf::from(format!("{}.tmp", final_path.display())),
+            final_path: final_path.to_path_buf(),
+            committed: false,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    pub(crate) fn commit(mut self) -> Result<()> {
+        fs::rename(&self.temp_path, &self.final_path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt;
+
+/// Kinds of asset recognised inside opaque entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+    Adx,
+    Hca,
+    Dds,
+    /// A standalone @UTF table, such as an ACB.
+    Utf,
+}
+
+impl AssetKind {
+    pub fn extension(self) -> &'static str {
+        match self {
+            AssetKind::Adx => "adx",
+            AssetKind::Hca => "hca",
+            AssetKind::Dds => "dds",
+            AssetKind::Utf => "utf",
+        }
+    }
+}
+
+impl fmt::Display for AssetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// An asset found inside a blob.
+#[derive(Debug, Clone)]
+pub struct CarvedAsset {
+    pub kind: AssetKind,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// Finds the ADX, HCA, DDS and @UTF assets embedded in `data`.
+///
+/// Sizes come from the asset's own header where the format records them;
+/// otherwise an asset runs up to the next one or the end of the data. Hits
+/// inside an asset of known size (an HCA inside an ACB, say) belong to it and
+/// aren't reported separately.
+pub fn carve(data: &[u8]) -> Vec<CarvedAsset> {
+    let mut hits: Vec<(usize, AssetKind, Option<usize>)> = Vec::new();
+    let mut pos = 0;
+    while pos + 4 <= data.len() {
+        let found = match data[pos] {
+            0x80 => adx_size(&data[pos..]).map(|size| (AssetKind::Adx, Some(size))),
+            b'H' | 0xC8 => hca_size(&data[pos..]).map(|size| (AssetKind::Hca, Some(size))),
+            b'D' => is_dds(&data[pos..]).then_some((AssetKind::Dds, None)),
+            b'@' => utf_size(&data[pos..]).map(|size| (AssetKind::Utf, Some(size))),
+            _ => None,
+        };
+        match found {
+            Some((kind, size)) => {
+                hits.push((pos, kind, size));
+                // Whatever a sized asset holds is part of it
+                pos = pos.saturating_add(size.unwrap_or(4).max(1));
+            }
+            None => pos += 1,
+        }
+    }
+
+    let mut assets = Vec::with_capacity(hits.len());
+    for (i, &(offset, kind, size)) in hits.iter().enumerate() {
+        let next = hits.get(i + 1).map_or(data.len(), |hit| hit.0);
+        let end = size.map_or(next, |size| offset.saturating_add(size).min(data.len()));
+        assets.push(CarvedAsset {
+            kind,
+            offset,
+            size: end - offset,
+        });
+    }
+    assets
+}
+
+/// Guesses a file extension from the first bytes of `data`: `hca`, `adx`,
+/// `usm`, `dds` or `utf`.
+pub fn sniff_extension(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"CRID") {
+        return Some("usm");
+    }
+    let kind = match data.first()? {
+        0x80 => adx_size(data).map(|_| AssetKind::Adx),
+        b'H' | 0xC8 => hca_size(data).map(|_| AssetKind::Hca),
+        b'D' => is_dds(data).then_some(AssetKind::Dds),
+        b'@' => data.starts_with(b"@UTF").then_some(AssetKind::Utf),
+        _ => None,
+    };
+    kind.map(AssetKind::extension)
+}
+
+fn be16(data: &[u8], at: usize) -> Option<usize> {
+    let bytes = data.get(at..at + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn be32(data: &[u8], at: usize) -> Option<usize> {
+    let bytes = data.get(at..at + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/// ADX: `80 00`, the offset of the "(c)CRI" notice ending the header, then
+/// block layout and sample count; an optional `80 01` footer follows the data.
+fn adx_size(data: &[u8]) -> Option<usize> {
+    if data.get(1) != Some(&0x00) {
+        return None;
+    }
+    let copyright = be16(data, 2)?;
+    if copyright < 6 || data.get(copyright - 2..copyright + 4)? != b"(c)CRI" {
+        return None;
+    }
+    let block_size = *data.get(5)? as usize;
+    let bit_depth = *data.get(6)? as usize;
+    let channels = *data.get(7)? as usize;
+    let samples = be32(data, 12)?;
+    if block_size < 3 || bit_depth == 0 || channels == 0 {
+        return None;
+    }
+
+    // A bit depth wider than the block leaves no room for a sample
+    let samples_per_block = (block_size - 2) * 8 / bit_depth;
+    if samples_per_block == 0 {
+        return None;
+    }
+    let mut size = samples
+        .div_ceil(samples_per_block)
+        .checked_mul(block_size)?
+        .checked_mul(channels)?
+        .checked_add(copyright + 4)?;
+    if data.get(size..size.checked_add(2)?) == Some(&[0x80, 0x01]) {
+        size = size.checked_add(4 + be16(data, size + 2).unwrap_or(0))?;
+    }
+    Some(size)
+}
+
+/// HCA: `HCA\0` (each byte may have its top bit set when the file is
+/// ciphered), header size, then a `fmt` chunk with the block count and a
+/// `comp` or `dec` chunk with the block size.
+fn hca_size(data: &[u8]) -> Option<usize> {
+    let unmasked = |at: usize| -> Option<[u8; 4]> {
+        let bytes = data.get(at..at + 4)?;
+        Some([
+            bytes[0] & 0x7F,
+            bytes[1] & 0x7F,
+            bytes[2] & 0x7F,
+            bytes[3] & 0x7F,
+        ])
+    };
+    if unmasked(0)? != *b"HCA\0" || unmasked(8)? != *b"fmt\0" {
+        return None;
+    }
+    let header_size = be16(data, 6)?;
+    let blocks = be32(data, 16)?;
+    let block_size = match &unmasked(24)? {
+        b"comp" | b"dec\0" => be16(data, 28)?,
+        _ => return None,
+    };
+    if header_size < 24 || block_size == 0 {
+        return None;
+    }
+    blocks.checked_mul(block_size)?.checked_add(header_size)
+}
+
+/// DDS: `DDS ` followed by the 124-byte header's own size.
+fn is_dds(data: &[u8]) -> bool {
+    data.starts_with(b"DDS ") && data.get(4..8) == Some(&124u32.to_le_bytes())
+}
+
+/// @UTF: the signature and the table size that follows it.
+fn utf_size(data: &[u8]) -> Option<usize> {
+    if !data.starts_with(b"@UTF") {
+        return None;
+    }
+    let size = 8 + be32(data, 4)?;
+    (size <= data.len()).then_some(size)
+}
@@ -0,0 +1,157 @@
+use crate::compression::{
+    compress_crilayla, decompress_crilayla_into, decompress_crilayla_lenient_into,
+};
+use crate::error::{CpkError, Result};
+use log::debug;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// A compression scheme entries can be stored with.
+///
+/// Extraction asks each registered codec in turn whether it recognises an
+/// entry's stored bytes; packing compresses with the codec it's given.
+pub trait Codec: Debug + Send + Sync {
+    /// Short lowercase name, e.g. `crilayla`.
+    fn name(&self) -> &'static str;
+
+    /// Whether `data`, as stored in the archive, is in this codec's format.
+    fn detect(&self, data: &[u8]) -> bool;
+
+    /// Decodes `data` into `out`, replacing its contents.
+    fn decompress(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()>;
+
+    /// Like `decompress`, making up what damaged data doesn't hold instead of
+    /// failing, and returning how many bytes were made up. Codecs without a
+    /// lenient mode decode as `decompress` does.
+    fn decompress_lenient(&self, data: &[u8], out: &mut Vec<u8>) -> Result<u64> {
+        self.decompress(data, out).map(|_| 0)
+    }
+
+    /// Checks the header of `data` against the `extract_size` the tables
+    /// give, before decoding it. A header that can't be decoded is an error;
+    /// a mismatch decoding can get past is returned as a warning.
+    fn validate(&self, _data: &[u8], _extract_size: Option<u64>) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Inputs shorter than this are stored as they are: compressing them
+    /// can't make them smaller.
+    fn min_input_len(&self) -> usize {
+        1
+    }
+}
+
+/// CRI's LZ scheme, recognised by its `CRILAYLA` signature.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Crilayla;
+
+impl Codec for Crilayla {
+    fn name(&self) -> &'static str {
+        "crilayla"
+    }
+
+    fn detect(&self, data: &[u8]) -> bool {
+        data.starts_with(b"CRILAYLA")
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        decompress_crilayla_into(data, out)
+    }
+
+    fn decompress_lenient(&self, data: &[u8], out: &mut Vec<u8>) -> Result<u64> {
+        decompress_crilayla_lenient_into(data, out)
+    }
+
+    fn validate(&self, data: &[u8], extract_size: Option<u64>) -> Result<Option<String>> {
+        if data.len() < 16 {
+            return Ok(None);
+        }
+        let uncompressed_size = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as u64;
+        let header_offset = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as u64;
+        debug!(
+            "CRILAYLA header: uncompressed_size={}, header_offset={}",
+            uncompressed_size, header_offset
+        );
+
+        if header_offset + 0x110 > data.len() as u64 {
+            return Err(CpkError::Compression(format!(
+                "Invalid CRILAYLA header: header_offset={} + 0x110 > data.len()={}",
+                header_offset,
+                data.len()
+            )));
+        }
+        // The raw prefix isn't counted in the header's size
+        Ok(extract_size
+            .filter(|&size| uncompressed_size + PREFIX_LEN as u64 != size)
+            .map(|size| {
+                format!(
+                    "CRILAYLA uncompressed size mismatch: header says {}, extract_size is {}",
+                    uncompressed_size + PREFIX_LEN as u64,
+                    size
+                )
+            }))
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        compress_crilayla(data)
+    }
+
+    /// Only what follows the raw prefix is compressed.
+    fn min_input_len(&self) -> usize {
+        PREFIX_LEN + 1
+    }
+}
+
+/// Bytes at the start of the content CRILAYLA keeps uncompressed, after the
+/// compressed data.
+const PREFIX_LEN: usize = 0x100;
+
+/// The codecs extraction recognises, asked in the order they were registered.
+#[derive(Debug, Clone)]
+pub struct CodecRegistry {
+    codecs: Vec<Arc<dyn Codec>>,
+}
+
+impl Default for CodecRegistry {
+    /// A registry holding CRILAYLA only.
+    fn default() -> Self {
+        Self {
+            codecs: vec![Arc::new(Crilayla)],
+        }
+    }
+}
+
+impl CodecRegistry {
+    /// A registry recognising nothing, so every entry is extracted as stored.
+    pub fn empty() -> Self {
+        Self { codecs: Vec::new() }
+    }
+
+    /// Adds `codec`, replacing a registered one with the same name in its place.
+    pub fn register(&mut self, codec: Arc<dyn Codec>) {
+        match self.codecs.iter_mut().find(|c| c.name() == codec.name()) {
+            Some(existing) => *existing = codec,
+            None => self.codecs.push(codec),
+        }
+    }
+
+    /// The codec new entries are compressed with, the first registered.
+    pub fn compressor(&self) -> Option<&Arc<dyn Codec>> {
+        self.codecs.first()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Codec>> {
+        self.codecs.iter().find(|c| c.name() == name)
+    }
+
+    /// The first codec recognising `data`.
+    pub fn detect(&self, data: &[u8]) -> Option<&Arc<dyn Codec>> {
+        self.codecs.iter().find(|c| c.detect(data))
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.codecs.iter().map(|c| c.name()).collect()
+    }
+}
@@ -1,9 +1,29 @@
+use crate::codec::Codec;
 use crate::endian::EndianReader;
 use crate::error::{CpkError, Result};
 use log::debug;
-use std::io::Cursor;
+use std::io::{Cursor, Read, Seek, SeekFrom};
 
 pub fn decompress_crilayla(input: &[u8]) -> Result<Vec<u8>> {
+    let mut result = Vec::new();
+    decompress_crilayla_into(input, &mut result)?;
+    Ok(result)
+}
+
+/// Like `decompress_crilayla`, decoding into `result` so its allocation can be
+/// reused across entries.
+pub fn decompress_crilayla_into(input: &[u8], result: &mut Vec<u8>) -> Result<()> {
+    decode_crilayla(input, result, false).map(|_| ())
+}
+
+/// Like `decompress_crilayla_into`, but backreferences reaching past the end of
+/// the output produce zero bytes instead of an error, for salvaging what a
+/// damaged stream still holds. Returns how many bytes were filled in that way.
+pub fn decompress_crilayla_lenient_into(input: &[u8], result: &mut Vec<u8>) -> Result<u64> {
+    decode_crilayla(input, result, true)
+}
+
+fn decode_crilayla(input: &[u8], result: &mut Vec<u8>, lenient: bool) -> Result<u64> {
     if input.len() < 16 {
         return Err(CpkError::Compression(
             "Input too short for CRILAYLA".to_string(),
@@ -40,7 +60,8 @@ pub fn decompress_crilayla(input: &[u8]) -> Result<Vec<u8>> {
         )));
     }
 
-    let mut result = vec![0u8; uncompressed_size + 0x100];
+    result.clear();
+    result.resize(uncompressed_size + 0x100, 0);
 
     // Copy uncompressed 0x100 header to start of file
     result[0..0x100].copy_from_slice(
@@ -62,6 +83,7 @@ pub fn decompress_crilayla(input: &[u8]) -> Result<Vec<u8>> {
     let mut bit_pool: u8 = 0;
     let mut bits_left = 0i32;
     let mut bytes_output = 0i32;
+    let mut faked = 0u64;
 
     let vle_lens = [2usize, 3, 5, 8];
 
@@ -100,7 +122,7 @@ pub fn decompress_crilayla(input: &[u8]) -> Result<Vec<u8>> {
                 backreference_length += this_level;
 
                 // If this level is not max, we stop and won't use the extension loop
-                if this_level != ((1 << vle_lens[level]) - 1) as i32 {
+                if this_level != (1 << vle_lens[level]) - 1 {
                     used_all_levels = false;
                     break;
                 }
@@ -126,7 +148,9 @@ pub fn decompress_crilayla(input: &[u8]) -> Result<Vec<u8>> {
                 offset_bits, backreference_length, bytes_output, backreference_offset
             );
 
-            if backreference_offset < 0 || backreference_offset as usize >= result.len() {
+            if !lenient
+                && (backreference_offset < 0 || backreference_offset as usize >= result.len())
+            {
                 return Err(CpkError::Compression(format!(
                     "Invalid backreference offset: {} (buffer size: {}, output_end: {}, bytes_output: {}, offset_bits: {})",
                     backreference_offset,
@@ -152,7 +176,13 @@ pub fn decompress_crilayla(input: &[u8]) -> Result<Vec<u8>> {
                     )));
                 }
 
-                result[output_pos] = result[backreference_offset as usize];
+                result[output_pos] = match usize::try_from(backreference_offset) {
+                    Ok(source) if source < result.len() => result[source],
+                    _ => {
+                        faked += 1;
+                        0
+                    }
+                };
                 backreference_offset -= 1;
                 bytes_output += 1;
             }
@@ -180,7 +210,7 @@ pub fn decompress_crilayla(input: &[u8]) -> Result<Vec<u8>> {
         bytes_output, uncompressed_size
     );
 
-    Ok(result)
+    Ok(faked)
 }
 
 // get_next_bits is assumed to exist in this module with the same signature as in your code.
@@ -226,3 +256,739 @@ fn get_next_bits(
 
     Ok(out_bits)
 }
+
+/// Fully decodes an in-memory CRILAYLA stream and checks that it terminates cleanly.
+///
+/// Unlike `decompress_crilayla`, which tolerates streams that overrun or leave
+/// data unread, any such irregularity is reported as an error.
+pub fn check_crilayla(input: &[u8]) -> Result<u64> {
+    if input.len() < 0x10 {
+        return Err(CpkError::Compression(
+            "Input too short for CRILAYLA".to_string(),
+        ));
+    }
+    let header_offset = u32::from_le_bytes(input[12..16].try_into().unwrap()) as usize;
+    if header_offset + 0x110 != input.len() {
+        return Err(CpkError::Compression(format!(
+            "Stream is {} bytes but its header describes {}",
+            input.len(),
+            header_offset + 0x110
+        )));
+    }
+
+    CrilaylaDecoder::new(Cursor::new(input))?.check()
+}
+
+/// Output bytes between decoder checkpoints.
+const CHECKPOINT_INTERVAL: usize = 1024 * 1024;
+
+/// Backreferences reach at most 0x1FFF + 3 bytes past the byte being written.
+const WINDOW_SIZE: usize = 0x4000;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+
+/// Compressed input is read backwards from the source in blocks of this size.
+const INPUT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Streaming CRILAYLA decompressor.
+///
+/// CRILAYLA encodes its output last byte first, so the first byte of the file is
+/// only known once the whole stream has been decoded. Rather than holding the
+/// full output, the decoder makes one pass over the stream recording a
+/// checkpoint (bit position plus the backreference window) every
+/// `CHECKPOINT_INTERVAL` output bytes, and then regenerates each block on
+/// demand from the checkpoint above it. Memory use is bounded by the window,
+/// the checkpoints and one block, at the cost of decoding the stream twice.
+pub struct CrilaylaDecoder<R> {
+    input: BackwardInput<R>,
+    prefix: Vec<u8>,
+    uncompressed_size: usize,
+    checkpoints: Vec<DecodeState>,
+    block: Vec<u8>,
+    block_index: Option<usize>,
+    position: u64,
+}
+
+impl<R: Read + Seek> CrilaylaDecoder<R> {
+    /// Wraps a source positioned at the start of a CRILAYLA stream.
+    pub fn new(mut source: R) -> Result<Self> {
+        let base = source.stream_position()?;
+
+        let mut header = [0u8; 16];
+        source.read_exact(&mut header)?;
+        if &header[0..8] != b"CRILAYLA" {
+            return Err(CpkError::Compression(
+                "Missing CRILAYLA signature".to_string(),
+            ));
+        }
+        let uncompressed_size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let header_offset = u32::from_le_bytes(header[12..16].try_into().unwrap()) as u64;
+
+        // The raw 0x100 bytes sit behind the compressed stream
+        let mut prefix = vec![0u8; 0x100];
+        source.seek(SeekFrom::Start(base + 0x10 + header_offset))?;
+        source.read_exact(&mut prefix)?;
+
+        debug!(
+            "CRILAYLA stream: uncompressed_size={}, header_offset={}",
+            uncompressed_size, header_offset
+        );
+
+        Ok(Self {
+            input: BackwardInput::new(source, base, 0x10 + header_offset),
+            prefix,
+            uncompressed_size,
+            checkpoints: Vec::new(),
+            block: Vec::new(),
+            block_index: None,
+            position: 0,
+        })
+    }
+
+    /// Total size of the decompressed data, including the raw 0x100-byte prefix.
+    pub fn decompressed_len(&self) -> u64 {
+        (self.uncompressed_size + 0x100) as u64
+    }
+
+    /// Decodes the whole stream without keeping the output, checking that it
+    /// produces exactly the declared size and consumes the entire bitstream.
+    ///
+    /// Returns the decompressed size, including the raw prefix.
+    pub fn check(&mut self) -> Result<u64> {
+        let mut state = DecodeState::new(self.uncompressed_size);
+        state.decode(&mut self.input, 0, None)?;
+
+        if let Some((_, left)) = state.pending {
+            return Err(CpkError::Compression(format!(
+                "Backreference runs {} bytes past the start of the output",
+                left
+            )));
+        }
+
+        // Bytes between the 16-byte header and the first bit read; the encoder pads to 4
+        let unused = self.input.len as i64 + state.input_offset + 1 - 0x10;
+        if unused > 3 {
+            return Err(CpkError::Compression(format!(
+                "Stream ended with {} bytes of compressed data unread",
+                unused
+            )));
+        }
+
+        Ok(self.decompressed_len())
+    }
+
+    /// Decodes the whole stream once, recording where each block starts.
+    fn index(&mut self) -> Result<()> {
+        let blocks = self.uncompressed_size.div_ceil(CHECKPOINT_INTERVAL);
+        let mut checkpoints = vec![DecodeState::new(self.uncompressed_size); blocks];
+
+        let mut state = DecodeState::new(self.uncompressed_size);
+        for k in (0..blocks).rev() {
+            checkpoints[k] = state.clone();
+            state.decode(&mut self.input, k * CHECKPOINT_INTERVAL, None)?;
+        }
+
+        self.checkpoints = checkpoints;
+        Ok(())
+    }
+
+    fn load_block(&mut self, k: usize) -> Result<()> {
+        if self.checkpoints.is_empty() {
+            self.index()?;
+        }
+
+        let start = k * CHECKPOINT_INTERVAL;
+        let end = (start + CHECKPOINT_INTERVAL).min(self.uncompressed_size);
+        self.block.resize(end - start, 0);
+
+        let mut state = self.checkpoints[k].clone();
+        state.decode(&mut self.input, start, Some(&mut self.block))?;
+        self.block_index = Some(k);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for CrilaylaDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let position = self.position as usize;
+        if buf.is_empty() || position >= self.uncompressed_size + 0x100 {
+            return Ok(0);
+        }
+
+        let copied = if position < 0x100 {
+            let n = buf.len().min(0x100 - position);
+            buf[..n].copy_from_slice(&self.prefix[position..position + n]);
+            n
+        } else {
+            let offset = position - 0x100;
+            let k = offset / CHECKPOINT_INTERVAL;
+            if self.block_index != Some(k) {
+                self.load_block(k).map_err(std::io::Error::other)?;
+            }
+            let in_block = offset - k * CHECKPOINT_INTERVAL;
+            let n = buf.len().min(self.block.len() - in_block);
+            buf[..n].copy_from_slice(&self.block[in_block..in_block + n]);
+            n
+        };
+
+        self.position += copied as u64;
+        Ok(copied)
+    }
+}
+
+/// Resumable decoder state; positions are relative to the end of the 0x100 prefix.
+#[derive(Clone)]
+struct DecodeState {
+    input_offset: i64,
+    bit_pool: u8,
+    bits_left: u32,
+    size: usize,
+    // Number of bytes still to be produced, i.e. one past the next output position
+    remaining: usize,
+    // A backreference interrupted at a block boundary: (source position, bytes left)
+    pending: Option<(usize, usize)>,
+    window: Box<[u8]>,
+}
+
+impl DecodeState {
+    fn new(uncompressed_size: usize) -> Self {
+        Self {
+            input_offset: -1,
+            bit_pool: 0,
+            bits_left: 0,
+            size: uncompressed_size,
+            remaining: uncompressed_size,
+            pending: None,
+            window: vec![0u8; WINDOW_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Decodes downwards until `stop` bytes remain, storing output at `out[pos - stop]`.
+    fn decode<R: Read + Seek>(
+        &mut self,
+        input: &mut BackwardInput<R>,
+        stop: usize,
+        mut out: Option<&mut Vec<u8>>,
+    ) -> Result<()> {
+        while self.remaining > stop {
+            if let Some((source, left)) = self.pending.take() {
+                let pos = self.remaining - 1;
+                let byte = self.window[source & WINDOW_MASK];
+                self.emit(pos, byte, stop, &mut out);
+                if left > 1 {
+                    self.pending = Some((source - 1, left - 1));
+                }
+                continue;
+            }
+
+            if self.next_bits(input, 1)? == 0 {
+                let byte = self.next_bits(input, 8)? as u8;
+                let pos = self.remaining - 1;
+                self.emit(pos, byte, stop, &mut out);
+                continue;
+            }
+
+            let offset_bits = self.next_bits(input, 13)? as usize;
+            let mut length = 3usize;
+            let mut used_all_levels = true;
+            for bits in [2u32, 3, 5, 8] {
+                let level = self.next_bits(input, bits)? as usize;
+                length += level;
+                if level != (1 << bits) - 1 {
+                    used_all_levels = false;
+                    break;
+                }
+            }
+            if used_all_levels {
+                loop {
+                    let extra = self.next_bits(input, 8)? as usize;
+                    length += extra;
+                    if extra != 255 {
+                        break;
+                    }
+                }
+            }
+
+            let source = self.remaining - 1 + offset_bits + 3;
+            if source >= self.size {
+                return Err(CpkError::Compression(format!(
+                    "Invalid backreference offset {} at output position {}",
+                    source,
+                    self.remaining - 1
+                )));
+            }
+            self.pending = Some((source, length));
+        }
+
+        Ok(())
+    }
+
+    fn emit(&mut self, pos: usize, byte: u8, stop: usize, out: &mut Option<&mut Vec<u8>>) {
+        self.window[pos & WINDOW_MASK] = byte;
+        if let Some(out) = out {
+            out[pos - stop] = byte;
+        }
+        self.remaining -= 1;
+    }
+
+    fn next_bits<R: Read + Seek>(
+        &mut self,
+        input: &mut BackwardInput<R>,
+        bit_count: u32,
+    ) -> Result<u16> {
+        let mut out_bits = 0u16;
+        let mut produced = 0u32;
+
+        while produced < bit_count {
+            if self.bits_left == 0 {
+                self.bit_pool = input.byte_at(self.input_offset)?;
+                self.bits_left = 8;
+                self.input_offset -= 1;
+            }
+
+            let take = self.bits_left.min(bit_count - produced);
+            let mask = ((1u32 << take) - 1) as u8;
+            out_bits =
+                (out_bits << take) | ((self.bit_pool >> (self.bits_left - take)) & mask) as u16;
+            self.bits_left -= take;
+            produced += take;
+        }
+
+        Ok(out_bits)
+    }
+}
+
+/// Random access to the compressed stream, cached in blocks read back to front.
+struct BackwardInput<R> {
+    source: R,
+    base: u64,
+    len: u64,
+    buffer: Vec<u8>,
+    buffer_start: u64,
+}
+
+impl<R: Read + Seek> BackwardInput<R> {
+    fn new(source: R, base: u64, len: u64) -> Self {
+        Self {
+            source,
+            base,
+            len,
+            buffer: Vec::new(),
+            buffer_start: 0,
+        }
+    }
+
+    /// Reads the byte at `offset`; negative offsets count back from the end of the stream.
+    fn byte_at(&mut self, offset: i64) -> Result<u8> {
+        let index = self.len as i64 + offset;
+        if index < 0 {
+            return Err(CpkError::Compression(
+                "CRILAYLA stream ended before all output was produced".to_string(),
+            ));
+        }
+        let index = index as u64;
+
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        if index < self.buffer_start || index >= buffer_end {
+            let start = (index + 1).saturating_sub(INPUT_BLOCK_SIZE as u64);
+            self.buffer.resize((index + 1 - start) as usize, 0);
+            self.source.seek(SeekFrom::Start(self.base + start))?;
+            self.source.read_exact(&mut self.buffer)?;
+            self.buffer_start = start;
+        }
+
+        Ok(self.buffer[(index - self.buffer_start) as usize])
+    }
+}
+
+/// Backreference distances are stored as `distance - 3` in 13 bits.
+const MIN_DISTANCE: usize = 3;
+const MAX_DISTANCE: usize = 0x1FFF + MIN_DISTANCE;
+const MIN_MATCH: usize = 3;
+
+const HASH_BITS: u32 = 15;
+const CHAIN_SIZE: usize = 0x4000;
+const CHAIN_MASK: usize = CHAIN_SIZE - 1;
+
+/// Candidates examined per position; bounds the worst case on repetitive data.
+const MAX_CHAIN: usize = 256;
+/// Matches at least this long are taken without looking for a better one at the next byte.
+const NICE_LENGTH: usize = 258;
+
+/// Compresses `input` into a CRILAYLA stream.
+///
+/// The first 0x100 bytes are stored raw as the format requires; the rest is
+/// LZ-compressed back to front. Matches are found through hash chains over the
+/// 13-bit window, with one step of lazy evaluation, so the result is close to
+/// what CRI's own packer produces.
+pub fn compress_crilayla(input: &[u8]) -> Result<Vec<u8>> {
+    if input.len() < 0x100 {
+        return Err(CpkError::Compression(format!(
+            "Input too short for CRILAYLA ({} bytes, at least 0x100 needed)",
+            input.len()
+        )));
+    }
+
+    // The decoder produces the file from its last byte, so match on the reversed data
+    let (prefix, body) = input.split_at(0x100);
+    let data: Vec<u8> = body.iter().rev().copied().collect();
+
+    let mut finder = MatchFinder::new(&data);
+    let mut bits = BitWriter::default();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let (mut length, distance) = finder.find(pos);
+
+        if (MIN_MATCH..NICE_LENGTH).contains(&length) && pos + 1 < data.len() {
+            finder.insert(pos);
+            let (next_length, _) = finder.find(pos + 1);
+            if next_length > length {
+                // A longer match starts one byte later; emit this byte as a literal
+                length = 0;
+            }
+        } else {
+            finder.insert(pos);
+        }
+
+        if length < MIN_MATCH {
+            bits.put(0, 1);
+            bits.put(data[pos] as u32, 8);
+            pos += 1;
+            continue;
+        }
+
+        bits.put(1, 1);
+        bits.put((distance - MIN_DISTANCE) as u32, 13);
+        put_length(&mut bits, length - MIN_MATCH);
+
+        for p in pos + 1..pos + length {
+            finder.insert(p);
+        }
+        pos += length;
+    }
+
+    let mut stream = bits.finish();
+    stream.reverse();
+    // Pad at the front so the raw prefix stays 4-byte aligned
+    let padding = (4 - stream.len() % 4) % 4;
+
+    let mut output = Vec::with_capacity(0x10 + padding + stream.len() + 0x100);
+    output.extend_from_slice(b"CRILAYLA");
+    output.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    output.extend_from_slice(&((padding + stream.len()) as u32).to_le_bytes());
+    output.resize(output.len() + padding, 0);
+    output.extend_from_slice(&stream);
+    output.extend_from_slice(prefix);
+
+    debug!(
+        "CRILAYLA: compressed {} bytes to {}",
+        input.len(),
+        output.len()
+    );
+    Ok(output)
+}
+
+/// Writes a match length (minus 3) using CRILAYLA's 2/3/5/8-bit levels and 8-bit extension.
+fn put_length(bits: &mut BitWriter, mut remaining: usize) {
+    for level_bits in [2u32, 3, 5, 8] {
+        let max = (1usize << level_bits) - 1;
+        let value = remaining.min(max);
+        bits.put(value as u32, level_bits);
+        remaining -= value;
+        if value != max {
+            return;
+        }
+    }
+    loop {
+        let value = remaining.min(255);
+        bits.put(value as u32, 8);
+        remaining -= value;
+        if value != 255 {
+            return;
+        }
+    }
+}
+
+/// Hash chains indexing every position of the (reversed) input by its next three bytes.
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    inserted: usize,
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; CHAIN_SIZE],
+            inserted: 0,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> Option<usize> {
+        let bytes = self.data.get(pos..pos + MIN_MATCH)?;
+        let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        Some((value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize)
+    }
+
+    /// Adds `pos` to its chain; positions are inserted once each, in order.
+    fn insert(&mut self, pos: usize) {
+        if pos < self.inserted {
+            return;
+        }
+        if let Some(hash) = self.hash(pos) {
+            self.prev[pos & CHAIN_MASK] = self.head[hash];
+            self.head[hash] = pos;
+        }
+        self.inserted = pos + 1;
+    }
+
+    /// Returns the longest (length, distance) match for `pos` among earlier positions.
+    fn find(&self, pos: usize) -> (usize, usize) {
+        let Some(hash) = self.hash(pos) else {
+            return (0, 0);
+        };
+
+        let limit = self.data.len() - pos;
+        let (mut best_length, mut best_distance) = (0, 0);
+        let mut candidate = self.head[hash];
+
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX {
+                break;
+            }
+            let distance = pos - candidate;
+            if distance > MAX_DISTANCE {
+                break;
+            }
+
+            if distance >= MIN_DISTANCE {
+                let length = self.data[candidate..]
+                    .iter()
+                    .zip(&self.data[pos..pos + limit])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = distance;
+                    if length >= NICE_LENGTH || length == limit {
+                        break;
+                    }
+                }
+            }
+
+            // The chain is a ring; a newer position in the slot means the chain has ended
+            let next = self.prev[candidate & CHAIN_MASK];
+            if next == usize::MAX || next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+
+        (best_length, best_distance)
+    }
+}
+
+/// MSB-first bit packer.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pool: u8,
+    used: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.pool = (self.pool << 1) | ((value >> i) & 1) as u8;
+            self.used += 1;
+            if self.used == 8 {
+                self.bytes.push(self.pool);
+                self.pool = 0;
+                self.used = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.used > 0 {
+            self.bytes.push(self.pool << (8 - self.used));
+        }
+        self.bytes
+    }
+}
+
+/// Entry content in the form it is written to an archive.
+#[derive(Debug, Clone)]
+pub struct StoredData {
+    pub data: Vec<u8>,
+    /// Size of the content once extracted; equals `data.len()` when stored raw.
+    pub extract_size: u64,
+    /// CRC-32 of the extracted content.
+    pub crc: u32,
+}
+
+impl StoredData {
+    pub fn raw(data: Vec<u8>) -> Self {
+        let extract_size = data.len() as u64;
+        let crc = crc32(&data);
+        Self {
+            data,
+            extract_size,
+            crc,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.data.len() as u64 != self.extract_size
+    }
+}
+
+/// Decides whether entries are written CRILAYLA-compressed or raw.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionPolicy {
+    pub enabled: bool,
+    /// Smallest fraction of the size compression must save for the result to be kept.
+    pub min_saving: f64,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_saving: 0.05,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    /// Compresses `data` with `codec` if enabled, falling back to storing it
+    /// raw when compression doesn't pay off, as CRI's packer does.
+    pub fn store(&self, codec: &dyn Codec, data: Vec<u8>) -> Result<StoredData> {
+        if !self.enabled || data.len() < codec.min_input_len() {
+            return Ok(StoredData::raw(data));
+        }
+
+        let compressed = codec.compress(&data)?;
+        let limit = data.len() as f64 * (1.0 - self.min_saving);
+        if compressed.len() as f64 > limit {
+            debug!(
+                "{}: storing {} bytes raw, compression only reached {}",
+                codec.name(),
+                data.len(),
+                compressed.len()
+            );
+            return Ok(StoredData::raw(data));
+        }
+
+        Ok(StoredData {
+            extract_size: data.len() as u64,
+            crc: crc32(&data),
+            data: compressed,
+        })
+    }
+}
+
+/// CRC-32 (the zlib variant) of extracted content, as kept in a TOC's CRC column.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Repetitive text, which compresses well, of `len` bytes.
+    fn text(len: usize) -> Vec<u8> {
+        b"alpha bravo charlie delta "
+            .iter()
+            .copied()
+            .cycle()
+            .take(len)
+            .collect()
+    }
+
+    /// Pseudo-random bytes, which don't compress, of `len` bytes.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crilayla_round_trips() {
+        for data in [text(0x100), text(0x5123), noise(0x3000), noise(0x101)] {
+            let compressed = compress_crilayla(&data).unwrap();
+            assert!(compressed.starts_with(b"CRILAYLA"));
+            assert_eq!(decompress_crilayla(&compressed).unwrap(), data);
+            assert_eq!(check_crilayla(&compressed).unwrap(), data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn crilayla_needs_its_raw_prefix() {
+        assert!(compress_crilayla(&text(0xFF)).is_err());
+        assert!(decompress_crilayla(b"CRILAYLA").is_err());
+    }
+
+    #[test]
+    fn policy_keeps_compression_only_when_it_pays() {
+        let policy = CompressionPolicy {
+            enabled: true,
+            ..Default::default()
+        };
+        let codec = crate::codec::Crilayla;
+        let stored = policy.store(&codec, text(0x2000)).unwrap();
+        assert!(stored.is_compressed());
+        assert_eq!(stored.extract_size, 0x2000);
+        assert_eq!(stored.crc, crc32(&text(0x2000)));
+
+        let stored = policy.store(&codec, noise(0x2000)).unwrap();
+        assert!(!stored.is_compressed());
+        assert_eq!(stored.data, noise(0x2000));
+
+        let disabled = CompressionPolicy::default();
+        assert!(
+            !disabled
+                .store(&codec, text(0x2000))
+                .unwrap()
+                .is_compressed()
+        );
+    }
+
+    #[test]
+    fn lenient_decoding_zero_fills_what_a_damaged_stream_lost() {
+        let data = text(0x2000);
+        let compressed = compress_crilayla(&data).unwrap();
+        let mut output = Vec::new();
+        let faked = decompress_crilayla_lenient_into(&compressed, &mut output).unwrap();
+        assert_eq!((faked, &output), (0, &data));
+
+        // Flip each byte of the LZ stream in turn; some damage sends a
+        // backreference past the end of the output
+        let mut salvaged = 0;
+        for i in 0x10..compressed.len() - 0x100 {
+            let mut damaged = compressed.clone();
+            damaged[i] ^= 0xFF;
+            if decompress_crilayla(&damaged).is_ok() {
+                continue;
+            }
+            if let Ok(faked) = decompress_crilayla_lenient_into(&damaged, &mut output) {
+                assert!(faked > 0);
+                assert_eq!(output.len(), data.len());
+                salvaged += 1;
+            }
+        }
+        assert!(salvaged > 0);
+    }
+}
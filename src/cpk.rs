@@ -1,15 +1,33 @@
-use crate::compression::decompress_crilayla;
+use crate::cancel::{CancellationToken, StagedFile};
+use crate::carve;
+use crate::codec::CodecRegistry;
+use crate::compression::{CompressionPolicy, StoredData};
+use crate::crypt::{Cipher, CipherRegistry, XorStream};
+use crate::diagnostic::Diagnostic;
+use crate::digest::HashAlgorithm;
 use crate::endian::EndianReader;
 use crate::error::{CpkError, Result};
-use crate::utf::{CellValue, Utf};
+use crate::escape::{self, NameEscape};
+use crate::filter::EntryFilter;
+use crate::journal::ExtractJournal;
+use crate::options::ParseOptions;
+use crate::pread;
+use crate::process::PostProcessor;
+use crate::replaced;
+use crate::rollback::{self, Rollback};
+use crate::sparse;
+use crate::utf::{Cell, CellValue, Column, Utf};
 use log::{debug, info, warn};
-use std::collections::HashMap;
-use std::fs::{File, create_dir_all};
-use std::io::{BufReader, Read, Seek, SeekFrom};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug, Clone)]
 pub struct FileEntry {
+    /// `None` when the table has no DirName column, `Some("")` for an empty DirName.
     pub dir_name: Option<String>,
     pub file_name: String,
     pub file_size: u64,
@@ -19,14 +37,26 @@ pub struct FileEntry {
     pub file_offset: u64,
     pub file_offset_pos: u64,
     pub id: Option<u32>,
+    pub row: Option<u32>,
+    /// CRC-32 of the extracted content, from the TOC's CRC column.
+    pub crc: Option<u32>,
     pub user_string: Option<String>,
     pub local_dir: Option<String>,
+    /// Last update time from the ETOC's UpdateDateTime column, still packed;
+    /// `updated_at` decodes it.
+    pub updated: Option<u64>,
     pub toc_name: String,
     pub file_type: String,
     pub encrypted: bool,
     pub offset: u64,
 }
 
+impl Default for FileEntry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FileEntry {
     pub fn new() -> Self {
         Self {
@@ -39,14 +69,309 @@ impl FileEntry {
             file_offset: 0,
             file_offset_pos: 0,
             id: None,
+            row: None,
+            crc: None,
             user_string: None,
             local_dir: None,
+            updated: None,
             toc_name: String::new(),
             file_type: String::new(),
             encrypted: false,
             offset: 0,
         }
     }
+
+    /// Archive path of the entry (`DirName/FileName`, or just `FileName` at the root).
+    pub fn full_path(&self) -> String {
+        match self.dir() {
+            Some(dir) => format!("{}/{}", dir, self.file_name),
+            None => self.file_name.clone(),
+        }
+    }
+
+    /// The directory part of the path; both an empty and an absent DirName mean the root.
+    pub fn dir(&self) -> Option<&str> {
+        self.dir_name.as_deref().filter(|dir| !dir.is_empty())
+    }
+
+    /// The entry's last update time from the ETOC, or `None` without one or
+    /// when it isn't a valid date.
+    ///
+    /// CRI packs it big-endian as year (16 bits), month, day, hour, minute and
+    /// second (8 bits each), with the low byte unused.
+    #[cfg(feature = "chrono")]
+    pub fn updated_at(&self) -> Option<chrono::NaiveDateTime> {
+        let packed = self.updated?;
+        let field = |shift: u32| ((packed >> shift) & 0xFF) as u32;
+        chrono::NaiveDate::from_ymd_opt((packed >> 48) as i32, field(40), field(32))?.and_hms_opt(
+            field(24),
+            field(16),
+            field(8),
+        )
+    }
+}
+
+/// A file stored in an archive, as returned by `Cpk::entries`, without the
+/// pseudo-rows (`CPK_HDR`, `TOC_HDR`, `CONTENT_OFFSET`, ...) `file_table` also holds.
+#[derive(Debug, Clone, Copy)]
+pub struct CpkFile<'a> {
+    entry: &'a FileEntry,
+}
+
+impl<'a> CpkFile<'a> {
+    /// Full archive path, or the zero-padded ID for ITOC-only entries.
+    pub fn path(&self) -> String {
+        self.entry.full_path()
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        self.entry.id
+    }
+
+    /// Size of the data as stored in the archive.
+    pub fn stored_size(&self) -> u64 {
+        self.entry.file_size
+    }
+
+    /// Size once extracted, the stored size when the table doesn't say.
+    pub fn extracted_size(&self) -> u64 {
+        self.entry.extract_size.unwrap_or(self.entry.file_size)
+    }
+
+    /// Whether the data is stored compressed, i.e. smaller than it extracts to.
+    pub fn is_compressed(&self) -> bool {
+        self.stored_size() < self.extracted_size()
+    }
+
+    /// Absolute offset of the stored data in the archive file.
+    pub fn file_offset(&self) -> u64 {
+        self.entry.file_offset
+    }
+
+    /// CRC-32 of the extracted content, when the TOC has a CRC column.
+    pub fn crc(&self) -> Option<u32> {
+        self.entry.crc
+    }
+
+    pub fn user_string(&self) -> Option<&'a str> {
+        self.entry.user_string.as_deref()
+    }
+
+    /// Packed ETOC update time; see `FileEntry::updated_at`.
+    pub fn updated(&self) -> Option<u64> {
+        self.entry.updated
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn updated_at(&self) -> Option<chrono::NaiveDateTime> {
+        self.entry.updated_at()
+    }
+
+    /// The underlying table row, for reading the entry or fields not wrapped here.
+    pub fn entry(&self) -> &'a FileEntry {
+        self.entry
+    }
+}
+
+/// Outcome of extracting one entry, reported by `extract_file_with`/`extract_all_with`.
+#[derive(Debug)]
+pub struct ExtractRecord<'a> {
+    pub entry: &'a FileEntry,
+    /// Bytes written, or `None` if the entry was skipped or failed.
+    pub written: Option<u64>,
+    pub duration: Duration,
+    pub warnings: Vec<String>,
+    pub error: Option<String>,
+    /// `CpkError::kind` of the failure, if any.
+    pub error_kind: Option<&'static str>,
+    /// Hex digest of the written content when hashing is enabled.
+    pub digest: Option<String>,
+    /// Where the entry was written, below the output directory, once renaming,
+    /// escaping and a guessed extension are applied; `None` when nothing was.
+    pub output_path: Option<PathBuf>,
+}
+
+/// Progress of `extract_file_with`/`extract_all_with`, reported for each entry in turn.
+#[derive(Debug)]
+pub enum ExtractEvent<'a> {
+    /// About to extract the `index`th (from 0) of `total` entries, which hold
+    /// `total_bytes` once extracted.
+    Started {
+        entry: &'a FileEntry,
+        index: usize,
+        total: usize,
+        total_bytes: u64,
+    },
+    /// The entry is done, whatever the outcome.
+    Finished(ExtractRecord<'a>),
+}
+
+/// How an archive addresses its entries (the header's `CpkMode`), which decides
+/// the tables it carries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CpkMode {
+    /// ITOC only.
+    Id = 0,
+    /// TOC only.
+    #[default]
+    FileName = 1,
+    /// TOC and ITOC.
+    FileNameAndId = 2,
+    /// TOC and GTOC.
+    FileNameAndGroup = 3,
+    /// ITOC and GTOC.
+    IdAndGroup = 4,
+    /// TOC, ITOC and GTOC.
+    FileNameIdAndGroup = 5,
+}
+
+impl CpkMode {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(CpkMode::Id),
+            1 => Some(CpkMode::FileName),
+            2 => Some(CpkMode::FileNameAndId),
+            3 => Some(CpkMode::FileNameAndGroup),
+            4 => Some(CpkMode::IdAndGroup),
+            5 => Some(CpkMode::FileNameIdAndGroup),
+            _ => None,
+        }
+    }
+
+    pub fn value(self) -> u32 {
+        self as u32
+    }
+
+    pub fn has_toc(self) -> bool {
+        !matches!(self, CpkMode::Id | CpkMode::IdAndGroup)
+    }
+
+    pub fn has_itoc(self) -> bool {
+        !matches!(self, CpkMode::FileName | CpkMode::FileNameAndGroup)
+    }
+
+    pub fn has_gtoc(self) -> bool {
+        matches!(
+            self,
+            CpkMode::FileNameAndGroup | CpkMode::IdAndGroup | CpkMode::FileNameIdAndGroup
+        )
+    }
+
+    /// The same addressing with a GTOC added.
+    pub fn with_groups(self) -> Self {
+        match self {
+            CpkMode::Id | CpkMode::IdAndGroup => CpkMode::IdAndGroup,
+            CpkMode::FileName | CpkMode::FileNameAndGroup => CpkMode::FileNameAndGroup,
+            CpkMode::FileNameAndId | CpkMode::FileNameIdAndGroup => CpkMode::FileNameIdAndGroup,
+        }
+    }
+}
+
+/// How flat extraction names entries whose file names clash once their
+/// directories are dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlatCollision {
+    /// Append `~N` to the file stem, as for paths that only differ by case.
+    #[default]
+    Suffix,
+    /// Keep the directory in the name, with `_` in place of each `/`.
+    DirPrefix,
+}
+
+/// What extraction does with rows whose DirName/FileName an earlier row of the
+/// same table already uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Extract every row, appending `~N` to the later ones' file stems.
+    #[default]
+    Suffix,
+    /// Extract only the first row with the path.
+    First,
+    /// Refuse to extract rows that share their path.
+    Error,
+}
+
+/// Files at least this large are written in chunks of this size when
+/// extraction is journaled, so an interrupted run can continue them.
+const CHECKPOINT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Header and TOC columns a rewrite derives from where things end up.
+const LAYOUT_COLUMNS: &[&str] = &[
+    "FileOffset",
+    "FileSize",
+    "ExtractSize",
+    "ContentOffset",
+    "ContentSize",
+    "TocOffset",
+    "TocSize",
+    "ItocOffset",
+    "ItocSize",
+    "GtocOffset",
+    "GtocSize",
+    "EtocOffset",
+    "EtocSize",
+    "EnabledPackedSize",
+    "EnabledDataSize",
+];
+
+/// How path lookups (`find`, path targets) compare archive paths.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathCase {
+    /// `Data/A.BIN` finds `data/a.bin`, as CRI's loaders do.
+    #[default]
+    Insensitive,
+    Sensitive,
+}
+
+/// What extraction does when an output file already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExistingPolicy {
+    #[default]
+    Overwrite,
+    Skip,
+    /// Skip only files whose size already matches the extracted size.
+    SkipSameSize,
+    /// Overwrite only files older than the archive itself.
+    OverwriteOlder,
+    /// Ask on the terminal for each file; files are kept when there is no terminal.
+    Prompt,
+}
+
+impl ExistingPolicy {
+    fn skips(self, path: &Path, extract_size: u64, archive_modified: Option<SystemTime>) -> bool {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return false;
+        };
+        match self {
+            ExistingPolicy::Overwrite => false,
+            ExistingPolicy::Skip => true,
+            ExistingPolicy::SkipSameSize => metadata.len() == extract_size,
+            ExistingPolicy::OverwriteOlder => match (metadata.modified(), archive_modified) {
+                (Ok(existing), Some(archive)) => existing >= archive,
+                _ => false,
+            },
+            ExistingPolicy::Prompt => !confirm_overwrite(path),
+        }
+    }
+}
+
+fn confirm_overwrite(path: &Path) -> bool {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        warn!(
+            "{} exists and there is no terminal to ask, keeping it",
+            path.display()
+        );
+        return false;
+    }
+
+    eprint!("{} already exists. Overwrite? [y/N] ", path.display());
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if stdin.read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
 #[derive(Debug)]
@@ -55,6 +380,9 @@ pub struct Cpk {
     pub file_table: Vec<FileEntry>,
     pub cpk_data: HashMap<String, CellValue>,
 
+    // Lowercase full path -> FILE entries in `file_table`, built once the tables are read
+    path_index: HashMap<String, Vec<usize>>,
+
     // Packet data
     cpk_packet: Vec<u8>,
     toc_packet: Option<Vec<u8>>,
@@ -62,12 +390,62 @@ pub struct Cpk {
     etoc_packet: Option<Vec<u8>>,
     gtoc_packet: Option<Vec<u8>>,
 
+    // File the archive was read from, and where inside it the archive starts
+    source_path: Option<PathBuf>,
+    base_offset: u64,
+
+    // Checked between entries by extraction and rewrites
+    cancel: CancellationToken,
+    // Whether bulk extraction skips entries that fail instead of stopping
+    keep_going: bool,
+    existing: ExistingPolicy,
+    // Digest computed over each extracted file while it's in memory
+    hash: Option<HashAlgorithm>,
+    // Whether assets embedded in extracted files are split out next to them
+    carve: bool,
+    // Whether extension-less entries get an extension guessed from their content
+    auto_ext: bool,
+    // Whether entries are extracted as stored, without decompressing them
+    raw: bool,
+    // Whether extraction keeps a journal in the output directory and skips
+    // what an interrupted run already wrote
+    resume: bool,
+    // Whether out-of-range backreferences decode to zeros instead of failing
+    lenient_decompress: bool,
+    // Codecs stored entries are recognised and decoded with
+    codecs: CodecRegistry,
+    // Ciphers encrypted tables are recognised with, and the one they used
+    ciphers: CipherRegistry,
+    table_cipher: Arc<dyn Cipher>,
+    // Run in order on each extracted entry's content
+    processors: Vec<Box<dyn PostProcessor>>,
+    // Writes every entry straight into the output directory when set
+    flat: Option<FlatCollision>,
+    duplicates: DuplicatePolicy,
+    name_escape: NameEscape,
+    path_case: PathCase,
+    // Leading directories removed from extracted paths, without slashes at either end
+    strip_prefix: Option<String>,
+    // Directory extracted files are written below; empty means the current one
+    output_dir: PathBuf,
+    // Recoverable problems found while reading the tables
+    diagnostics: Vec<Diagnostic>,
+    // Limits and behaviour for reading the tables
+    options: ParseOptions,
+
     // Offsets
     toc_offset: u64,
     etoc_offset: u64,
     itoc_offset: u64,
     gtoc_offset: u64,
     content_offset: u64,
+    align: u16,
+}
+
+impl Default for Cpk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpk {
@@ -75,26 +453,211 @@ impl Cpk {
         Self {
             file_table: Vec::new(),
             cpk_data: HashMap::new(),
+            path_index: HashMap::new(),
             cpk_packet: Vec::new(),
             toc_packet: None,
             itoc_packet: None,
             etoc_packet: None,
             gtoc_packet: None,
+            source_path: None,
+            base_offset: 0,
+            cancel: CancellationToken::new(),
+            keep_going: false,
+            existing: ExistingPolicy::Overwrite,
+            hash: None,
+            carve: false,
+            auto_ext: false,
+            raw: false,
+            resume: false,
+            lenient_decompress: false,
+            codecs: CodecRegistry::default(),
+            ciphers: CipherRegistry::default(),
+            table_cipher: Arc::new(XorStream),
+            processors: Vec::new(),
+            flat: None,
+            name_escape: NameEscape::default(),
+            duplicates: DuplicatePolicy::default(),
+            path_case: PathCase::default(),
+            strip_prefix: None,
+            output_dir: PathBuf::new(),
+            diagnostics: Vec::new(),
+            options: ParseOptions::default(),
             toc_offset: 0xFFFFFFFFFFFFFFFF,
             etoc_offset: 0xFFFFFFFFFFFFFFFF,
             itoc_offset: 0xFFFFFFFFFFFFFFFF,
             gtoc_offset: 0xFFFFFFFFFFFFFFFF,
             content_offset: 0,
+            align: 0x800,
+        }
+    }
+
+    /// Creates a reader for a CPK embedded `base_offset` bytes into a larger file.
+    pub fn with_base_offset(base_offset: u64) -> Self {
+        Self {
+            base_offset,
+            ..Self::new()
         }
     }
 
+    /// Makes `read_cpk` skip TOC rows that can't be parsed, leaving their entries
+    /// out and noting each in `diagnostics`, instead of failing. Commands that
+    /// write a new archive from this one leave those entries out as well.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.options.lenient = lenient;
+    }
+
+    /// Sets the limits and behaviour used by the next `read_cpk`.
+    pub fn set_parse_options(&mut self, options: ParseOptions) {
+        self.options = options;
+    }
+
+    pub fn parse_options(&self) -> &ParseOptions {
+        &self.options
+    }
+
+    /// Makes extraction and rewrites stop at the next entry boundary once `token` is cancelled.
+    pub fn set_cancellation(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
+
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// Makes extraction log and skip entries that fail, returning
+    /// `CpkError::EntriesFailed` at the end instead of stopping at the first one.
+    pub fn set_keep_going(&mut self, keep_going: bool) {
+        self.keep_going = keep_going;
+    }
+
+    /// Decides whether extraction replaces files that are already on disk.
+    pub fn set_existing_policy(&mut self, policy: ExistingPolicy) {
+        self.existing = policy;
+    }
+
+    /// Makes extraction digest each file it writes, reported in `ExtractRecord::digest`,
+    /// so checking the output doesn't need another pass over it.
+    pub fn set_hash(&mut self, hash: Option<HashAlgorithm>) {
+        self.hash = hash;
+    }
+
+    /// Makes extraction also split the ADX, HCA, DDS and @UTF assets found
+    /// inside each file into a `<file>.carved` folder, for banks that
+    /// concatenate assets.
+    pub fn set_carve(&mut self, carve: bool) {
+        self.carve = carve;
+    }
+
+    /// Makes extraction write entries exactly as stored, leaving CRILAYLA data
+    /// compressed.
+    pub fn set_raw(&mut self, raw: bool) {
+        self.raw = raw;
+    }
+
+    /// Makes extraction journal its progress in the output directory, so that
+    /// running it again after an interruption skips the entries already
+    /// extracted and continues large files from their last checkpoint.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    /// Makes decompression fill backreferences reaching past the end of the
+    /// output with zeros, with a warning counting them, instead of failing.
+    pub fn set_lenient_decompress(&mut self, lenient: bool) {
+        self.lenient_decompress = lenient;
+    }
+
+    /// The codecs entries are decoded with, CRILAYLA only unless more are
+    /// registered. Replacements are compressed with the first.
+    pub fn codecs(&self) -> &CodecRegistry {
+        &self.codecs
+    }
+
+    pub fn codecs_mut(&mut self) -> &mut CodecRegistry {
+        &mut self.codecs
+    }
+
+    /// The ciphers encrypted tables are decrypted with, the XOR stream only
+    /// unless more are registered. Register them before `read_cpk`.
+    pub fn ciphers_mut(&mut self) -> &mut CipherRegistry {
+        &mut self.ciphers
+    }
+
+    /// Runs `processor` on every entry extracted from now on, after the ones
+    /// added before it. Raw extraction skips processors.
+    pub fn add_processor<P: PostProcessor + 'static>(&mut self, processor: P) {
+        self.processors.push(Box::new(processor));
+    }
+
+    /// Makes extraction append an extension guessed from the content (`.hca`,
+    /// `.adx`, `.usm`, `.dds`, `.utf`) to entries whose name has none, such as
+    /// ID-named ones.
+    pub fn set_auto_extension(&mut self, auto_ext: bool) {
+        self.auto_ext = auto_ext;
+    }
+
+    /// Makes extraction ignore DirName and write every entry directly into
+    /// the output directory, naming clashing entries as `collision` says.
+    pub fn set_flat(&mut self, collision: Option<FlatCollision>) {
+        self.flat = collision;
+    }
+
+    /// Sets what extraction does with rows sharing their path with an earlier row.
+    pub fn set_duplicates(&mut self, policy: DuplicatePolicy) {
+        self.duplicates = policy;
+    }
+
+    /// Sets how extraction writes names the filesystem can't hold.
+    pub fn set_name_escape(&mut self, escape: NameEscape) {
+        self.name_escape = escape;
+    }
+
+    pub fn set_path_case(&mut self, case: PathCase) {
+        self.path_case = case;
+    }
+
+    /// Makes extraction drop the leading directories `prefix` (e.g. `data/`)
+    /// from the paths that start with them, matched case-insensitively.
+    pub fn set_strip_prefix(&mut self, prefix: Option<&str>) {
+        self.strip_prefix = prefix
+            .map(normalize_separators)
+            .filter(|prefix| !prefix.is_empty());
+    }
+
+    /// Writes extracted files below `dir` instead of the current directory.
+    pub fn set_output_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.output_dir = dir.as_ref().to_path_buf();
+    }
+
+    /// Reads the archive's tables. An archive whose in-place modification was
+    /// interrupted is first restored from its rollback record.
     pub fn read_cpk<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        if rollback::pending(path.as_ref()) {
+            warn!(
+                "{} is being modified in place, or was interrupted while it was; \
+                 what's read may be half updated until a modification recovers it",
+                path.as_ref().display()
+            );
+        }
         let file = File::open(&path)?;
         let file_size = file.metadata()?.len();
         let mut reader = EndianReader::new(BufReader::new(file), false); // Start with big endian
+        self.clear_tables();
+        self.source_path = Some(path.as_ref().to_path_buf());
 
         info!("File size: {} bytes", file_size);
 
+        if self.base_offset >= file_size {
+            return Err(CpkError::InvalidFormat(format!(
+                "Archive offset 0x{:X} is beyond the end of the file ({} bytes)",
+                self.base_offset, file_size
+            )));
+        }
+        if self.base_offset != 0 {
+            info!("Archive starts at offset 0x{:X}", self.base_offset);
+            reader.seek(SeekFrom::Start(self.base_offset))?;
+        }
+
         // Check CPK signature
         let signature = reader.read_bytes(4)?;
         debug!(
@@ -110,7 +673,7 @@ impl Cpk {
         debug!("Position before reading UTF data: {}", current_pos);
 
         let (utf_data, is_encrypted) = self.read_utf_data(&mut reader, file_size)?;
-        self.cpk_packet = utf_data.clone();
+        self.cpk_packet = utf_data;
 
         // Add CPK header entry
         let cpk_entry = FileEntry {
@@ -125,16 +688,18 @@ impl Cpk {
         self.file_table.push(cpk_entry);
 
         // Parse UTF data
-        let mut utf = Utf::new();
-        utf.read_utf(&utf_data)?;
+        let mut utf = self.table_reader(false);
+        utf.read_utf(&self.cpk_packet)
+            .map_err(|e| e.in_table("CPK"))?;
+        self.collect_diagnostics("CPK", &mut utf);
 
         // Store CPK data
         for (i, column) in utf.columns.iter().enumerate() {
-            if let Some(row) = utf.rows.first() {
-                if let Some(cell) = row.get(i) {
-                    self.cpk_data
-                        .insert(column.name.clone(), cell.value.clone());
-                }
+            if let Some(row) = utf.rows.first()
+                && let Some(cell) = row.get(i)
+            {
+                self.cpk_data
+                    .insert(column.name.clone(), cell.value.clone());
             }
         }
 
@@ -170,7 +735,7 @@ impl Cpk {
         if self.content_offset != 0 {
             let content_entry = FileEntry {
                 file_name: "CONTENT_OFFSET".to_string(),
-                file_offset: self.content_offset,
+                file_offset: checked_offset(self.base_offset, self.content_offset, "Content")?,
                 file_type: "CONTENT".to_string(),
                 toc_name: "CPK".to_string(),
                 ..FileEntry::new()
@@ -189,12 +754,13 @@ impl Cpk {
 
         debug!("Files: {}", files);
         debug!("Align: 0x{:X}", align);
+        self.align = align;
 
         // Read TOC if present
         if self.toc_offset != 0xFFFFFFFFFFFFFFFF {
             let toc_entry = FileEntry {
                 file_name: "TOC_HDR".to_string(),
-                file_offset: self.toc_offset,
+                file_offset: checked_offset(self.base_offset, self.toc_offset, "TOC")?,
                 file_type: "HDR".to_string(),
                 toc_name: "CPK".to_string(),
                 ..FileEntry::new()
@@ -207,7 +773,7 @@ impl Cpk {
         if self.etoc_offset != 0xFFFFFFFFFFFFFFFF {
             let etoc_entry = FileEntry {
                 file_name: "ETOC_HDR".to_string(),
-                file_offset: self.etoc_offset,
+                file_offset: checked_offset(self.base_offset, self.etoc_offset, "ETOC")?,
                 file_type: "HDR".to_string(),
                 toc_name: "CPK".to_string(),
                 ..FileEntry::new()
@@ -220,7 +786,7 @@ impl Cpk {
         if self.itoc_offset != 0xFFFFFFFFFFFFFFFF {
             let itoc_entry = FileEntry {
                 file_name: "ITOC_HDR".to_string(),
-                file_offset: self.itoc_offset,
+                file_offset: checked_offset(self.base_offset, self.itoc_offset, "ITOC")?,
                 file_type: "HDR".to_string(),
                 toc_name: "CPK".to_string(),
                 ..FileEntry::new()
@@ -233,7 +799,7 @@ impl Cpk {
         if self.gtoc_offset != 0xFFFFFFFFFFFFFFFF {
             let gtoc_entry = FileEntry {
                 file_name: "GTOC_HDR".to_string(),
-                file_offset: self.gtoc_offset,
+                file_offset: checked_offset(self.base_offset, self.gtoc_offset, "GTOC")?,
                 file_type: "HDR".to_string(),
                 toc_name: "CPK".to_string(),
                 ..FileEntry::new()
@@ -242,11 +808,62 @@ impl Cpk {
             self.read_gtoc(&mut reader, file_size)?;
         }
 
+        // Listed after the data appended last, when an append-style patch left any
+        if let Some(size) = self.archive_size()
+            && self
+                .base_offset
+                .checked_add(size)
+                .is_some_and(|end| end <= file_size)
+            && let Some(list) = replaced::read(&mut reader, self.base_offset, size)?
+        {
+            self.list_replaced(&list.ranges, (size - list.len, list.len));
+        }
+
+        if !self.options.retain_packets {
+            self.toc_packet = None;
+            self.itoc_packet = None;
+            self.etoc_packet = None;
+            self.gtoc_packet = None;
+        }
+
+        self.index_paths();
         Ok(())
     }
 
+    /// An empty table to parse one of the archive's packets into; only the TOC
+    /// may be read leniently.
+    fn table_reader(&self, lenient: bool) -> Utf {
+        Utf::with_options(ParseOptions {
+            lenient: lenient && self.options.lenient,
+            ..self.options.clone()
+        })
+    }
+
+    /// Fails for archives read without keeping their table packets, which every
+    /// rewrite starts from.
+    pub(crate) fn require_packets(&self) -> Result<()> {
+        match self.options.retain_packets {
+            true => Ok(()),
+            false => Err(CpkError::Unsupported(
+                "the table packets were not kept (ParseOptions::retain_packets)".to_string(),
+            )),
+        }
+    }
+
+    fn index_paths(&mut self) {
+        self.path_index.clear();
+        for (idx, entry) in self.file_table.iter().enumerate() {
+            if entry.file_type == "FILE" {
+                self.path_index
+                    .entry(entry.full_path().to_lowercase())
+                    .or_default()
+                    .push(idx);
+            }
+        }
+    }
+
     fn read_utf_data<R: Read + Seek>(
-        &self,
+        &mut self,
         reader: &mut EndianReader<R>,
         file_size: u64,
     ) -> Result<(Vec<u8>, bool)> {
@@ -279,10 +896,10 @@ impl Cpk {
             )));
         }
 
-        if utf_size > 100_000_000 {
+        if utf_size > self.options.max_utf_size {
             return Err(CpkError::InvalidFormat(format!(
-                "UTF size ({}) seems unreasonably large",
-                utf_size
+                "UTF size ({}) is over the {} byte limit",
+                utf_size, self.options.max_utf_size
             )));
         }
 
@@ -314,64 +931,40 @@ impl Cpk {
         reader.set_endian(false); // Back to big endian
 
         // Check if encrypted
-        let is_encrypted = !(utf_packet.len() >= 4
-            && utf_packet[0] == 0x40
-            && utf_packet[1] == 0x55
-            && utf_packet[2] == 0x54
-            && utf_packet[3] == 0x46); // @UTF
+        let is_encrypted = !utf_packet.starts_with(b"@UTF");
 
         if is_encrypted {
-            debug!("UTF data is encrypted, decrypting...");
-            utf_packet = self.decrypt_utf(&utf_packet);
+            let Some(cipher) = self.ciphers.detect(&utf_packet) else {
+                return Err(CpkError::InvalidFormat(format!(
+                    "Invalid UTF signature, and no known cipher ({}) decrypts it",
+                    self.ciphers.names().join(", ")
+                )));
+            };
+            debug!(
+                "UTF data is encrypted, decrypting with {}...",
+                cipher.name()
+            );
+            cipher.decrypt(&mut utf_packet);
+            self.table_cipher = cipher.clone();
         } else {
             debug!("UTF data is not encrypted");
         }
 
-        // Verify the decrypted data starts with @UTF
-        if utf_packet.len() < 4 || &utf_packet[0..4] != b"@UTF" {
-            return Err(CpkError::InvalidFormat(
-                "Invalid UTF signature after decryption".to_string(),
-            ));
-        }
-
         Ok((utf_packet, is_encrypted))
     }
 
-    fn decrypt_utf(&self, input: &[u8]) -> Vec<u8> {
-        let mut result = vec![0u8; input.len()];
-        let mut m = 0x0000655f_u32;
-        let t = 0x00004115_u32;
-
-        for (i, &byte) in input.iter().enumerate() {
-            result[i] = byte ^ (m & 0xff) as u8;
-            m = m.wrapping_mul(t);
-        }
-
-        result
-    }
-
     fn read_toc<R: Read + Seek>(
         &mut self,
         reader: &mut EndianReader<R>,
         file_size: u64,
     ) -> Result<()> {
-        let f_toc_offset = if self.toc_offset > 0x800 {
-            0x800
-        } else {
-            self.toc_offset
-        };
-
-        let add_offset = if self.content_offset == 0xFFFFFFFFFFFFFFFF {
-            f_toc_offset
-        } else if self.toc_offset == 0xFFFFFFFFFFFFFFFF {
-            self.content_offset
-        } else if self.content_offset < f_toc_offset {
-            self.content_offset
-        } else {
-            f_toc_offset
-        };
+        let add_offset = toc_base_offset(self.toc_offset, self.content_offset);
 
-        reader.seek(SeekFrom::Start(self.toc_offset))?;
+        reader.seek(SeekFrom::Start(checked_offset(
+            self.base_offset,
+            self.toc_offset,
+            "TOC",
+        )?))?;
 
         let signature = reader.read_bytes(4)?;
         if &signature != b"TOC " {
@@ -379,7 +972,6 @@ impl Cpk {
         }
 
         let (utf_data, is_encrypted) = self.read_utf_data(reader, file_size)?;
-        self.toc_packet = Some(utf_data.clone());
 
         // Update TOC header entry
         if let Some(entry) = self
@@ -391,22 +983,34 @@ impl Cpk {
             entry.file_size = utf_data.len() as u64;
         }
 
-        let mut utf = Utf::new();
-        utf.read_utf(&utf_data)?;
+        let mut utf = self.table_reader(true);
+        utf.read_utf(&utf_data).map_err(|e| e.in_table("TOC"))?;
+        self.collect_diagnostics("TOC", &mut utf);
+        for column in ["FileName", "FileSize", "FileOffset"] {
+            if !utf.has_column(column) {
+                self.note("TOC", "columns", format!("no {} column", column));
+            }
+        }
+        self.toc_packet = Some(utf_data);
 
         // Parse file entries
         for row_idx in 0..utf.num_rows {
+            if utf.skipped_rows.contains(&row_idx) {
+                continue;
+            }
             let mut entry = FileEntry::new();
             entry.toc_name = "TOC".to_string();
             entry.file_type = "FILE".to_string();
-            entry.offset = add_offset;
+            entry.offset = checked_offset(self.base_offset, add_offset, "TOC")?;
+            entry.row = Some(row_idx);
 
+            // A present but zero-valued DirName column still means "empty", not "absent"
             if let Some(dir_name) = utf.get_column_data(row_idx as usize, "DirName") {
-                entry.dir_name = dir_name.as_string().map(|s| s.to_string());
+                entry.dir_name = Some(normalize_separators(dir_name.as_string().unwrap_or("")));
             }
 
             if let Some(file_name) = utf.get_column_data(row_idx as usize, "FileName") {
-                entry.file_name = file_name.as_string().unwrap_or("").to_string();
+                entry.file_name = normalize_separators(file_name.as_string().unwrap_or(""));
             }
 
             if let Some(file_size) = utf.get_column_data(row_idx as usize, "FileSize") {
@@ -414,7 +1018,7 @@ impl Cpk {
                     "Raw FileSize value for '{}': {:?}",
                     entry.file_name, file_size
                 );
-                entry.file_size = file_size.as_u64().unwrap_or(0);
+                entry.file_size = self.toc_integer(row_idx, "FileSize", file_size);
                 debug!(
                     "Converted FileSize for '{}': {}",
                     entry.file_name, entry.file_size
@@ -446,11 +1050,17 @@ impl Cpk {
                     "Raw FileOffset value for '{}': {:?}",
                     entry.file_name, file_offset
                 );
-                let base_offset = file_offset.as_u64().unwrap_or(0);
-                entry.file_offset = base_offset + add_offset;
+                let base_offset = self.toc_integer(row_idx, "FileOffset", file_offset);
+                entry.file_offset = checked_offset(entry.offset, base_offset, &entry.file_name)?;
+                if entry.file_offset.checked_add(entry.file_size).is_none() {
+                    return Err(CpkError::InvalidFormat(format!(
+                        "{} has a size of {} bytes past any file",
+                        entry.file_name, entry.file_size
+                    )));
+                }
                 debug!(
                     "Converted FileOffset for '{}': 0x{:X} (base: 0x{:X} + add_offset: 0x{:X})",
-                    entry.file_name, entry.file_offset, base_offset, add_offset
+                    entry.file_name, entry.file_offset, base_offset, entry.offset
                 );
                 entry.file_offset_pos = utf
                     .get_column_position(row_idx as usize, "FileOffset")
@@ -463,6 +1073,14 @@ impl Cpk {
                 entry.id = id.as_u32();
             }
 
+            if let Some(crc) = utf.get_column_data(row_idx as usize, "CRC") {
+                // The checksum is a bit pattern, whichever integer type holds it
+                entry.crc = match crc {
+                    CellValue::Int32(crc) => Some(*crc as u32),
+                    crc => crc.as_u32(),
+                };
+            }
+
             if let Some(user_string) = utf.get_column_data(row_idx as usize, "UserString") {
                 entry.user_string = user_string.as_string().map(|s| s.to_string());
             }
@@ -483,7 +1101,11 @@ impl Cpk {
         reader: &mut EndianReader<R>,
         file_size: u64,
     ) -> Result<()> {
-        reader.seek(SeekFrom::Start(self.etoc_offset))?;
+        reader.seek(SeekFrom::Start(checked_offset(
+            self.base_offset,
+            self.etoc_offset,
+            "ETOC",
+        )?))?;
 
         let signature = reader.read_bytes(4)?;
         if &signature != b"ETOC" {
@@ -493,7 +1115,6 @@ impl Cpk {
         }
 
         let (utf_data, is_encrypted) = self.read_utf_data(reader, file_size)?;
-        self.etoc_packet = Some(utf_data.clone());
 
         // Update ETOC header entry
         if let Some(entry) = self
@@ -505,10 +1126,12 @@ impl Cpk {
             entry.file_size = utf_data.len() as u64;
         }
 
-        let mut utf = Utf::new();
-        utf.read_utf(&utf_data)?;
+        let mut utf = self.table_reader(false);
+        utf.read_utf(&utf_data).map_err(|e| e.in_table("ETOC"))?;
+        self.collect_diagnostics("ETOC", &mut utf);
+        self.etoc_packet = Some(utf_data);
 
-        // Update file entries with LocalDir information
+        // Update file entries with LocalDir and UpdateDateTime information
         let file_indices: Vec<_> = self
             .file_table
             .iter()
@@ -521,6 +1144,9 @@ impl Cpk {
             if let Some(local_dir) = utf.get_column_data(i, "LocalDir") {
                 self.file_table[idx].local_dir = local_dir.as_string().map(|s| s.to_string());
             }
+            if let Some(updated) = utf.get_column_data(i, "UpdateDateTime") {
+                self.file_table[idx].updated = updated.as_u64().filter(|&packed| packed != 0);
+            }
         }
 
         Ok(())
@@ -532,7 +1158,11 @@ impl Cpk {
         align: u16,
         file_size: u64,
     ) -> Result<()> {
-        reader.seek(SeekFrom::Start(self.itoc_offset))?;
+        reader.seek(SeekFrom::Start(checked_offset(
+            self.base_offset,
+            self.itoc_offset,
+            "ITOC",
+        )?))?;
 
         let signature = reader.read_bytes(4)?;
         if &signature != b"ITOC" {
@@ -542,7 +1172,6 @@ impl Cpk {
         }
 
         let (utf_data, is_encrypted) = self.read_utf_data(reader, file_size)?;
-        self.itoc_packet = Some(utf_data.clone());
 
         // Update ITOC header entry
         if let Some(entry) = self
@@ -554,64 +1183,68 @@ impl Cpk {
             entry.file_size = utf_data.len() as u64;
         }
 
-        let mut utf = Utf::new();
-        utf.read_utf(&utf_data)?;
+        let mut utf = self.table_reader(false);
+        utf.read_utf(&utf_data).map_err(|e| e.in_table("ITOC"))?;
+        self.collect_diagnostics("ITOC", &mut utf);
+        self.itoc_packet = Some(utf_data);
 
         // Read DataL and DataH
         let mut size_table = HashMap::new();
         let mut extract_size_table = HashMap::new();
         let mut ids = Vec::new();
 
-        if let Some(data_l) = utf.get_column_data(0, "DataL") {
-            if let Some(data_l_bytes) = data_l.as_data() {
-                let mut data_utf = Utf::new();
-                data_utf.read_utf(data_l_bytes)?;
-
-                for row_idx in 0..data_utf.num_rows {
-                    if let Some(id) = data_utf.get_column_data(row_idx as usize, "ID") {
-                        if let Some(file_size) =
-                            data_utf.get_column_data(row_idx as usize, "FileSize")
-                        {
-                            let id_val = id.as_u16().unwrap_or(0) as u32;
-                            size_table.insert(id_val, file_size.as_u16().unwrap_or(0) as u64);
-                            ids.push(id_val);
-                        }
-                        if let Some(extract_size) =
-                            data_utf.get_column_data(row_idx as usize, "ExtractSize")
-                        {
-                            let id_val = id.as_u16().unwrap_or(0) as u32;
-                            extract_size_table
-                                .insert(id_val, extract_size.as_u16().unwrap_or(0) as u64);
-                        }
+        if let Some(data_l) = utf.get_column_data(0, "DataL")
+            && let Some(data_l_bytes) = data_l.as_data()
+        {
+            let mut data_utf = utf
+                .read_nested(data_l_bytes)
+                .map_err(|e| e.in_table("ITOC DataL"))?;
+            self.collect_diagnostics("ITOC DataL", &mut data_utf);
+
+            for row_idx in 0..data_utf.num_rows {
+                if let Some(id) = data_utf.get_column_data(row_idx as usize, "ID") {
+                    if let Some(file_size) = data_utf.get_column_data(row_idx as usize, "FileSize")
+                    {
+                        let id_val = id.as_u16().unwrap_or(0) as u32;
+                        size_table.insert(id_val, file_size.as_u16().unwrap_or(0) as u64);
+                        ids.push(id_val);
+                    }
+                    if let Some(extract_size) =
+                        data_utf.get_column_data(row_idx as usize, "ExtractSize")
+                    {
+                        let id_val = id.as_u16().unwrap_or(0) as u32;
+                        extract_size_table
+                            .insert(id_val, extract_size.as_u16().unwrap_or(0) as u64);
                     }
                 }
             }
         }
 
-        if let Some(data_h) = utf.get_column_data(0, "DataH") {
-            if let Some(data_h_bytes) = data_h.as_data() {
-                let mut data_utf = Utf::new();
-                data_utf.read_utf(data_h_bytes)?;
-
-                for row_idx in 0..data_utf.num_rows {
-                    if let Some(id) = data_utf.get_column_data(row_idx as usize, "ID") {
-                        if let Some(file_size) =
-                            data_utf.get_column_data(row_idx as usize, "FileSize")
-                        {
-                            let id_val = id.as_u16().unwrap_or(0) as u32;
-                            size_table.insert(id_val, file_size.as_u32().unwrap_or(0) as u64);
-                            if !ids.contains(&id_val) {
-                                ids.push(id_val);
-                            }
-                        }
-                        if let Some(extract_size) =
-                            data_utf.get_column_data(row_idx as usize, "ExtractSize")
-                        {
-                            let id_val = id.as_u16().unwrap_or(0) as u32;
-                            extract_size_table
-                                .insert(id_val, extract_size.as_u32().unwrap_or(0) as u64);
+        if let Some(data_h) = utf.get_column_data(0, "DataH")
+            && let Some(data_h_bytes) = data_h.as_data()
+        {
+            let mut data_utf = utf
+                .read_nested(data_h_bytes)
+                .map_err(|e| e.in_table("ITOC DataH"))?;
+            self.collect_diagnostics("ITOC DataH", &mut data_utf);
+
+            for row_idx in 0..data_utf.num_rows {
+                if let Some(id) = data_utf.get_column_data(row_idx as usize, "ID") {
+                    if let Some(file_size) = data_utf.get_column_data(row_idx as usize, "FileSize")
+                    {
+                        let id_val = id.as_u16().unwrap_or(0) as u32;
+                        size_table.insert(id_val, file_size.as_u32().unwrap_or(0) as u64);
+                        if !ids.contains(&id_val) {
+                            ids.push(id_val);
                         }
                     }
+                    if let Some(extract_size) =
+                        data_utf.get_column_data(row_idx as usize, "ExtractSize")
+                    {
+                        let id_val = id.as_u16().unwrap_or(0) as u32;
+                        extract_size_table
+                            .insert(id_val, extract_size.as_u32().unwrap_or(0) as u64);
+                    }
                 }
             }
         }
@@ -620,13 +1253,15 @@ impl Cpk {
         ids.sort();
 
         // Create file entries
-        let mut base_offset = self.content_offset;
-        for id in ids {
+        let mut base_offset = checked_offset(self.base_offset, self.content_offset, "Content")?;
+        let align = align.max(1) as u64;
+        for (row, id) in ids.into_iter().enumerate() {
             let mut entry = FileEntry::new();
             entry.toc_name = "ITOC".to_string();
             entry.file_type = "FILE".to_string();
             entry.file_name = format!("{:04}", id);
             entry.id = Some(id);
+            entry.row = Some(row as u32);
             entry.file_offset = base_offset;
 
             if let Some(&file_size) = size_table.get(&id) {
@@ -638,12 +1273,11 @@ impl Cpk {
             }
 
             // Calculate next offset with alignment
-            let file_size = entry.file_size;
-            if file_size % align as u64 > 0 {
-                base_offset += file_size + (align as u64 - (file_size % align as u64));
-            } else {
-                base_offset += file_size;
-            }
+            base_offset = checked_offset(
+                base_offset,
+                entry.file_size.next_multiple_of(align),
+                &entry.file_name,
+            )?;
 
             self.file_table.push(entry);
         }
@@ -654,9 +1288,13 @@ impl Cpk {
     fn read_gtoc<R: Read + Seek>(
         &mut self,
         reader: &mut EndianReader<R>,
-        _file_size: u64,
+        file_size: u64,
     ) -> Result<()> {
-        reader.seek(SeekFrom::Start(self.gtoc_offset))?;
+        reader.seek(SeekFrom::Start(checked_offset(
+            self.base_offset,
+            self.gtoc_offset,
+            "GTOC",
+        )?))?;
 
         let signature = reader.read_bytes(4)?;
         if &signature != b"GTOC" {
@@ -665,10 +1303,60 @@ impl Cpk {
             ));
         }
 
-        // Skip implementation for now (not commonly used)
+        let (utf_data, is_encrypted) = self.read_utf_data(reader, file_size)?;
+
+        // Update GTOC header entry
+        if let Some(entry) = self
+            .file_table
+            .iter_mut()
+            .find(|e| e.file_name == "GTOC_HDR")
+        {
+            entry.encrypted = is_encrypted;
+            entry.file_size = utf_data.len() as u64;
+        }
+
+        // Group contents are not parsed yet (not commonly used)
+        self.gtoc_packet = Some(utf_data);
         Ok(())
     }
 
+    /// Keeps the problems found while parsing one of the archive's tables.
+    fn collect_diagnostics(&mut self, table: &str, utf: &mut Utf) {
+        let found = utf.diagnostics.drain(..).map(|d| d.in_table(table));
+        self.diagnostics.extend(found);
+    }
+
+    fn note(&mut self, table: &str, location: &str, message: String) {
+        warn!("{}: {}: {}", table, location, message);
+        self.diagnostics.push(Diagnostic {
+            table: table.to_string(),
+            location: location.to_string(),
+            message,
+        });
+    }
+
+    /// A TOC size or offset cell, noting values that aren't an unsigned integer.
+    fn toc_integer(&mut self, row: u32, column: &str, value: &CellValue) -> u64 {
+        match (value, value.as_u64()) {
+            (_, Some(value)) => value,
+            (CellValue::None, None) => 0,
+            (value, None) => {
+                let location = format!("row {}, column {}", row, column);
+                self.note(
+                    "TOC",
+                    &location,
+                    format!("{} is not a size, using 0", value),
+                );
+                0
+            }
+        }
+    }
+
+    /// Recoverable problems found by the last `read_cpk`, in the order they were met.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
     fn get_column_data_or_default(
         &self,
         utf: &Utf,
@@ -679,82 +1367,762 @@ impl Cpk {
         utf.get_column_data_or_default(row, column_name, default_type)
     }
 
-    pub fn extract_file<P: AsRef<Path>>(&self, cpk_path: P, target: &str) -> Result<()> {
-        let target_lower = target.to_lowercase();
-        let entries: Vec<_> = self
-            .file_table
-            .iter()
-            .filter(|e| e.file_type == "FILE")
-            .filter(|e| {
-                let full_path = match (&e.dir_name, &e.file_name) {
-                    (Some(dir), file_name) => format!("{}/{}", dir, file_name),
-                    (None, file_name) => file_name.clone(),
-                };
-                full_path.to_lowercase() == target_lower
-            })
-            .collect();
+    pub fn base_offset(&self) -> u64 {
+        self.base_offset
+    }
 
-        if entries.is_empty() {
-            return Err(CpkError::FileNotFound(target.to_string()));
-        }
+    /// Moves where the archive is read from, before `read_cpk`.
+    pub fn set_base_offset(&mut self, base_offset: u64) {
+        self.base_offset = base_offset;
+    }
 
-        let file = File::open(cpk_path)?;
-        let mut reader = BufReader::new(file);
+    pub fn align(&self) -> u16 {
+        self.align
+    }
+
+    /// Whether the TOC keeps a CRC of each entry.
+    pub fn has_crc(&self) -> bool {
+        self.file_table.iter().any(|e| e.crc.is_some())
+    }
 
-        for entry in entries {
-            self.extract_single_file(&mut reader, entry)?;
+    /// The header's `CpkMode`, or the mode implied by the tables present when the
+    /// header doesn't declare a known one.
+    pub fn cpk_mode(&self) -> Option<CpkMode> {
+        if let Some(mode) = self
+            .cpk_data
+            .get("CpkMode")
+            .and_then(|v| v.as_u32())
+            .and_then(CpkMode::from_value)
+        {
+            return Some(mode);
         }
 
-        Ok(())
+        const ABSENT: u64 = 0xFFFFFFFFFFFFFFFF;
+        match (
+            self.toc_offset != ABSENT,
+            self.itoc_offset != ABSENT,
+            self.gtoc_offset != ABSENT,
+        ) {
+            (false, true, false) => Some(CpkMode::Id),
+            (true, false, false) => Some(CpkMode::FileName),
+            (true, true, false) => Some(CpkMode::FileNameAndId),
+            (true, false, true) => Some(CpkMode::FileNameAndGroup),
+            (false, true, true) => Some(CpkMode::IdAndGroup),
+            (true, true, true) => Some(CpkMode::FileNameIdAndGroup),
+            (false, false, _) => None,
+        }
     }
 
-    pub fn extract_all<P: AsRef<Path>>(&self, cpk_path: P) -> Result<()> {
-        let file = File::open(cpk_path)?;
-        let mut reader = BufReader::new(file);
+    /// Returns the decrypted table packet behind a header pseudo-entry (CPK_HDR, TOC_HDR, ...).
+    pub fn header_packet(&self, name: &str) -> Option<&[u8]> {
+        match name {
+            "CPK_HDR" => Some(&self.cpk_packet),
+            "TOC_HDR" => self.toc_packet.as_deref(),
+            "ETOC_HDR" => self.etoc_packet.as_deref(),
+            "ITOC_HDR" => self.itoc_packet.as_deref(),
+            "GTOC_HDR" => self.gtoc_packet.as_deref(),
+            _ => None,
+        }
+    }
 
+    /// Writes the decrypted table packets to `<NAME>.utf` files.
+    pub fn extract_headers(&self) -> Result<()> {
+        self.require_packets()?;
         for entry in &self.file_table {
-            if entry.file_type != "FILE" {
+            if entry.file_type != "CPK" && entry.file_type != "HDR" {
                 continue;
             }
-            self.extract_single_file(&mut reader, entry)?;
+            if let Some(packet) = self.header_packet(&entry.file_name) {
+                if !self.output_dir.as_os_str().is_empty() {
+                    create_dir_all(&self.output_dir)?;
+                }
+                let output_path = self.output_dir.join(format!("{}.utf", entry.file_name));
+                info!(
+                    "Extracting: {} ({} bytes)",
+                    output_path.display(),
+                    packet.len()
+                );
+                std::fs::write(&output_path, packet)?;
+            }
         }
 
         Ok(())
     }
 
-    fn extract_single_file<R: Read + Seek>(&self, reader: &mut R, entry: &FileEntry) -> Result<()> {
-        let output_path = match (&entry.dir_name, &entry.file_name) {
-            (Some(dir), file_name) => {
-                create_dir_all(dir)?;
-                format!("{}/{}", dir, file_name)
-            }
-            (None, file_name) => file_name.clone(),
-        };
+    /// Returns the archive's total size as declared by the header's offset/size columns.
+    pub fn archive_size(&self) -> Option<u64> {
+        ["Content", "Toc", "Etoc", "Itoc", "Gtoc"]
+            .iter()
+            .filter_map(|region| {
+                let offset = self.cpk_data.get(&format!("{}Offset", region))?.as_u64()?;
+                let size = self.cpk_data.get(&format!("{}Size", region))?.as_u64()?;
+                offset.checked_add(size)
+            })
+            .max()
+    }
 
-        debug!("Extracting file: {}", output_path);
-        debug!("  Offset: 0x{:X}", entry.file_offset);
-        debug!("  Size: {}", entry.file_size);
-        debug!("  Extract Size: {:?}", entry.extract_size);
+    /// Each file stored in the archive once, in table order.
+    pub fn entries(&self) -> Vec<CpkFile<'_>> {
+        self.unique_files()
+            .into_iter()
+            .map(|entry| CpkFile { entry })
+            .collect()
+    }
 
-        // Check for zero-sized files
-        if entry.file_size == 0 {
-            warn!("File {} has zero size, skipping", output_path);
-            return Ok(());
+    /// FILE entries, skipping ITOC rows that describe the same data as a TOC row.
+    pub fn unique_files(&self) -> Vec<&FileEntry> {
+        let mut seen = HashSet::new();
+        self.file_table
+            .iter()
+            .filter(|e| e.file_type == "FILE")
+            .filter(|e| seen.insert((e.file_offset, e.file_size)))
+            .collect()
+    }
+
+    /// FILE rows sharing their DirName/FileName with another row of the same
+    /// table, grouped by path in table order.
+    pub fn duplicate_paths(&self) -> Vec<Vec<&FileEntry>> {
+        self.duplicate_rows()
+            .into_iter()
+            .map(|rows| rows.into_iter().map(|idx| &self.file_table[idx]).collect())
+            .collect()
+    }
+
+    fn duplicate_rows(&self) -> Vec<Vec<usize>> {
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        let mut by_path: HashMap<_, usize> = HashMap::new();
+        for (idx, entry) in self.file_table.iter().enumerate() {
+            if entry.file_type != "FILE" {
+                continue;
+            }
+            let key = (entry.toc_name.as_str(), entry.full_path());
+            match by_path.get(&key) {
+                Some(&group) => groups[group].push(idx),
+                None => {
+                    by_path.insert(key, groups.len());
+                    groups.push(vec![idx]);
+                }
+            }
         }
+        groups.retain(|rows| rows.len() > 1);
+        groups
+    }
 
-        // Seek to file position
-        reader.seek(SeekFrom::Start(entry.file_offset))?;
+    /// Gives ITOC entries the paths `names` maps their IDs to, returning how many
+    /// were renamed. IDs the TOC already names are left alone.
+    pub fn apply_names(&mut self, names: &HashMap<u32, String>) -> usize {
+        let named: HashSet<u32> = self
+            .file_table
+            .iter()
+            .filter(|e| e.file_type == "FILE" && e.toc_name == "TOC")
+            .filter_map(|e| e.id)
+            .collect();
 
-        // Read the full file data
+        let mut renamed = 0;
+        for entry in &mut self.file_table {
+            if entry.file_type != "FILE" || entry.toc_name != "ITOC" {
+                continue;
+            }
+            let Some(id) = entry.id.filter(|id| !named.contains(id)) else {
+                continue;
+            };
+            let Some(path) = names.get(&id).map(|path| normalize_separators(path)) else {
+                continue;
+            };
+            match path.rsplit_once('/') {
+                Some((dir, file)) => {
+                    entry.dir_name = Some(dir.to_string());
+                    entry.file_name = file.to_string();
+                }
+                None if !path.is_empty() => {
+                    entry.dir_name = None;
+                    entry.file_name = path;
+                }
+                None => continue,
+            }
+            renamed += 1;
+        }
+        self.index_paths();
+        renamed
+    }
+
+    /// The TOC's FILE entries; ITOC-only archives have no paths to address them by.
+    pub(crate) fn toc_files(&self) -> Result<Vec<&FileEntry>> {
+        let files: Vec<&FileEntry> = self
+            .file_table
+            .iter()
+            .filter(|e| e.file_type == "FILE" && e.toc_name == "TOC")
+            .collect();
+        if files.is_empty() && self.file_table.iter().any(|e| e.file_type == "FILE") {
+            return Err(CpkError::Unsupported(format!(
+                "{} has no TOC, its entries have no paths",
+                self.source_path()?.display()
+            )));
+        }
+        Ok(files)
+    }
+
+    /// Finds a FILE entry matching `predicate`, preferring the TOC over the ITOC.
+    fn find_index<F: Fn(&FileEntry) -> bool>(&self, predicate: F) -> Option<usize> {
+        let mut matches = self
+            .file_table
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.file_type == "FILE" && predicate(e));
+        let (first_idx, first) = matches.next()?;
+        if first.toc_name == "TOC" {
+            return Some(first_idx);
+        }
+        Some(
+            matches
+                .find(|(_, e)| e.toc_name == "TOC")
+                .map_or(first_idx, |(idx, _)| idx),
+        )
+    }
+
+    /// Looks up the FILE entry at `path` (`/` or `\` separated, compared as
+    /// `set_path_case` says), preferring the TOC row when the ITOC has one too.
+    pub fn find(&self, path: &str) -> Option<&FileEntry> {
+        let indices = self.path_indices(path);
+        let mut entries = indices.iter().map(|&idx| &self.file_table[idx]);
+        let first = entries.clone().next()?;
+        Some(entries.find(|e| e.toc_name == "TOC").unwrap_or(first))
+    }
+
+    /// Looks up the FILE entry with `id`, preferring the TOC row when the ITOC
+    /// has one too.
+    pub fn find_by_id(&self, id: u32) -> Option<&FileEntry> {
+        self.find_index(|e| e.id == Some(id))
+            .map(|idx| &self.file_table[idx])
+    }
+
+    /// Indices of the FILE entries at `path`, in table order.
+    fn path_indices(&self, path: &str) -> Vec<usize> {
+        let path = normalize_separators(path);
+        let Some(indices) = self.path_index.get(&path.to_lowercase()) else {
+            return Vec::new();
+        };
+        indices
+            .iter()
+            .copied()
+            .filter(|&idx| {
+                self.path_case == PathCase::Insensitive || self.file_table[idx].full_path() == path
+            })
+            .collect()
+    }
+
+    /// Looks up the FILE entries addressed by `target` (a path, `#row:N` or `#id:N`).
+    pub fn find_entries(&self, target: &str, filter: &EntryFilter) -> Result<Vec<&FileEntry>> {
+        Ok(self
+            .find_targets(target, filter)?
+            .into_iter()
+            .map(|idx| &self.file_table[idx])
+            .collect())
+    }
+
+    /// Resolves a target (a path, `#row:N` or `#id:N`) to indices into the file table.
+    fn find_targets(&self, target: &str, filter: &EntryFilter) -> Result<Vec<usize>> {
+        let parse_address = |value: &str| {
+            value
+                .parse::<u32>()
+                .map_err(|_| CpkError::Parse(format!("Invalid address: {}", target)))
+        };
+
+        let indices: Vec<usize> = if let Some(row) = target.strip_prefix("#row:") {
+            let row = parse_address(row)?;
+            self.find_index(|e| e.row == Some(row))
+                .into_iter()
+                .collect()
+        } else if let Some(id) = target.strip_prefix("#id:") {
+            let id = parse_address(id)?;
+            self.find_index(|e| e.id == Some(id)).into_iter().collect()
+        } else {
+            self.path_indices(target)
+        };
+
+        let indices: Vec<usize> = indices
+            .into_iter()
+            .filter(|&idx| filter.matches(&self.file_table[idx]))
+            .collect();
+        if indices.is_empty() {
+            return Err(CpkError::FileNotFound(target.to_string()));
+        }
+
+        Ok(indices)
+    }
+
+    /// Extracts the entries matching `target`, either a path or a `#row:N`/`#id:N` address.
+    pub fn extract_file<P: AsRef<Path>>(
+        &self,
+        cpk_path: P,
+        target: &str,
+        filter: &EntryFilter,
+    ) -> Result<()> {
+        self.extract_file_with(cpk_path, target, filter, |_| {})
+    }
+
+    /// Like `extract_file`, calling `on_entry` with the outcome of each entry.
+    pub fn extract_file_with<P: AsRef<Path>, F: FnMut(ExtractEvent)>(
+        &self,
+        cpk_path: P,
+        target: &str,
+        filter: &EntryFilter,
+        on_entry: F,
+    ) -> Result<()> {
+        let indices = self.find_targets(target, filter)?;
+        self.extract_entries(cpk_path, indices, on_entry)
+    }
+
+    pub fn extract_all<P: AsRef<Path>>(&self, cpk_path: P, filter: &EntryFilter) -> Result<()> {
+        self.extract_all_with(cpk_path, filter, |_| {})
+    }
+
+    /// Like `extract_all`, calling `on_entry` as each entry starts and finishes.
+    ///
+    /// Fails with `FileNotFound` when the archive holds entries but `filter`
+    /// matches none of them.
+    pub fn extract_all_with<P: AsRef<Path>, F: FnMut(ExtractEvent)>(
+        &self,
+        cpk_path: P,
+        filter: &EntryFilter,
+        on_entry: F,
+    ) -> Result<()> {
+        let indices: Vec<usize> = self
+            .file_table
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.file_type == "FILE" && filter.matches(e))
+            .map(|(idx, _)| idx)
+            .collect();
+        if indices.is_empty() && self.file_table.iter().any(|e| e.file_type == "FILE") {
+            return Err(CpkError::FileNotFound(
+                "no entry matches the filter".to_string(),
+            ));
+        }
+
+        self.extract_entries(cpk_path, indices, on_entry)
+    }
+
+    fn extract_entries<P: AsRef<Path>, F: FnMut(ExtractEvent)>(
+        &self,
+        cpk_path: P,
+        mut indices: Vec<usize>,
+        mut on_entry: F,
+    ) -> Result<()> {
+        match self.duplicates {
+            DuplicatePolicy::Suffix => {}
+            DuplicatePolicy::First => {
+                let later: HashSet<usize> = self
+                    .duplicate_rows()
+                    .into_iter()
+                    .flat_map(|rows| rows.into_iter().skip(1))
+                    .collect();
+                indices.retain(|idx| {
+                    let keep = !later.contains(idx);
+                    if !keep {
+                        warn!(
+                            "Skipping {}: an earlier entry has the same path",
+                            self.file_table[*idx].full_path()
+                        );
+                    }
+                    keep
+                });
+            }
+            DuplicatePolicy::Error => {
+                let selected: HashSet<usize> = indices.iter().copied().collect();
+                let paths: Vec<String> = self
+                    .duplicate_rows()
+                    .into_iter()
+                    .filter(|rows| rows.iter().any(|idx| selected.contains(idx)))
+                    .map(|rows| self.file_table[rows[0]].full_path())
+                    .collect();
+                if !paths.is_empty() {
+                    return Err(CpkError::DuplicatePaths(paths));
+                }
+            }
+        }
+
+        // Visiting entries in offset order keeps reads close to sequential
+        indices.sort_by_key(|&idx| self.file_table[idx].file_offset);
+
+        let output_paths = self.output_paths();
+        // Recorded up front so an interrupted run still leaves the originals
+        let escaped: Vec<(String, String)> = indices
+            .iter()
+            .filter_map(|idx| {
+                let original = self.archive_output_path(&self.file_table[*idx]);
+                let written = &output_paths[idx];
+                (self.name_escape.escape(&original) != original).then(|| {
+                    debug!("{}: escaped to {}", original, written);
+                    (written.clone(), original)
+                })
+            })
+            .collect();
+        escape::record_names(&self.output_dir, &escaped)?;
+        let mut journal = match self.resume {
+            true => {
+                if !self.output_dir.as_os_str().is_empty() {
+                    create_dir_all(&self.output_dir)?;
+                }
+                Some(ExtractJournal::open(&self.output_dir, &cpk_path)?)
+            }
+            false => None,
+        };
+        let file = File::open(cpk_path)?;
+        let mut scratch = Scratch::default();
+        let mut failed = 0;
+        let total = indices.len();
+        let total_bytes = indices
+            .iter()
+            .map(|&idx| {
+                let entry = &self.file_table[idx];
+                entry.extract_size.unwrap_or(entry.file_size)
+            })
+            .sum();
+
+        for (index, idx) in indices.into_iter().enumerate() {
+            self.cancel.check()?;
+            let entry = &self.file_table[idx];
+            on_entry(ExtractEvent::Started {
+                entry,
+                index,
+                total,
+                total_bytes,
+            });
+            let start = Instant::now();
+            let mut warnings = Vec::new();
+            let output_path = &output_paths[&idx];
+            let natural = self.natural_output_path(entry);
+            if *output_path != natural {
+                let message = if natural != self.archive_output_path(entry) {
+                    format!(
+                        "{} escapes to {}, which another entry has, extracting it as {}",
+                        entry.full_path(),
+                        natural,
+                        output_path
+                    )
+                } else if self.is_escaped_name(&natural) {
+                    format!(
+                        "{} is what another entry's name escapes to, extracting it as {}",
+                        entry.full_path(),
+                        output_path
+                    )
+                } else {
+                    format!(
+                        "{} collides with another entry, extracting it as {}",
+                        entry.full_path(),
+                        output_path
+                    )
+                };
+                warn!("{}", message);
+                warnings.push(message);
+            }
+            let result = self.extract_single_file(
+                &file,
+                entry,
+                output_path,
+                &mut scratch,
+                &mut warnings,
+                journal.as_mut(),
+            );
+            let digest = match (self.hash, &result) {
+                (Some(hash), Ok(Some(_))) => Some(hash.hex_digest(scratch.data())),
+                _ => None,
+            };
+            scratch.trim();
+
+            let written = result.as_ref().ok().and_then(Option::as_ref);
+            on_entry(ExtractEvent::Finished(ExtractRecord {
+                entry,
+                written: written.map(|(size, _)| *size),
+                duration: start.elapsed(),
+                warnings,
+                error: result.as_ref().err().map(|e| e.to_string()),
+                error_kind: result.as_ref().err().map(|e| e.kind()),
+                digest,
+                output_path: written.map(|(_, path)| path.clone()),
+            }));
+            match result {
+                Err(e) if self.keep_going => {
+                    warn!("Failed to extract {}: {}", entry.full_path(), e);
+                    failed += 1;
+                }
+                result => {
+                    result?;
+                }
+            }
+        }
+
+        match failed {
+            0 => match journal {
+                Some(journal) => journal.finish(),
+                None => Ok(()),
+            },
+            n => Err(CpkError::EntriesFailed(n)),
+        }
+    }
+
+    /// Where each FILE entry is extracted to, keyed by file table index.
+    ///
+    /// Paths that only differ by case would overwrite each other on Windows and
+    /// macOS, so every entry after the first in table order gets `~N` appended
+    /// to its file stem, on every platform alike. Flat extraction drops the
+    /// directories first, and may keep them in the name instead.
+    fn output_paths(&self) -> HashMap<usize, String> {
+        let mut taken = HashSet::new();
+        let mut paths = HashMap::new();
+
+        for (idx, entry) in self.file_table.iter().enumerate() {
+            if entry.file_type != "FILE" {
+                continue;
+            }
+            let mut path = self.natural_output_path(entry);
+            if taken.insert(path.to_lowercase()) {
+                paths.insert(idx, path);
+                continue;
+            }
+            if self.flat == Some(FlatCollision::DirPrefix) {
+                path = entry.full_path().replace('/', "_");
+                if taken.insert(path.to_lowercase()) {
+                    paths.insert(idx, path);
+                    continue;
+                }
+            }
+
+            let name_start = path.rfind('/').map_or(0, |i| i + 1);
+            let (stem, extension) = match path[name_start..].rfind('.') {
+                Some(dot) if dot > 0 => path.split_at(name_start + dot),
+                _ => (path.as_str(), ""),
+            };
+            let renamed = (1..)
+                .map(|n| format!("{}~{}{}", stem, n, extension))
+                .find(|candidate| taken.insert(candidate.to_lowercase()))
+                .expect("unbounded candidates");
+            paths.insert(idx, renamed);
+        }
+
+        paths
+    }
+
+    /// Where an entry is extracted to when nothing else has that name.
+    fn natural_output_path(&self, entry: &FileEntry) -> String {
+        let path = self.archive_output_path(entry);
+        self.name_escape.escape(&path).into_owned()
+    }
+
+    /// Whether some entry's name only becomes `path` once escaped.
+    fn is_escaped_name(&self, path: &str) -> bool {
+        self.file_table
+            .iter()
+            .filter(|e| e.file_type == "FILE")
+            .any(|e| {
+                let original = self.archive_output_path(e);
+                let escaped = self.name_escape.escape(&original);
+                escaped != original && escaped.eq_ignore_ascii_case(path)
+            })
+    }
+
+    /// `natural_output_path` before names the filesystem can't hold are escaped.
+    fn archive_output_path(&self, entry: &FileEntry) -> String {
+        if self.flat.is_some() {
+            return entry.file_name.replace('/', "_");
+        }
+        let path = entry.full_path();
+        if let Some(prefix) = &self.strip_prefix
+            && let Some((head, rest)) = path.split_at_checked(prefix.len())
+            && head.eq_ignore_ascii_case(prefix)
+            && let Some(rest) = rest.strip_prefix('/')
+            && !rest.is_empty()
+        {
+            return rest.to_string();
+        }
+        path
+    }
+
+    /// Reads an entry's content, decompressing it if it's stored compressed.
+    ///
+    /// The archive is reopened from the path given to `read_cpk`.
+    pub fn read_entry(&self, entry: &FileEntry) -> Result<Vec<u8>> {
+        let file = File::open(self.source_path()?)?;
+        self.read_entry_from(&file, entry)
+    }
+
+    /// Reads an entry's data exactly as stored, without decompressing it.
+    pub fn read_entry_raw(&self, entry: &FileEntry) -> Result<Vec<u8>> {
+        let file = File::open(self.source_path()?)?;
+        self.read_entry_raw_from(&file, entry)
+    }
+
+    /// `read_entry` on an already open archive file, with positioned reads.
+    pub(crate) fn read_entry_from(&self, file: &File, entry: &FileEntry) -> Result<Vec<u8>> {
+        let mut scratch = Scratch::default();
+        self.load_entry(file, entry, &mut scratch, &mut Vec::new())?;
+        Ok(scratch.into_data())
+    }
+
+    pub(crate) fn read_entry_raw_from(&self, file: &File, entry: &FileEntry) -> Result<Vec<u8>> {
         let mut data = vec![0u8; entry.file_size as usize];
-        match reader.read_exact(&mut data) {
-            Ok(()) => {
-                debug!("Successfully read {} bytes", data.len());
+        pread::read_exact_at(file, &mut data, entry.file_offset)?;
+        Ok(data)
+    }
+
+    /// Writes an entry's (decompressed) content to `writer`.
+    pub fn extract_entry_to<W: Write>(&self, entry: &FileEntry, mut writer: W) -> Result<()> {
+        let data = self.read_entry(entry)?;
+        writer.write_all(&data)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn source_path(&self) -> Result<&Path> {
+        self.source_path
+            .as_deref()
+            .ok_or_else(|| CpkError::InvalidFormat("No archive has been read yet".to_string()))
+    }
+
+    /// Writes one entry below the current directory, returning the number of
+    /// bytes written or `None` if it was skipped.
+    fn extract_single_file(
+        &self,
+        file: &File,
+        entry: &FileEntry,
+        output_path: &str,
+        scratch: &mut Scratch,
+        warnings: &mut Vec<String>,
+        mut journal: Option<&mut ExtractJournal>,
+    ) -> Result<Option<(u64, PathBuf)>> {
+        if let Some(path) = journal.as_deref().and_then(|j| j.finished(output_path)) {
+            info!("Skipping {}, an earlier run extracted it", path.display());
+            return Ok(None);
+        }
+        let key = output_path;
+        let mut output_path = self.output_dir.join(output_path);
+        if let Some(dir) = output_path.parent() {
+            create_dir_all(dir)?;
+        }
+
+        // Check for zero-sized files
+        if entry.file_size == 0 {
+            let message = format!("File {} has zero size, skipping", output_path.display());
+            warn!("{}", message);
+            warnings.push(message);
+            return Ok(None);
+        }
+
+        // The extension depends on the content, so it has to be read before
+        // the destination can be checked
+        let guess_extension = self.auto_ext && !entry.file_name.contains('.');
+        if guess_extension {
+            self.load_for_extraction(file, entry, scratch, warnings)?;
+            if let Some(extension) = carve::sniff_extension(scratch.data()) {
+                let mut name = output_path.into_os_string();
+                name.push(".");
+                name.push(extension);
+                output_path = PathBuf::from(name);
             }
-            Err(e) => {
-                return Err(CpkError::Io(e));
+        }
+
+        let extract_size = match self.raw {
+            true => entry.file_size,
+            false => entry.extract_size.unwrap_or(entry.file_size),
+        };
+        let archive_modified = self
+            .source_path
+            .as_ref()
+            .and_then(|path| std::fs::metadata(path).ok())
+            .and_then(|metadata| metadata.modified().ok());
+        // A file an earlier run was part way through is ours to finish
+        let checkpoint = journal
+            .as_deref()
+            .map_or(0, |j| j.checkpoint(key, &output_path));
+        if checkpoint == 0
+            && self
+                .existing
+                .skips(&output_path, extract_size, archive_modified)
+        {
+            info!("Skipping {}, it already exists", output_path.display());
+            return Ok(None);
+        }
+
+        if !guess_extension {
+            self.load_for_extraction(file, entry, scratch, warnings)?;
+        }
+        let data = scratch.data();
+
+        info!(
+            "Extracting: {} ({} bytes)",
+            output_path.display(),
+            data.len()
+        );
+        match journal.as_deref_mut() {
+            Some(journal) if data.len() >= CHECKPOINT_SIZE => {
+                sparse::write_file_checkpointed(
+                    &output_path,
+                    data,
+                    checkpoint,
+                    CHECKPOINT_SIZE,
+                    |offset| journal.record_checkpoint(key, offset),
+                )?;
             }
+            _ => sparse::write_file(&output_path, data)?,
+        }
+        if self.carve {
+            write_carved(&output_path, data)?;
+        }
+        if let Some(journal) = journal {
+            journal.record_finished(key, data.len() as u64, &output_path)?;
+        }
+
+        Ok(Some((data.len() as u64, output_path)))
+    }
+
+    /// Reads an entry into `scratch` the way extraction writes it.
+    fn load_for_extraction(
+        &self,
+        file: &File,
+        entry: &FileEntry,
+        scratch: &mut Scratch,
+        warnings: &mut Vec<String>,
+    ) -> Result<()> {
+        if self.raw {
+            return self.read_stored(file, entry, scratch);
+        }
+        self.load_entry(file, entry, scratch, warnings)?;
+        for processor in &self.processors {
+            processor.process(entry, scratch.data_mut())?;
         }
+        Ok(())
+    }
+
+    /// Reads an entry's stored bytes into `scratch` as they are.
+    fn read_stored(&self, file: &File, entry: &FileEntry, scratch: &mut Scratch) -> Result<()> {
+        scratch.decoded = false;
+        scratch.input.clear();
+        scratch.input.resize(entry.file_size as usize, 0);
+        pread::read_exact_at(file, &mut scratch.input, entry.file_offset)?;
+        debug!("Successfully read {} bytes", scratch.input.len());
+        Ok(())
+    }
+
+    /// Reads an entry into `scratch`, decompressing it if needed.
+    fn load_entry(
+        &self,
+        file: &File,
+        entry: &FileEntry,
+        scratch: &mut Scratch,
+        warnings: &mut Vec<String>,
+    ) -> Result<()> {
+        let entry_path = entry.full_path();
+
+        debug!("Reading file: {}", entry_path);
+        debug!("  Offset: 0x{:X}", entry.file_offset);
+        debug!("  Size: {}", entry.file_size);
+        debug!("  Extract Size: {:?}", entry.extract_size);
+
+        // Read the full file data
+        self.read_stored(file, entry, scratch)?;
+        let data = &scratch.input;
+        let codec = self.codecs.detect(data);
 
         let should_decompress = if let Some(extract_size) = entry.extract_size {
             let compression_ratio = entry.file_size as f32 / extract_size as f32;
@@ -764,76 +2132,1642 @@ impl Cpk {
             );
             compression_ratio < 1.0
         } else {
-            // Fallback: check for CRILAYLA signature if no extract_size
-            data.len() >= 8 && &data[0..8] == b"CRILAYLA"
+            // Fallback: check for a known codec's signature if no extract_size
+            codec.is_some()
         };
 
-        if should_decompress && data.len() >= 8 && &data[0..8] == b"CRILAYLA" {
-            info!(
-                "Decompressing CRILAYLA file: {} (compressed size: {})",
-                output_path,
-                data.len()
-            );
-
-            // Read the uncompressed size from the header for validation
-            if data.len() >= 16 {
-                let uncompressed_size =
-                    u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
-                let header_offset =
-                    u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
-
-                debug!(
-                    "CRILAYLA header: uncompressed_size={}, header_offset={}",
-                    uncompressed_size, header_offset
+        match codec.filter(|_| should_decompress) {
+            Some(codec) => {
+                info!(
+                    "Decompressing {} file: {} (compressed size: {})",
+                    codec.name(),
+                    entry_path,
+                    data.len()
                 );
+                if let Some(message) = codec.validate(data, entry.extract_size)? {
+                    warn!("{}", message);
+                    warnings.push(message);
+                }
 
-                // Validate against extract_size if available
-                if let Some(extract_size) = entry.extract_size {
-                    if uncompressed_size + 0x100 != extract_size as usize {
-                        warn!(
-                            "CRILAYLA uncompressed size mismatch: header says {}, extract_size is {}",
-                            uncompressed_size + 0x100,
-                            extract_size
+                if self.lenient_decompress {
+                    let faked = codec.decompress_lenient(data, &mut scratch.output)?;
+                    if faked > 0 {
+                        let message = format!(
+                            "{}: {} bytes the damaged data doesn't hold were filled with zeros",
+                            entry_path, faked
                         );
+                        warn!("{}", message);
+                        warnings.push(message);
                     }
+                } else {
+                    codec.decompress(data, &mut scratch.output)?;
                 }
-
-                // Validate the header makes sense
-                if header_offset + 0x110 > data.len() {
-                    return Err(CpkError::Compression(format!(
-                        "Invalid CRILAYLA header: header_offset={} + 0x110 > data.len()={}",
-                        header_offset,
-                        data.len()
-                    )));
-                }
+                scratch.decoded = true;
+                info!("Decompressed to {} bytes", scratch.output.len());
             }
+            None if should_decompress => {
+                let message = format!(
+                    "File {} should be compressed (ratio < 1.0) but matches no codec ({})",
+                    entry_path,
+                    self.codecs.names().join(", ")
+                );
+                warn!("{}", message);
+                warnings.push(message);
+            }
+            None => {}
+        }
 
-            data = decompress_crilayla(&data)?;
-            info!("Decompressed to {} bytes", data.len());
-        } else if should_decompress {
-            warn!(
-                "File {} should be compressed (ratio < 1.0) but doesn't have CRILAYLA signature",
-                output_path
-            );
+        Ok(())
+    }
+
+    /// Replaces the entries matching `target` and writes the rebuilt archive to `output_path`.
+    pub fn replace_file<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+        &mut self,
+        cpk_path: P,
+        target: &str,
+        replacement_path: Q,
+        output_path: R,
+        compression: &CompressionPolicy,
+    ) -> Result<()> {
+        let replacement = [(target.to_string(), replacement_path.as_ref().to_path_buf())];
+        self.replace_files(cpk_path, &replacement, output_path, compression)
+    }
+
+    /// Performs several replacements in a single archive rewrite.
+    ///
+    /// When the output is the input itself and every replacement fits where
+    /// the old data was, the archive is patched in place instead.
+    pub fn replace_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        cpk_path: P,
+        replacements: &[(String, PathBuf)],
+        output_path: Q,
+        compression: &CompressionPolicy,
+    ) -> Result<()> {
+        self.require_packets()?;
+        let resolved = self.resolve_replacements(replacements, compression)?;
+
+        let cpk_path = cpk_path.as_ref();
+        if same_file(cpk_path, output_path.as_ref()) && self.patch_in_place(cpk_path, &resolved)? {
+            return Ok(());
         }
+        self.rewrite(cpk_path, output_path, resolved)
+    }
 
-        info!("Extracting: {} ({} bytes)", output_path, data.len());
-        std::fs::write(&output_path, &data)?;
+    /// Reads and stores each replacement, paired with the entries its target matches.
+    fn resolve_replacements(
+        &self,
+        replacements: &[(String, PathBuf)],
+        compression: &CompressionPolicy,
+    ) -> Result<Vec<(usize, StoredData)>> {
+        // Compressed entries are only recognisable through a differing ExtractSize
+        let mut compression = *compression;
+        if compression.enabled && !self.can_store_compressed()? {
+            warn!("TOC has no ExtractSize column, storing replacements uncompressed");
+            compression.enabled = false;
+        }
+        let codec = self.codecs.compressor();
+        if compression.enabled && codec.is_none() {
+            warn!("No codec is registered, storing replacements uncompressed");
+            compression.enabled = false;
+        }
 
-        Ok(())
+        let mut resolved = Vec::new();
+        for (target, replacement_path) in replacements {
+            self.cancel.check()?;
+            let data = std::fs::read(replacement_path)?;
+            let data = match codec {
+                Some(codec) => compression.store(&**codec, data)?,
+                None => StoredData::raw(data),
+            };
+            info!("Replacing {} with {}", target, replacement_path.display());
+            if data.is_compressed() {
+                debug!(
+                    "Compressed {} from {} to {} bytes",
+                    replacement_path.display(),
+                    data.extract_size,
+                    data.data.len()
+                );
+            }
+            for idx in self.find_targets(target, &EntryFilter::default())? {
+                resolved.push((idx, data.clone()));
+            }
+        }
+        Ok(resolved)
     }
 
-    pub fn replace_file<P: AsRef<Path>>(
+    /// Replaces entries the way CriPakTools patches archives: the new data is
+    /// appended after the end of the archive and only the TOC cells of the
+    /// replaced entries change, so everything else keeps its position and the
+    /// patched archive diffs cleanly against the original.
+    ///
+    /// The old data stays behind unreferenced. Its ranges are listed after the
+    /// appended data, so layout checks tell it apart from damage. Archives with
+    /// an ITOC are refused, since their offsets follow from the entry order.
+    pub fn append_files<P: AsRef<Path>, Q: AsRef<Path>>(
         &mut self,
-        _cpk_path: P,
-        _target: &str,
-        _replacement_path: P,
-        _output_path: P,
+        cpk_path: P,
+        replacements: &[(String, PathBuf)],
+        output_path: Q,
+        compression: &CompressionPolicy,
     ) -> Result<()> {
-        // This is a simplified implementation
-        // A full implementation would need to handle UTF table updates
-        Err(CpkError::Unsupported(
-            "File replacement not yet implemented".to_string(),
-        ))
+        self.require_packets()?;
+        let (Some(packet), None) = (&self.toc_packet, &self.itoc_packet) else {
+            return Err(CpkError::Unsupported(
+                "Appending needs an archive with a TOC and no ITOC".to_string(),
+            ));
+        };
+        let mut toc_packet = packet.clone();
+        let mut toc = Utf::new();
+        toc.read_utf(&toc_packet)?;
+        for column in ["FileSize", "FileOffset", "ExtractSize"] {
+            if toc.has_column(column) && !toc.is_per_row(column) {
+                return Err(CpkError::Unsupported(format!(
+                    "TOC column '{}' is not stored per row",
+                    column
+                )));
+            }
+        }
+        let has_extract_size = toc.has_column("ExtractSize");
+        let has_crc = patches_crc(&toc);
+
+        let resolved = self.resolve_replacements(replacements, compression)?;
+        // A later replacement of the same entry wins, as in a rewrite
+        let mut patches: BTreeMap<usize, StoredData> = BTreeMap::new();
+        for (idx, data) in resolved {
+            let entry = &self.file_table[idx];
+            if entry.toc_name != "TOC" || entry.row.is_none() {
+                return Err(CpkError::InvalidFormat(format!(
+                    "Entry '{}' has no TOC row to patch",
+                    entry.full_path()
+                )));
+            }
+            patches.insert(idx, data);
+        }
+
+        // What earlier patches left behind stays listed, along with their list
+        let mut replaced: Vec<(u64, u64)> = self
+            .file_table
+            .iter()
+            .filter(|e| matches!(e.file_type.as_str(), "REPLACED" | "REPLACED_LIST"))
+            .map(|e| (e.file_offset - self.base_offset, e.file_size))
+            .collect();
+        for &idx in patches.keys() {
+            let old = &self.file_table[idx];
+            let (start, end) = (old.file_offset, old.file_offset + old.file_size);
+            // Data another entry still points into isn't left behind
+            let shared = self.file_table.iter().enumerate().any(|(i, e)| {
+                e.file_type == "FILE"
+                    && !patches.contains_key(&i)
+                    && e.file_offset < end
+                    && start < e.file_offset + e.file_size
+            });
+            let range = (start - self.base_offset, old.file_size);
+            if old.file_size > 0 && !shared && !replaced.contains(&range) {
+                replaced.push(range);
+            }
+        }
+        replaced.sort_unstable();
+
+        let cpk_path = cpk_path.as_ref();
+        let output_path = output_path.as_ref();
+        let staged = (!same_file(cpk_path, output_path)).then(|| StagedFile::new(output_path));
+        let target = match &staged {
+            Some(staged) => {
+                std::fs::copy(cpk_path, staged.path())?;
+                staged.path()
+            }
+            None => cpk_path,
+        };
+        let rollback = match staged {
+            Some(_) => None,
+            None => Some(Rollback::begin(target, &self.table_regions())?),
+        };
+        let mut file = OpenOptions::new().write(true).open(target)?;
+
+        let result = (|| {
+            let align = self.align.max(1) as u64;
+            let toc_base = toc_base_offset(self.toc_offset, self.content_offset);
+            let mut written = file.metadata()?.len().saturating_sub(self.base_offset);
+            let mut packed_delta = 0i64;
+            let mut data_delta = 0i64;
+            let mut new_offsets = Vec::new();
+            file.seek(SeekFrom::Start(self.base_offset + written))?;
+            for (&idx, data) in &patches {
+                self.cancel.check()?;
+                let entry = &self.file_table[idx];
+                let row = entry.row.unwrap_or_default() as usize;
+                let offset = align_up(written, align);
+                write_padding(&mut file, offset - written)?;
+                file.write_all(&data.data)?;
+                written = offset + data.data.len() as u64;
+
+                toc.patch_cell(&mut toc_packet, row, "FileOffset", offset - toc_base)?;
+                toc.patch_cell(&mut toc_packet, row, "FileSize", data.data.len() as u64)?;
+                if has_extract_size {
+                    toc.patch_cell(&mut toc_packet, row, "ExtractSize", data.extract_size)?;
+                }
+                if has_crc {
+                    toc.patch_cell(&mut toc_packet, row, "CRC", data.crc as u64)?;
+                }
+                packed_delta += data.data.len() as i64 - entry.file_size as i64;
+                data_delta +=
+                    data.extract_size as i64 - entry.extract_size.unwrap_or(entry.file_size) as i64;
+                new_offsets.push(self.base_offset + offset);
+            }
+            let list = replaced::encode(&replaced);
+            let list_range = (written, list.len() as u64);
+            file.write_all(&list)?;
+            written += list.len() as u64;
+
+            // The content area now runs to the end of the appended data
+            let mut header_packet = self.cpk_packet.clone();
+            let mut header = Utf::new();
+            header.read_utf(&header_packet)?;
+            let mut header_updates = Vec::new();
+            for (column, value) in [
+                (
+                    "ContentSize",
+                    Some(written.saturating_sub(self.content_offset)),
+                ),
+                ("FileSize", Some(written)),
+                (
+                    "EnabledPackedSize",
+                    self.cpk_data
+                        .get("EnabledPackedSize")
+                        .and_then(|v| v.as_u64())
+                        .map(|v| (v as i64 + packed_delta).max(0) as u64),
+                ),
+                (
+                    "EnabledDataSize",
+                    self.cpk_data
+                        .get("EnabledDataSize")
+                        .and_then(|v| v.as_u64())
+                        .map(|v| (v as i64 + data_delta).max(0) as u64),
+                ),
+            ] {
+                if let Some(value) = value
+                    && header.is_per_row(column)
+                {
+                    header.patch_cell(&mut header_packet, 0, column, value)?;
+                    header_updates.push((column, value));
+                }
+            }
+
+            self.write_tables_in_place(&mut file, &toc_packet, &header_packet)?;
+            Ok((new_offsets, list_range, header_packet, header_updates))
+        })();
+        let (new_offsets, list_range, header_packet, header_updates) =
+            settle_in_place(rollback, file, result)?;
+        if let Some(staged) = staged {
+            staged.commit()?;
+        }
+        info!(
+            "Appended {} entr{} to {}",
+            patches.len(),
+            if patches.len() == 1 { "y" } else { "ies" },
+            output_path.display()
+        );
+
+        for ((idx, data), offset) in patches.into_iter().zip(new_offsets) {
+            let entry = &mut self.file_table[idx];
+            entry.file_offset = offset;
+            entry.file_size = data.data.len() as u64;
+            if has_extract_size {
+                entry.extract_size = Some(data.extract_size);
+            }
+            if has_crc {
+                entry.crc = Some(data.crc);
+            }
+        }
+        self.list_replaced(&replaced, list_range);
+        self.apply_header_updates(header_updates);
+        self.toc_packet = Some(toc_packet);
+        self.cpk_packet = header_packet;
+        Ok(())
+    }
+
+    /// Replaces the entries describing data append-style patches left behind
+    /// with `ranges` and their `list`, as `(offset, length)` from the archive's start.
+    fn list_replaced(&mut self, ranges: &[(u64, u64)], list: (u64, u64)) {
+        self.file_table
+            .retain(|e| !matches!(e.file_type.as_str(), "REPLACED" | "REPLACED_LIST"));
+        for &(offset, len) in ranges {
+            self.file_table.push(FileEntry {
+                file_name: "REPLACED".to_string(),
+                file_offset: self.base_offset + offset,
+                file_size: len,
+                file_type: "REPLACED".to_string(),
+                toc_name: "CPK".to_string(),
+                ..FileEntry::new()
+            });
+        }
+        self.file_table.push(FileEntry {
+            file_name: "REPLACED_LIST".to_string(),
+            file_offset: self.base_offset + list.0,
+            file_size: list.1,
+            file_type: "REPLACED_LIST".to_string(),
+            toc_name: "CPK".to_string(),
+            ..FileEntry::new()
+        });
+    }
+
+    /// Where the TOC and header tables sit in the archive file, as `(offset, length)`.
+    fn table_regions(&self) -> Vec<(u64, u64)> {
+        let mut regions = vec![(self.base_offset, self.cpk_packet.len() as u64 + 0x10)];
+        if let Some(packet) = &self.toc_packet {
+            regions.push((
+                self.base_offset + self.toc_offset,
+                packet.len() as u64 + 0x10,
+            ));
+        }
+        regions
+    }
+
+    /// Writes patched TOC and header packets back over the originals; patched
+    /// cells keep their width, so the tables keep their size.
+    fn write_tables_in_place(
+        &self,
+        file: &mut File,
+        toc_packet: &[u8],
+        header_packet: &[u8],
+    ) -> Result<()> {
+        file.seek(SeekFrom::Start(self.base_offset + self.toc_offset))?;
+        file.write_all(&encode_table(
+            b"TOC ",
+            toc_packet,
+            self.table_encryption("TOC_HDR"),
+        ))?;
+        file.seek(SeekFrom::Start(self.base_offset))?;
+        file.write_all(&encode_table(
+            b"CPK ",
+            header_packet,
+            self.table_encryption("CPK_HDR"),
+        ))?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn apply_header_updates(&mut self, updates: Vec<(&str, u64)>) {
+        for (column, value) in updates {
+            self.cpk_data
+                .insert(column.to_string(), CellValue::UInt64(value));
+        }
+    }
+
+    /// Sets the UserString of every TOC entry matching `filter` and writes the
+    /// archive to `output_path`, returning how many entries changed.
+    ///
+    /// The TOC grows or shrinks with its string table, so the archive is
+    /// rewritten rather than patched.
+    pub fn set_user_strings<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        cpk_path: P,
+        filter: &EntryFilter,
+        user_string: &str,
+        output_path: Q,
+    ) -> Result<usize> {
+        self.require_packets()?;
+        let Some(packet) = &self.toc_packet else {
+            return Err(CpkError::Unsupported(
+                "Archive has no TOC to hold user strings".to_string(),
+            ));
+        };
+        let mut toc = Utf::new();
+        toc.read_utf(packet)?;
+        if !toc.has_column("UserString") {
+            return Err(CpkError::Unsupported(
+                "TOC has no UserString column".to_string(),
+            ));
+        }
+
+        let matched: Vec<usize> = self
+            .file_table
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.toc_name == "TOC" && e.file_type == "FILE" && filter.matches(e))
+            .map(|(idx, _)| idx)
+            .collect();
+        if matched.is_empty() {
+            return Err(CpkError::FileNotFound(filter.patterns.join(", ")));
+        }
+
+        for &idx in &matched {
+            let row = self.file_table[idx].row.unwrap_or_default() as usize;
+            toc.set_cell(
+                row,
+                "UserString",
+                CellValue::String(user_string.to_string()),
+            )?;
+        }
+        let previous = self.toc_packet.replace(toc.to_bytes()?);
+        if let Err(e) = self.rewrite(cpk_path, output_path, Vec::new()) {
+            self.toc_packet = previous;
+            return Err(e);
+        }
+
+        for &idx in &matched {
+            self.file_table[idx].user_string = Some(user_string.to_string());
+        }
+        Ok(matched.len())
+    }
+
+    /// Moves the TOC entry at path `from` to path `to` and writes the archive
+    /// to `output_path`.
+    ///
+    /// Only the DirName/FileName cells change; the string table grows as
+    /// needed and the file data is copied as is. Rows keep their place, so a
+    /// header saying the TOC is sorted is set to say it isn't.
+    pub fn rename_entry<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        cpk_path: P,
+        from: &str,
+        to: &str,
+        output_path: Q,
+    ) -> Result<()> {
+        self.require_packets()?;
+        let Some(packet) = &self.toc_packet else {
+            return Err(CpkError::Unsupported(
+                "Archive has no TOC holding entry names".to_string(),
+            ));
+        };
+        let mut toc = Utf::new();
+        toc.read_utf(packet)?;
+
+        let (from, to) = (normalize_separators(from), normalize_separators(to));
+        if to.is_empty() {
+            return Err(CpkError::InvalidFormat("New path is empty".to_string()));
+        }
+        let toc_files = || {
+            self.file_table
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| e.toc_name == "TOC" && e.file_type == "FILE")
+        };
+        let idx = toc_files()
+            .find(|(_, e)| e.full_path() == from)
+            .map(|(idx, _)| idx)
+            .ok_or_else(|| CpkError::FileNotFound(from.clone()))?;
+        if toc_files().any(|(other, e)| other != idx && e.full_path() == to) {
+            return Err(CpkError::InvalidFormat(format!(
+                "{} is already in the archive",
+                to
+            )));
+        }
+
+        let row = self.file_table[idx].row.unwrap_or_default() as usize;
+        let (dir, file) = match toc.has_column("DirName") {
+            true => to.rsplit_once('/').unwrap_or(("", &to)),
+            false => ("", to.as_str()),
+        };
+        if toc.has_column("DirName") {
+            toc.set_cell(row, "DirName", CellValue::String(dir.to_string()))?;
+        }
+        toc.set_cell(row, "FileName", CellValue::String(file.to_string()))?;
+
+        // Readers may bisect a TOC the header says is sorted, and the new
+        // name can break that order
+        let sorted = self
+            .cpk_data
+            .get("Sorted")
+            .and_then(CellValue::as_u64)
+            .is_some_and(|sorted| sorted != 0);
+        let mut header_packet = self.cpk_packet.clone();
+        let mut unsorted = None;
+        if sorted {
+            let mut header = Utf::new();
+            header.read_utf(&header_packet)?;
+            let zero = header
+                .columns
+                .iter()
+                .find(|c| c.name == "Sorted")
+                .ok_or_else(|| CpkError::Parse("Unknown column: Sorted".to_string()))?
+                .parse_value("0")?;
+            match header.is_per_row("Sorted") {
+                true => header.patch_cell(&mut header_packet, 0, "Sorted", 0)?,
+                false => {
+                    header.set_cell(0, "Sorted", zero.clone())?;
+                    header_packet = header.to_bytes()?;
+                }
+            }
+            unsorted = Some(zero);
+        }
+
+        let previous = self.toc_packet.replace(toc.to_bytes()?);
+        let previous_header = std::mem::replace(&mut self.cpk_packet, header_packet);
+        if let Err(e) = self.rewrite(cpk_path, output_path, Vec::new()) {
+            self.toc_packet = previous;
+            self.cpk_packet = previous_header;
+            return Err(e);
+        }
+
+        if let Some(zero) = unsorted {
+            self.cpk_data.insert("Sorted".to_string(), zero);
+        }
+        let entry = &mut self.file_table[idx];
+        entry.dir_name = toc.has_column("DirName").then(|| dir.to_string());
+        entry.file_name = file.to_string();
+        Ok(())
+    }
+
+    /// Sets one cell of a table (`CPK`, `TOC`, `ITOC`, `GTOC` or `ETOC`, with or
+    /// without the `_HDR` suffix) to `value`, parsed for the column's type, and
+    /// writes the archive to `output_path`, re-encrypting the table if it was.
+    ///
+    /// The layout cells (sizes and offsets) of the header and TOC are
+    /// recomputed by the rewrite, so editing them is refused. Afterwards the
+    /// archive written is read back, so this describes it.
+    pub fn set_table_cell<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        cpk_path: P,
+        table: &str,
+        row: usize,
+        column: &str,
+        value: &str,
+        output_path: Q,
+    ) -> Result<()> {
+        self.require_packets()?;
+        let name = table.to_uppercase();
+        let name = name.strip_suffix("_HDR").unwrap_or(&name);
+        if LAYOUT_COLUMNS.contains(&column) {
+            return Err(CpkError::Unsupported(format!(
+                "{} is part of the archive layout and is recomputed on writing",
+                column
+            )));
+        }
+        let packet = self
+            .table_packet_mut(name)
+            .ok_or_else(|| CpkError::FileNotFound(format!("{} table", table)))?;
+
+        let mut utf = Utf::new();
+        utf.read_utf(packet).map_err(|e| e.in_table(name))?;
+        let parsed = utf
+            .columns
+            .iter()
+            .find(|c| c.name == column)
+            .ok_or_else(|| CpkError::Parse(format!("Unknown column: {}", column)))?
+            .parse_value(value)?;
+        utf.set_cell(row, column, parsed)?;
+        let previous = std::mem::replace(packet, utf.to_bytes()?);
+
+        if let Err(e) = self.rewrite(cpk_path, &output_path, Vec::new()) {
+            if let Some(packet) = self.table_packet_mut(name) {
+                *packet = previous;
+            }
+            return Err(e);
+        }
+        self.read_cpk(output_path)
+    }
+
+    /// Forgets everything read from an archive, keeping the settings, so
+    /// `read_cpk` describes only the archive it reads.
+    fn clear_tables(&mut self) {
+        let settings = Self::new();
+        self.file_table.clear();
+        self.cpk_data.clear();
+        self.path_index.clear();
+        self.cpk_packet.clear();
+        self.toc_packet = None;
+        self.itoc_packet = None;
+        self.etoc_packet = None;
+        self.gtoc_packet = None;
+        self.diagnostics.clear();
+        self.toc_offset = settings.toc_offset;
+        self.etoc_offset = settings.etoc_offset;
+        self.itoc_offset = settings.itoc_offset;
+        self.gtoc_offset = settings.gtoc_offset;
+        self.content_offset = settings.content_offset;
+        self.align = settings.align;
+    }
+
+    /// The decrypted packet of a table named `CPK`, `TOC`, `ITOC`, `GTOC` or `ETOC`.
+    fn table_packet_mut(&mut self, name: &str) -> Option<&mut Vec<u8>> {
+        match name {
+            "CPK" => Some(&mut self.cpk_packet),
+            "TOC" => self.toc_packet.as_mut(),
+            "ITOC" => self.itoc_packet.as_mut(),
+            "GTOC" => self.gtoc_packet.as_mut(),
+            "ETOC" => self.etoc_packet.as_mut(),
+            _ => None,
+        }
+    }
+
+    fn can_store_compressed(&self) -> Result<bool> {
+        match &self.toc_packet {
+            Some(packet) => {
+                let mut toc = Utf::new();
+                toc.read_utf(packet)?;
+                Ok(toc.has_column("ExtractSize"))
+            }
+            // ITOC size tables always carry ExtractSize
+            None => Ok(true),
+        }
+    }
+
+    /// Overwrites the replaced entries where they already are, zero-filling the
+    /// rest of their old space, and patches the size cells in the TOC and the
+    /// header without moving anything else.
+    ///
+    /// Returns false, leaving the archive untouched, when that isn't possible:
+    /// a replacement is larger than the data it replaces, or small enough to
+    /// leave more than alignment padding after it (which would read as an
+    /// unaccounted gap), the archive has an ITOC (whose offsets follow from the
+    /// sizes), or the data is shared with another entry. The file is modified directly; the regions about to be
+    /// overwritten are saved to a rollback record first, so an interrupted
+    /// patch is undone the next time the archive is modified.
+    fn patch_in_place(
+        &mut self,
+        cpk_path: &Path,
+        replacements: &[(usize, StoredData)],
+    ) -> Result<bool> {
+        let Some(packet) = &self.toc_packet else {
+            return Ok(false);
+        };
+        if self.itoc_packet.is_some() {
+            return Ok(false);
+        }
+
+        let mut toc_packet = packet.clone();
+        let mut toc = Utf::new();
+        toc.read_utf(&toc_packet)?;
+        let has_extract_size = toc.has_column("ExtractSize");
+        if !toc.is_per_row("FileSize") || (has_extract_size && !toc.is_per_row("ExtractSize")) {
+            return Ok(false);
+        }
+        let has_crc = patches_crc(&toc);
+
+        // A later replacement of the same entry wins, as in a rewrite
+        let mut patches: HashMap<usize, &StoredData> = HashMap::new();
+        for (idx, data) in replacements {
+            patches.insert(*idx, data);
+        }
+
+        let mut packed_delta = 0i64;
+        let mut data_delta = 0i64;
+        for (&idx, data) in &patches {
+            let entry = &self.file_table[idx];
+            let Some(row) = entry.row else {
+                return Ok(false);
+            };
+            let new_size = data.data.len() as u64;
+            if entry.toc_name != "TOC" || new_size > entry.file_size {
+                return Ok(false);
+            }
+            let align = (self.align as u64).max(1);
+            let start = entry.file_offset - self.base_offset;
+            if align_up(start + new_size, align) != align_up(start + entry.file_size, align) {
+                return Ok(false);
+            }
+            let shared = self.file_table.iter().enumerate().any(|(other, e)| {
+                other != idx
+                    && e.file_type == "FILE"
+                    && e.file_size > 0
+                    && e.file_offset == entry.file_offset
+            });
+            if shared {
+                return Ok(false);
+            }
+
+            toc.patch_cell(
+                &mut toc_packet,
+                row as usize,
+                "FileSize",
+                data.data.len() as u64,
+            )?;
+            if has_extract_size {
+                toc.patch_cell(
+                    &mut toc_packet,
+                    row as usize,
+                    "ExtractSize",
+                    data.extract_size,
+                )?;
+            }
+            packed_delta += data.data.len() as i64 - entry.file_size as i64;
+            data_delta +=
+                data.extract_size as i64 - entry.extract_size.unwrap_or(entry.file_size) as i64;
+        }
+
+        let mut header_packet = self.cpk_packet.clone();
+        let mut header = Utf::new();
+        header.read_utf(&header_packet)?;
+        let mut header_updates = Vec::new();
+        for (column, delta) in [
+            ("EnabledPackedSize", packed_delta),
+            ("EnabledDataSize", data_delta),
+        ] {
+            if let Some(value) = self.cpk_data.get(column).and_then(|v| v.as_u64())
+                && header.is_per_row(column)
+            {
+                let value = (value as i64 + delta).max(0) as u64;
+                header.patch_cell(&mut header_packet, 0, column, value)?;
+                header_updates.push((column, value));
+            }
+        }
+
+        let mut regions = self.table_regions();
+        regions.extend(patches.keys().map(|&idx| {
+            let entry = &self.file_table[idx];
+            (entry.file_offset, entry.file_size)
+        }));
+        let rollback = Rollback::begin(cpk_path, &regions)?;
+        let mut file = OpenOptions::new().write(true).open(cpk_path)?;
+        let result = (|| {
+            for (&idx, data) in &patches {
+                self.cancel.check()?;
+                let entry = &self.file_table[idx];
+                file.seek(SeekFrom::Start(entry.file_offset))?;
+                file.write_all(&data.data)?;
+                write_padding(&mut file, entry.file_size - data.data.len() as u64)?;
+            }
+            self.write_tables_in_place(&mut file, &toc_packet, &header_packet)
+        })();
+        settle_in_place(Some(rollback), file, result)?;
+        info!(
+            "Patched {} entr{} in place in {}",
+            patches.len(),
+            if patches.len() == 1 { "y" } else { "ies" },
+            cpk_path.display()
+        );
+
+        for (&idx, data) in &patches {
+            let entry = &mut self.file_table[idx];
+            entry.file_size = data.data.len() as u64;
+            if has_extract_size {
+                entry.extract_size = Some(data.extract_size);
+            }
+            if has_crc {
+                entry.crc = Some(data.crc);
+            }
+        }
+        self.apply_header_updates(header_updates);
+        self.toc_packet = Some(toc_packet);
+        self.cpk_packet = header_packet;
+        Ok(true)
+    }
+
+    /// Copies the archive to `output_path`, substituting the data of the given
+    /// file table entries and updating the size/offset cells that describe them.
+    fn rewrite<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        cpk_path: P,
+        output_path: Q,
+        replacements: Vec<(usize, StoredData)>,
+    ) -> Result<()> {
+        let mut source = BufReader::new(File::open(cpk_path)?);
+        let align = self.align.max(1) as u64;
+
+        // Collect the stored blobs; a TOC row and an ITOC ID can share one
+        let has_itoc = self.itoc_packet.is_some();
+        let mut slots: Vec<ContentSlot> = Vec::new();
+        let mut slot_of_entry: HashMap<usize, usize> = HashMap::new();
+        let mut slot_of_id: HashMap<u32, usize> = HashMap::new();
+
+        for (idx, entry) in self.file_table.iter().enumerate() {
+            if entry.file_type != "FILE" || entry.toc_name != "TOC" {
+                continue;
+            }
+            let itoc_id = if has_itoc { entry.id } else { None };
+            if let Some(id) = itoc_id {
+                slot_of_id.insert(id, slots.len());
+            }
+            slot_of_entry.insert(idx, slots.len());
+            slots.push(ContentSlot::new(entry, entry.row, itoc_id));
+        }
+        for (idx, entry) in self.file_table.iter().enumerate() {
+            if entry.file_type != "FILE" || entry.toc_name != "ITOC" {
+                continue;
+            }
+            match entry.id.and_then(|id| slot_of_id.get(&id)) {
+                Some(&slot) => {
+                    slot_of_entry.insert(idx, slot);
+                }
+                None => {
+                    slot_of_entry.insert(idx, slots.len());
+                    slots.push(ContentSlot::new(entry, None, entry.id));
+                }
+            }
+        }
+
+        for (idx, data) in replacements {
+            let slot = slot_of_entry.get(&idx).ok_or_else(|| {
+                CpkError::InvalidFormat(format!(
+                    "Entry '{}' has no stored data to replace",
+                    self.file_table[idx].file_name
+                ))
+            })?;
+            slots[*slot].replacement = Some(data);
+        }
+
+        // ITOC offsets are implied by ID order, otherwise keep the original order
+        let mut order: Vec<usize> = (0..slots.len()).collect();
+        if has_itoc {
+            order.sort_by_key(|&i| (slots[i].itoc_id, slots[i].source_offset));
+        } else {
+            order.sort_by_key(|&i| slots[i].source_offset);
+        }
+
+        let mut tables = Vec::new();
+        if let Some(packet) = &self.toc_packet {
+            tables.push(TableRegion::new(
+                b"TOC ",
+                self.toc_offset,
+                packet.clone(),
+                self.is_table_encrypted("TOC_HDR"),
+            ));
+        }
+        if let Some(packet) = &self.itoc_packet {
+            let packet = rebuild_itoc(packet, &slots)?;
+            tables.push(TableRegion::new(
+                b"ITOC",
+                self.itoc_offset,
+                packet,
+                self.is_table_encrypted("ITOC_HDR"),
+            ));
+        }
+        if let Some(packet) = &self.gtoc_packet {
+            tables.push(TableRegion::new(
+                b"GTOC",
+                self.gtoc_offset,
+                packet.clone(),
+                self.is_table_encrypted("GTOC_HDR"),
+            ));
+        }
+        if let Some(packet) = &self.etoc_packet {
+            tables.push(TableRegion::new(
+                b"ETOC",
+                self.etoc_offset,
+                packet.clone(),
+                self.is_table_encrypted("ETOC_HDR"),
+            ));
+        }
+        tables.sort_by_key(|t| t.source_offset);
+
+        // Tables ahead of the content keep their place unless one of them grew
+        let header_end = tables
+            .iter()
+            .map(|t| t.source_offset)
+            .chain(std::iter::once(self.content_offset))
+            .min()
+            .unwrap_or(self.content_offset);
+        let mut pos = header_end;
+        for table in tables
+            .iter_mut()
+            .filter(|t| t.source_offset < self.content_offset)
+        {
+            table.new_offset = pos.max(table.source_offset);
+            pos = table.new_offset + table.len();
+        }
+
+        let content_offset = if pos <= self.content_offset {
+            self.content_offset
+        } else {
+            align_up(pos, align)
+        };
+        let mut pos = content_offset;
+        for &i in &order {
+            slots[i].new_offset = pos;
+            pos += align_up(slots[i].stored_size(), align);
+        }
+        let content_end = pos;
+
+        let old_content_end = self.content_offset
+            + self
+                .cpk_data
+                .get("ContentSize")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(content_end - content_offset);
+        let shift = content_end as i64 - old_content_end as i64;
+        for table in tables
+            .iter_mut()
+            .filter(|t| t.source_offset >= self.content_offset)
+        {
+            table.new_offset = pos.max((table.source_offset as i64 + shift) as u64);
+            pos = table.new_offset + table.len();
+        }
+        let total_size = pos;
+
+        // Point the TOC rows at the relocated data
+        let new_toc_offset = tables
+            .iter()
+            .find(|t| &t.signature == b"TOC ")
+            .map_or(self.toc_offset, |t| t.new_offset);
+        if let Some(table) = tables.iter_mut().find(|t| &t.signature == b"TOC ") {
+            let mut toc = Utf::new();
+            toc.read_utf(&table.packet)?;
+            let toc_base = toc_base_offset(new_toc_offset, content_offset);
+            let has_crc = patches_crc(&toc);
+
+            for slot in &slots {
+                let Some(row) = slot.toc_row else { continue };
+                let row = row as usize;
+                toc.patch_cell(&mut table.packet, row, "FileSize", slot.stored_size())?;
+                if toc.has_column("ExtractSize") {
+                    toc.patch_cell(&mut table.packet, row, "ExtractSize", slot.extract_size())?;
+                }
+                toc.patch_cell(
+                    &mut table.packet,
+                    row,
+                    "FileOffset",
+                    slot.new_offset - toc_base,
+                )?;
+                if let (true, Some(stored)) = (has_crc, &slot.replacement) {
+                    toc.patch_cell(&mut table.packet, row, "CRC", stored.crc as u64)?;
+                }
+            }
+        }
+
+        // Update the header's view of the layout
+        let mut header_packet = self.cpk_packet.clone();
+        let mut header = Utf::new();
+        header.read_utf(&header_packet)?;
+
+        let old_packed: u64 = slots.iter().map(|s| s.stored_size).sum();
+        let new_packed: u64 = slots.iter().map(|s| s.stored_size()).sum();
+        let old_data: u64 = slots.iter().map(|s| s.extract_size).sum();
+        let new_data: u64 = slots.iter().map(|s| s.extract_size()).sum();
+        let old_total = self.archive_size().unwrap_or(total_size);
+
+        let mut updates = vec![
+            ("ContentOffset", content_offset),
+            ("ContentSize", content_end - content_offset),
+        ];
+        for table in &tables {
+            let (offset_column, size_column) = match &table.signature {
+                b"TOC " => ("TocOffset", "TocSize"),
+                b"ITOC" => ("ItocOffset", "ItocSize"),
+                b"GTOC" => ("GtocOffset", "GtocSize"),
+                _ => ("EtocOffset", "EtocSize"),
+            };
+            updates.push((offset_column, table.new_offset));
+            updates.push((size_column, table.len()));
+        }
+        for (column, delta_from) in [
+            ("EnabledPackedSize", (old_packed, new_packed)),
+            ("EnabledDataSize", (old_data, new_data)),
+            ("FileSize", (old_total, total_size)),
+        ] {
+            if let Some(value) = self.cpk_data.get(column).and_then(|v| v.as_u64()) {
+                let (old, new) = delta_from;
+                updates.push((column, (value + new).saturating_sub(old)));
+            }
+        }
+        for (column, value) in updates {
+            if header.is_per_row(column) {
+                header.patch_cell(&mut header_packet, 0, column, value)?;
+            }
+        }
+
+        // Write everything out through a temporary file so output may equal input
+        let output_path = output_path.as_ref();
+        let staged = StagedFile::new(output_path);
+        let mut out = BufWriter::new(File::create(staged.path())?);
+
+        let mut header_region = vec![0u8; header_end as usize];
+        source.seek(SeekFrom::Start(self.base_offset))?;
+        source.read_exact(&mut header_region)?;
+        let header_table = encode_table(b"CPK ", &header_packet, self.table_encryption("CPK_HDR"));
+        if header_table.len() > header_region.len() {
+            return Err(CpkError::InvalidFormat(
+                "CPK header overlaps the first table".to_string(),
+            ));
+        }
+        header_region[..header_table.len()].copy_from_slice(&header_table);
+        out.write_all(&header_region)?;
+        let mut written = header_end;
+
+        for table in tables.iter().filter(|t| t.new_offset < content_offset) {
+            write_padding(&mut out, table.new_offset - written)?;
+            out.write_all(&encode_table(
+                &table.signature,
+                &table.packet,
+                table.encrypted.then_some(&*self.table_cipher),
+            ))?;
+            written = table.new_offset + table.len();
+        }
+
+        for &i in &order {
+            self.cancel.check()?;
+            let slot = &slots[i];
+            write_padding(&mut out, slot.new_offset - written)?;
+            match &slot.replacement {
+                Some(stored) => out.write_all(&stored.data)?,
+                None => {
+                    source.seek(SeekFrom::Start(slot.source_offset))?;
+                    let copied =
+                        std::io::copy(&mut (&mut source).take(slot.stored_size), &mut out)?;
+                    if copied != slot.stored_size {
+                        return Err(CpkError::InvalidFormat(format!(
+                            "Entry at 0x{:X} is truncated",
+                            slot.source_offset
+                        )));
+                    }
+                }
+            }
+            written = slot.new_offset + slot.stored_size();
+        }
+        write_padding(&mut out, content_end - written)?;
+        written = content_end;
+
+        for table in tables.iter().filter(|t| t.new_offset >= content_offset) {
+            write_padding(&mut out, table.new_offset - written)?;
+            out.write_all(&encode_table(
+                &table.signature,
+                &table.packet,
+                table.encrypted.then_some(&*self.table_cipher),
+            ))?;
+            written = table.new_offset + table.len();
+        }
+
+        out.flush()?;
+        drop(out);
+        staged.commit()?;
+
+        info!("Wrote {} ({} bytes)", output_path.display(), written);
+        Ok(())
+    }
+
+    /// Whether a table (`CPK_HDR`, `TOC_HDR`, `ITOC_HDR`, ...) is stored encrypted.
+    pub fn is_table_encrypted(&self, name: &str) -> bool {
+        self.file_table
+            .iter()
+            .any(|e| e.file_name == name && e.encrypted)
+    }
+
+    /// The cipher encrypted tables were found in, and are written back with;
+    /// the XOR stream when none are encrypted.
+    pub fn table_cipher(&self) -> &Arc<dyn Cipher> {
+        &self.table_cipher
+    }
+
+    /// The cipher to write a table with, if it's stored encrypted.
+    fn table_encryption(&self, name: &str) -> Option<&dyn Cipher> {
+        self.is_table_encrypted(name).then_some(&*self.table_cipher)
+    }
+}
+
+/// XORs a table packet in place; the stream is symmetric, so this both encrypts and decrypts.
+pub fn decrypt_utf(data: &mut [u8]) {
+    let mut m = 0x0000655f_u32;
+    let t = 0x00004115_u32;
+
+    for byte in data {
+        *byte ^= (m & 0xff) as u8;
+        m = m.wrapping_mul(t);
+    }
+}
+
+/// Frames a table packet as stored in the archive: signature, 0xFF, packet size,
+/// packet, encrypted with `cipher` if given.
+pub(crate) fn encode_table(
+    signature: &[u8; 4],
+    packet: &[u8],
+    cipher: Option<&dyn Cipher>,
+) -> Vec<u8> {
+    let mut table = Vec::with_capacity(packet.len() + 0x10);
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&0xFFu32.to_le_bytes());
+    table.extend_from_slice(&(packet.len() as u64).to_le_bytes());
+    table.extend_from_slice(packet);
+    if let Some(cipher) = cipher {
+        cipher.encrypt(&mut table[0x10..]);
+    }
+    table
+}
+
+/// Archive paths use `/`; some tools write `\` instead, and stray leading or
+/// trailing separators would produce empty path components.
+fn normalize_separators(path: &str) -> String {
+    path.replace('\\', "/").trim_matches('/').to_string()
+}
+
+/// Base that TOC FileOffset values are relative to.
+/// `offset` past `base`, refusing offsets from a crafted header or table
+/// that would wrap around instead of failing.
+fn checked_offset(base: u64, offset: u64, what: &str) -> Result<u64> {
+    base.checked_add(offset).ok_or_else(|| {
+        CpkError::InvalidFormat(format!(
+            "{} offset 0x{:X} past 0x{:X} is beyond any file",
+            what, offset, base
+        ))
+    })
+}
+
+pub(crate) fn toc_base_offset(toc_offset: u64, content_offset: u64) -> u64 {
+    let f_toc_offset = if toc_offset > 0x800 {
+        0x800
+    } else {
+        toc_offset
+    };
+
+    if content_offset == 0xFFFFFFFFFFFFFFFF {
+        f_toc_offset
+    } else if toc_offset == 0xFFFFFFFFFFFFFFFF || content_offset < f_toc_offset {
+        content_offset
+    } else {
+        f_toc_offset
+    }
+}
+
+/// Whether replacements should update the TOC's CRC column; one that isn't
+/// stored per row can't be, and is left stale.
+fn patches_crc(toc: &Utf) -> bool {
+    if toc.is_per_row("CRC") {
+        return true;
+    }
+    if toc.has_column("CRC") {
+        warn!("TOC CRC column is not stored per row, leaving it unchanged");
+    }
+    false
+}
+
+/// Writes the assets embedded in `data` to `<output_path>.carved/`, unless
+/// the file is just one asset.
+fn write_carved(output_path: &Path, data: &[u8]) -> Result<()> {
+    let assets = carve::carve(data);
+    if let [only] = assets.as_slice()
+        && only.offset == 0
+        && only.size == data.len()
+    {
+        return Ok(());
+    }
+    if assets.is_empty() {
+        return Ok(());
+    }
+
+    let mut dir = output_path.as_os_str().to_owned();
+    dir.push(".carved");
+    let dir = PathBuf::from(dir);
+    create_dir_all(&dir)?;
+    for (i, asset) in assets.iter().enumerate() {
+        let name = format!("{:03}_{:08X}.{}", i, asset.offset, asset.kind);
+        std::fs::write(
+            dir.join(name),
+            &data[asset.offset..asset.offset + asset.size],
+        )?;
+    }
+    info!("Carved {} assets into {}", assets.len(), dir.display());
+    Ok(())
+}
+
+/// Whether both paths name the same existing file.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Ends an in-place modification: drops its rollback record once `result`
+/// says it succeeded, or puts the archive back as it was if it failed.
+fn settle_in_place<T>(rollback: Option<Rollback>, file: File, result: Result<T>) -> Result<T> {
+    let Some(rollback) = rollback else {
+        return result;
+    };
+    match result {
+        Ok(value) => {
+            rollback.commit(&file)?;
+            Ok(value)
+        }
+        Err(e) => {
+            drop(file);
+            if let Err(restore) = rollback.restore() {
+                warn!(
+                    "Couldn't restore the archive after a failed patch: {}",
+                    restore
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+pub(crate) fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+pub(crate) fn write_padding<W: Write>(writer: &mut W, count: u64) -> Result<()> {
+    std::io::copy(&mut std::io::repeat(0).take(count), writer)?;
+    Ok(())
+}
+
+/// Read and decompression buffers reused from one entry to the next, so
+/// extracting many small files doesn't allocate for each of them.
+#[derive(Default)]
+struct Scratch {
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// Whether the last entry was decompressed into `output`.
+    decoded: bool,
+}
+
+impl Scratch {
+    /// Buffers grown past this are released after use rather than kept for the next entry.
+    const KEEP_LIMIT: usize = 64 << 20;
+
+    /// The last entry's data.
+    fn data(&self) -> &[u8] {
+        if self.decoded {
+            &self.output
+        } else {
+            &self.input
+        }
+    }
+
+    fn data_mut(&mut self) -> &mut Vec<u8> {
+        if self.decoded {
+            &mut self.output
+        } else {
+            &mut self.input
+        }
+    }
+
+    fn into_data(self) -> Vec<u8> {
+        if self.decoded {
+            self.output
+        } else {
+            self.input
+        }
+    }
+
+    fn trim(&mut self) {
+        for buffer in [&mut self.input, &mut self.output] {
+            if buffer.capacity() > Self::KEEP_LIMIT {
+                *buffer = Vec::new();
+            }
+        }
+    }
+}
+
+/// One stored blob in the content area along with the TOC row and ITOC ID referencing it.
+struct ContentSlot {
+    source_offset: u64,
+    stored_size: u64,
+    extract_size: u64,
+    toc_row: Option<u32>,
+    itoc_id: Option<u32>,
+    replacement: Option<StoredData>,
+    new_offset: u64,
+}
+
+impl ContentSlot {
+    fn new(entry: &FileEntry, toc_row: Option<u32>, itoc_id: Option<u32>) -> Self {
+        Self {
+            source_offset: entry.file_offset,
+            stored_size: entry.file_size,
+            extract_size: entry.extract_size.unwrap_or(entry.file_size),
+            toc_row,
+            itoc_id,
+            replacement: None,
+            new_offset: 0,
+        }
+    }
+
+    fn stored_size(&self) -> u64 {
+        self.replacement
+            .as_ref()
+            .map_or(self.stored_size, |stored| stored.data.len() as u64)
+    }
+
+    fn extract_size(&self) -> u64 {
+        self.replacement
+            .as_ref()
+            .map_or(self.extract_size, |stored| stored.extract_size)
+    }
+}
+
+/// A table packet written back to a rebuilt archive.
+struct TableRegion {
+    signature: [u8; 4],
+    source_offset: u64,
+    packet: Vec<u8>,
+    encrypted: bool,
+    new_offset: u64,
+}
+
+impl TableRegion {
+    fn new(signature: &[u8; 4], source_offset: u64, packet: Vec<u8>, encrypted: bool) -> Self {
+        Self {
+            signature: *signature,
+            source_offset,
+            packet,
+            encrypted,
+            new_offset: source_offset,
+        }
+    }
+
+    /// Size on disk including the 16-byte table header.
+    fn len(&self) -> u64 {
+        self.packet.len() as u64 + 0x10
+    }
+}
+
+/// Regenerates the ITOC size tables, moving IDs between DataL (16-bit sizes)
+/// and DataH (32-bit sizes) as their sizes require.
+fn rebuild_itoc(packet: &[u8], slots: &[ContentSlot]) -> Result<Vec<u8>> {
+    let mut itoc = Utf::new();
+    itoc.read_utf(packet)?;
+
+    let mut sized: Vec<&ContentSlot> = slots.iter().filter(|s| s.itoc_id.is_some()).collect();
+    sized.sort_by_key(|s| s.itoc_id);
+    let (low, high): (Vec<&ContentSlot>, Vec<&ContentSlot>) = sized
+        .into_iter()
+        .partition(|s| s.stored_size() <= 0xFFFF && s.extract_size() <= 0xFFFF);
+
+    for (column, count_column, table_name, size_type, entries) in [
+        ("DataL", "FilesL", "CpkItocL", 0x02u8, &low),
+        ("DataH", "FilesH", "CpkItocH", 0x04u8, &high),
+    ] {
+        let template = itoc
+            .get_column_data(0, column)
+            .and_then(|v| v.as_data())
+            .filter(|d| !d.is_empty());
+        let table = rebuild_size_table(template, table_name, size_type, entries)?;
+
+        if itoc.has_column(column) {
+            itoc.set_cell(0, column, CellValue::Data(table))?;
+        }
+        if itoc.has_column(count_column) {
+            itoc.set_cell(0, count_column, CellValue::UInt32(entries.len() as u32))?;
+        }
+    }
+
+    itoc.to_bytes()
+}
+
+fn rebuild_size_table(
+    template: Option<&[u8]>,
+    table_name: &str,
+    size_type: u8,
+    entries: &[&ContentSlot],
+) -> Result<Vec<u8>> {
+    let mut table = Utf::new();
+    match template {
+        Some(bytes) => table.read_utf(bytes)?,
+        None => {
+            table.name = table_name.to_string();
+            for (name, column_type) in [
+                ("ID", 0x02),
+                ("FileSize", size_type),
+                ("ExtractSize", size_type),
+            ] {
+                table.columns.push(Column {
+                    flags: 0x50 | column_type,
+                    name: name.to_string(),
+                    constant: None,
+                });
+            }
+        }
+    }
+
+    // Keep any extra per-ID columns of rows that survive
+    let id_column = table.columns.iter().position(|c| c.name == "ID");
+    let mut previous: HashMap<u32, Vec<Cell>> = HashMap::new();
+    for row in table.rows.drain(..) {
+        if let Some(id) = id_column.and_then(|col| row[col].value.as_u32()) {
+            previous.insert(id, row);
+        }
+    }
+
+    for (row_idx, slot) in entries.iter().enumerate() {
+        let id = slot.itoc_id.unwrap_or_default();
+        let row = previous.remove(&id).unwrap_or_else(|| {
+            table
+                .columns
+                .iter()
+                .map(|c| Cell {
+                    value: c.constant.clone().unwrap_or(CellValue::None),
+                    position: 0,
+                })
+                .collect()
+        });
+        table.rows.push(row);
+
+        table.set_cell(row_idx, "ID", CellValue::UInt32(id))?;
+        table.set_cell(row_idx, "FileSize", CellValue::UInt64(slot.stored_size()))?;
+        if table.has_column("ExtractSize") {
+            table.set_cell(
+                row_idx,
+                "ExtractSize",
+                CellValue::UInt64(slot.extract_size()),
+            )?;
+        }
+    }
+
+    table.to_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{TestArchive, read_back};
+    use crate::{layout, verify};
+
+    fn archive(path: &Path) -> Cpk {
+        TestArchive {
+            files: 12,
+            max_size: 0x2000,
+            seed: 11,
+            ..Default::default()
+        }
+        .write_to(path)
+    }
+
+    /// Replaces `target` in the archive at `path` with `data`.
+    fn replace(path: &Path, target: &str, data: &[u8]) -> Cpk {
+        let replacement = path.with_extension("new");
+        std::fs::write(&replacement, data).unwrap();
+        let mut cpk = read_back(path);
+        cpk.replace_file(
+            path,
+            target,
+            &replacement,
+            path,
+            &CompressionPolicy::default(),
+        )
+        .unwrap();
+        read_back(path)
+    }
+
+    fn assert_clean_layout(path: &Path) {
+        let file_len = std::fs::metadata(path).unwrap().len();
+        let layout = verify::check_layout(&read_back(path), file_len);
+        assert!(layout.is_clean(), "{:?}", layout);
+    }
+
+    #[test]
+    fn same_size_replacement_is_patched_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.cpk");
+        let before = archive(&path);
+        let metadata = std::fs::metadata(&path).unwrap();
+        let target = "dir01/file00001.bin";
+        let old = before.read_entry(before.find(target).unwrap()).unwrap();
+        let new: Vec<u8> = old.iter().map(|byte| !byte).collect();
+
+        let after = replace(&path, target, &new);
+        // A rewrite would have replaced the file
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().ino(), metadata.ino());
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), metadata.len());
+        assert!(!rollback::pending(&path));
+        for (old, new) in before.file_table.iter().zip(&after.file_table) {
+            assert_eq!(old.file_offset, new.file_offset, "{}", old.full_path());
+        }
+        assert_eq!(after.read_entry(after.find(target).unwrap()).unwrap(), new);
+        assert_clean_layout(&path);
+    }
+
+    #[test]
+    fn replacement_leaving_a_gap_rewrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.cpk");
+        archive(&path);
+        let target = "dir03/file00003.bin";
+
+        // Past the alignment, so patching would leave unaccounted bytes
+        let after = replace(&path, target, &[0xA5; 0x10]);
+        assert_eq!(
+            after.read_entry(after.find(target).unwrap()).unwrap(),
+            [0xA5; 0x10]
+        );
+        assert_clean_layout(&path);
+    }
+
+    #[test]
+    fn appended_replacements_leave_a_clean_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.cpk");
+        let before = archive(&path);
+        let (first, last) = ("dir00/file00000.txt", "dir11/file00011.bin");
+        let replacement = dir.path().join("new.bin");
+        std::fs::write(&replacement, [0x5A; 3000]).unwrap();
+
+        // The second patch supersedes data and a list the first appended
+        for targets in [vec![first, last], vec![first]] {
+            let replacements: Vec<(String, PathBuf)> = targets
+                .iter()
+                .map(|target| (target.to_string(), replacement.clone()))
+                .collect();
+            let mut cpk = read_back(&path);
+            cpk.append_files(&path, &replacements, &path, &CompressionPolicy::default())
+                .unwrap();
+            assert_clean_layout(&path);
+        }
+
+        let after = read_back(&path);
+        for entry in before.toc_files().unwrap() {
+            let patched = after.find(&entry.full_path()).unwrap();
+            match entry.full_path().as_str() {
+                path if path == first || path == last => {
+                    assert_eq!(after.read_entry(patched).unwrap(), [0x5A; 3000])
+                }
+                _ => assert_eq!(patched.file_offset, entry.file_offset),
+            }
+        }
+        let replaced: Vec<_> = layout::regions(&after)
+            .into_iter()
+            .filter(|r| r.kind == layout::RegionKind::Replaced)
+            .collect();
+        assert_eq!(replaced.len(), 4);
+    }
+
+    #[test]
+    fn offsets_wrapping_around_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.cpk");
+        let cpk = archive(&path);
+        let mut packet = cpk.cpk_packet.clone();
+        let mut header = Utf::new();
+        header.read_utf(&packet).unwrap();
+        header
+            .patch_cell(&mut packet, 0, "TocOffset", u64::MAX - 0x10)
+            .unwrap();
+        let mut embedded = vec![0u8; 0x800];
+        embedded.extend(encode_table(b"CPK ", &packet, None));
+        std::fs::write(&path, embedded).unwrap();
+
+        let mut cpk = Cpk::with_base_offset(0x800);
+        assert!(matches!(
+            cpk.read_cpk(&path),
+            Err(CpkError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn extracting_with_a_filter_matching_nothing_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.cpk");
+        let mut cpk = archive(&path);
+        cpk.set_output_dir(dir.path().join("out"));
+        let filter = EntryFilter {
+            extensions: vec!["xyz".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            cpk.extract_all(&path, &filter),
+            Err(CpkError::FileNotFound(_))
+        ));
+        assert!(!dir.path().join("out").exists());
+    }
+
+    /// Deflate behind a `ZLIB` signature, standing in for a game's own scheme.
+    #[derive(Debug)]
+    struct Zlib;
+
+    impl crate::codec::Codec for Zlib {
+        fn name(&self) -> &'static str {
+            "zlib"
+        }
+
+        fn detect(&self, data: &[u8]) -> bool {
+            data.starts_with(b"ZLIB")
+        }
+
+        fn decompress(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            out.clear();
+            flate2::read::ZlibDecoder::new(&data[4..]).read_to_end(out)?;
+            Ok(())
+        }
+
+        fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(b"ZLIB".to_vec(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+    }
+
+    #[test]
+    fn replacements_are_compressed_with_the_registered_codec() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.cpk");
+        archive(&path);
+        let target = "dir04/file00004.txt";
+        let new = b"charlie delta ".repeat(0x100);
+        let replacement = dir.path().join("new.txt");
+        std::fs::write(&replacement, &new).unwrap();
+        let zlib_only = || {
+            let mut codecs = CodecRegistry::empty();
+            codecs.register(Arc::new(Zlib));
+            codecs
+        };
+
+        let mut cpk = read_back(&path);
+        *cpk.codecs_mut() = zlib_only();
+        let compression = CompressionPolicy {
+            enabled: true,
+            ..Default::default()
+        };
+        cpk.replace_file(&path, target, &replacement, &path, &compression)
+            .unwrap();
+
+        let mut after = read_back(&path);
+        *after.codecs_mut() = zlib_only();
+        let entry = after.find(target).unwrap();
+        assert!(after.read_entry_raw(entry).unwrap().starts_with(b"ZLIB"));
+        assert_eq!(after.read_entry(entry).unwrap(), new);
     }
 }
@@ -0,0 +1,94 @@
+use crate::cpk::decrypt_utf;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// An encryption scheme @UTF tables can be stored with.
+///
+/// Reading asks each registered cipher in turn whether it can decrypt a table
+/// that doesn't start with `@UTF`; writing re-encrypts tables with the cipher
+/// they were found in.
+pub trait Cipher: Debug + Send + Sync {
+    /// Short lowercase name, e.g. `xor`.
+    fn name(&self) -> &'static str;
+
+    /// Whether `data`, a table packet as stored, decrypts to an @UTF table.
+    fn detect(&self, data: &[u8]) -> bool;
+
+    fn decrypt(&self, data: &mut [u8]);
+
+    fn encrypt(&self, data: &mut [u8]);
+}
+
+/// CRI's XOR stream (seed 0x655F, multiplier 0x4115), used by stock CPK tools.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XorStream;
+
+impl Cipher for XorStream {
+    fn name(&self) -> &'static str {
+        "xor"
+    }
+
+    fn detect(&self, data: &[u8]) -> bool {
+        let mut head = [0u8; 4];
+        match data.get(..4) {
+            Some(bytes) => head.copy_from_slice(bytes),
+            None => return false,
+        }
+        decrypt_utf(&mut head);
+        &head == b"@UTF"
+    }
+
+    fn decrypt(&self, data: &mut [u8]) {
+        decrypt_utf(data);
+    }
+
+    fn encrypt(&self, data: &mut [u8]) {
+        // The stream is symmetric
+        decrypt_utf(data);
+    }
+}
+
+/// The ciphers table reading recognises, asked in the order they were registered.
+#[derive(Debug, Clone)]
+pub struct CipherRegistry {
+    ciphers: Vec<Arc<dyn Cipher>>,
+}
+
+impl Default for CipherRegistry {
+    /// A registry holding the XOR stream only.
+    fn default() -> Self {
+        Self {
+            ciphers: vec![Arc::new(XorStream)],
+        }
+    }
+}
+
+impl CipherRegistry {
+    /// A registry recognising nothing, so only plain tables can be read.
+    pub fn empty() -> Self {
+        Self {
+            ciphers: Vec::new(),
+        }
+    }
+
+    /// Adds `cipher`, replacing a registered one with the same name in its place.
+    pub fn register(&mut self, cipher: Arc<dyn Cipher>) {
+        match self.ciphers.iter_mut().find(|c| c.name() == cipher.name()) {
+            Some(existing) => *existing = cipher,
+            None => self.ciphers.push(cipher),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Cipher>> {
+        self.ciphers.iter().find(|c| c.name() == name)
+    }
+
+    /// The first cipher `data` decrypts to an @UTF table with.
+    pub fn detect(&self, data: &[u8]) -> Option<&Arc<dyn Cipher>> {
+        self.ciphers.iter().find(|c| c.detect(data))
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.ciphers.iter().map(|c| c.name()).collect()
+    }
+}
@@ -0,0 +1,145 @@
+use crate::builder::{self, CpkBuilder};
+use crate::cache::{ContentDigests, check_key};
+use crate::cancel::CancellationToken;
+use crate::compression::CompressionPolicy;
+use crate::cpk::{Cpk, CpkMode, FileEntry};
+use crate::digest::HashAlgorithm;
+use crate::error::{CpkError, Result};
+use crate::merge::{path_mode, path_split};
+use log::debug;
+use std::path::{Path, PathBuf};
+
+/// What went into a delta archive.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DeltaSummary {
+    /// Base entries whose content differs.
+    pub changed: usize,
+    /// Files with no counterpart in the base.
+    pub added: usize,
+    /// Files identical to their base entry, left out.
+    pub unchanged: usize,
+}
+
+/// Pairs every file below `dir` with the archive path it stands for, in the
+/// form [`delta`] and [`Cpk::replace_files`] take.
+pub fn dir_replacements<P: AsRef<Path>>(dir: P) -> Result<Vec<(String, PathBuf)>> {
+    builder::files_below(dir.as_ref())
+}
+
+/// Writes a standalone archive holding only the replacements that differ from
+/// `base`, for engines that layer patch archives over the base at runtime.
+///
+/// Changed entries keep the base's path and ID; new files get IDs past the
+/// base's highest one so they can't shadow an unrelated entry. The output uses
+/// the base's tables, alignment, table encryption, CRC column and compression
+/// codec, but no groups.
+pub fn delta<P: AsRef<Path>>(
+    base: &Cpk,
+    replacements: &[(String, PathBuf)],
+    output_path: P,
+    compression: CompressionPolicy,
+    cancel: &CancellationToken,
+) -> Result<DeltaSummary> {
+    let mut digests = ContentDigests::new();
+    delta_with(
+        base,
+        replacements,
+        output_path,
+        compression,
+        &mut digests,
+        cancel,
+    )
+}
+
+/// Like `delta`, comparing base entries in `digests` (as
+/// `VerifyCache::contents` returns them for an unchanged archive) by their
+/// digest instead of reading them, and adding the digests of the others to it.
+pub fn delta_with<P: AsRef<Path>>(
+    base: &Cpk,
+    replacements: &[(String, PathBuf)],
+    output_path: P,
+    mut compression: CompressionPolicy,
+    digests: &mut ContentDigests,
+    cancel: &CancellationToken,
+) -> Result<DeltaSummary> {
+    let mode = match base.cpk_mode() {
+        Some(mode) if !mode.has_toc() => CpkMode::Id,
+        _ => path_mode(base),
+    };
+    let mut next_id = base
+        .file_table
+        .iter()
+        .filter(|e| e.file_type == "FILE")
+        .filter_map(|e| e.id)
+        .max()
+        .map_or(0, |id| id + 1);
+
+    let mut summary = DeltaSummary::default();
+    let mut builder = CpkBuilder::new()
+        .mode(mode)
+        .path_split(path_split(base))
+        .align(base.align())
+        .encrypt_tables(base.is_table_encrypted("TOC_HDR"))
+        .cipher(base.table_cipher().clone())
+        .crc(base.has_crc())
+        .cancellation(cancel.clone());
+    match base.codecs().compressor() {
+        Some(codec) => builder = builder.codec(codec.clone()),
+        None => compression.enabled = false,
+    }
+    builder = builder.compression(compression);
+
+    for (target, local_path) in replacements {
+        cancel.check()?;
+        let data = std::fs::read(local_path)?;
+        match base_entry(base, target)? {
+            Some(entry) => {
+                let key = check_key(entry);
+                let base_digest = match digests.get(&key) {
+                    Some(digest) => digest.clone(),
+                    None => {
+                        let digest = HashAlgorithm::Sha256.hex_digest(&base.read_entry(entry)?);
+                        digests.insert(key, digest.clone());
+                        digest
+                    }
+                };
+                if base_digest == HashAlgorithm::Sha256.hex_digest(&data) {
+                    debug!("{}: unchanged", target);
+                    summary.unchanged += 1;
+                    continue;
+                }
+                debug!("{}: changed", target);
+                builder = builder.add_data_with_id(entry.full_path(), entry.id, data);
+                summary.changed += 1;
+            }
+            None if mode.has_toc() => {
+                debug!("{}: added as ID {}", target, next_id);
+                builder = builder.add_data_with_id(target.as_str(), Some(next_id), data);
+                next_id += 1;
+                summary.added += 1;
+            }
+            None => return Err(CpkError::FileNotFound(target.clone())),
+        }
+    }
+
+    if builder.is_empty() {
+        return Err(CpkError::InvalidFormat(
+            "No file differs from the base archive".to_string(),
+        ));
+    }
+    builder.write(output_path)?;
+    Ok(summary)
+}
+
+/// The base entry `target` replaces, or `None` for a path the base lacks.
+fn base_entry<'a>(base: &'a Cpk, target: &str) -> Result<Option<&'a FileEntry>> {
+    match base.find_entries(target, &Default::default()) {
+        Ok(entries) => Ok(entries
+            .iter()
+            .find(|e| e.toc_name == "TOC")
+            .or(entries.first())
+            .copied()),
+        Err(CpkError::FileNotFound(_)) if !target.starts_with('#') => Ok(None),
+        Err(e) => Err(e),
+    }
+}
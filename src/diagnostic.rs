@@ -0,0 +1,38 @@
+use std::fmt;
+
+/// A recoverable problem noticed while reading an archive. Parsing went on with
+/// a default or shortened value, so what was read may not match the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Table it was found in, e.g. `TOC (CpkTocInfo)`.
+    pub table: String,
+    /// Column, row or cell within the table.
+    pub location: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Names the CPK table (`TOC`, `ITOC`, ...) the @UTF table came from.
+    pub fn in_table(mut self, name: &str) -> Self {
+        self.table = qualified_table(name, &self.table);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} table, {}: {}",
+            self.table, self.location, self.message
+        )
+    }
+}
+
+/// `name` followed by the @UTF table name, unless that one is unknown.
+pub(crate) fn qualified_table(name: &str, table: &str) -> String {
+    match table {
+        "@UTF" | "" => name.to_string(),
+        _ => format!("{} ({})", name, table),
+    }
+}
@@ -0,0 +1,123 @@
+use crate::compression::crc32;
+use crate::cpk::{Cpk, FileEntry};
+use crate::error::Result;
+use crate::filter::EntryFilter;
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Digest computed over extracted content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    /// The zlib CRC-32, as in a TOC's CRC column.
+    Crc32,
+}
+
+impl HashAlgorithm {
+    /// Lowercase hex digest of `data`.
+    pub fn hex_digest(self, data: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Sha256 => Sha256::digest(data)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            HashAlgorithm::Crc32 => format!("{:08x}", crc32(data)),
+        }
+    }
+}
+
+/// Digests the content of every entry `filter` keeps on `jobs` threads,
+/// returning them in table order.
+///
+/// Every FILE path gets its digest, except ITOC rows repeating a TOC row.
+/// Data several entries share is read and digested once. Each thread
+/// decompresses one entry at a time, reading the archive with positioned
+/// reads on a shared handle, so memory stays at about one entry per thread.
+/// The first error stops every thread.
+pub fn hash_entries<'a>(
+    cpk: &'a Cpk,
+    algorithm: HashAlgorithm,
+    filter: &EntryFilter,
+    jobs: usize,
+) -> Result<Vec<(&'a FileEntry, String)>> {
+    let files = cpk.file_table.iter().filter(|e| e.file_type == "FILE");
+    let toc_data: HashSet<(u64, u64)> = files
+        .clone()
+        .filter(|e| e.toc_name == "TOC")
+        .map(|e| (e.file_offset, e.file_size))
+        .collect();
+    let listed: Vec<&FileEntry> = files
+        .filter(|e| e.toc_name == "TOC" || !toc_data.contains(&(e.file_offset, e.file_size)))
+        .filter(|e| filter.matches(e))
+        .collect();
+
+    // The entries actually read, one per stored blob
+    let mut blob_index = HashMap::new();
+    let mut entries = Vec::new();
+    let blobs: Vec<usize> = listed
+        .iter()
+        .map(|entry| {
+            *blob_index
+                .entry((entry.file_offset, entry.file_size))
+                .or_insert_with(|| {
+                    entries.push(*entry);
+                    entries.len() - 1
+                })
+        })
+        .collect();
+    let file = File::open(cpk.source_path()?)?;
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let jobs = jobs.clamp(1, entries.len().max(1));
+    debug!("hash: {} entries on {} threads", entries.len(), jobs);
+
+    let results: Vec<Result<Vec<(usize, String)>>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut digests = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(entry) = entries.get(index) else {
+                            return Ok(digests);
+                        };
+                        if failed.load(Ordering::Relaxed) {
+                            return Ok(digests);
+                        }
+                        let digest = cpk
+                            .cancellation()
+                            .check()
+                            .and_then(|_| cpk.read_entry_from(&file, entry))
+                            .map(|data| algorithm.hex_digest(&data));
+                        match digest {
+                            Ok(digest) => digests.push((index, digest)),
+                            Err(e) => {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("hash worker panicked"))
+            .collect()
+    });
+
+    let mut digests = vec![String::new(); entries.len()];
+    for result in results {
+        for (index, digest) in result? {
+            digests[index] = digest;
+        }
+    }
+    Ok(listed
+        .into_iter()
+        .zip(blobs)
+        .map(|(entry, blob)| (entry, digests[blob].clone()))
+        .collect())
+}
@@ -85,6 +85,14 @@ impl<R: Read> EndianReader<R> {
         }
     }
 
+    pub fn read_f64(&mut self) -> Result<f64> {
+        if self.is_little_endian {
+            Ok(self.reader.read_f64::<LittleEndian>()?)
+        } else {
+            Ok(self.reader.read_f64::<BigEndian>()?)
+        }
+    }
+
     pub fn read_bytes(&mut self, count: usize) -> Result<Vec<u8>> {
         debug!("read_bytes: Attempting to read {} bytes", count);
         let mut buffer = vec![0u8; count];
@@ -120,8 +128,18 @@ impl<R: Read> EndianReader<R> {
     }
 
     pub fn read_cstring(&mut self, max_length: Option<usize>) -> Result<String> {
+        self.read_cstring_as(max_length.unwrap_or(255), encoding_rs::SHIFT_JIS)
+            .map(|(string, _)| string)
+    }
+
+    /// Reads a NUL-terminated string of at most `max` bytes in `encoding`, and
+    /// whether some of them weren't valid in it and were replaced with U+FFFD.
+    pub fn read_cstring_as(
+        &mut self,
+        max: usize,
+        encoding: &'static encoding_rs::Encoding,
+    ) -> Result<(String, bool)> {
         let mut bytes = Vec::new();
-        let max = max_length.unwrap_or(255);
 
         debug!("read_cstring: Starting, max_length: {}", max);
 
@@ -145,11 +163,10 @@ impl<R: Read> EndianReader<R> {
             }
         }
 
-        // Use Shift-JIS encoding like the original C# code
-        let (decoded, _, _) = encoding_rs::SHIFT_JIS.decode(&bytes);
+        let (decoded, _, had_errors) = encoding.decode(&bytes);
         let result = decoded.into_owned();
         debug!("read_cstring: Read string: '{}'", result);
-        Ok(result)
+        Ok((result, had_errors))
     }
 }
 
@@ -184,6 +201,10 @@ impl<W: Write> EndianWriter<W> {
         Ok(self.writer.write_u8(value)?)
     }
 
+    pub fn write_i8(&mut self, value: i8) -> Result<()> {
+        Ok(self.writer.write_i8(value)?)
+    }
+
     pub fn write_u16(&mut self, value: u16) -> Result<()> {
         if self.is_little_endian {
             Ok(self.writer.write_u16::<LittleEndian>(value)?)
@@ -192,6 +213,14 @@ impl<W: Write> EndianWriter<W> {
         }
     }
 
+    pub fn write_i16(&mut self, value: i16) -> Result<()> {
+        if self.is_little_endian {
+            Ok(self.writer.write_i16::<LittleEndian>(value)?)
+        } else {
+            Ok(self.writer.write_i16::<BigEndian>(value)?)
+        }
+    }
+
     pub fn write_u32(&mut self, value: u32) -> Result<()> {
         if self.is_little_endian {
             Ok(self.writer.write_u32::<LittleEndian>(value)?)
@@ -200,6 +229,14 @@ impl<W: Write> EndianWriter<W> {
         }
     }
 
+    pub fn write_i32(&mut self, value: i32) -> Result<()> {
+        if self.is_little_endian {
+            Ok(self.writer.write_i32::<LittleEndian>(value)?)
+        } else {
+            Ok(self.writer.write_i32::<BigEndian>(value)?)
+        }
+    }
+
     pub fn write_u64(&mut self, value: u64) -> Result<()> {
         if self.is_little_endian {
             Ok(self.writer.write_u64::<LittleEndian>(value)?)
@@ -208,6 +245,14 @@ impl<W: Write> EndianWriter<W> {
         }
     }
 
+    pub fn write_i64(&mut self, value: i64) -> Result<()> {
+        if self.is_little_endian {
+            Ok(self.writer.write_i64::<LittleEndian>(value)?)
+        } else {
+            Ok(self.writer.write_i64::<BigEndian>(value)?)
+        }
+    }
+
     pub fn write_f32(&mut self, value: f32) -> Result<()> {
         if self.is_little_endian {
             Ok(self.writer.write_f32::<LittleEndian>(value)?)
@@ -216,9 +261,21 @@ impl<W: Write> EndianWriter<W> {
         }
     }
 
+    pub fn write_f64(&mut self, value: f64) -> Result<()> {
+        if self.is_little_endian {
+            Ok(self.writer.write_f64::<LittleEndian>(value)?)
+        } else {
+            Ok(self.writer.write_f64::<BigEndian>(value)?)
+        }
+    }
+
     pub fn write_bytes(&mut self, data: &[u8]) -> Result<()> {
         Ok(self.writer.write_all(data)?)
     }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
 }
 
 #[allow(dead_code)]
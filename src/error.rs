@@ -1,3 +1,4 @@
+use crate::diagnostic::qualified_table;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -29,6 +30,72 @@ pub enum CpkError {
 
     #[error("Unsupported feature: {0}")]
     Unsupported(String),
+
+    #[error("{table} table, {location} at packet offset 0x{offset:X}: {error}")]
+    Table {
+        table: String,
+        location: String,
+        offset: u64,
+        error: Box<CpkError>,
+    },
+
+    #[cfg(feature = "sqlite")]
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    #[error("{0} entries failed")]
+    EntriesFailed(usize),
+
+    #[error("Post-processing failed: {0}")]
+    PostProcess(String),
+
+    #[error("Paths used by more than one entry: {}", .0.join(", "))]
+    DuplicatePaths(Vec<String>),
+}
+
+impl CpkError {
+    /// Short category name used in summaries and reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CpkError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => "truncated",
+            CpkError::Io(_) => "io",
+            CpkError::InvalidSignature | CpkError::InvalidUtfSignature => "signature",
+            CpkError::FileNotFound(_) => "not-found",
+            CpkError::InvalidFormat(_) => "format",
+            CpkError::Compression(_) => "compression",
+            CpkError::Encryption(_) => "encryption",
+            CpkError::Parse(_) => "parse",
+            CpkError::Unsupported(_) => "unsupported",
+            CpkError::Table { error, .. } => error.kind(),
+            #[cfg(feature = "sqlite")]
+            CpkError::Database(_) => "database",
+            CpkError::Cancelled => "cancelled",
+            CpkError::EntriesFailed(_) => "failed",
+            CpkError::PostProcess(_) => "post-process",
+            CpkError::DuplicatePaths(_) => "duplicate",
+        }
+    }
+
+    /// Names the CPK table (`TOC`, `ITOC`, ...) an @UTF parse error came from.
+    pub fn in_table(self, name: &str) -> CpkError {
+        match self {
+            CpkError::Table {
+                table,
+                location,
+                offset,
+                error,
+            } => CpkError::Table {
+                table: qualified_table(name, &table),
+                location,
+                offset,
+                error,
+            },
+            other => other,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, CpkError>;
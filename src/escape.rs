@@ -0,0 +1,243 @@
+use crate::error::{CpkError, Result};
+use log::debug;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// File in an extraction directory listing the names extraction had to
+/// escape, as `written,original` CSV rows relative to the directory.
+pub const NAMES_FILE: &str = ".cpk-names";
+
+/// How extraction writes path components the local filesystem can't hold:
+/// the characters Windows reserves (`<>:"\|?*` and control characters), a
+/// trailing dot or space, and device names such as `CON` or `LPT1`.
+///
+/// An escaping mode checks names the same way on every platform, so an
+/// extraction made with one can be copied anywhere; only Windows escapes by
+/// default. The original of every escaped name is recorded in [`NAMES_FILE`],
+/// which packing the directory reads to restore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameEscape {
+    /// Each offending character becomes `%XX` per UTF-8 byte.
+    Percent,
+    /// Each offending character becomes `_`.
+    Underscore,
+    /// Names are written as they are, and may fail to be created.
+    None,
+}
+
+const RESERVED: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+
+fn is_reserved_char(c: char) -> bool {
+    c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*')
+}
+
+/// Whether `stem` (a component up to its first dot) names a device.
+fn is_device(stem: &str) -> bool {
+    let upper = stem.trim_end_matches(' ').to_ascii_uppercase();
+    if RESERVED.contains(&upper.as_str()) {
+        return true;
+    }
+    match upper
+        .strip_prefix("COM")
+        .or_else(|| upper.strip_prefix("LPT"))
+    {
+        Some(digit) => matches!(digit, "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9"),
+        None => false,
+    }
+}
+
+impl Default for NameEscape {
+    /// `Percent` on Windows, which can't create the names, `None` elsewhere.
+    fn default() -> Self {
+        match cfg!(windows) {
+            true => NameEscape::Percent,
+            false => NameEscape::None,
+        }
+    }
+}
+
+impl NameEscape {
+    /// `path`, a `/`-separated archive path, with each component escaped.
+    pub fn escape<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if *self == NameEscape::None {
+            return Cow::Borrowed(path);
+        }
+        let components: Vec<Cow<str>> = path
+            .split('/')
+            .map(|component| self.escape_component(component))
+            .collect();
+        match components.iter().all(|c| matches!(c, Cow::Borrowed(_))) {
+            true => Cow::Borrowed(path),
+            false => Cow::Owned(components.join("/")),
+        }
+    }
+
+    fn escape_component<'a>(&self, component: &'a str) -> Cow<'a, str> {
+        let stem_len = component.find('.').unwrap_or(component.len());
+        let device = stem_len > 0 && is_device(&component[..stem_len]);
+        let trailing = component.len() - component.trim_end_matches(['.', ' ']).len();
+        // "." and ".." are left alone: the archive path is walked as given
+        let trailing = match component {
+            "." | ".." => 0,
+            _ => trailing,
+        };
+        if !device && trailing == 0 && !component.chars().any(is_reserved_char) {
+            return Cow::Borrowed(component);
+        }
+
+        let mut escaped = String::with_capacity(component.len() + 6);
+        for (i, c) in component.char_indices() {
+            // The last character of a device name is enough to make it a file
+            let escape = is_reserved_char(c)
+                || i >= component.len() - trailing
+                || (device && i + c.len_utf8() == stem_len);
+            if !escape {
+                escaped.push(c);
+                continue;
+            }
+            match self {
+                NameEscape::Percent => {
+                    let mut buf = [0u8; 4];
+                    for byte in c.encode_utf8(&mut buf).bytes() {
+                        escaped.push_str(&format!("%{:02X}", byte));
+                    }
+                }
+                NameEscape::Underscore | NameEscape::None => escaped.push('_'),
+            }
+        }
+        Cow::Owned(escaped)
+    }
+}
+
+/// The `written -> original` names recorded in `dir`'s [`NAMES_FILE`], empty
+/// when there's none.
+pub fn read_names(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let path = dir.join(NAMES_FILE);
+    let mut reader = match csv::ReaderBuilder::new().from_path(&path) {
+        Ok(reader) => reader,
+        Err(e) if matches!(e.kind(), csv::ErrorKind::Io(io) if io.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            return Ok(BTreeMap::new());
+        }
+        Err(e) => return Err(CpkError::Parse(format!("{}: {}", path.display(), e))),
+    };
+
+    let mut names = BTreeMap::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+        match (record.get(0), record.get(1)) {
+            (Some(written), Some(original)) => {
+                names.insert(written.to_string(), original.to_string());
+            }
+            _ => {
+                return Err(CpkError::Parse(format!(
+                    "{}: expected 'written,original' on line {}",
+                    path.display(),
+                    record.position().map_or(0, |p| p.line())
+                )));
+            }
+        }
+    }
+
+    debug!("names: {} escaped names in {}", names.len(), path.display());
+    Ok(names)
+}
+
+/// Adds `escaped` to the names recorded in `dir`, keeping earlier ones.
+pub fn record_names(dir: &Path, escaped: &[(String, String)]) -> Result<()> {
+    if escaped.is_empty() {
+        return Ok(());
+    }
+    let mut names = read_names(dir)?;
+    names.extend(escaped.iter().cloned());
+    if !dir.as_os_str().is_empty() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let path = dir.join(NAMES_FILE);
+    let error = |e: csv::Error| CpkError::Parse(format!("{}: {}", path.display(), e));
+    let mut writer = csv::Writer::from_path(&path).map_err(error)?;
+    writer
+        .write_record(["written", "original"])
+        .map_err(error)?;
+    for (written, original) in &names {
+        writer.write_record([written, original]).map_err(error)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::CpkBuilder;
+    use crate::filter::EntryFilter;
+    use crate::generate::read_back;
+
+    #[test]
+    fn only_names_windows_refuses_are_escaped() {
+        let percent = NameEscape::Percent;
+        assert_eq!(percent.escape("a/b.txt"), "a/b.txt");
+        assert_eq!(percent.escape("what?/a<b>.txt"), "what%3F/a%3Cb%3E.txt");
+        assert_eq!(percent.escape("CON.txt"), "CO%4E.txt");
+        assert_eq!(percent.escape("com1"), "com%31");
+        assert_eq!(percent.escape("COM10.txt"), "COM10.txt");
+        assert_eq!(percent.escape("trailing. /../x"), "trailing%2E%20/../x");
+        assert_eq!(NameEscape::Underscore.escape("a:b/NUL"), "a_b/NU_");
+        assert_eq!(NameEscape::None.escape("a:b/NUL"), "a:b/NUL");
+    }
+
+    #[test]
+    fn escaped_names_are_restored_when_packing() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("in.cpk");
+        let odd = [
+            ("odd/CON.txt", "odd/CO%4E.txt"),
+            ("odd/what?.bin", "odd/what%3F.bin"),
+            ("odd/pipe|d.", "odd/pipe%7Cd%2E"),
+        ];
+        let mut builder = CpkBuilder::new().add_file("plain.txt", b"plain".to_vec());
+        for (name, _) in odd {
+            builder = builder.add_file(name, name.as_bytes().to_vec());
+        }
+        builder.write(&archive).unwrap();
+
+        let out = dir.path().join("out");
+        let mut cpk = read_back(&archive);
+        cpk.set_name_escape(NameEscape::Percent);
+        cpk.set_output_dir(&out);
+        cpk.extract_all(&archive, &EntryFilter::default()).unwrap();
+        let names = read_names(&out).unwrap();
+        assert_eq!(names.len(), odd.len());
+        for (name, escaped) in odd {
+            assert!(out.join(escaped).is_file());
+            assert_eq!(names[escaped], name);
+        }
+
+        let packed = dir.path().join("packed.cpk");
+        CpkBuilder::new()
+            .add_dir(&out)
+            .unwrap()
+            .write(&packed)
+            .unwrap();
+        let packed = read_back(&packed);
+        assert_eq!(packed.file_table.len(), cpk.file_table.len());
+        for (name, _) in odd {
+            let entry = packed.find(name).unwrap();
+            assert_eq!(packed.read_entry(entry).unwrap(), name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn recorded_names_accumulate() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_names(dir.path()).unwrap().is_empty());
+        record_names(dir.path(), &[("a%3F".to_string(), "a?".to_string())]).unwrap();
+        record_names(dir.path(), &[("b_".to_string(), "b:".to_string())]).unwrap();
+        let names = read_names(dir.path()).unwrap();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names["a%3F"], "a?");
+        assert_eq!(names["b_"], "b:");
+    }
+}
@@ -0,0 +1,106 @@
+use crate::cpk::FileEntry;
+use std::collections::HashSet;
+
+/// Criteria used to narrow down which FILE entries a command operates on.
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// File name extensions (without the dot) to keep; empty keeps everything.
+    pub extensions: Vec<String>,
+    /// Wildcard patterns matched against the full path; empty keeps everything.
+    /// `*` and `?` stay within one directory, `**` spans any number of them.
+    pub patterns: Vec<String>,
+    /// Full paths to keep, lowercase; `None` keeps everything.
+    pub paths: Option<HashSet<String>>,
+}
+
+impl EntryFilter {
+    pub fn matches(&self, entry: &FileEntry) -> bool {
+        // Sizes are compared against the extracted (decompressed) size
+        let size = entry.extract_size.unwrap_or(entry.file_size);
+
+        if let Some(min_size) = self.min_size
+            && size < min_size
+        {
+            return false;
+        }
+        if let Some(max_size) = self.max_size
+            && size > max_size
+        {
+            return false;
+        }
+
+        if !self.extensions.is_empty() {
+            let extension = entry.file_name.rsplit_once('.').map(|(_, ext)| ext);
+            let wanted = extension.is_some_and(|extension| {
+                self.extensions
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(extension))
+            });
+            if !wanted {
+                return false;
+            }
+        }
+
+        if let Some(paths) = &self.paths
+            && !paths.contains(&entry.full_path().to_lowercase())
+        {
+            return false;
+        }
+
+        if !self.patterns.is_empty() {
+            let path = entry.full_path().to_lowercase();
+            if !self
+                .patterns
+                .iter()
+                .any(|pattern| wildcard_match(&pattern.to_lowercase(), &path))
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+pub(crate) fn wildcard_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+
+    // matched[j]: whether the pattern read so far matches path[..j]
+    let mut matched = vec![false; path.len() + 1];
+    matched[0] = true;
+
+    let mut i = 0;
+    while i < pattern.len() {
+        let mut next = vec![false; path.len() + 1];
+        if pattern[i] == '*' && pattern.get(i + 1) == Some(&'*') {
+            // `**/` may also match no directory at all
+            let optional_slash = pattern.get(i + 2) == Some(&'/');
+            let mut reachable = false;
+            for j in 0..=path.len() {
+                reachable |= matched[j];
+                next[j] = reachable && (!optional_slash || j == 0 || path[j - 1] == '/');
+            }
+            if optional_slash {
+                for j in 0..=path.len() {
+                    next[j] |= matched[j];
+                }
+            }
+            i += if optional_slash { 3 } else { 2 };
+        } else {
+            for j in 0..=path.len() {
+                next[j] = match pattern[i] {
+                    '*' => matched[j] || (j > 0 && next[j - 1] && path[j - 1] != '/'),
+                    '?' => j > 0 && matched[j - 1] && path[j - 1] != '/',
+                    c => j > 0 && matched[j - 1] && path[j - 1] == c,
+                };
+            }
+            i += 1;
+        }
+        matched = next;
+    }
+
+    matched[path.len()]
+}
@@ -0,0 +1,145 @@
+use crate::builder::CpkBuilder;
+use crate::compression::CompressionPolicy;
+use crate::cpk::CpkMode;
+
+/// Words the compressible entries are made of.
+const WORDS: &[&str] = &[
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india", "juliet",
+    "kilo", "lima", "mike", "november", "oscar", "papa",
+];
+
+/// Properties of a fabricated archive. The same settings always give the same
+/// archive, so a problem can be reproduced from the command line alone.
+#[derive(Debug, Clone)]
+pub struct TestArchive {
+    pub files: usize,
+    pub mode: CpkMode,
+    /// Store the compressible half of the entries CRILAYLA-compressed.
+    pub compress: bool,
+    pub encrypt: bool,
+    /// Largest entry size in bytes.
+    pub max_size: usize,
+    pub seed: u64,
+}
+
+impl Default for TestArchive {
+    fn default() -> Self {
+        Self {
+            files: 100,
+            mode: CpkMode::FileName,
+            compress: false,
+            encrypt: false,
+            max_size: 0x4000,
+            seed: 0,
+        }
+    }
+}
+
+impl TestArchive {
+    /// A builder holding the generated entries.
+    ///
+    /// Entries alternate between repetitive text, which compresses well, and
+    /// random bytes, which doesn't, and are spread over a few directories.
+    pub fn builder(&self) -> CpkBuilder {
+        let mut rng = SplitMix64(self.seed);
+        let mut builder = CpkBuilder::new()
+            .mode(self.mode)
+            .encrypt_tables(self.encrypt)
+            .compression(CompressionPolicy {
+                enabled: self.compress,
+                ..Default::default()
+            });
+
+        for i in 0..self.files {
+            let size = (rng.next() % self.max_size.max(1) as u64) as usize;
+            let (extension, data) = if i % 2 == 0 {
+                ("txt", text(&mut rng, size))
+            } else {
+                ("bin", noise(&mut rng, size))
+            };
+            let path = format!("dir{:02}/file{:05}.{}", i % 16, i, extension);
+            builder = builder.add_file(path, data);
+        }
+        builder
+    }
+}
+
+fn text(rng: &mut SplitMix64, size: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(size + 16);
+    while data.len() < size {
+        data.extend_from_slice(WORDS[(rng.next() % WORDS.len() as u64) as usize].as_bytes());
+        data.push(b' ');
+    }
+    data.truncate(size);
+    data
+}
+
+fn noise(rng: &mut SplitMix64, size: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(size + 8);
+    while data.len() < size {
+        data.extend_from_slice(&rng.next().to_le_bytes());
+    }
+    data.truncate(size);
+    data
+}
+
+/// Small, fixed pseudo-random generator so fixtures don't depend on a crate's
+/// version or the platform.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+impl TestArchive {
+    /// Writes the archive to `path` and reads it back.
+    pub(crate) fn write_to(&self, path: &std::path::Path) -> crate::Cpk {
+        self.builder().write(path).unwrap();
+        read_back(path)
+    }
+}
+
+/// Reads the archive at `path`.
+#[cfg(test)]
+pub(crate) fn read_back(path: &std::path::Path) -> crate::Cpk {
+    let mut cpk = crate::Cpk::new();
+    cpk.read_cpk(path).unwrap();
+    cpk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_settings_give_the_same_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = TestArchive {
+            files: 24,
+            mode: CpkMode::FileNameAndId,
+            compress: true,
+            encrypt: true,
+            max_size: 0x3000,
+            seed: 7,
+        };
+        let write = |settings: &TestArchive, name: &str| {
+            let path = dir.path().join(name);
+            settings.builder().write(&path).unwrap();
+            std::fs::read(path).unwrap()
+        };
+        assert_eq!(write(&settings, "a.cpk"), write(&settings, "b.cpk"));
+
+        let other = TestArchive {
+            seed: 8,
+            ..settings.clone()
+        };
+        assert_ne!(write(&other, "c.cpk"), write(&settings, "a.cpk"));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::cpk::Cpk;
+use crate::error::{CpkError, Result};
+use crate::utf::Utf;
+use log::debug;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A named set of entries listed in the GTOC, which games use to load related
+/// files together.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    /// Attribute the group is filed under; groups without one share an unnamed attribute.
+    pub attribute: Option<String>,
+    /// Archive paths of the member entries.
+    pub files: Vec<String>,
+}
+
+/// Reads group definitions from JSON, either as lists of paths
+/// (`{"stage1": ["a.bin", "b.bin"]}`) or with an attribute
+/// (`{"stage1": {"attribute": "STAGE", "files": ["a.bin"]}}`).
+pub fn read_groups<P: AsRef<Path>>(path: P) -> Result<Vec<Group>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)?;
+    let value: serde_json::Value = serde_json::from_str(&text)
+        .map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+    let invalid = |name: &str| {
+        CpkError::Parse(format!(
+            "{}: group '{}' must be a list of paths or an object with \"files\"",
+            path.display(),
+            name
+        ))
+    };
+
+    let object = value.as_object().ok_or_else(|| {
+        CpkError::Parse(format!(
+            "{}: expected an object of group -> files",
+            path.display()
+        ))
+    })?;
+
+    let mut groups = Vec::with_capacity(object.len());
+    for (name, definition) in object {
+        let (attribute, files) = match definition {
+            serde_json::Value::Array(_) => (None, definition),
+            serde_json::Value::Object(fields) => (
+                fields
+                    .get("attribute")
+                    .and_then(|a| a.as_str())
+                    .map(str::to_string),
+                fields.get("files").ok_or_else(|| invalid(name))?,
+            ),
+            _ => return Err(invalid(name)),
+        };
+        let files = files
+            .as_array()
+            .ok_or_else(|| invalid(name))?
+            .iter()
+            .map(|file| {
+                file.as_str()
+                    .map(|file| file.replace('\\', "/").trim_matches('/').to_string())
+                    .ok_or_else(|| invalid(name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        groups.push(Group {
+            name: name.clone(),
+            attribute,
+            files,
+        });
+    }
+
+    debug!("groups: {} from {}", groups.len(), path.display());
+    Ok(groups)
+}
+
+/// Reads the groups of an archive's GTOC, with members resolved to TOC paths.
+///
+/// Only the layout written by `CpkBuilder` is understood: a GTOC laid out
+/// differently, as the ones of retail archives may be, is refused with
+/// `CpkError::Unsupported` rather than taken for having no groups. `Ok(None)`
+/// means the archive has no GTOC.
+pub fn archive_groups(cpk: &Cpk) -> Result<Option<Vec<Group>>> {
+    cpk.require_packets()?;
+    let Some(packet) = cpk.header_packet("GTOC_HDR") else {
+        return Ok(None);
+    };
+    let mut gtoc = Utf::with_options(cpk.parse_options().clone());
+    gtoc.read_utf(packet).map_err(|e| e.in_table("GTOC"))?;
+
+    let table = |column: &str| -> Result<Option<Utf>> {
+        match gtoc.get_column_data(0, column).and_then(|v| v.as_data()) {
+            Some(bytes) if !bytes.is_empty() => {
+                let table = gtoc
+                    .read_nested(bytes)
+                    .map_err(|e| e.in_table(&format!("GTOC {}", column)))?;
+                Ok(Some(table))
+            }
+            _ => Ok(None),
+        }
+    };
+    let unknown = |what: &str| {
+        CpkError::Unsupported(format!(
+            "GTOC layout not understood ({}); its groups can't be read",
+            what
+        ))
+    };
+    let (Some(gdata), Some(fdata), Some(attrdata)) =
+        (table("Gdata")?, table("Fdata")?, table("Attrdata")?)
+    else {
+        return Err(unknown("Gdata, Fdata or Attrdata is missing"));
+    };
+    if let Some(column) = ["Gname", "Aindex", "Child", "Files"]
+        .into_iter()
+        .find(|c| !gdata.has_column(c))
+    {
+        return Err(unknown(&format!("Gdata has no {} column", column)));
+    }
+    if !fdata.has_column("ID") {
+        return Err(unknown("Fdata has no ID column"));
+    }
+
+    let paths: HashMap<u32, String> = cpk
+        .file_table
+        .iter()
+        .filter(|e| e.file_type == "FILE" && e.toc_name == "TOC")
+        .filter_map(|e| Some((e.id?, e.full_path())))
+        .collect();
+
+    let mut groups = Vec::with_capacity(gdata.rows.len());
+    for row in 0..gdata.rows.len() {
+        let value = |column: &str| gdata.get_column_data(row, column);
+        let name = value("Gname")
+            .and_then(|v| v.as_string())
+            .unwrap_or_default();
+        let attribute = value("Aindex")
+            .and_then(|v| v.as_u32())
+            .and_then(|index| attrdata.get_column_data(index as usize, "Aname"))
+            .and_then(|v| v.as_string())
+            .filter(|name| !name.is_empty());
+        let first = value("Child").and_then(|v| v.as_u32()).unwrap_or(0) as usize;
+        let count = value("Files").and_then(|v| v.as_u32()).unwrap_or(0) as usize;
+
+        let mut files = Vec::with_capacity(count);
+        for link in first..first + count {
+            let id = fdata
+                .get_column_data(link, "ID")
+                .and_then(|v| v.as_u32())
+                .ok_or_else(|| {
+                    CpkError::InvalidFormat(format!("GTOC link {} is out of range", link))
+                })?;
+            let path = paths.get(&id).ok_or_else(|| {
+                CpkError::InvalidFormat(format!("GTOC group '{}' lists unknown ID {}", name, id))
+            })?;
+            files.push(path.clone());
+        }
+        groups.push(Group {
+            name: name.to_string(),
+            attribute: attribute.map(str::to_string),
+            files,
+        });
+    }
+    Ok(Some(groups))
+}
@@ -0,0 +1,32 @@
+use std::io::{self, Write};
+
+/// Writes `data` as a canonical hex+ASCII dump, labelling lines from `start`.
+pub fn write_hexdump<W: Write>(mut writer: W, data: &[u8], start: u64) -> io::Result<()> {
+    for (i, line) in data.chunks(16).enumerate() {
+        write!(writer, "{:08x} ", start + (i * 16) as u64)?;
+
+        for column in 0..16 {
+            if column == 8 {
+                write!(writer, " ")?;
+            }
+            match line.get(column) {
+                Some(byte) => write!(writer, " {:02x}", byte)?,
+                None => write!(writer, "   ")?,
+            }
+        }
+
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(writer, "  |{}|", ascii)?;
+    }
+
+    writeln!(writer, "{:08x}", start + data.len() as u64)
+}
@@ -0,0 +1,151 @@
+use crate::error::Result;
+use crate::filter::wildcard_match;
+use log::debug;
+use std::path::Path;
+
+/// File in a directory being packed listing what to leave out of the archive.
+pub(crate) const IGNORE_FILE: &str = ".cpkignore";
+
+/// The patterns of a `.cpkignore`, in gitignore syntax: one per line, `#`
+/// comments, `!` re-including what an earlier line left out, a trailing `/`
+/// for directories only, and a leading or inner `/` anchoring the pattern to
+/// the directory instead of matching names at any depth. The last matching
+/// line decides.
+#[derive(Debug, Default)]
+pub(crate) struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    /// Matched against the whole relative path rather than the name.
+    anchored: bool,
+}
+
+impl IgnoreRules {
+    /// The rules in `dir`'s ignore file, none when there's no such file.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(IGNORE_FILE);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let rules: Vec<Rule> = text.lines().filter_map(Rule::parse).collect();
+        debug!("ignore: {} patterns in {}", rules.len(), path.display());
+        Ok(Self { rules })
+    }
+
+    /// Whether `relative`, a `/`-separated path below the directory, is left out.
+    pub fn is_ignored(&self, relative: &str, is_dir: bool) -> bool {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let subject = if rule.anchored { relative } else { name };
+            if wildcard_match(&rule.pattern, subject) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        // A backslash keeps a leading `!` or `#` literal
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let pattern = line.trim_start_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            pattern: pattern.to_string(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::CpkBuilder;
+    use crate::generate::read_back;
+
+    fn rules(text: &str) -> IgnoreRules {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(IGNORE_FILE), text).unwrap();
+        IgnoreRules::load(dir.path()).unwrap()
+    }
+
+    #[test]
+    fn last_matching_line_decides() {
+        let rules = rules("# comment\n*.bin\n!keep.bin\n/top.txt\ncache/\ndocs/*.md\n\\#hash\n");
+        assert!(rules.is_ignored("a.bin", false));
+        assert!(rules.is_ignored("deep/er/a.bin", false));
+        assert!(!rules.is_ignored("deep/keep.bin", false));
+        assert!(rules.is_ignored("top.txt", false));
+        assert!(!rules.is_ignored("sub/top.txt", false));
+        assert!(rules.is_ignored("sub/cache", true));
+        assert!(!rules.is_ignored("sub/cache", false));
+        assert!(rules.is_ignored("docs/a.md", false));
+        assert!(!rules.is_ignored("other/docs/a.md", false));
+        assert!(rules.is_ignored("#hash", false));
+        assert!(!rules.is_ignored("comment", false));
+    }
+
+    #[test]
+    fn no_ignore_file_ignores_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = IgnoreRules::load(dir.path()).unwrap();
+        assert!(!rules.is_ignored("anything", false));
+    }
+
+    #[test]
+    fn packing_leaves_out_what_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        for path in ["a.txt", "b.bin", "keep.bin", "skip/c.txt", "sub/skip/d.txt"] {
+            let path = source.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"data").unwrap();
+        }
+        std::fs::write(source.join(IGNORE_FILE), "*.bin\n!keep.bin\n/skip/\n").unwrap();
+
+        let packed = dir.path().join("packed.cpk");
+        CpkBuilder::new()
+            .add_dir(&source)
+            .unwrap()
+            .write(&packed)
+            .unwrap();
+        let mut paths: Vec<String> = read_back(&packed)
+            .toc_files()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.full_path())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["a.txt", "keep.bin", "sub/skip/d.txt"]);
+    }
+}
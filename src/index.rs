@@ -0,0 +1,171 @@
+use crate::cancel::CancellationToken;
+use crate::cpk::Cpk;
+use crate::digest::HashAlgorithm;
+use crate::error::Result;
+use log::{info, warn};
+use rusqlite::{Connection, params};
+use std::path::{Path, PathBuf};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS archives (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        size INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS entries (
+        archive_id INTEGER NOT NULL REFERENCES archives(id) ON DELETE CASCADE,
+        path TEXT NOT NULL,
+        entry_id INTEGER,
+        size INTEGER NOT NULL,
+        stored_size INTEGER NOT NULL,
+        offset INTEGER NOT NULL,
+        sha256 TEXT
+    );
+    CREATE INDEX IF NOT EXISTS entries_path ON entries(path COLLATE NOCASE);
+    CREATE INDEX IF NOT EXISTS entries_sha256 ON entries(sha256);
+";
+
+/// What an indexing run catalogued.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IndexSummary {
+    pub archives: usize,
+    pub entries: usize,
+    /// Archives that couldn't be read and were left out.
+    pub failed: usize,
+}
+
+/// One catalogued entry.
+#[derive(Debug, Clone)]
+pub struct IndexedEntry {
+    pub archive: PathBuf,
+    pub path: String,
+    pub id: Option<u32>,
+    /// Extracted size.
+    pub size: u64,
+    pub stored_size: u64,
+    pub sha256: Option<String>,
+}
+
+/// Catalogs every entry of `archives` into the SQLite database at `db_path`,
+/// replacing what an earlier run recorded for the same archives.
+///
+/// With `hash`, each entry is read and decompressed to record its SHA-256,
+/// which is slow on large archives but lets identical files be found across
+/// archives. Each archive is read by a `Cpk` from `new_reader`, so reading
+/// options such as the base offset apply to all of them.
+pub fn build_index<P: AsRef<Path>, F: Fn() -> Cpk>(
+    archives: &[PathBuf],
+    db_path: P,
+    hash: bool,
+    new_reader: F,
+    cancel: &CancellationToken,
+) -> Result<IndexSummary> {
+    let mut db = Connection::open(db_path)?;
+    db.execute_batch("PRAGMA foreign_keys = ON;")?;
+    db.execute_batch(SCHEMA)?;
+
+    let mut summary = IndexSummary::default();
+    let tx = db.transaction()?;
+    for archive in archives {
+        cancel.check()?;
+        let mut cpk = new_reader();
+        if let Err(e) = cpk.read_cpk(archive) {
+            warn!("Skipping {}: {}", archive.display(), e);
+            summary.failed += 1;
+            continue;
+        }
+
+        let name = archive.to_string_lossy();
+        tx.execute("DELETE FROM archives WHERE path = ?1", params![name])?;
+        tx.execute(
+            "INSERT INTO archives (path, size) VALUES (?1, ?2)",
+            params![name, std::fs::metadata(archive)?.len() as i64],
+        )?;
+        let archive_id = tx.last_insert_rowid();
+
+        let mut insert = tx.prepare_cached(
+            "INSERT INTO entries (archive_id, path, entry_id, size, stored_size, offset, sha256)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for entry in cpk.unique_files() {
+            cancel.check()?;
+            let sha256 = match hash {
+                true => match cpk.read_entry(entry) {
+                    Ok(data) => Some(HashAlgorithm::Sha256.hex_digest(&data)),
+                    Err(e) => {
+                        warn!("{}: {}: {}", archive.display(), entry.full_path(), e);
+                        None
+                    }
+                },
+                false => None,
+            };
+            insert.execute(params![
+                archive_id,
+                entry.full_path(),
+                entry.id,
+                entry.extract_size.unwrap_or(entry.file_size) as i64,
+                entry.file_size as i64,
+                entry.file_offset as i64,
+                sha256,
+            ])?;
+            summary.entries += 1;
+        }
+        info!("Indexed {}", archive.display());
+        summary.archives += 1;
+    }
+    tx.commit()?;
+
+    Ok(summary)
+}
+
+/// Finds the catalogued entries matching `pattern`, case-insensitively.
+///
+/// `*` and `?` are wildcards. A pattern without `/` is also matched against
+/// file names alone, so `bgm01.acb` finds it in whichever directory it is;
+/// a 64-digit hex string finds entries by SHA-256.
+pub fn query<P: AsRef<Path>>(db_path: P, pattern: &str) -> Result<Vec<IndexedEntry>> {
+    let db = Connection::open(db_path)?;
+    db.execute_batch(SCHEMA)?;
+
+    let is_sha256 = pattern.len() == 64 && pattern.chars().all(|c| c.is_ascii_hexdigit());
+    let like = to_like(&pattern.replace('\\', "/"));
+    let mut statement = db.prepare(
+        "SELECT archives.path, entries.path, entry_id, entries.size, stored_size, sha256
+         FROM entries JOIN archives ON archives.id = entries.archive_id
+         WHERE entries.path LIKE ?1 ESCAPE '\\'
+            OR (?2 AND entries.path LIKE '%/' || ?1 ESCAPE '\\')
+            OR sha256 = lower(?3)
+         ORDER BY archives.path, entries.path",
+    )?;
+    let rows = statement.query_map(
+        params![like, !pattern.contains('/'), is_sha256.then_some(pattern)],
+        |row| {
+            Ok(IndexedEntry {
+                archive: PathBuf::from(row.get::<_, String>(0)?),
+                path: row.get(1)?,
+                id: row.get(2)?,
+                size: row.get::<_, i64>(3)? as u64,
+                stored_size: row.get::<_, i64>(4)? as u64,
+                sha256: row.get(5)?,
+            })
+        },
+    )?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Turns a wildcard pattern into a LIKE pattern escaped with `\`.
+fn to_like(pattern: &str) -> String {
+    let mut like = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        match c {
+            '*' => like.push('%'),
+            '?' => like.push('_'),
+            '%' | '_' | '\\' => {
+                like.push('\\');
+                like.push(c);
+            }
+            c => like.push(c),
+        }
+    }
+    like
+}
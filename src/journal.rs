@@ -0,0 +1,484 @@
+use crate::cache::archive_key;
+use crate::compression::StoredData;
+use crate::error::Result;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "cpk-extract-journal 1";
+const PACK_HEADER: &str = "cpk-pack-journal 1";
+
+/// Progress of an extraction into one directory, kept in a file there so a run
+/// stopped by a crash or Ctrl+C can pick up where it left off.
+///
+/// Each finished entry is appended as it completes, and large files also note
+/// how much of them is safely on disk. The journal only applies to the archive
+/// it was started for: a changed archive starts it over. It's removed once a
+/// run finishes without failures.
+#[derive(Debug)]
+pub struct ExtractJournal {
+    path: PathBuf,
+    file: File,
+    // Output path -> (bytes written, path actually written to)
+    done: HashMap<String, (u64, PathBuf)>,
+    // Output path -> bytes of it already written and synced
+    partial: HashMap<String, u64>,
+}
+
+impl ExtractJournal {
+    pub const FILE_NAME: &'static str = ".cpk-extract-journal";
+
+    /// Opens the journal in `dir` for extracting `archive`, continuing it if it
+    /// was started for the same archive and starting it over otherwise.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, archive: Q) -> Result<Self> {
+        let path = dir.as_ref().join(Self::FILE_NAME);
+        let (archive_path, size, mtime) = archive_key(archive.as_ref())?;
+        let header = format!("{}\t{}\t{}\t{}", HEADER, size, mtime, archive_path);
+
+        let mut done = HashMap::new();
+        let mut partial = HashMap::new();
+        let continued = match File::open(&path) {
+            Ok(existing) => {
+                let mut lines = BufReader::new(existing).lines();
+                match lines.next().transpose()? {
+                    Some(first) if first == header => {
+                        for line in lines {
+                            let line = line?;
+                            let mut fields = line.splitn(4, '\t');
+                            let (kind, bytes, key) = (fields.next(), fields.next(), fields.next());
+                            let Some(bytes) = bytes.and_then(|b| b.parse::<u64>().ok()) else {
+                                // A line cut short by the interruption
+                                continue;
+                            };
+                            match (kind, key) {
+                                (Some("done"), Some(key)) => {
+                                    let written = fields.next().unwrap_or(key);
+                                    partial.remove(key);
+                                    done.insert(key.to_string(), (bytes, PathBuf::from(written)));
+                                }
+                                (Some("part"), Some(key)) => {
+                                    partial.insert(key.to_string(), bytes);
+                                }
+                                _ => {}
+                            }
+                        }
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+
+        let file = match continued {
+            true => {
+                info!(
+                    "Resuming extraction: {} entries already done, {} partly written",
+                    done.len(),
+                    partial.len()
+                );
+                OpenOptions::new().append(true).open(&path)?
+            }
+            false => {
+                let mut file = File::create(&path)?;
+                writeln!(file, "{}", header)?;
+                file.flush()?;
+                file
+            }
+        };
+
+        Ok(Self {
+            path,
+            file,
+            done,
+            partial,
+        })
+    }
+
+    /// Where a finished entry was written, if it's still there in full.
+    pub fn finished(&self, key: &str) -> Option<&Path> {
+        let (written, path) = self.done.get(key)?;
+        let on_disk = std::fs::metadata(path).ok()?.len();
+        (on_disk == *written).then_some(path.as_path())
+    }
+
+    /// How many leading bytes of `path` an earlier run wrote for `key`.
+    pub fn checkpoint(&self, key: &str, path: &Path) -> u64 {
+        match self.partial.get(key) {
+            Some(&offset) if std::fs::metadata(path).is_ok_and(|m| m.len() >= offset) => offset,
+            _ => 0,
+        }
+    }
+
+    pub fn record_finished(&mut self, key: &str, written: u64, path: &Path) -> Result<()> {
+        self.append(&format!("done\t{}\t{}\t{}", written, key, path.display()))?;
+        self.partial.remove(key);
+        self.done
+            .insert(key.to_string(), (written, path.to_path_buf()));
+        Ok(())
+    }
+
+    /// Notes that the first `offset` bytes of `key` are written and synced.
+    pub fn record_checkpoint(&mut self, key: &str, offset: u64) -> Result<()> {
+        self.append(&format!("part\t{}\t{}", offset, key))?;
+        self.partial.insert(key.to_string(), offset);
+        Ok(())
+    }
+
+    fn append(&mut self, line: &str) -> Result<()> {
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Removes the journal once everything has been extracted.
+    pub fn finish(self) -> Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)?;
+        debug!("Extraction complete, removed {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// Compressed entries of an archive being packed, kept next to the output in
+/// `<output>.pack/` so a pack stopped part way can reuse them.
+///
+/// Each source file's stored form is appended to a spool file as soon as it's
+/// compressed, then journaled with the source's size and modification time;
+/// files stored raw are journaled without a copy and read from the source
+/// again. A changed source file is compressed again, and different compression
+/// settings start the journal over.
+#[derive(Debug)]
+pub(crate) struct PackJournal {
+    dir: PathBuf,
+    journal: File,
+    spool: File,
+    spool_path: PathBuf,
+    spool_len: u64,
+    // Canonical source path -> how it was stored
+    entries: HashMap<String, SpooledEntry>,
+}
+
+/// Where a source file's stored form can be read from.
+#[derive(Debug, Clone)]
+pub(crate) struct SpooledEntry {
+    source_size: u64,
+    source_mtime: i64,
+    /// Offset in the spool, or `None` when stored raw and read from the source.
+    pub offset: Option<u64>,
+    pub size: u64,
+    pub extract_size: u64,
+    pub crc: u32,
+}
+
+impl PackJournal {
+    /// Opens the journal for packing to `output` with `settings` describing how
+    /// entries are compressed.
+    pub fn open(output: &Path, settings: &str) -> Result<Self> {
+        let mut name = output.as_os_str().to_os_string();
+        name.push(".pack");
+        let dir = PathBuf::from(name);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("journal");
+        let spool_path = dir.join("spool");
+        let header = format!("{}\t{}", PACK_HEADER, settings);
+
+        let spool_len = std::fs::metadata(&spool_path).map_or(0, |m| m.len());
+        let mut entries = HashMap::new();
+        let mut spool_end = 0;
+        let continued = match File::open(&path) {
+            Ok(existing) => {
+                let mut lines = BufReader::new(existing).lines();
+                match lines.next().transpose()? {
+                    Some(first) if first == header => {
+                        for line in lines {
+                            let line = line?;
+                            let Some((source, entry)) = parse_spooled(&line) else {
+                                // A line cut short by the interruption
+                                continue;
+                            };
+                            if let Some(offset) = entry.offset {
+                                if offset + entry.size > spool_len {
+                                    continue;
+                                }
+                                spool_end = spool_end.max(offset + entry.size);
+                            }
+                            entries.insert(source, entry);
+                        }
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+
+        let journal = match continued {
+            true => {
+                info!("Resuming pack: {} entries already stored", entries.len());
+                OpenOptions::new().append(true).open(&path)?
+            }
+            false => {
+                let mut journal = File::create(&path)?;
+                writeln!(journal, "{}", header)?;
+                journal.flush()?;
+                journal
+            }
+        };
+        // Data past the last journaled entry was never finished
+        let mut spool = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&spool_path)?;
+        spool.set_len(spool_end)?;
+        spool.seek(SeekFrom::Start(spool_end))?;
+
+        Ok(Self {
+            dir,
+            journal,
+            spool,
+            spool_path,
+            spool_len: spool_end,
+            entries,
+        })
+    }
+
+    pub fn spool_path(&self) -> &Path {
+        &self.spool_path
+    }
+
+    /// How an earlier run stored `source`, if the file hasn't changed since.
+    pub fn stored(&self, source: &Path) -> Result<Option<SpooledEntry>> {
+        let (key, size, mtime) = archive_key(source)?;
+        Ok(self
+            .entries
+            .get(&key)
+            .filter(|e| e.source_size == size as u64 && e.source_mtime == mtime)
+            .cloned())
+    }
+
+    /// Journals how `source` is stored, spooling the data if it's compressed.
+    pub fn record(&mut self, source: &Path, stored: &StoredData) -> Result<SpooledEntry> {
+        let (key, size, mtime) = archive_key(source)?;
+        let offset = match stored.is_compressed() {
+            true => {
+                let offset = self.spool_len;
+                self.spool.write_all(&stored.data)?;
+                self.spool.sync_data()?;
+                self.spool_len += stored.data.len() as u64;
+                Some(offset)
+            }
+            false => None,
+        };
+        let entry = SpooledEntry {
+            source_size: size as u64,
+            source_mtime: mtime,
+            offset,
+            size: stored.data.len() as u64,
+            extract_size: stored.extract_size,
+            crc: stored.crc,
+        };
+
+        writeln!(
+            self.journal,
+            "{}\t{}\t{}\t{}\t{}\t{:08x}\t{}",
+            entry.source_size,
+            entry.source_mtime,
+            entry.offset.map_or("-".to_string(), |o| o.to_string()),
+            entry.size,
+            entry.extract_size,
+            entry.crc,
+            key
+        )?;
+        self.journal.flush()?;
+        self.entries.insert(key, entry.clone());
+        Ok(entry)
+    }
+
+    /// Removes the journal and spool once the archive has been written.
+    pub fn finish(self) -> Result<()> {
+        drop(self.journal);
+        drop(self.spool);
+        std::fs::remove_dir_all(&self.dir)?;
+        debug!("Pack complete, removed {}", self.dir.display());
+        Ok(())
+    }
+}
+
+fn parse_spooled(line: &str) -> Option<(String, SpooledEntry)> {
+    let mut fields = line.splitn(7, '\t');
+    let source_size = fields.next()?.parse().ok()?;
+    let source_mtime = fields.next()?.parse().ok()?;
+    let offset = match fields.next()? {
+        "-" => None,
+        offset => Some(offset.parse().ok()?),
+    };
+    let size = fields.next()?.parse().ok()?;
+    let extract_size = fields.next()?.parse().ok()?;
+    let crc = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let source = fields.next()?.to_string();
+    Some((
+        source,
+        SpooledEntry {
+            source_size,
+            source_mtime,
+            offset,
+            size,
+            extract_size,
+            crc,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Crilayla;
+    use crate::compression::CompressionPolicy;
+    use crate::filter::EntryFilter;
+    use crate::generate::{TestArchive, read_back};
+
+    fn archive(path: &Path) -> crate::Cpk {
+        TestArchive {
+            files: 8,
+            max_size: 0x1000,
+            seed: 2,
+            ..Default::default()
+        }
+        .write_to(path)
+    }
+
+    #[test]
+    fn entries_and_checkpoints_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in.cpk");
+        archive(&source);
+        let (done, partial) = (dir.path().join("done.bin"), dir.path().join("partial.bin"));
+        std::fs::write(&done, [1, 2, 3]).unwrap();
+        std::fs::write(&partial, [0; 16]).unwrap();
+
+        let mut journal = ExtractJournal::open(dir.path(), &source).unwrap();
+        journal.record_finished("done.bin", 3, &done).unwrap();
+        journal.record_checkpoint("partial.bin", 10).unwrap();
+        drop(journal);
+        // As a line cut short by the interruption would be
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(ExtractJournal::FILE_NAME))
+            .unwrap();
+        write!(file, "part\t").unwrap();
+        drop(file);
+
+        let journal = ExtractJournal::open(dir.path(), &source).unwrap();
+        assert_eq!(journal.finished("done.bin"), Some(done.as_path()));
+        assert_eq!(journal.checkpoint("partial.bin", &partial), 10);
+        // A file shorter than its checkpoint, or changed since, starts over
+        std::fs::write(&partial, [0; 4]).unwrap();
+        assert_eq!(journal.checkpoint("partial.bin", &partial), 0);
+        std::fs::write(&done, [1]).unwrap();
+        assert_eq!(journal.finished("done.bin"), None);
+        journal.finish().unwrap();
+        assert!(!dir.path().join(ExtractJournal::FILE_NAME).exists());
+    }
+
+    #[test]
+    fn another_archive_starts_the_journal_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in.cpk");
+        archive(&source);
+        let done = dir.path().join("done.bin");
+        std::fs::write(&done, [1, 2, 3]).unwrap();
+        let mut journal = ExtractJournal::open(dir.path(), &source).unwrap();
+        journal.record_finished("done.bin", 3, &done).unwrap();
+        drop(journal);
+
+        OpenOptions::new()
+            .append(true)
+            .open(&source)
+            .unwrap()
+            .write_all(&[0; 0x800])
+            .unwrap();
+        let journal = ExtractJournal::open(dir.path(), &source).unwrap();
+        assert_eq!(journal.finished("done.bin"), None);
+    }
+
+    #[test]
+    fn resumed_extraction_skips_what_was_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in.cpk");
+        archive(&source);
+        let out = dir.path().join("out");
+        let blocked = out.join("dir03/file00003.bin");
+        std::fs::create_dir_all(&blocked).unwrap();
+
+        let mut cpk = read_back(&source);
+        cpk.set_output_dir(&out);
+        cpk.set_resume(true);
+        cpk.set_keep_going(true);
+        let result = cpk.extract_all(&source, &EntryFilter::default());
+        assert!(matches!(result, Err(crate::CpkError::EntriesFailed(1))));
+        assert!(out.join(ExtractJournal::FILE_NAME).exists());
+
+        // Same size, so only the journal can tell it apart from the entry
+        let done = out.join("dir00/file00000.txt");
+        let marker = vec![b'x'; std::fs::metadata(&done).unwrap().len() as usize];
+        std::fs::write(&done, &marker).unwrap();
+        std::fs::remove_dir(&blocked).unwrap();
+        cpk.extract_all(&source, &EntryFilter::default()).unwrap();
+        assert!(!out.join(ExtractJournal::FILE_NAME).exists());
+
+        assert_eq!(std::fs::read(&done).unwrap(), marker);
+        let entry = cpk.find("dir03/file00003.bin").unwrap();
+        assert_eq!(
+            std::fs::read(&blocked).unwrap(),
+            cpk.read_entry(entry).unwrap()
+        );
+    }
+
+    #[test]
+    fn spooled_entries_are_reused_until_their_source_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.cpk");
+        let source = dir.path().join("a.txt");
+        let data = b"alpha bravo ".repeat(0x100);
+        std::fs::write(&source, &data).unwrap();
+        let compression = CompressionPolicy {
+            enabled: true,
+            ..Default::default()
+        };
+        let stored = compression.store(&Crilayla, data.clone()).unwrap();
+
+        let mut journal = PackJournal::open(&output, "crilayla").unwrap();
+        assert!(journal.stored(&source).unwrap().is_none());
+        journal.record(&source, &stored).unwrap();
+        drop(journal);
+
+        let journal = PackJournal::open(&output, "crilayla").unwrap();
+        let entry = journal.stored(&source).unwrap().unwrap();
+        assert_eq!(
+            (entry.size, entry.extract_size),
+            (stored.data.len() as u64, data.len() as u64)
+        );
+        let spool = std::fs::read(journal.spool_path()).unwrap();
+        let offset = entry.offset.unwrap() as usize;
+        assert_eq!(
+            &spool[offset..offset + entry.size as usize],
+            stored.data.as_slice()
+        );
+        std::fs::write(&source, b"changed").unwrap();
+        assert!(journal.stored(&source).unwrap().is_none());
+        drop(journal);
+
+        // Other settings start over
+        std::fs::write(&source, &data).unwrap();
+        let journal = PackJournal::open(&output, "raw").unwrap();
+        assert!(journal.stored(&source).unwrap().is_none());
+        journal.finish().unwrap();
+        assert!(!dir.path().join("out.cpk.pack").exists());
+    }
+}
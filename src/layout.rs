@@ -0,0 +1,137 @@
+use crate::cpk::Cpk;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Header,
+    Table,
+    Entry,
+    /// Data an append-style patch left behind.
+    Replaced,
+    Gap,
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RegionKind::Header => "header",
+            RegionKind::Table => "table",
+            RegionKind::Entry => "entry",
+            RegionKind::Replaced => "replaced",
+            RegionKind::Gap => "gap",
+        };
+        f.pad(name)
+    }
+}
+
+/// A byte range of the archive file and what it belongs to. `end` is exclusive.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+    pub owner: String,
+}
+
+impl Region {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Collects the header, table and entry regions of a parsed archive, sorted by offset.
+pub fn regions(cpk: &Cpk) -> Vec<Region> {
+    let mut regions = Vec::new();
+    let mut toc_ranges = Vec::new();
+
+    for entry in &cpk.file_table {
+        match entry.file_type.as_str() {
+            "CPK" => regions.push(Region {
+                start: cpk.base_offset(),
+                end: cpk.base_offset() + 0x10 + entry.file_size,
+                kind: RegionKind::Header,
+                owner: entry.file_name.clone(),
+            }),
+            // Tables are stored behind a 16-byte signature/size header
+            "HDR" => regions.push(Region {
+                start: entry.file_offset,
+                end: entry.file_offset + 0x10 + entry.file_size,
+                kind: RegionKind::Table,
+                owner: entry.file_name.clone(),
+            }),
+            "REPLACED_LIST" => regions.push(Region {
+                start: entry.file_offset,
+                end: entry.file_offset + entry.file_size,
+                kind: RegionKind::Table,
+                owner: entry.file_name.clone(),
+            }),
+            "REPLACED" => regions.push(Region {
+                start: entry.file_offset,
+                end: entry.file_offset + entry.file_size,
+                kind: RegionKind::Replaced,
+                owner: "replaced data".to_string(),
+            }),
+            "FILE" => {
+                let range = (entry.file_offset, entry.file_offset + entry.file_size);
+                // Combined archives describe the same data in both the TOC and ITOC
+                if entry.toc_name == "ITOC" && toc_ranges.contains(&range) {
+                    continue;
+                }
+                if entry.toc_name == "TOC" {
+                    toc_ranges.push(range);
+                }
+
+                let owner = match entry.id {
+                    Some(id) if entry.toc_name == "TOC" => {
+                        format!("{} (ID {})", entry.full_path(), id)
+                    }
+                    _ => entry.full_path(),
+                };
+                regions.push(Region {
+                    start: range.0,
+                    end: range.1,
+                    kind: RegionKind::Entry,
+                    owner,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    regions.sort_by_key(|r| (r.start, r.end));
+    regions
+}
+
+/// Inserts gap regions for bytes up to `end` that no region accounts for.
+pub fn with_gaps(regions: Vec<Region>, end: u64) -> Vec<Region> {
+    let mut result = Vec::with_capacity(regions.len() * 2);
+    let mut covered = regions.first().map_or(end, |r| r.start);
+
+    for region in regions {
+        if region.start > covered {
+            result.push(Region {
+                start: covered,
+                end: region.start,
+                kind: RegionKind::Gap,
+                owner: String::new(),
+            });
+        }
+        covered = covered.max(region.end);
+        result.push(region);
+    }
+
+    if end > covered {
+        result.push(Region {
+            start: covered,
+            end,
+            kind: RegionKind::Gap,
+            owner: String::new(),
+        });
+    }
+
+    result
+}
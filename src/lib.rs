@@ -0,0 +1,52 @@
+//! Reading, extracting and rebuilding CRIWARE CPK archives.
+
+pub mod acb;
+pub mod afs;
+pub mod bench;
+pub mod builder;
+pub mod cache;
+pub mod cancel;
+pub mod carve;
+pub mod codec;
+pub mod compression;
+pub mod cpk;
+pub mod crypt;
+pub mod delta;
+pub mod diagnostic;
+pub mod digest;
+mod endian;
+pub mod error;
+pub mod escape;
+pub mod filter;
+pub mod generate;
+pub mod group;
+pub mod hexdump;
+mod ignore;
+#[cfg(feature = "sqlite")]
+pub mod index;
+pub mod journal;
+pub mod layout;
+pub mod mapping;
+pub mod merge;
+pub mod modpack;
+pub mod options;
+mod pread;
+pub mod process;
+pub mod reader;
+pub mod renumber;
+mod replaced;
+pub mod report;
+pub mod rollback;
+pub mod scan;
+pub mod search;
+mod sparse;
+pub mod split;
+pub mod utf;
+pub mod verify;
+
+pub use builder::CpkBuilder;
+pub use cancel::CancellationToken;
+pub use cpk::{Cpk, CpkFile, FileEntry};
+pub use error::{CpkError, Result};
+pub use options::ParseOptions;
+pub use reader::CpkReader;
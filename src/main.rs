@@ -1,84 +1,2020 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
-use log::info;
-use std::path::PathBuf;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use colored::{ColoredString, Colorize};
+use log::{debug, info, warn};
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fs::create_dir_all;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-mod compression;
-mod cpk;
-mod endian;
-mod error;
-mod utf;
-
-use cpk::Cpk;
+use cpk_tool_rs::afs::Afs;
+use cpk_tool_rs::builder::{IdStrategy, PathSplit};
+#[cfg(feature = "sqlite")]
+use cpk_tool_rs::cache::VerifyCache;
+use cpk_tool_rs::compression::{
+    CompressionPolicy, compress_crilayla, decompress_crilayla, decompress_crilayla_lenient_into,
+};
+use cpk_tool_rs::cpk::{
+    CpkMode, DuplicatePolicy, ExistingPolicy, ExtractEvent, FlatCollision, PathCase,
+};
+use cpk_tool_rs::crypt::CipherRegistry;
+use cpk_tool_rs::digest::{self, HashAlgorithm};
+use cpk_tool_rs::error::CpkError;
+use cpk_tool_rs::escape::NameEscape;
+use cpk_tool_rs::filter::EntryFilter;
+use cpk_tool_rs::generate::TestArchive;
+#[cfg(feature = "sqlite")]
+use cpk_tool_rs::index;
+use cpk_tool_rs::process::CommandProcessor;
+use cpk_tool_rs::report::RunReport;
+use cpk_tool_rs::utf::{CellValue, Utf};
+use cpk_tool_rs::{
+    CancellationToken, Cpk, CpkBuilder, FileEntry, acb, bench, delta, group, hexdump, layout,
+    mapping, merge, modpack, renumber, rollback, scan, search, split, verify,
+};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = EXIT_STATUS_HELP)]
 struct Cli {
+    /// Offset of the CPK inside the input file (decimal or 0x-prefixed hex)
+    #[arg(long, global = true, default_value = "0", value_parser = parse_offset)]
+    offset: u64,
+
+    /// Skip TOC rows that can't be parsed instead of giving up on the archive
+    #[arg(long, global = true)]
+    lenient: bool,
+
+    /// Decode CRILAYLA backreferences that point outside the data as zeros instead
+    /// of failing, reporting how many bytes were made up
+    #[arg(long, global = true)]
+    lenient_decompress: bool,
+
+    /// Plain output even on a terminal (also set by NO_COLOR)
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Also write debug-level logs to this file; RUST_LOG still sets what the console shows
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Match archive paths given on the command line case-sensitively
+    #[arg(long, global = true)]
+    case_sensitive: bool,
+
+    /// Answer yes to confirmation prompts, such as reading a CPK found appended
+    /// to the input instead of at its start
+    #[arg(short = 'y', long, global = true)]
+    yes: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Args)]
+struct FilterArgs {
+    /// Only include entries at least this large when extracted (e.g. 512K, 10M)
+    #[arg(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+    /// Only include entries at most this large when extracted (e.g. 512K, 10M)
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Only include entries with these extensions (comma-separated, e.g. txt,lua)
+    #[arg(long = "ext", value_delimiter = ',')]
+    extensions: Vec<String>,
+    /// Only include entries whose path matches this wildcard pattern (*, ?, **); repeatable
+    #[arg(long = "filter")]
+    patterns: Vec<String>,
+    /// Only include the entries whose paths are listed in this file, one per
+    /// line; - reads the list from standard input
+    #[arg(long, value_name = "FILE")]
+    paths_from: Option<PathBuf>,
+    /// Separate paths with NUL instead of newlines, in --paths-from and in the
+    /// output of list, for xargs -0 and find -print0
+    #[arg(short = '0', long)]
+    null: bool,
+}
+
+impl FilterArgs {
+    fn to_filter(&self) -> Result<EntryFilter> {
+        let paths = match &self.paths_from {
+            Some(list) => {
+                let text = match list.as_os_str() == "-" {
+                    true => std::io::read_to_string(std::io::stdin().lock())?,
+                    false => std::fs::read_to_string(list)?,
+                };
+                let separator = if self.null { '\0' } else { '\n' };
+                let paths: HashSet<String> = text
+                    .split(separator)
+                    .map(|path| path.strip_suffix('\r').unwrap_or(path))
+                    .filter(|path| !path.is_empty())
+                    .map(|path| path.replace('\\', "/").to_lowercase())
+                    .collect();
+                debug!("{} paths listed in {}", paths.len(), list.display());
+                Some(paths)
+            }
+            None => None,
+        };
+        Ok(EntryFilter {
+            min_size: self.min_size,
+            max_size: self.max_size,
+            extensions: self
+                .extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_string())
+                .collect(),
+            patterns: self
+                .patterns
+                .iter()
+                .map(|pattern| pattern.replace('\\', "/"))
+                .collect(),
+            paths,
+        })
+    }
+}
+
+#[derive(Args)]
+struct CompressArgs {
+    /// Store new data CRILAYLA-compressed where it pays off
+    #[arg(long)]
+    compress: bool,
+    /// Store data raw unless compression saves at least this many percent
+    #[arg(long, default_value_t = 5.0, requires = "compress")]
+    min_saving: f64,
+}
+
+impl CompressArgs {
+    fn to_policy(&self) -> CompressionPolicy {
+        CompressionPolicy {
+            enabled: self.compress,
+            min_saving: self.min_saving / 100.0,
+        }
+    }
+}
+
+/// Rewrites the original CriPakTools invocations to their subcommand form, so
+/// existing scripts keep working:
+///
+/// - `IN_FILE` lists the archive
+/// - `IN_FILE EXTRACT_ME` (or `ALL`) extracts
+/// - `IN_FILE REPLACE_ME REPLACE_WITH [OUT_FILE]` replaces
+///
+/// Anything starting with a subcommand or a flag is passed through untouched.
+fn compat_args(args: Vec<OsString>) -> Vec<OsString> {
+    let Some(first) = args.get(1) else {
+        return args;
+    };
+    let is_known = first.to_str().is_some_and(|name| {
+        name.starts_with('-') || Cli::command().find_subcommand(name).is_some()
+    });
+    if is_known || !Path::new(first).is_file() {
+        return args;
+    }
+
+    let mut rewritten = vec![args[0].clone()];
+    let rest = &args[1..];
+    match rest.len() {
+        1 => rewritten.push("list".into()),
+        2 => rewritten.push("extract".into()),
+        3 | 4 => rewritten.push("replace".into()),
+        _ => return args,
+    }
+    rewritten.extend(rest.iter().take(3).cloned());
+    if let Some(output) = rest.get(3) {
+        rewritten.push("--output".into());
+        rewritten.push(output.clone());
+    }
+    rewritten
+}
+
+/// One problem listed in the summary printed at the end of a run.
+struct Failure {
+    entry: String,
+    kind: &'static str,
+    offset: Option<u64>,
+    message: String,
+}
+
+/// An archive reader set up from the global options.
+fn open_cpk(cli: &Cli) -> Cpk {
+    let mut cpk = Cpk::with_base_offset(cli.offset);
+    cpk.set_lenient(cli.lenient);
+    cpk.set_lenient_decompress(cli.lenient_decompress);
+    if cli.case_sensitive {
+        cpk.set_path_case(PathCase::Sensitive);
+    }
+    cpk
+}
+
+impl Commands {
+    /// The archive arguments that may be `-` for standard input: those of the
+    /// commands that only read an archive.
+    fn stdin_inputs(&mut self) -> Vec<&mut PathBuf> {
+        let paths: Vec<&mut PathBuf> = match self {
+            Commands::List { inputs, .. } => inputs.iter_mut().collect(),
+            Commands::Extract { paths, .. } => paths.iter_mut().collect(),
+            Commands::Info { input, .. }
+            | Commands::Groups { input, .. }
+            | Commands::Ids { input, .. }
+            | Commands::Layout { input }
+            | Commands::Hash { input, .. }
+            | Commands::Verify { input, .. } => vec![input],
+            _ => Vec::new(),
+        };
+        paths
+            .into_iter()
+            .filter(|path| path.as_os_str() == "-")
+            .collect()
+    }
+}
+
+/// Standard input saved to a temporary file, for an archive given as `-`:
+/// reading one needs to seek, which a pipe can't. Removed when dropped.
+struct StdinSpool {
+    path: PathBuf,
+}
+
+impl StdinSpool {
+    /// Spools standard input if the command reads its archive from it, and
+    /// points the command at the copy. A file redirected to standard input
+    /// can already seek, so it's read where it is instead.
+    fn for_command(command: &mut Commands) -> Result<Option<Self>> {
+        let list_on_stdin = match command {
+            Commands::List { filter, .. }
+            | Commands::Extract { filter, .. }
+            | Commands::Hash { filter, .. }
+            | Commands::Grep { filter, .. } => filter
+                .paths_from
+                .as_ref()
+                .is_some_and(|list| list.as_os_str() == "-"),
+            _ => false,
+        };
+        let mut inputs = command.stdin_inputs();
+        if list_on_stdin && !inputs.is_empty() {
+            anyhow::bail!("standard input can't hold both the archive and --paths-from");
+        }
+        if inputs.len() > 1 {
+            anyhow::bail!("standard input (-) can only be given once");
+        }
+        let Some(input) = inputs.pop() else {
+            return Ok(None);
+        };
+        if std::io::stdin().is_terminal() {
+            anyhow::bail!("'-' reads the archive from standard input, which is a terminal");
+        }
+
+        #[cfg(unix)]
+        if std::fs::metadata("/dev/stdin").is_ok_and(|metadata| metadata.is_file()) {
+            debug!("stdin: a file, read in place");
+            *input = PathBuf::from("/dev/stdin");
+            return Ok(None);
+        }
+
+        let (spool, mut file) = Self::create()?;
+        let copied = std::io::copy(&mut std::io::stdin().lock(), &mut file)?;
+        debug!(
+            "stdin: {} bytes spooled to {}",
+            copied,
+            spool.path.display()
+        );
+        *input = spool.path.clone();
+        Ok(Some(spool))
+    }
+
+    /// Creates a new file only this user can read in the temporary directory,
+    /// under a name that can't be guessed, so nothing already there (such as
+    /// a symlink planted by someone else) is opened instead.
+    fn create() -> Result<(Self, std::fs::File)> {
+        use std::hash::{BuildHasher, Hasher};
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut attempts = 0;
+        loop {
+            // RandomState is seeded from the OS for each process
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write_u32(std::process::id());
+            hasher.write_u32(attempts);
+            let path =
+                std::env::temp_dir().join(format!("cpk-tools-stdin-{:016x}.cpk", hasher.finish()));
+            match options.open(&path) {
+                Ok(file) => return Ok((Self { path }, file)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempts < 16 => {
+                    attempts += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for StdinSpool {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Could not remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Reads `input` into `cpk`. Without `--offset`, an input that doesn't start
+/// with a CPK header is searched for an archive appended to it, which is read
+/// once confirmed.
+fn read_input(cli: &Cli, cpk: &mut Cpk, input: &Path) -> cpk_tool_rs::error::Result<()> {
+    if rollback::pending(input) {
+        eprintln!(
+            "warning: {} is being modified in place, or a modification of it was interrupted; \
+             what's shown may be half updated until a command modifying it restores it",
+            input.display()
+        );
+    }
+    match cpk.read_cpk(input) {
+        Err(CpkError::InvalidSignature) if cli.offset == 0 => {}
+        result => return result,
+    }
+    let Some(hit) = scan::find_appended(input)? else {
+        return Err(CpkError::InvalidSignature);
+    };
+
+    let question = format!(
+        "{} doesn't start with a CPK header, but an archive of {} files was found at 0x{:X}. Read it?",
+        input.display(),
+        hit.files,
+        hit.offset
+    );
+    if !confirm(cli, &question)? {
+        return Err(CpkError::InvalidSignature);
+    }
+    cpk.set_base_offset(hit.offset);
+    cpk.read_cpk(input)
+}
+
+/// Like `read_input`, for commands that modify the archive: an in-place
+/// modification of it that was interrupted is undone first.
+fn read_for_update(cli: &Cli, cpk: &mut Cpk, input: &Path) -> cpk_tool_rs::error::Result<()> {
+    rollback::recover(input)?;
+    read_input(cli, cpk, input)
+}
+
+/// Asks a yes/no question on stderr. `--yes` answers it; with no terminal to
+/// ask on, the answer is no.
+fn confirm(cli: &Cli, question: &str) -> std::io::Result<bool> {
+    use std::io::Write;
+
+    if cli.yes {
+        eprintln!("{} yes (--yes)", question);
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        eprintln!("{} Not asked without a terminal; pass --yes", question);
+        return Ok(false);
+    }
+
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Prints the problems a run worked around, after its regular output.
+fn print_warnings(warnings: &[String]) {
+    if warnings.is_empty() {
+        return;
+    }
+
+    let heading = format!("{} warning(s):", warnings.len()).yellow();
+    let stdout_color = colored::control::SHOULD_COLORIZE.should_colorize();
+    colored::control::set_override(STDERR_COLOR.load(Ordering::Relaxed));
+    eprintln!();
+    eprintln!("{}", heading);
+    colored::control::set_override(stdout_color);
+    for warning in warnings {
+        eprintln!("  {}", warning);
+    }
+}
+
+/// Opens the cache `--cache` asks for, if any. One that can't be opened is
+/// noted in `warnings` and done without.
+#[cfg(feature = "sqlite")]
+fn open_cache(cache: &Option<Option<PathBuf>>, warnings: &mut Vec<String>) -> Option<VerifyCache> {
+    let Some(path) = cache.clone()?.or_else(VerifyCache::default_path) else {
+        warnings.push("Not caching: no cache directory, give --cache a path".to_string());
+        return None;
+    };
+    match VerifyCache::open(&path) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            warnings.push(format!("Not caching in {}: {}", path.display(), e));
+            None
+        }
+    }
+}
+
+/// Parse diagnostics of an archive, prefixed with `label` when a run covers several.
+fn diagnostic_warnings(cpk: &Cpk, label: &str) -> Vec<String> {
+    cpk.diagnostics()
+        .iter()
+        .map(|diagnostic| format!("{}{}", label, diagnostic))
+        .collect()
+}
+
+/// Prints every failure of the run in one place, after the interleaved log output.
+fn print_failures(failures: &[Failure]) {
+    if failures.is_empty() {
+        return;
+    }
+
+    let width = failures
+        .iter()
+        .map(|f| f.entry.len())
+        .max()
+        .unwrap_or(0)
+        .min(48);
+    println!();
+    println!("{}", format!("{} problem(s):", failures.len()).red().bold());
+    for failure in failures {
+        let offset = failure
+            .offset
+            .map_or("-".to_string(), |offset| format!("0x{:X}", offset));
+        println!(
+            "  {:<width$}  {}  {:>10}  {}",
+            failure.entry,
+            format!("{:<11}", failure.kind).red(),
+            offset,
+            failure.message,
+            width = width
+        );
+    }
+}
+
+/// Whether `path` is a file with a CPK header at `offset`.
+fn is_cpk(path: &Path, offset: u64) -> bool {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut signature = [0u8; 4];
+    std::fs::File::open(path)
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut signature)
+        })
+        .is_ok()
+        && &signature == b"CPK "
+}
+
+/// Prints what decides which repack options suit an archive: its mode, flags,
+/// tables with their encryption, and codec fields.
+fn print_info(cpk: &Cpk) {
+    let header = |column: &str| cpk.cpk_data.get(column);
+    let show = |name: &str, column: &str| {
+        if let Some(value) = header(column) {
+            println!("{} {}", label(name), value);
+        }
+    };
+
+    if let (Some(version), Some(revision)) = (header("Version"), header("Revision")) {
+        println!("{} {}.{}", label("Version:"), version, revision);
+    }
+    show("Tool:", "Tvers");
+    match (header("CpkMode"), cpk.cpk_mode()) {
+        (Some(value), mode) if mode.map(CpkMode::value) == value.as_u32() => {
+            println!(
+                "{} {} ({:?})",
+                label("CpkMode:"),
+                value,
+                mode.unwrap_or_default()
+            );
+        }
+        (Some(value), _) => println!("{} {} {}", label("CpkMode:"), value, "(unknown)".yellow()),
+        (None, Some(mode)) => {
+            println!("{} {:?} (from the tables present)", label("CpkMode:"), mode)
+        }
+        (None, None) => {}
+    }
+    println!("{} 0x{:X}", label("Align:"), cpk.align());
+    if let Some(sorted) = header("Sorted").and_then(|v| v.as_u64()) {
+        println!(
+            "{} {}",
+            label("Sorted:"),
+            if sorted != 0 { "yes" } else { "no" }
+        );
+    }
+    show("Files:", "Files");
+    show("Codec:", "Codec");
+    show("DpkItoc:", "DpkItoc");
+    show("CrcMode:", "CrcMode");
+    show("TocCrc:", "EnableTocCrc");
+    show("FileCrc:", "EnableFileCrc");
+    println!(
+        "{} {}",
+        label("Entry CRCs:"),
+        if cpk.has_crc() { "yes" } else { "no" }
+    );
+
+    println!();
+    println!(
+        "{}",
+        format!(
+            "{:<6}  {:<10}  {:>10}  Encrypted",
+            "Table", "Offset", "Size"
+        )
+        .bold()
+    );
+    for entry in cpk
+        .file_table
+        .iter()
+        .filter(|e| e.file_name.ends_with("_HDR"))
+    {
+        let name = entry.file_name.trim_end_matches("_HDR");
+        println!(
+            "{:<6}  0x{:08X}  {}  {}",
+            name,
+            entry.file_offset,
+            number(format!("{:>10}", entry.file_size)),
+            if cpk.is_table_encrypted(&entry.file_name) {
+                "yes"
+            } else {
+                "no"
+            }
+        );
+    }
+}
+
+/// A left-aligned field name in `info` output.
+fn label(text: &str) -> ColoredString {
+    format!("{:<12}", text).bold()
+}
+
+/// Counts and sizes stand out from the text around them; pad before styling.
+fn number(text: String) -> ColoredString {
+    text.cyan()
+}
+
+/// Prints every column of the header row, including ones this tool doesn't interpret.
+fn print_header_columns(cpk: &Cpk) -> Result<()> {
+    let mut header = Utf::new();
+    header.read_utf(cpk.header_packet("CPK_HDR").unwrap_or_default())?;
+
+    println!(
+        "{}",
+        format!("{:<20}  {:<6}  {:<8}  Value", "Column", "Type", "Storage").bold()
+    );
+    for (i, column) in header.columns.iter().enumerate() {
+        let value = header
+            .rows
+            .first()
+            .and_then(|row| row.get(i))
+            .map(|cell| &cell.value)
+            .or(column.constant.as_ref())
+            .unwrap_or(&CellValue::None);
+        println!(
+            "{:<20}  {:<6}  {:<8}  {}",
+            column.name,
+            column.type_name(),
+            column.storage_name(),
+            value
+        );
+    }
+    Ok(())
+}
+
+/// Prints one line per group with its attribute, member count and sizes, then
+/// the totals, as `list` does for files.
+fn print_groups(cpk: &Cpk, groups: &[group::Group], members: bool) {
+    let width = groups
+        .iter()
+        .map(|g| g.name.len())
+        .max()
+        .unwrap_or(0)
+        .clamp(5, 32);
+    let attribute_width = groups
+        .iter()
+        .filter_map(|g| g.attribute.as_ref().map(String::len))
+        .max()
+        .unwrap_or(0)
+        .max(9);
+    println!(
+        "{}",
+        format!(
+            "{:<width$}  {:<attribute_width$}  {:>6}  {:>12}  {:>12}",
+            "Group",
+            "Attribute",
+            "Files",
+            "Stored",
+            "Extracted",
+            width = width,
+            attribute_width = attribute_width
+        )
+        .bold()
+    );
+
+    let mut member_entries = 0;
+    for group in groups {
+        let entries: Vec<&FileEntry> = group.files.iter().filter_map(|f| cpk.find(f)).collect();
+        let stored: u64 = entries.iter().map(|e| e.file_size).sum();
+        let extracted: u64 = entries
+            .iter()
+            .map(|e| e.extract_size.unwrap_or(e.file_size))
+            .sum();
+        member_entries += group.files.len();
+        println!(
+            "{:<width$}  {:<attribute_width$}  {}  {}  {}",
+            group.name,
+            group.attribute.as_deref().unwrap_or("-"),
+            number(format!("{:>6}", group.files.len())),
+            number(format!("{:>12}", stored)),
+            number(format!("{:>12}", extracted)),
+            width = width,
+            attribute_width = attribute_width
+        );
+        if members {
+            for file in &group.files {
+                println!("    {}", file);
+            }
+        }
+    }
+
+    println!();
+    println!(
+        "{} groups, {} members",
+        number(groups.len().to_string()),
+        number(member_entries.to_string())
+    );
+}
+
+/// Prints the layout of an @UTF table: sizes and offsets, then each column
+/// with where it sits in a row, or its value when shared by every row.
+fn print_utf_schema(table: &Utf) {
+    println!("{} {}", label("Table:"), table.name);
+    println!(
+        "{} {} bytes",
+        label("Size:"),
+        number(table.table_size.to_string())
+    );
+    println!("{} {}", label("Rows:"), number(table.num_rows.to_string()));
+    println!("{} {} bytes", label("Row length:"), table.row_length);
+    println!(
+        "{} rows 0x{:X}, strings 0x{:X}, data 0x{:X}",
+        label("Offsets:"),
+        table.rows_offset,
+        table.strings_offset,
+        table.data_offset
+    );
+    println!(
+        "{} {}",
+        label("Columns:"),
+        number(table.columns.len().to_string())
+    );
+    println!();
+
+    println!(
+        "{}",
+        format!(
+            "{:<24}  {:<5}  {:<6}  {:<8}  {:<6}  Constant",
+            "Column", "Flags", "Type", "Storage", "Offset"
+        )
+        .bold()
+    );
+    let mut offset = 0;
+    for column in &table.columns {
+        let position = match column.storage() {
+            0x50 => {
+                offset += column.value_size();
+                format!("0x{:X}", offset - column.value_size())
+            }
+            _ => "-".to_string(),
+        };
+        let constant = column
+            .constant
+            .as_ref()
+            .map_or(String::new(), ToString::to_string);
+        println!(
+            "{:<24}  0x{:02X}   {:<6}  {:<8}  {:<6}  {}",
+            column.name,
+            column.flags,
+            column.type_name(),
+            column.storage_name(),
+            position,
+            constant
+        );
+    }
+    for diagnostic in &table.diagnostics {
+        warn!("{}", diagnostic);
+    }
+}
+
+/// Folder names for several archives: their file stems, numbered when repeated.
+fn archive_stems(inputs: &[PathBuf]) -> Vec<String> {
+    unique_names(inputs.iter().map(|input| {
+        input
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "archive".to_string())
+    }))
+}
+
+/// Numbers names that repeat (ignoring case) so each one is distinct.
+fn unique_names<I: IntoIterator<Item = String>>(names: I) -> Vec<String> {
+    let mut taken = std::collections::HashSet::new();
+    names
+        .into_iter()
+        .map(|name| {
+            (1..)
+                .map(|n| match n {
+                    1 => name.clone(),
+                    n => format!("{}~{}", name, n),
+                })
+                .find(|candidate| taken.insert(candidate.to_lowercase()))
+                .expect("unbounded candidates")
+        })
+        .collect()
+}
+
+/// Finds the `.cpk` files below each of `roots`, along with the folder each one
+/// is extracted to: its path relative to the root without the extension, under
+/// the root's own name when there are several roots.
+fn find_archives(roots: &[PathBuf]) -> Result<(Vec<PathBuf>, Vec<String>)> {
+    let mut archives = Vec::new();
+    let mut folders = Vec::new();
+
+    for root in roots {
+        let mut found = Vec::new();
+        let mut pending = vec![root.clone()];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else if path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("cpk"))
+                {
+                    found.push(path);
+                }
+            }
+        }
+        found.sort();
+
+        for path in found {
+            let relative = path.strip_prefix(root).unwrap_or(&path).with_extension("");
+            let mut folder = PathBuf::new();
+            if roots.len() > 1 {
+                folder.push(root.file_name().unwrap_or(root.as_os_str()));
+            }
+            folder.push(relative);
+            folders.push(folder.to_string_lossy().replace('\\', "/"));
+            archives.push(path);
+        }
+    }
+
+    Ok((archives, unique_names(folders)))
+}
+
+/// Turns the first Ctrl+C into a cancellation request; a second one exits immediately.
+fn cancel_on_interrupt() -> Result<CancellationToken> {
+    use signal_hook::consts::SIGINT;
+
+    let flag = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register_conditional_shutdown(SIGINT, 130, Arc::clone(&flag))?;
+    signal_hook::flag::register(SIGINT, Arc::clone(&flag))?;
+    Ok(flag.into())
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OverwriteMode {
+    /// Replace existing files
+    Always,
+    /// Keep existing files
+    Never,
+    /// Replace existing files older than the archive
+    Newer,
+    /// Ask for each existing file
+    Prompt,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CollisionMode {
+    /// Append ~N to the later file's name
+    Suffix,
+    /// Keep the directory in the name, joined with underscores
+    DirPrefix,
+}
+
+impl CollisionMode {
+    fn to_flat_collision(self) -> FlatCollision {
+        match self {
+            CollisionMode::Suffix => FlatCollision::Suffix,
+            CollisionMode::DirPrefix => FlatCollision::DirPrefix,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DupesMode {
+    /// Extract every entry, appending ~N to the later ones' names
+    Suffix,
+    /// Extract only the first entry with the path
+    First,
+    /// Refuse to extract entries sharing a path
+    Error,
+}
+
+impl DupesMode {
+    fn to_duplicate_policy(self) -> DuplicatePolicy {
+        match self {
+            DupesMode::Suffix => DuplicatePolicy::Suffix,
+            DupesMode::First => DuplicatePolicy::First,
+            DupesMode::Error => DuplicatePolicy::Error,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PathSplitMode {
+    /// DirName is everything up to the last /: a/b/c.bin is a/b + c.bin
+    Parent,
+    /// DirName is the first directory: a/b/c.bin is a + b/c.bin
+    First,
+    /// DirName is empty and FileName holds the whole path
+    None,
+}
+
+impl PathSplitMode {
+    fn to_path_split(self) -> PathSplit {
+        match self {
+            PathSplitMode::Parent => PathSplit::Parent,
+            PathSplitMode::First => PathSplit::FirstComponent,
+            PathSplitMode::None => PathSplit::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum IdMode {
+    /// The lowest free IDs in path order, for new archives
+    Sequential,
+    /// The IDs listed in --id-manifest, for repacks
+    FromManifest,
+    /// Derived from each path, so they don't shift between builds
+    Hash,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EscapeMode {
+    /// %XX for each byte of the offending characters
+    Percent,
+    /// _ in place of the offending characters
+    Underscore,
+    /// Write names unchanged
+    None,
+}
+
+impl EscapeMode {
+    fn to_name_escape(self) -> NameEscape {
+        match self {
+            EscapeMode::Percent => NameEscape::Percent,
+            EscapeMode::Underscore => NameEscape::Underscore,
+            EscapeMode::None => NameEscape::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ProgressFormat {
+    /// One JSON object per line on stderr
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HashArg {
+    Sha256,
+    Crc32,
+}
+
+impl HashArg {
+    fn to_algorithm(self) -> HashAlgorithm {
+        match self {
+            HashArg::Sha256 => HashAlgorithm::Sha256,
+            HashArg::Crc32 => HashAlgorithm::Crc32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TableMode {
+    /// Entries addressed by path (TOC)
+    #[value(alias = "toc")]
+    Filename,
+    /// Entries addressed by ID (ITOC)
+    #[value(alias = "itoc")]
+    Id,
+    /// Both a TOC and an ITOC
+    Both,
+}
+
+impl TableMode {
+    fn to_cpk_mode(self) -> CpkMode {
+        match self {
+            TableMode::Filename => CpkMode::FileName,
+            TableMode::Id => CpkMode::Id,
+            TableMode::Both => CpkMode::FileNameAndId,
+        }
+    }
+}
+
+fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let upper = s.trim().to_uppercase();
+    let digits = upper.trim_end_matches('B');
+    let (number, multiplier) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1u64 << 10),
+        Some('M') => (&digits[..digits.len() - 1], 1u64 << 20),
+        Some('G') => (&digits[..digits.len() - 1], 1u64 << 30),
+        Some('T') => (&digits[..digits.len() - 1], 1u64 << 40),
+        _ => (digits, 1),
+    };
+
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+fn parse_offset(s: &str) -> std::result::Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("invalid offset '{}': {}", s, e))
+}
+
+fn parse_align(s: &str) -> std::result::Result<u16, String> {
+    parse_offset(s)
+        .ok()
+        .and_then(|align| u16::try_from(align).ok())
+        .filter(|align| *align > 0)
+        .ok_or_else(|| format!("invalid alignment '{}'", s))
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// List all files in the CPK archive
     List {
+        /// Input CPK file(s); - reads one from standard input
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Show the archive header: mode, flags, tables and codec fields
+    Info {
+        /// Input CPK file
+        input: PathBuf,
+        /// Also list every header column with its type and value, known or not
+        #[arg(long)]
+        all: bool,
+    },
+    /// List the GTOC's groups with their attributes, member counts and sizes
+    Groups {
+        /// Input CPK file
+        input: PathBuf,
+        /// Also list each group's member paths
+        #[arg(long)]
+        members: bool,
+    },
+    /// Extract a specific file or all files
+    Extract {
+        /// Input CPK file(s) (- for standard input), optionally followed by the file
+        /// to extract (or "#row:N"); everything is extracted when no file is given
+        #[arg(value_name = "INPUT... [TARGET]", required = true)]
+        paths: Vec<PathBuf>,
+        /// Extract the entry at this TOC row (same as "#row:N")
+        #[arg(long)]
+        index: Option<u32>,
+        /// Also write the decrypted header tables (CPK_HDR.utf, TOC_HDR.utf, ...)
+        #[arg(long)]
+        include_headers: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Write a JSON report of every entry processed to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+        /// Skip entries that fail to extract instead of stopping at the first one
+        #[arg(long)]
+        keep_going: bool,
+        /// Treat the inputs as directories and extract every .cpk below them,
+        /// each into a folder mirroring its location
+        #[arg(long)]
+        recurse: bool,
+        /// What to do with files that already exist at the destination
+        #[arg(long, value_enum, default_value = "always")]
+        overwrite: OverwriteMode,
+        /// Leave files that already exist at the destination untouched (--overwrite never)
+        #[arg(long, conflicts_with = "overwrite")]
+        skip_existing: bool,
+        /// With --skip-existing, only skip files whose size already matches
+        #[arg(long, requires = "skip_existing")]
+        same_size: bool,
+        /// CSV of ID -> path (as written by `ids --csv`) naming ID-only entries
+        #[arg(long, value_name = "CSV")]
+        names: Option<PathBuf>,
+        /// Digest every extracted file as it is written
+        #[arg(long, value_enum)]
+        hash: Option<HashArg>,
+        /// Write the digests to this file (sha256sum format) instead of standard output
+        #[arg(long, requires = "hash")]
+        manifest: Option<PathBuf>,
+        /// Also split the ADX/HCA/DDS/@UTF assets embedded in each file into
+        /// a `<file>.carved` folder next to it
+        #[arg(long)]
+        carve: bool,
+        /// Append an extension guessed from the content (.hca, .adx, .usm, .dds, .utf)
+        /// to entries named without one
+        #[arg(long)]
+        auto_ext: bool,
+        /// Write entries exactly as stored, leaving CRILAYLA-compressed ones compressed
+        #[arg(long, conflicts_with_all = ["carve", "auto_ext"])]
+        raw: bool,
+        /// Pipe each file through this shell command before writing it; it reads the
+        /// content on stdin and writes the result to stdout, with CPK_ENTRY_PATH,
+        /// CPK_ENTRY_ID and CPK_ENTRY_SIZE set. Repeatable, run in order
+        #[arg(long, value_name = "COMMAND", conflicts_with = "raw")]
+        exec: Vec<String>,
+        /// Journal progress in the output folder; rerunning with --resume after an
+        /// interruption skips what was extracted and continues large files
+        #[arg(long)]
+        resume: bool,
+        /// Write every file directly into the output folder, ignoring directories
+        #[arg(long)]
+        flat: bool,
+        /// Remove these leading directories (e.g. data/) from the paths that start with them
+        #[arg(long, value_name = "DIR", conflicts_with = "flat")]
+        strip_prefix: Option<String>,
+        /// With --flat, how to name files whose names clash
+        #[arg(long, value_enum, default_value = "suffix", requires = "flat")]
+        on_collision: CollisionMode,
+        /// What to do with entries whose directory and file name an earlier entry
+        /// already uses
+        #[arg(long, value_enum, default_value = "suffix")]
+        dupes: DupesMode,
+        /// How to write names Windows can't hold (reserved characters, trailing
+        /// dots, device names); the originals are kept in .cpk-names for pack
+        /// [default: percent on Windows, none elsewhere]
+        #[arg(long, value_enum)]
+        escape: Option<EscapeMode>,
+        /// Report each entry as it starts and finishes, for frontends wrapping this tool
+        #[arg(long, value_enum)]
+        progress: Option<ProgressFormat>,
+    },
+    /// Replace a file in the CPK archive
+    Replace {
+        /// Input CPK file
+        input: PathBuf,
+        /// File to replace ("#row:N" and "#id:N" address table rows); omit with --id
+        #[arg(required_unless_present_any = ["id", "map"])]
+        target: Option<String>,
+        /// Replacement file
+        replacement: Option<PathBuf>,
+        /// Replace the entry with this ID (for ITOC-only archives)
+        #[arg(long, conflicts_with = "map")]
+        id: Option<u32>,
+        /// CSV or JSON mapping of archive path (or ID) to replacement file
+        #[arg(long, conflicts_with_all = ["target", "replacement"])]
+        map: Option<PathBuf>,
+        /// Output CPK file (optional, defaults to modifying input)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Append the new data after the archive and patch only the TOC cells,
+        /// leaving every other entry where it was (TOC-only archives)
+        #[arg(long)]
+        append: bool,
+        #[command(flatten)]
+        compression: CompressArgs,
+    },
+    /// Set the UserString of the TOC entries matching a pattern
+    SetMeta {
+        /// Input CPK file
+        input: PathBuf,
+        /// Wildcard pattern (*, ?, **) selecting the entries; repeatable
+        #[arg(long = "match", required = true)]
+        patterns: Vec<String>,
+        /// New UserString value
+        #[arg(long)]
+        user_string: String,
+        /// Output CPK file (optional, defaults to modifying input)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Move an entry to another path, leaving its data as is
+    Rename {
+        /// Input CPK file
+        input: PathBuf,
+        /// Current path of the entry
+        from: String,
+        /// New path of the entry
+        to: String,
+        /// Output CPK file (optional, defaults to modifying input)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Reassign entry IDs across the TOC and ITOC
+    Renumber {
+        /// Input CPK file
+        input: PathBuf,
+        /// First ID when numbering sequentially in table order
+        #[arg(long, default_value = "0", conflicts_with = "map")]
+        start: u32,
+        /// CSV of old,new IDs; IDs not listed are kept
+        #[arg(long)]
+        map: Option<PathBuf>,
+        /// Output CPK file (optional, defaults to modifying input)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Build an archive from the files below a directory
+    Pack {
+        /// Directory to pack; a .cpkignore in it lists files to leave out,
+        /// gitignore-style
+        input: PathBuf,
+        /// CPK file to write
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Boundary every entry starts on (decimal or 0x-prefixed hex)
+        #[arg(long, value_parser = parse_align, default_value = "0x800")]
+        align: u16,
+        /// Align entries to 0x800-byte sectors and pad the archive to whole
+        /// sectors, for archives streamed from disc images
+        #[arg(long, conflicts_with = "align")]
+        disc_image: bool,
+        /// Encrypt the header and TOC tables
+        #[arg(long)]
+        encrypt_tables: bool,
+        /// Add a CRC column with each file's CRC-32 (implied by a template that has one)
+        #[arg(long)]
+        crc: bool,
+        /// Tables to write
+        #[arg(long, value_enum, default_value_t = TableMode::Filename)]
+        mode: TableMode,
+        /// Take the table mode from this archive instead
+        #[arg(long, value_name = "CPK", conflicts_with = "mode")]
+        template: Option<PathBuf>,
+        /// JSON of group name -> archive paths to list in a GTOC
+        #[arg(long, value_name = "JSON")]
+        groups: Option<PathBuf>,
+        /// Where paths are cut into the TOC's DirName and FileName
+        #[arg(long, value_enum, default_value = "parent")]
+        path_split: PathSplitMode,
+        /// How entries are numbered
+        #[arg(long, value_enum, default_value = "sequential")]
+        ids: IdMode,
+        /// With --ids from-manifest, the ID -> path CSV (as written by `ids --csv`)
+        /// of the archive being rebuilt
+        #[arg(long, value_name = "CSV", required_if_eq("ids", "from-manifest"))]
+        id_manifest: Option<PathBuf>,
+        #[command(flatten)]
+        compression: CompressArgs,
+        /// Keep compressed files in OUTPUT.pack/ as they're done, so rerunning with
+        /// --resume after an interruption only compresses what's left
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Combine a patch archive with a base archive into a single archive
+    Merge {
+        /// Base CPK file
+        base: PathBuf,
+        /// Patch CPK file; its entries replace base entries with the same path, or
+        /// the same ID when either archive has no TOC. --offset applies to both
+        patch: PathBuf,
+        /// Merged CPK file to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Fabricate an archive with controlled properties, for reproducing bugs
+    GenTest {
+        /// CPK file to write
+        output: PathBuf,
+        /// Number of entries
+        #[arg(long, default_value_t = 100)]
+        files: usize,
+        /// Tables to write
+        #[arg(long, value_enum, default_value_t = TableMode::Filename)]
+        mode: TableMode,
+        /// Store the text entries CRILAYLA-compressed
+        #[arg(long)]
+        compress: bool,
+        /// Encrypt the header and TOC tables
+        #[arg(long)]
+        encrypt: bool,
+        /// Largest entry size (e.g. 16K)
+        #[arg(long, value_parser = parse_size, default_value = "16K")]
+        max_size: u64,
+        /// Seed of the generated content; the same seed gives the same archive
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Catalog every entry of every CPK below the given directories into a SQLite database
+    #[cfg(feature = "sqlite")]
+    Index {
+        /// Directories searched for .cpk files
+        #[arg(required = true)]
+        roots: Vec<PathBuf>,
+        /// Database to write; archives indexed before are updated in place
+        #[arg(long)]
+        db: PathBuf,
+        /// Also record each entry's SHA-256 (reads and decompresses everything)
+        #[arg(long)]
+        hash: bool,
+    },
+    /// Find which indexed archives hold a file
+    #[cfg(feature = "sqlite")]
+    Query {
+        /// Database written by `index`
+        #[arg(long)]
+        db: PathBuf,
+        /// Path or file name (*, ? wildcards), or a SHA-256
+        pattern: String,
+    },
+    /// Write a patch archive holding only the files that differ from a base archive
+    Delta {
+        /// Base CPK file
+        base: PathBuf,
+        /// Directory of modified files laid out like the archive
+        #[arg(required_unless_present = "map")]
+        input: Option<PathBuf>,
+        /// CSV or JSON mapping of archive path (or ID) to replacement file
+        #[arg(long, conflicts_with = "input")]
+        map: Option<PathBuf>,
+        /// Delta CPK file to write
+        #[arg(short, long)]
+        output: PathBuf,
+        #[command(flatten)]
+        compression: CompressArgs,
+        /// Remember the digests of the base's entries, so comparing against it
+        /// again while it's unchanged needs no decompression, in PATH
+        /// [default: ~/.cache/cpk-tools/verify.sqlite]
+        #[cfg(feature = "sqlite")]
+        #[arg(long, value_name = "PATH", num_args = 0..=1, require_equals = true)]
+        cache: Option<Option<PathBuf>>,
+    },
+    /// Split the archive into volumes no larger than a given size
+    Split {
+        /// Input CPK file
+        input: PathBuf,
+        /// Largest volume to write (e.g. 4G, 700M)
+        #[arg(long, value_parser = parse_size)]
+        max_size: u64,
+        /// Volume name prefix (defaults to the input path without its extension)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Measure decompression speed on a sample of compressed entries
+    Bench {
+        /// Input CPK file
+        input: PathBuf,
+        /// Number of entries to sample
+        #[arg(long, default_value_t = 32)]
+        sample: usize,
+        /// Times each decoder runs over the sample
+        #[arg(long, default_value_t = 5)]
+        iterations: usize,
+    },
+    /// List every entry ID with its path, size and offset
+    Ids {
         /// Input CPK file
         input: PathBuf,
+        /// Write CSV (id,path,size,offset) instead of a table
+        #[arg(long)]
+        csv: bool,
+    },
+    /// Print the physical offset map of the archive
+    Layout {
+        /// Input CPK file
+        input: PathBuf,
+    },
+    /// Digest the content of every entry, in sha256sum format
+    Hash {
+        /// Input CPK file
+        input: PathBuf,
+        #[arg(long, value_enum, default_value = "sha256")]
+        algorithm: HashArg,
+        /// Entries decompressed and digested at once [default: the number of CPUs]
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Write the digests to this file instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Check the archive for structural problems
+    Verify {
+        /// Input CPK file
+        input: PathBuf,
+        /// Also decompress every entry and check it against its ExtractSize
+        #[arg(long)]
+        deep: bool,
+        /// Write a JSON report of the checks to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+        /// With --deep, remember the results so checking the archive again while
+        /// it's unchanged is quick, in PATH [default: ~/.cache/cpk-tools/verify.sqlite]
+        #[cfg(feature = "sqlite")]
+        #[arg(long, value_name = "PATH", num_args = 0..=1, require_equals = true, requires = "deep")]
+        cache: Option<Option<PathBuf>>,
+    },
+    /// Search the contents of archive entries for a string
+    Grep {
+        /// Input CPK file
+        input: PathBuf,
+        /// Text to search for
+        pattern: String,
+        /// Match ASCII letters case-insensitively
+        #[arg(short, long)]
+        ignore_case: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Print a hex+ASCII dump of an entry's contents
+    Hexdump {
+        /// Input CPK file
+        input: PathBuf,
+        /// Entry to dump ("#row:N" and "#id:N" address table rows)
+        target: String,
+        /// First byte of the entry to show (decimal or 0x-prefixed hex)
+        #[arg(short, long, default_value = "0", value_parser = parse_offset)]
+        start: u64,
+        /// Number of bytes to show (defaults to the rest of the entry)
+        #[arg(short = 'n', long, value_parser = parse_size)]
+        length: Option<u64>,
+        /// Dump the bytes as stored instead of decompressing them
+        #[arg(long)]
+        raw: bool,
+    },
+    /// List the cues of an ACB sound bank and the waveforms they play
+    Acb {
+        /// ACB file, or a CPK archive containing it
+        input: PathBuf,
+        /// Entry to read from the archive when the input is a CPK
+        entry: Option<String>,
+    },
+    /// Work with legacy AFS containers
+    Afs {
+        #[command(subcommand)]
+        command: AfsCommands,
+    },
+    /// Inspect @UTF tables
+    Utf {
+        #[command(subcommand)]
+        command: UtfCommands,
+    },
+    /// Package replacement files for distribution, or apply such a package
+    Mod {
+        #[command(subcommand)]
+        command: ModCommands,
+    },
+    /// Compress or decompress loose CRILAYLA files
+    Crilayla {
+        #[command(subcommand)]
+        command: CrilaylaCommands,
+    },
+    /// Search a file for embedded CPK archives
+    Scan {
+        /// File to search (executable, disc image, container...)
+        input: PathBuf,
+        /// Write each archive found to this directory as a standalone .cpk
+        #[arg(short, long)]
+        extract: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AfsCommands {
+    /// List all files in the AFS container
+    List {
+        /// Input AFS file
+        input: PathBuf,
     },
     /// Extract a specific file or all files
     Extract {
+        /// Input AFS file
+        input: PathBuf,
+        /// File to extract, or "all" for all files
+        target: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ModCommands {
+    /// Write a package of the files in a folder that differ from their entries,
+    /// with a manifest of their digests and the archive they apply to
+    Export {
+        /// CPK file the package is made against
+        input: PathBuf,
+        /// Folder of replacement files, laid out by archive path
+        replacements: PathBuf,
+        /// Package folder to write
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Check a package against its manifest and the archive, then replace the
+    /// entries it holds
+    Apply {
+        /// Package folder
+        package: PathBuf,
+        /// CPK file to modify
+        input: PathBuf,
+        /// Output CPK file (optional, defaults to modifying input)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Replace entries even when they hold something other than what the
+        /// package was made against
+        #[arg(long)]
+        force: bool,
+        #[command(flatten)]
+        compression: CompressArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum UtfCommands {
+    /// Print a table's name, size, row layout and columns
+    Inspect {
+        /// Raw @UTF file (encrypted or not), or a CPK archive
+        input: PathBuf,
+        /// With a CPK, the table (CPK_HDR, TOC_HDR, ITOC_HDR, ETOC_HDR, GTOC_HDR)
+        /// or the entry holding it
+        table: Option<String>,
+    },
+    /// Change one cell of an archive's table, rewriting and re-encrypting it
+    Set {
         /// Input CPK file
         input: PathBuf,
-        /// File to extract (or "all" for all files)
-        target: String,
+        /// Table holding the cell: CPK, TOC, ITOC, GTOC or ETOC
+        #[arg(long)]
+        table: String,
+        /// Row index, from 0
+        #[arg(long)]
+        row: usize,
+        /// Column name
+        #[arg(long)]
+        column: String,
+        /// New value, parsed for the column's type; data is given in hex.
+        /// Sizes and offsets can't be set, the archive's layout decides them
+        #[arg(long, allow_hyphen_values = true)]
+        value: String,
+        /// Output CPK file (optional, defaults to modifying input)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CrilaylaCommands {
+    /// Compress a file into a CRILAYLA stream
+    Compress {
+        /// File to compress (at least 256 bytes)
+        input: PathBuf,
+        /// Output file (defaults to the input with `.crilayla` appended)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
     },
-    /// Replace a file in the CPK archive
-    Replace {
-        /// Input CPK file
+    /// Decompress a CRILAYLA stream
+    Decompress {
+        /// CRILAYLA-compressed file
         input: PathBuf,
-        /// File to replace
-        target: String,
-        /// Replacement file
-        replacement: PathBuf,
-        /// Output CPK file (optional, defaults to modifying input)
+        /// Output file (defaults to the input without its `.crilayla` extension,
+        /// or with `.dec` appended)
         #[arg(short, long)]
         output: Option<PathBuf>,
     },
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Whether what's printed to stderr is coloured; `colored` only looks at stdout.
+static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
+
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0    success
+  1    fatal error, nothing or only part of the work was done
+  2    finished, but some entries or archives failed
+  3    verify found the archive damaged
+  4    no entry matched the target or query
+  64   invalid arguments
+  130  interrupted";
+
+/// Exit statuses other than 0 (success) and 1 (any other error), see `EXIT_STATUS_HELP`.
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    PartialFailure = 2,
+    VerifyMismatch = 3,
+    NotFound = 4,
+    Usage = 64,
+    Cancelled = 130,
+}
+
+/// An error that ends the run with a specific exit status.
+#[derive(Debug)]
+struct Exit {
+    outcome: Outcome,
+    message: String,
+}
+
+impl std::fmt::Display for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+fn fail(outcome: Outcome, message: String) -> anyhow::Error {
+    Exit { outcome, message }.into()
+}
+
+fn exit_status(error: &anyhow::Error) -> u8 {
+    if let Some(exit) = error.downcast_ref::<Exit>() {
+        return exit.outcome as u8;
+    }
+    let outcome = match error.downcast_ref::<CpkError>() {
+        Some(CpkError::EntriesFailed(_)) => Outcome::PartialFailure,
+        Some(CpkError::FileNotFound(_)) => Outcome::NotFound,
+        Some(CpkError::Cancelled) => Outcome::Cancelled,
+        _ => return 1,
+    };
+    outcome as u8
+}
+
+fn main() -> ExitCode {
+    let status = match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            debug!("Error: {:?}", e);
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit_status(&e))
+        }
+    };
+    log::logger().flush();
+    status
+}
+
+/// Sends records to the console logger as RUST_LOG configures it, and every
+/// record up to debug level to the log file, if any.
+struct TeeLogger {
+    console: env_logger::Logger,
+    file: Option<std::sync::Mutex<std::io::BufWriter<std::fs::File>>>,
+    start: std::time::Instant,
+}
+
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.console.enabled(metadata)
+            || (self.file.is_some() && metadata.level() <= log::Level::Debug)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.console.matches(record) {
+            self.console.log(record);
+        }
+        if let Some(file) = &self.file
+            && record.level() <= log::Level::Debug
+            && let Ok(mut file) = file.lock()
+        {
+            use std::io::Write;
+            let _ = writeln!(
+                file,
+                "{:>10.3} {:<5} {}: {}",
+                self.start.elapsed().as_secs_f64(),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = &self.file
+            && let Ok(mut file) = file.lock()
+        {
+            use std::io::Write;
+            let _ = file.flush();
+        }
+    }
+}
+
+fn init_logging(log_file: Option<&Path>) -> Result<()> {
+    let console = env_logger::Builder::from_default_env().build();
+    let mut max_level = console.filter();
+    let file = match log_file {
+        Some(path) => {
+            max_level = max_level.max(log::LevelFilter::Debug);
+            let file = std::fs::File::create(path)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            Some(std::sync::Mutex::new(std::io::BufWriter::new(file)))
+        }
+        None => None,
+    };
+    log::set_boxed_logger(Box::new(TeeLogger {
+        console,
+        file,
+        start: std::time::Instant::now(),
+    }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
 
-    println!("CriPakTools (Rust Edition)\n");
+fn run() -> Result<()> {
+    // Kept off stdout so CSV output can be piped
+    eprintln!("CriPakTools (Rust Edition)\n");
 
-    let cli = Cli::parse();
+    let mut cli = match Cli::try_parse_from(compat_args(std::env::args_os().collect())) {
+        Ok(cli) => cli,
+        // --help and --version
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            let _ = e.print();
+            std::process::exit(Outcome::Usage as i32);
+        }
+    };
+    init_logging(cli.log_file.as_deref())?;
+    debug!("{:?}", std::env::args_os().collect::<Vec<_>>());
+    let _spool = StdinSpool::for_command(&mut cli.command)?;
+    let cancel = cancel_on_interrupt()?;
+    let plain = cli.no_color || std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    if plain || !std::io::stdout().is_terminal() {
+        colored::control::set_override(false);
+    }
+    STDERR_COLOR.store(!plain && std::io::stderr().is_terminal(), Ordering::Relaxed);
 
     match &cli.command {
-        Commands::List { input } => {
-            let mut cpk = Cpk::new();
-            cpk.read_cpk(input)?;
-
-            for entry in &cpk.file_table {
-                if entry.file_type == "FILE" {
-                    let full_path = match (&entry.dir_name, &entry.file_name) {
-                        (Some(dir), file_name) => format!("{}/{}", dir, file_name),
-                        (None, file_name) => file_name.clone(),
-                    };
-                    println!("{}", full_path);
+        Commands::List { inputs, filter } => {
+            let null = filter.null;
+            let filter = filter.to_filter()?;
+            let stems = (inputs.len() > 1).then(|| archive_stems(inputs));
+            let mut warnings = Vec::new();
+
+            for (i, input) in inputs.iter().enumerate() {
+                let mut cpk = open_cpk(&cli);
+                read_input(&cli, &mut cpk, input)?;
+                let (prefix, label) = match &stems {
+                    Some(stems) => (format!("{}/", stems[i]), format!("{}: ", stems[i])),
+                    None => (String::new(), String::new()),
+                };
+                warnings.extend(diagnostic_warnings(&cpk, &label));
+
+                // (files, stored bytes, extracted bytes) per TOC
+                let mut totals: BTreeMap<&str, (u64, u64, u64)> = BTreeMap::new();
+
+                for entry in &cpk.file_table {
+                    if entry.file_type == "FILE" && filter.matches(entry) {
+                        match null {
+                            true => print!("{}{}\0", prefix, entry.full_path()),
+                            false => println!("{}{}", prefix, entry.full_path()),
+                        }
+
+                        let total = totals.entry(&entry.toc_name).or_default();
+                        total.0 += 1;
+                        total.1 += entry.file_size;
+                        total.2 += entry.extract_size.unwrap_or(entry.file_size);
+                    }
+                }
+
+                // Nothing but the paths when they're meant for another program
+                if null {
+                    continue;
+                }
+                let (files, stored, extracted) = totals
+                    .values()
+                    .fold((0, 0, 0), |acc, t| (acc.0 + t.0, acc.1 + t.1, acc.2 + t.2));
+
+                println!();
+                println!(
+                    "{}{} files, {} bytes stored, {} bytes extracted",
+                    label,
+                    number(files.to_string()),
+                    number(stored.to_string()),
+                    number(extracted.to_string())
+                );
+                if totals.len() > 1 {
+                    // Padded to the grand totals so the breakdown lines up
+                    let width = |total: u64| total.to_string().len();
+                    for (toc_name, (toc_files, toc_stored, toc_extracted)) in &totals {
+                        println!(
+                            "  {:<5} {} files, {} bytes stored, {} bytes extracted",
+                            format!("{}:", toc_name),
+                            number(format!("{:>1$}", toc_files, width(files))),
+                            number(format!("{:>1$}", toc_stored, width(stored))),
+                            number(format!("{:>1$}", toc_extracted, width(extracted)))
+                        );
+                    }
                 }
+                if i + 1 < inputs.len() {
+                    println!();
+                }
+            }
+            print_warnings(&warnings);
+        }
+
+        Commands::Info { input, all } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+            print_info(&cpk);
+            if *all {
+                println!();
+                print_header_columns(&cpk)?;
             }
+            print_warnings(&diagnostic_warnings(&cpk, ""));
         }
 
-        Commands::Extract { input, target } => {
-            let mut cpk = Cpk::new();
-            cpk.read_cpk(input)?;
+        Commands::Groups { input, members } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+            let Some(groups) = group::archive_groups(&cpk)? else {
+                anyhow::bail!("{} has no GTOC", input.display());
+            };
+            print_groups(&cpk, &groups, *members);
+            print_warnings(&diagnostic_warnings(&cpk, ""));
+        }
 
-            if target.to_lowercase() == "all" {
-                info!("Extracting all files...");
-                cpk.extract_all(input)?;
+        Commands::Extract {
+            paths,
+            index,
+            include_headers,
+            filter,
+            report,
+            keep_going,
+            recurse,
+            overwrite,
+            skip_existing,
+            same_size,
+            names,
+            hash,
+            manifest,
+            carve,
+            auto_ext,
+            raw,
+            exec,
+            resume,
+            flat,
+            strip_prefix,
+            on_collision,
+            dupes,
+            escape,
+            progress,
+        } => {
+            // A trailing argument that isn't an archive names the entry to extract,
+            // and with several archives each one goes to a folder named after it
+            let (inputs, folders, target) = if *recurse {
+                let (archives, folders) = find_archives(paths)?;
+                if archives.is_empty() {
+                    anyhow::bail!("no .cpk files found");
+                }
+                (archives, Some(folders), None)
             } else {
-                info!("Extracting: {}", target);
-                cpk.extract_file(input, target)?;
+                let (inputs, target) = match paths.split_last() {
+                    Some((last, rest)) if !rest.is_empty() && !is_cpk(last, cli.offset) => {
+                        (rest, Some(last.to_string_lossy().into_owned()))
+                    }
+                    _ => (&paths[..], None),
+                };
+                let folders = (inputs.len() > 1).then(|| archive_stems(inputs));
+                (inputs.to_vec(), folders, target)
+            };
+
+            let entry_filter = filter.to_filter()?;
+
+            // "all" predates the optional target and is kept for existing scripts
+            let target = match (target, index) {
+                (_, Some(row)) => Some(format!("#row:{}", row)),
+                (Some(target), None) if target.eq_ignore_ascii_case("all") => {
+                    warn!("\"all\" is deprecated, omit the target to extract everything");
+                    None
+                }
+                (target, None) => target,
+            };
+            let existing = match (skip_existing, same_size, overwrite) {
+                (true, true, _) => ExistingPolicy::SkipSameSize,
+                (true, false, _) | (false, _, OverwriteMode::Never) => ExistingPolicy::Skip,
+                (false, _, OverwriteMode::Always) => ExistingPolicy::Overwrite,
+                (false, _, OverwriteMode::Newer) => ExistingPolicy::OverwriteOlder,
+                (false, _, OverwriteMode::Prompt) => ExistingPolicy::Prompt,
+            };
+            let names = names.as_ref().map(mapping::read_names).transpose()?;
+            let mut run_report = RunReport::new("extract", &inputs[0]);
+            let mut extracted = 0;
+            let mut failed = 0;
+            // Whether any archive held an entry the target and filter match
+            let mut matched = false;
+            let mut failures = Vec::new();
+            let mut digests = Vec::new();
+            let mut warnings = Vec::new();
+            let mut result = Ok(());
+
+            for (i, input) in inputs.iter().enumerate() {
+                if i > 0 {
+                    run_report.add_archive(input);
+                }
+                let mut cpk = open_cpk(&cli);
+                if let Err(e) = read_input(&cli, &mut cpk, input) {
+                    if !*keep_going {
+                        return Err(e.into());
+                    }
+                    warn!("Skipping {}: {}", input.display(), e);
+                    failures.push(Failure {
+                        entry: input.display().to_string(),
+                        kind: e.kind(),
+                        offset: None,
+                        message: e.to_string(),
+                    });
+                    failed += 1;
+                    continue;
+                }
+                if let Some(names) = &names {
+                    let renamed = cpk.apply_names(names);
+                    debug!("{}: named {} entries", input.display(), renamed);
+                }
+                cpk.set_cancellation(cancel.clone());
+                cpk.set_keep_going(*keep_going);
+                cpk.set_existing_policy(existing);
+                cpk.set_hash(hash.map(HashArg::to_algorithm));
+                cpk.set_carve(*carve);
+                cpk.set_auto_extension(*auto_ext);
+                cpk.set_raw(*raw);
+                cpk.set_resume(*resume);
+                for command in exec {
+                    cpk.add_processor(CommandProcessor::new(command));
+                }
+                cpk.set_flat(flat.then(|| on_collision.to_flat_collision()));
+                cpk.set_duplicates(dupes.to_duplicate_policy());
+                cpk.set_name_escape(
+                    escape.map_or_else(NameEscape::default, EscapeMode::to_name_escape),
+                );
+                cpk.set_strip_prefix(strip_prefix.as_deref());
+                let prefix = match &folders {
+                    Some(folders) => {
+                        cpk.set_output_dir(&folders[i]);
+                        format!("{}/", folders[i])
+                    }
+                    None => String::new(),
+                };
+                for diagnostic in cpk.diagnostics() {
+                    run_report.add_issue("diagnostic", diagnostic.to_string());
+                }
+                warnings.extend(diagnostic_warnings(&cpk, &prefix));
+
+                if *include_headers {
+                    info!("Extracting header tables...");
+                    cpk.extract_headers()?;
+                }
+
+                let mut done_bytes = 0;
+                let mut archive_bytes = 0;
+                let record = |event: ExtractEvent| {
+                    let r = match event {
+                        ExtractEvent::Started {
+                            entry,
+                            index,
+                            total,
+                            total_bytes,
+                        } => {
+                            archive_bytes = total_bytes;
+                            if progress.is_some() {
+                                eprintln!(
+                                    "{}",
+                                    json!({
+                                        "event": "entry-started",
+                                        "archive": input.display().to_string(),
+                                        "entry": format!("{}{}", prefix, entry.full_path()),
+                                        "index": index,
+                                        "total": total,
+                                        "size": entry.extract_size.unwrap_or(entry.file_size),
+                                    })
+                                );
+                            }
+                            return;
+                        }
+                        ExtractEvent::Finished(r) => r,
+                    };
+                    done_bytes += r.entry.extract_size.unwrap_or(r.entry.file_size);
+                    if progress.is_some() {
+                        let status = match (&r.error, r.written) {
+                            (Some(_), _) => "error",
+                            (None, Some(_)) => "ok",
+                            (None, None) => "skipped",
+                        };
+                        let percent = match archive_bytes {
+                            0 => 100.0,
+                            total => done_bytes as f64 * 100.0 / total as f64,
+                        };
+                        eprintln!(
+                            "{}",
+                            json!({
+                                "event": "entry-finished",
+                                "archive": input.display().to_string(),
+                                "entry": format!("{}{}", prefix, r.entry.full_path()),
+                                "status": status,
+                                "written": r.written,
+                                "done_bytes": done_bytes,
+                                "total_bytes": archive_bytes,
+                                "percent": (percent * 10.0).round() / 10.0,
+                            })
+                        );
+                    }
+                    if r.written.is_some() {
+                        extracted += 1;
+                    }
+                    if let Some(error) = &r.error {
+                        failures.push(Failure {
+                            entry: format!("{}{}", prefix, r.entry.full_path()),
+                            kind: r.error_kind.unwrap_or("error"),
+                            offset: Some(r.entry.file_offset),
+                            message: error.clone(),
+                        });
+                    }
+                    for warning in &r.warnings {
+                        warnings.push(format!("{}{}: {}", prefix, r.entry.full_path(), warning));
+                    }
+                    if let (Some(digest), Some(path)) = (&r.digest, &r.output_path) {
+                        digests.push((path.display().to_string(), digest.clone()));
+                    }
+                    run_report.add_extracted(&r);
+                };
+                let archive_result = match &target {
+                    None => {
+                        info!("Extracting all files from {}...", input.display());
+                        cpk.extract_all_with(input, &entry_filter, record)
+                    }
+                    Some(target) => {
+                        info!("Extracting: {}", target);
+                        cpk.extract_file_with(input, target, &entry_filter, record)
+                    }
+                };
+
+                match archive_result {
+                    Err(CpkError::EntriesFailed(n)) => {
+                        matched = true;
+                        failed += n;
+                    }
+                    // Only some of several archives are expected to hold the target
+                    Err(CpkError::FileNotFound(_)) if folders.is_some() => {
+                        debug!("{} has no matching entry", input.display());
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                    Ok(()) => matched = true,
+                }
+            }
+            if result.is_ok() && !matched && failed == 0 && folders.is_some() {
+                let what = target.unwrap_or_else(|| "no entry matches the filter".to_string());
+                result = Err(CpkError::FileNotFound(what));
+            }
+
+            if let Some(path) = report {
+                run_report.write(path, result.is_ok() && failed == 0)?;
+            }
+            if hash.is_some() {
+                digests.sort();
+                let lines: String = digests
+                    .iter()
+                    .map(|(path, digest)| format!("{}  {}\n", digest, path))
+                    .collect();
+                match manifest {
+                    Some(path) => std::fs::write(path, lines)?,
+                    None => print!("{}", lines),
+                }
+            }
+            print_warnings(&warnings);
+            print_failures(&failures);
+            match result {
+                Err(CpkError::Cancelled) => {
+                    return Err(fail(
+                        Outcome::Cancelled,
+                        format!("cancelled after extracting {} file(s)", extracted),
+                    ));
+                }
+                result => result?,
+            }
+            if failed > 0 {
+                return Err(fail(
+                    Outcome::PartialFailure,
+                    format!("extracted {} file(s), {} failed", extracted, failed),
+                ));
             }
         }
 
@@ -86,19 +2022,889 @@ fn main() -> Result<()> {
             input,
             target,
             replacement,
+            id,
+            map,
+            output,
+            append,
+            compression,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_for_update(&cli, &mut cpk, input)?;
+            cpk.set_cancellation(cancel.clone());
+            let output_path = output.as_ref().unwrap_or(input);
+            let compression = compression.to_policy();
+
+            let replacements = if let Some(map) = map {
+                let replacements = mapping::read_mapping(map)?;
+                info!(
+                    "Replacing {} file(s) in {}",
+                    replacements.len(),
+                    output_path.display()
+                );
+                replacements
+            } else {
+                // With --id the only positional after the input is the replacement
+                let (target, replacement) = match (id, target, replacement) {
+                    (Some(id), Some(replacement), None) => {
+                        (format!("#id:{}", id), PathBuf::from(replacement))
+                    }
+                    (None, Some(target), Some(replacement)) => {
+                        (target.clone(), replacement.clone())
+                    }
+                    _ => {
+                        return Err(fail(
+                            Outcome::Usage,
+                            "expected either <TARGET> <REPLACEMENT> or --id <ID> <REPLACEMENT>"
+                                .to_string(),
+                        ));
+                    }
+                };
+
+                info!(
+                    "Replacing {} with {} in {}",
+                    target,
+                    replacement.display(),
+                    output_path.display()
+                );
+                vec![(target, replacement)]
+            };
+
+            if *append {
+                cpk.append_files(input, &replacements, output_path, &compression)?;
+            } else {
+                cpk.replace_files(input, &replacements, output_path, &compression)?;
+            }
+        }
+
+        Commands::SetMeta {
+            input,
+            patterns,
+            user_string,
+            output,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_for_update(&cli, &mut cpk, input)?;
+            cpk.set_cancellation(cancel.clone());
+            let output_path = output.as_ref().unwrap_or(input);
+
+            let filter = EntryFilter {
+                patterns: patterns.iter().map(|p| p.replace('\\', "/")).collect(),
+                ..EntryFilter::default()
+            };
+            let changed = cpk.set_user_strings(input, &filter, user_string, output_path)?;
+            println!("{}: {} entries updated", output_path.display(), changed);
+        }
+
+        Commands::Rename {
+            input,
+            from,
+            to,
             output,
         } => {
-            let mut cpk = Cpk::new();
-            cpk.read_cpk(input)?;
+            let mut cpk = open_cpk(&cli);
+            read_for_update(&cli, &mut cpk, input)?;
+            cpk.set_cancellation(cancel.clone());
+            let output_path = output.as_ref().unwrap_or(input);
+
+            cpk.rename_entry(input, from, to, output_path)?;
+            println!("{}: {} -> {}", output_path.display(), from, to);
+        }
 
+        Commands::Renumber {
+            input,
+            start,
+            map,
+            output,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_for_update(&cli, &mut cpk, input)?;
             let output_path = output.as_ref().unwrap_or(input);
-            info!(
-                "Replacing {} with {} in {}",
-                target,
-                replacement.display(),
-                output_path.display()
+
+            let numbering = match map {
+                Some(path) => renumber::Numbering::Mapped(renumber::read_id_map(path)?),
+                None => renumber::Numbering::Sequential(*start),
+            };
+            let changed = renumber::renumber(&cpk, output_path, &numbering, &cancel)?;
+            println!("{}: {} IDs changed", output_path.display(), changed);
+        }
+
+        Commands::Pack {
+            input,
+            output,
+            align,
+            disc_image,
+            encrypt_tables,
+            crc,
+            mode,
+            template,
+            groups,
+            path_split,
+            ids,
+            id_manifest,
+            compression,
+            resume,
+        } => {
+            let (mode, crc) = match template {
+                Some(template) => {
+                    let mut cpk = open_cpk(&cli);
+                    read_input(&cli, &mut cpk, template)?;
+                    print_warnings(&diagnostic_warnings(
+                        &cpk,
+                        &format!("{}: ", template.display()),
+                    ));
+                    let mode = cpk.cpk_mode().ok_or_else(|| {
+                        anyhow::anyhow!("{} has no entry tables", template.display())
+                    })?;
+                    info!("Using {:?} from {}", mode, template.display());
+                    (mode, *crc || cpk.has_crc())
+                }
+                None => (mode.to_cpk_mode(), *crc),
+            };
+            let mut builder = CpkBuilder::new()
+                .mode(mode)
+                .align(*align)
+                .encrypt_tables(*encrypt_tables)
+                .crc(crc)
+                .path_split(path_split.to_path_split())
+                .ids(match (ids, id_manifest) {
+                    (IdMode::FromManifest, Some(manifest)) => IdStrategy::Mapped(
+                        mapping::read_names(manifest)?
+                            .into_iter()
+                            .map(|(id, path)| (path, id))
+                            .collect(),
+                    ),
+                    (IdMode::Hash, _) => IdStrategy::Hash,
+                    _ => IdStrategy::Sequential,
+                })
+                .compression(compression.to_policy())
+                .resumable(*resume)
+                .cancellation(cancel.clone());
+            if *disc_image {
+                builder = builder.disc_image();
+            }
+            if let Some(groups) = groups {
+                builder = builder.groups(group::read_groups(groups)?);
+            }
+            let builder = builder.add_dir(input)?;
+            let files = builder.len();
+            builder.write(output)?;
+            println!("{}: {} files", output.display(), files);
+        }
+
+        Commands::Merge {
+            base,
+            patch,
+            output,
+        } => {
+            let mut base_cpk = open_cpk(&cli);
+            read_input(&cli, &mut base_cpk, base)?;
+            let mut patch_cpk = open_cpk(&cli);
+            read_input(&cli, &mut patch_cpk, patch)?;
+
+            let summary = merge::merge(&base_cpk, &patch_cpk, output, &cancel)?;
+            println!(
+                "{}: {} kept, {} replaced, {} added",
+                output.display(),
+                summary.kept,
+                summary.replaced,
+                summary.added
+            );
+        }
+
+        Commands::GenTest {
+            output,
+            files,
+            mode,
+            compress,
+            encrypt,
+            max_size,
+            seed,
+        } => {
+            let archive = TestArchive {
+                files: *files,
+                mode: mode.to_cpk_mode(),
+                compress: *compress,
+                encrypt: *encrypt,
+                max_size: *max_size as usize,
+                seed: *seed,
+            };
+            archive
+                .builder()
+                .cancellation(cancel.clone())
+                .write(output)?;
+            println!("{}: {} files", output.display(), files);
+        }
+
+        #[cfg(feature = "sqlite")]
+        Commands::Index { roots, db, hash } => {
+            let (archives, _) = find_archives(roots)?;
+            if archives.is_empty() {
+                anyhow::bail!("no .cpk files found");
+            }
+            let summary = index::build_index(&archives, db, *hash, || open_cpk(&cli), &cancel)?;
+            println!(
+                "{}: {} entries from {} archive(s)",
+                db.display(),
+                summary.entries,
+                summary.archives
+            );
+            if summary.failed > 0 {
+                return Err(fail(
+                    Outcome::PartialFailure,
+                    format!("{} archive(s) could not be read", summary.failed),
+                ));
+            }
+        }
+
+        #[cfg(feature = "sqlite")]
+        Commands::Query { db, pattern } => {
+            let found = index::query(db, pattern)?;
+            for entry in &found {
+                let id = entry
+                    .id
+                    .map(|id| format!(", ID {}", id))
+                    .unwrap_or_default();
+                println!(
+                    "{}: {} ({} bytes{})",
+                    entry.archive.display(),
+                    entry.path,
+                    entry.size,
+                    id
+                );
+            }
+            if found.is_empty() {
+                return Err(fail(
+                    Outcome::NotFound,
+                    format!("no indexed entry matches {}", pattern),
+                ));
+            }
+        }
+
+        Commands::Delta {
+            base,
+            input,
+            map,
+            output,
+            compression,
+            #[cfg(feature = "sqlite")]
+            cache,
+        } => {
+            let mut base_cpk = open_cpk(&cli);
+            read_input(&cli, &mut base_cpk, base)?;
+
+            let replacements = match (input, map) {
+                (_, Some(map)) => mapping::read_mapping(map)?,
+                (Some(input), None) => delta::dir_replacements(input)?,
+                (None, None) => unreachable!("clap requires an input or --map"),
+            };
+            #[cfg(feature = "sqlite")]
+            let mut warnings = Vec::new();
+            #[cfg(feature = "sqlite")]
+            let mut cache = open_cache(cache, &mut warnings);
+            #[cfg(feature = "sqlite")]
+            let mut digests = match &cache {
+                Some(cache) => cache.contents(base).unwrap_or_else(|e| {
+                    warnings.push(format!("Couldn't read cached digests: {}", e));
+                    Default::default()
+                }),
+                None => Default::default(),
+            };
+            #[cfg(not(feature = "sqlite"))]
+            let mut digests = Default::default();
+            let summary = delta::delta_with(
+                &base_cpk,
+                &replacements,
+                output,
+                compression.to_policy(),
+                &mut digests,
+                &cancel,
+            );
+            #[cfg(feature = "sqlite")]
+            {
+                if let Some(cache) = &mut cache
+                    && let Err(e) = cache.store_contents(base, &digests)
+                {
+                    warnings.push(format!("Couldn't cache the digests: {}", e));
+                }
+                print_warnings(&warnings);
+            }
+            let summary = summary?;
+            println!(
+                "{}: {} changed, {} added, {} unchanged",
+                output.display(),
+                summary.changed,
+                summary.added,
+                summary.unchanged
+            );
+        }
+
+        Commands::Split {
+            input,
+            max_size,
+            output,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+
+            let stem = output.clone().unwrap_or_else(|| input.with_extension(""));
+            let volumes = split::split(&cpk, stem, *max_size, &cancel)?;
+            for volume in &volumes {
+                println!("{}", volume.display());
+            }
+            println!("{} volume(s)", volumes.len());
+        }
+
+        Commands::Bench {
+            input,
+            sample,
+            iterations,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+
+            let results = bench::run(&cpk, *sample, *iterations, &cancel)?;
+            if results.is_empty() {
+                println!("No compressed entries to benchmark");
+            }
+            for result in &results {
+                println!(
+                    "{:<16} {:>4} entries  {:>10} -> {:>10} bytes  x{}  {:>8.1} MB/s",
+                    result.codec,
+                    result.entries,
+                    result.input_bytes,
+                    result.output_bytes,
+                    result.iterations,
+                    result.megabytes_per_second()
+                );
+            }
+        }
+
+        Commands::Ids { input, csv } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+
+            let rows = mapping::id_rows(&cpk);
+            if *csv {
+                mapping::write_id_csv(&rows, std::io::stdout().lock())?;
+            } else {
+                for row in &rows {
+                    println!(
+                        "{:>6}  0x{:08X}  {:>10}  {}",
+                        row.id,
+                        row.offset,
+                        row.size,
+                        row.path.as_deref().unwrap_or("-")
+                    );
+                }
+            }
+        }
+
+        Commands::Layout { input } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+
+            let regions = layout::regions(&cpk);
+            let end = cpk
+                .archive_size()
+                .and_then(|size| cpk.base_offset().checked_add(size))
+                .into_iter()
+                .chain(regions.iter().map(|r| r.end))
+                .max()
+                .unwrap_or(0);
+
+            println!(
+                "{:<10}  {:<10}  {:>10}  {:<8}  Owner",
+                "Start", "End", "Size", "Kind"
+            );
+            for region in layout::with_gaps(regions, end) {
+                println!(
+                    "0x{:08X}  0x{:08X}  {:>10}  {:<8}  {}",
+                    region.start,
+                    region.end,
+                    region.len(),
+                    region.kind,
+                    region.owner
+                );
+            }
+        }
+
+        Commands::Hash {
+            input,
+            algorithm,
+            jobs,
+            output,
+            filter,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+            cpk.set_cancellation(cancel.clone());
+            let jobs =
+                jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            let digests =
+                digest::hash_entries(&cpk, algorithm.to_algorithm(), &filter.to_filter()?, jobs)?;
+
+            let mut lines: Vec<(String, String)> = digests
+                .into_iter()
+                .map(|(entry, digest)| (entry.full_path(), digest))
+                .collect();
+            lines.sort();
+            let lines: String = lines
+                .iter()
+                .map(|(path, digest)| format!("{}  {}\n", digest, path))
+                .collect();
+            match output {
+                Some(path) => std::fs::write(path, lines)?,
+                None => print!("{}", lines),
+            }
+            print_warnings(&diagnostic_warnings(&cpk, ""));
+        }
+
+        Commands::Verify {
+            input,
+            deep,
+            report,
+            #[cfg(feature = "sqlite")]
+            cache,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+            cpk.set_cancellation(cancel.clone());
+
+            let mut run_report = RunReport::new("verify", input);
+            for diagnostic in cpk.diagnostics() {
+                run_report.add_issue("diagnostic", diagnostic.to_string());
+            }
+            let mut warnings = diagnostic_warnings(&cpk, "");
+            for rows in cpk.duplicate_paths() {
+                let message = format!(
+                    "{} is used by {} {} entries",
+                    rows[0].full_path(),
+                    rows.len(),
+                    rows[0].toc_name
+                );
+                run_report.add_issue("duplicate", message.clone());
+                warnings.push(message);
+            }
+            let mut failures = Vec::new();
+            let mut issue = |kind: &'static str, region: &layout::Region, message: String| {
+                failures.push(Failure {
+                    entry: region.owner.clone(),
+                    kind,
+                    offset: Some(region.start),
+                    message: message.clone(),
+                });
+                run_report.add_issue(kind, message);
+            };
+
+            let file_len = std::fs::metadata(input)?.len();
+            let layout = verify::check_layout(&cpk, file_len);
+
+            for (a, b) in &layout.overlaps {
+                issue(
+                    "overlap",
+                    a,
+                    format!(
+                        "{} [0x{:X}..0x{:X}) and {} [0x{:X}..0x{:X})",
+                        a.owner, a.start, a.end, b.owner, b.start, b.end
+                    ),
+                );
+            }
+            for gap in &layout.gaps {
+                issue(
+                    "gap",
+                    gap,
+                    format!(
+                        "{} unaccounted bytes at [0x{:X}..0x{:X})",
+                        gap.len(),
+                        gap.start,
+                        gap.end
+                    ),
+                );
+            }
+            for region in &layout.truncated {
+                issue(
+                    "truncated",
+                    region,
+                    format!(
+                        "{} [0x{:X}..0x{:X}) extends past the end of the file (0x{:X})",
+                        region.owner, region.start, region.end, file_len
+                    ),
+                );
+            }
+
+            #[cfg(feature = "sqlite")]
+            let checks = match (deep, open_cache(cache, &mut warnings)) {
+                (false, _) => Vec::new(),
+                (true, None) => verify::check_entries(&cpk)?,
+                (true, Some(mut cache)) => {
+                    let mut known = cache.lookup(input).unwrap_or_else(|e| {
+                        warnings.push(format!("Couldn't read cached results: {}", e));
+                        Default::default()
+                    });
+                    let checks = verify::check_entries_with(&cpk, &mut known)?;
+                    if let Err(e) = cache.store(input, &known) {
+                        warnings.push(format!("Couldn't cache the results: {}", e));
+                    }
+                    checks
+                }
+            };
+            #[cfg(not(feature = "sqlite"))]
+            let checks = match deep {
+                true => verify::check_entries(&cpk)?,
+                false => Vec::new(),
+            };
+            let cached = checks.iter().filter(|check| check.cached).count();
+            if cached > 0 {
+                info!(
+                    "{} of {} entries unchanged since the last check",
+                    cached,
+                    checks.len()
+                );
+            }
+            let mut corrupt = 0;
+            for check in &checks {
+                if let Some(problem) = &check.problem {
+                    failures.push(Failure {
+                        entry: check.entry.full_path(),
+                        kind: "corrupt",
+                        offset: Some(check.entry.file_offset),
+                        message: problem.clone(),
+                    });
+                    corrupt += 1;
+                }
+                run_report.add_checked(check);
+            }
+
+            let clean = layout.is_clean() && corrupt == 0;
+            if let Some(path) = report {
+                run_report.write(path, clean)?;
+            }
+            print_warnings(&warnings);
+            print_failures(&failures);
+            if !clean {
+                return Err(fail(
+                    Outcome::VerifyMismatch,
+                    format!(
+                        "{} overlap(s), {} gap(s), {} truncated region(s), {} corrupt entries",
+                        layout.overlaps.len(),
+                        layout.gaps.len(),
+                        layout.truncated.len(),
+                        corrupt
+                    ),
+                ));
+            }
+            println!("{}", "OK".green());
+        }
+
+        Commands::Grep {
+            input,
+            pattern,
+            ignore_case,
+            filter,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+            let filter = filter.to_filter()?;
+
+            let (mut matched_entries, mut total) = (0, 0);
+            for entry in cpk.unique_files() {
+                if cancel.is_cancelled() {
+                    warn!("Cancelled, results are incomplete");
+                    break;
+                }
+                if !filter.matches(entry) {
+                    continue;
+                }
+                let data = match cpk.read_entry(entry) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("Skipping {}: {}", entry.full_path(), e);
+                        continue;
+                    }
+                };
+
+                let matches = search::find_matches(&data, pattern.as_bytes(), *ignore_case);
+                for &offset in &matches {
+                    match search::line_at(&data, offset, 120) {
+                        Some(line) => println!("{}:0x{:X}: {}", entry.full_path(), offset, line),
+                        None => println!("{}:0x{:X}", entry.full_path(), offset),
+                    }
+                }
+                if !matches.is_empty() {
+                    matched_entries += 1;
+                    total += matches.len();
+                }
+            }
+
+            println!();
+            println!("{} match(es) in {} file(s)", total, matched_entries);
+        }
+
+        Commands::Hexdump {
+            input,
+            target,
+            start,
+            length,
+            raw,
+        } => {
+            let mut cpk = open_cpk(&cli);
+            read_input(&cli, &mut cpk, input)?;
+
+            let entry = cpk.find_entries(target, &EntryFilter::default())?[0];
+            let data = if *raw {
+                cpk.read_entry_raw(entry)?
+            } else {
+                cpk.read_entry(entry)?
+            };
+
+            let from = (*start).min(data.len() as u64) as usize;
+            let to = match length {
+                Some(length) => from.saturating_add(*length as usize).min(data.len()),
+                None => data.len(),
+            };
+            hexdump::write_hexdump(std::io::stdout().lock(), &data[from..to], from as u64)?;
+        }
+
+        Commands::Acb { input, entry } => {
+            let data = match entry {
+                Some(target) => {
+                    let mut cpk = open_cpk(&cli);
+                    read_input(&cli, &mut cpk, input)?;
+                    let entry = cpk.find_entries(target, &EntryFilter::default())?[0];
+                    cpk.read_entry(entry)?
+                }
+                None => std::fs::read(input)?,
+            };
+
+            let cues = acb::read_cues(&data)?;
+            println!("{:>6}  {:<32}  Waveforms (AWB ID)", "CueId", "Name");
+            for cue in &cues {
+                let waveforms: Vec<String> = cue
+                    .waveforms
+                    .iter()
+                    .map(|w| {
+                        let awb_id = w.awb_id.map_or("?".to_string(), |id| id.to_string());
+                        let storage = if w.streaming { "stream" } else { "memory" };
+                        match w.encode_type {
+                            Some(codec) => {
+                                format!("#{} -> {} {} (type {})", w.index, storage, awb_id, codec)
+                            }
+                            None => format!("#{} -> {} {}", w.index, storage, awb_id),
+                        }
+                    })
+                    .collect();
+                println!(
+                    "{:>6}  {:<32}  {}",
+                    cue.id,
+                    cue.name.as_deref().unwrap_or("-"),
+                    waveforms.join(", ")
+                );
+            }
+            println!();
+            println!("{} cue(s)", cues.len());
+        }
+
+        Commands::Afs { command } => match command {
+            AfsCommands::List { input } => {
+                let mut afs = Afs::new();
+                afs.read_afs(input)?;
+
+                for entry in &afs.entries {
+                    println!("{}", entry.name);
+                }
+                let size: u64 = afs.entries.iter().map(|e| e.size).sum();
+                println!();
+                println!("{} files, {} bytes", afs.entries.len(), size);
+            }
+            AfsCommands::Extract { input, target } => {
+                let mut afs = Afs::new();
+                afs.read_afs(input)?;
+                afs.extract(input, target)?;
+            }
+        },
+
+        Commands::Mod { command } => match command {
+            ModCommands::Export {
+                input,
+                replacements,
+                output,
+            } => {
+                let mut cpk = open_cpk(&cli);
+                read_input(&cli, &mut cpk, input)?;
+                let replacements = delta::dir_replacements(replacements)?;
+                let manifest = modpack::export(&cpk, &replacements, output)?;
+                println!(
+                    "{}: {} of {} file(s) differ from {}",
+                    output.display(),
+                    manifest.entries.len(),
+                    replacements.len(),
+                    manifest.target.name
+                );
+            }
+            ModCommands::Apply {
+                package,
+                input,
+                output,
+                force,
+                compression,
+            } => {
+                let mut cpk = open_cpk(&cli);
+                read_for_update(&cli, &mut cpk, input)?;
+                cpk.set_cancellation(cancel.clone());
+                let output_path = output.as_ref().unwrap_or(input);
+                let summary = modpack::apply(
+                    &mut cpk,
+                    package,
+                    output_path,
+                    &compression.to_policy(),
+                    *force,
+                )?;
+                match summary.replaced {
+                    0 => println!(
+                        "{}: the package is already applied, nothing written",
+                        input.display()
+                    ),
+                    n => println!(
+                        "{}: {} entries replaced, {} already applied",
+                        output_path.display(),
+                        n,
+                        summary.already_applied
+                    ),
+                }
+            }
+        },
+
+        Commands::Utf { command } => match command {
+            UtfCommands::Inspect { input, table } => {
+                let mut packet = match table {
+                    Some(name) => {
+                        let mut cpk = open_cpk(&cli);
+                        read_input(&cli, &mut cpk, input)?;
+                        match cpk.header_packet(name) {
+                            Some(packet) => packet.to_vec(),
+                            None => {
+                                let entry = cpk.find_entries(name, &EntryFilter::default())?[0];
+                                cpk.read_entry(entry)?
+                            }
+                        }
+                    }
+                    None => std::fs::read(input)?,
+                };
+                if !packet.starts_with(b"@UTF") {
+                    match CipherRegistry::default().detect(&packet) {
+                        Some(cipher) => cipher.decrypt(&mut packet),
+                        None => anyhow::bail!("{} is not an @UTF table", input.display()),
+                    }
+                }
+
+                let mut utf = Utf::new();
+                utf.read_utf(&packet)?;
+                print_utf_schema(&utf);
+            }
+            UtfCommands::Set {
+                input,
+                table,
+                row,
+                column,
+                value,
+                output,
+            } => {
+                let mut cpk = open_cpk(&cli);
+                read_for_update(&cli, &mut cpk, input)?;
+                cpk.set_cancellation(cancel.clone());
+                let output_path = output.as_ref().unwrap_or(input);
+
+                cpk.set_table_cell(input, table, *row, column, value, output_path)?;
+                println!(
+                    "{}: {} row {}, {} = {}",
+                    output_path.display(),
+                    table,
+                    row,
+                    column,
+                    value
+                );
+            }
+        },
+
+        Commands::Crilayla { command } => {
+            let mut warnings = Vec::new();
+            let (input, output, data) = match command {
+                CrilaylaCommands::Compress { input, output } => {
+                    let output = output
+                        .clone()
+                        .unwrap_or_else(|| with_suffix(input, ".crilayla"));
+                    (input, output, compress_crilayla(&std::fs::read(input)?)?)
+                }
+                CrilaylaCommands::Decompress { input, output } => {
+                    let output = output.clone().unwrap_or_else(|| {
+                        match input.extension().is_some_and(|ext| ext == "crilayla") {
+                            true => input.with_extension(""),
+                            false => with_suffix(input, ".dec"),
+                        }
+                    });
+                    let data = std::fs::read(input)?;
+                    if !data.starts_with(b"CRILAYLA") {
+                        anyhow::bail!("{} is not CRILAYLA-compressed", input.display());
+                    }
+                    let decoded = match cli.lenient_decompress {
+                        true => {
+                            let mut decoded = Vec::new();
+                            let faked = decompress_crilayla_lenient_into(&data, &mut decoded)?;
+                            if faked > 0 {
+                                warnings.push(format!(
+                                    "{} bytes from out-of-range backreferences were filled with zeros",
+                                    faked
+                                ));
+                            }
+                            decoded
+                        }
+                        false => decompress_crilayla(&data)?,
+                    };
+                    (input, output, decoded)
+                }
+            };
+            std::fs::write(&output, &data)?;
+            println!(
+                "{} -> {} ({} bytes)",
+                input.display(),
+                output.display(),
+                data.len()
             );
-            cpk.replace_file(input, target, replacement, output_path)?;
+            print_warnings(&warnings);
+        }
+
+        Commands::Scan { input, extract } => {
+            let hits = scan::scan_file(input, cli.offset)?;
+            println!("Found {} CPK archive(s)", hits.len());
+
+            for hit in &hits {
+                match hit.size {
+                    Some(size) => println!(
+                        "  0x{:08X}  {} bytes  ({} files)",
+                        hit.offset, size, hit.files
+                    ),
+                    None => println!(
+                        "  0x{:08X}  unknown size  ({} files)",
+                        hit.offset, hit.files
+                    ),
+                }
+            }
+
+            if let Some(dir) = extract {
+                create_dir_all(dir)?;
+                let stem = input
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "archive".to_string());
+
+                for hit in &hits {
+                    let output_path = dir.join(format!("{}_{:08X}.cpk", stem, hit.offset));
+                    match scan::extract_hit(input, hit, &output_path) {
+                        Ok(()) => info!("Wrote {}", output_path.display()),
+                        Err(e) => warn!("Skipping archive at 0x{:X}: {}", hit.offset, e),
+                    }
+                }
+            }
         }
     }
 
@@ -0,0 +1,183 @@
+use crate::cpk::Cpk;
+use crate::error::{CpkError, Result};
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Reads an ID -> path CSV such as the one `write_id_csv` produces: the first
+/// two columns are used and a header row is skipped.
+pub fn read_names<P: AsRef<Path>>(path: P) -> Result<HashMap<u32, String>> {
+    let path = path.as_ref();
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+
+    let mut names = HashMap::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+        let id = record.get(0).unwrap_or_default();
+        let name = record.get(1).unwrap_or_default();
+        match id.parse::<u32>() {
+            Ok(id) if !name.is_empty() => {
+                names.insert(id, name.to_string());
+            }
+            Ok(_) => {}
+            Err(_) if i == 0 => {}
+            Err(_) => {
+                return Err(CpkError::Parse(format!(
+                    "{}: expected 'id,path' on line {}",
+                    path.display(),
+                    record.position().map_or(0, |p| p.line())
+                )));
+            }
+        }
+    }
+
+    debug!("names: {} IDs from {}", names.len(), path.display());
+    Ok(names)
+}
+
+/// An entry ID along with the path the TOC gives it, if any.
+#[derive(Debug, Clone)]
+pub struct IdRow {
+    pub id: u32,
+    pub path: Option<String>,
+    pub size: u64,
+    pub offset: u64,
+}
+
+/// Lists every ID of the archive in ascending order. IDs found in both the TOC
+/// and the ITOC take their path from the TOC.
+pub fn id_rows(cpk: &Cpk) -> Vec<IdRow> {
+    let mut rows: BTreeMap<u32, IdRow> = BTreeMap::new();
+    for entry in cpk.file_table.iter().filter(|e| e.file_type == "FILE") {
+        let Some(id) = entry.id else {
+            continue;
+        };
+        let named = entry.toc_name == "TOC";
+        let row = rows.entry(id).or_insert_with(|| IdRow {
+            id,
+            path: None,
+            size: entry.file_size,
+            offset: entry.file_offset,
+        });
+        if named && row.path.is_none() {
+            row.path = Some(entry.full_path());
+            row.size = entry.file_size;
+            row.offset = entry.file_offset;
+        }
+    }
+    rows.into_values().collect()
+}
+
+/// Writes `rows` as CSV with an `id,path,size,offset` header; IDs without a path
+/// get an empty path.
+pub fn write_id_csv<W: Write>(rows: &[IdRow], writer: W) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    let csv_error = |e: csv::Error| CpkError::Io(e.into());
+    writer
+        .write_record(["id", "path", "size", "offset"])
+        .map_err(csv_error)?;
+    for row in rows {
+        writer
+            .write_record([
+                row.id.to_string(),
+                row.path.clone().unwrap_or_default(),
+                row.size.to_string(),
+                row.offset.to_string(),
+            ])
+            .map_err(csv_error)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a replacement mapping of archive target -> local file.
+///
+/// JSON files hold an object (`{"dir/file.bin": "local.bin"}`); anything else is
+/// read as two-column CSV. Targets that are plain numbers address entries by ID,
+/// and relative local paths are resolved against the mapping file's directory.
+pub fn read_mapping<P: AsRef<Path>>(path: P) -> Result<Vec<(String, PathBuf)>> {
+    let path = path.as_ref();
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let is_json = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+    let pairs = if is_json {
+        read_json(path)?
+    } else {
+        read_csv(path)?
+    };
+
+    let mapping: Vec<(String, PathBuf)> = pairs
+        .into_iter()
+        .map(|(target, local)| {
+            let target = if !target.is_empty() && target.bytes().all(|b| b.is_ascii_digit()) {
+                format!("#id:{}", target)
+            } else {
+                target
+            };
+            (target, base_dir.join(local))
+        })
+        .collect();
+
+    debug!("mapping: {} entries from {}", mapping.len(), path.display());
+    Ok(mapping)
+}
+
+fn read_json(path: &Path) -> Result<Vec<(String, String)>> {
+    let text = std::fs::read_to_string(path)?;
+    let value: serde_json::Value = serde_json::from_str(&text)
+        .map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+
+    let object = value.as_object().ok_or_else(|| {
+        CpkError::Parse(format!(
+            "{}: expected an object of target -> file",
+            path.display()
+        ))
+    })?;
+
+    object
+        .iter()
+        .map(|(target, local)| match local.as_str() {
+            Some(local) => Ok((target.clone(), local.to_string())),
+            None => Err(CpkError::Parse(format!(
+                "{}: value for '{}' is not a string",
+                path.display(),
+                target
+            ))),
+        })
+        .collect()
+}
+
+fn read_csv(path: &Path) -> Result<Vec<(String, String)>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+
+    let mut pairs = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+        match (record.get(0), record.get(1)) {
+            (Some(target), Some(local)) if !target.is_empty() && !local.is_empty() => {
+                pairs.push((target.to_string(), local.to_string()));
+            }
+            _ => {
+                return Err(CpkError::Parse(format!(
+                    "{}: expected 'target,file' on line {}",
+                    path.display(),
+                    record.position().map_or(0, |p| p.line())
+                )));
+            }
+        }
+    }
+
+    Ok(pairs)
+}
@@ -0,0 +1,219 @@
+use crate::builder::{CpkBuilder, PathSplit};
+use crate::cancel::CancellationToken;
+use crate::cpk::{Cpk, CpkMode, FileEntry};
+use crate::error::{CpkError, Result};
+use crate::group;
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// What a merge took from each side.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MergeSummary {
+    /// Base entries written unchanged.
+    pub kept: usize,
+    /// Base entries whose data came from the patch.
+    pub replaced: usize,
+    /// Patch entries with no counterpart in the base.
+    pub added: usize,
+}
+
+/// How base and patch entries are told to be the same file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum MergeKey {
+    /// Case-insensitive path, when both archives have a TOC.
+    Path(String),
+    /// ITOC ID, when either has none.
+    Id(u32),
+}
+
+/// Writes a single archive holding `base` with `patch` layered on top.
+///
+/// Entries are matched by path, or by ID when either archive has no TOC; a
+/// replaced entry keeps the base's path and ID so lookups by either still
+/// resolve. New entries keep their patch ID unless the base already uses it,
+/// and can't be added to a base with a TOC from a patch without one. Stored
+/// data is copied as is, compressed entries stay compressed, and the output
+/// uses the base's alignment, table encryption, TOC, ITOC and CRC column if
+/// any.
+/// The base's groups are kept; patch-only entries join none.
+pub fn merge<P: AsRef<Path>>(
+    base: &Cpk,
+    patch: &Cpk,
+    output_path: P,
+    cancel: &CancellationToken,
+) -> Result<MergeSummary> {
+    let base_path = base.source_path()?;
+    let patch_path = patch.source_path()?;
+    let (base_files, base_named) = listed_files(base);
+    let (patch_files, patch_named) = listed_files(patch);
+    let by_path = base_named && patch_named;
+    let key = |entry: &FileEntry| match (by_path, entry.id) {
+        (true, _) => Ok(MergeKey::Path(entry.full_path().to_lowercase())),
+        (false, Some(id)) => Ok(MergeKey::Id(id)),
+        (false, None) => Err(CpkError::Unsupported(format!(
+            "{} has no ID; entries are matched by ID when an archive has no TOC",
+            entry.full_path()
+        ))),
+    };
+
+    let mut overrides: HashMap<MergeKey, &FileEntry> = patch_files
+        .iter()
+        .map(|entry| Ok((key(entry)?, *entry)))
+        .collect::<Result<_>>()?;
+
+    let mut summary = MergeSummary::default();
+    let mode = match base_named {
+        true => path_mode(base),
+        false => CpkMode::Id,
+    };
+    let mut builder = CpkBuilder::new()
+        .mode(mode)
+        .path_split(path_split(base))
+        .align(base.align())
+        .encrypt_tables(base.is_table_encrypted("TOC_HDR"))
+        .cipher(base.table_cipher().clone())
+        .crc(base.has_crc())
+        .cancellation(cancel.clone());
+    let mut used_ids = HashSet::new();
+
+    for entry in &base_files {
+        let path = entry.full_path();
+        used_ids.extend(entry.id);
+        match overrides.remove(&key(entry)?) {
+            Some(replacement) => {
+                debug!("{}: taken from the patch", path);
+                builder = builder.add_copied(path, entry.id, patch_path, replacement);
+                summary.replaced += 1;
+            }
+            None => {
+                builder = builder.add_copied(path, entry.id, base_path, entry);
+                summary.kept += 1;
+            }
+        }
+    }
+
+    // Entries only found in the patch, in the patch's order
+    for entry in &patch_files {
+        if !overrides.contains_key(&key(entry)?) {
+            continue;
+        }
+        if base_named && !patch_named {
+            return Err(CpkError::Unsupported(format!(
+                "ID {} is only in the patch, which has no path to give it in the base's TOC",
+                entry.id.unwrap_or_default()
+            )));
+        }
+        let id = entry.id.filter(|id| used_ids.insert(*id));
+        debug!("{}: added from the patch", entry.full_path());
+        builder = builder.add_copied(entry.full_path(), id, patch_path, entry);
+        summary.added += 1;
+    }
+
+    // Base entries keep their paths and IDs, so the groups stay valid, and a
+    // GTOC that can't be read still can be copied as it is
+    match group::archive_groups(base) {
+        Ok(Some(groups)) => builder = builder.groups(groups),
+        Ok(None) => {}
+        Err(CpkError::Unsupported(reason)) => {
+            info!("{}, copying the GTOC unchanged", reason);
+            if let Some(packet) = base.header_packet("GTOC_HDR") {
+                builder = builder.raw_gtoc(packet.to_vec());
+            }
+        }
+        Err(e) => return Err(e),
+    }
+
+    builder.write(output_path)?;
+    Ok(summary)
+}
+
+/// The files of an archive: its TOC rows, or its ITOC ones when it has no
+/// TOC, and whether they have paths.
+fn listed_files(cpk: &Cpk) -> (Vec<&FileEntry>, bool) {
+    let rows = |toc: &str| -> Vec<&FileEntry> {
+        cpk.file_table
+            .iter()
+            .filter(|e| e.file_type == "FILE" && e.toc_name == toc)
+            .collect()
+    };
+    match rows("TOC") {
+        files if files.is_empty() => (rows("ITOC"), false),
+        files => (files, true),
+    }
+}
+
+/// The mode for rewriting a TOC archive: keeps its ITOC; a GTOC is added back
+/// along with the groups.
+pub(crate) fn path_mode(cpk: &Cpk) -> CpkMode {
+    match cpk.cpk_mode() {
+        Some(mode) if mode.has_itoc() => CpkMode::FileNameAndId,
+        _ => CpkMode::FileName,
+    }
+}
+
+/// How the archive's TOC cuts paths into DirName and FileName, so a rewrite
+/// cuts them the same way.
+pub(crate) fn path_split(cpk: &Cpk) -> PathSplit {
+    let toc = cpk
+        .file_table
+        .iter()
+        .filter(|e| e.file_type == "FILE" && e.toc_name == "TOC");
+    let mut nested = false;
+    let mut dirs = false;
+    for entry in toc {
+        nested |= entry.file_name.contains('/');
+        dirs |= entry.dir().is_some();
+    }
+    match (nested, dirs) {
+        (false, _) => PathSplit::Parent,
+        (true, false) => PathSplit::None,
+        (true, true) => PathSplit::FirstComponent,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::TestArchive;
+
+    fn archive(path: &Path, files: usize, mode: CpkMode, seed: u64) -> Cpk {
+        TestArchive {
+            files,
+            mode,
+            max_size: 0x1000,
+            seed,
+            ..Default::default()
+        }
+        .write_to(path)
+    }
+
+    #[test]
+    fn archives_without_toc_are_matched_by_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = archive(&dir.path().join("base.cpk"), 5, CpkMode::Id, 1);
+        let patch = archive(&dir.path().join("patch.cpk"), 8, CpkMode::Id, 2);
+
+        let output = dir.path().join("merged.cpk");
+        let summary = merge(&base, &patch, &output, &CancellationToken::new()).unwrap();
+        assert_eq!((summary.kept, summary.replaced, summary.added), (0, 5, 3));
+        let merged = crate::generate::read_back(&output);
+        for id in 0..8 {
+            let expected = patch.read_entry(patch.find_by_id(id).unwrap()).unwrap();
+            let entry = merged.find_by_id(id).unwrap();
+            assert_eq!(merged.read_entry(entry).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn id_only_patch_cannot_add_to_a_named_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = archive(&dir.path().join("base.cpk"), 4, CpkMode::FileNameAndId, 1);
+        let patch = archive(&dir.path().join("patch.cpk"), 6, CpkMode::Id, 2);
+
+        let output = dir.path().join("merged.cpk");
+        let result = merge(&base, &patch, &output, &CancellationToken::new());
+        assert!(matches!(result, Err(CpkError::Unsupported(_))));
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1,471 @@
+use crate::compression::CompressionPolicy;
+use crate::cpk::{Cpk, FileEntry};
+use crate::digest::HashAlgorithm;
+use crate::error::{CpkError, Result};
+use crate::escape::NameEscape;
+use log::{debug, info, warn};
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest at the root of a mod package.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Value of the manifest's `format` field; bumped on incompatible changes.
+pub const FORMAT: &str = "cpk-mod/1";
+
+/// Folder of a mod package holding the replacement files.
+const PAYLOAD_DIR: &str = "files";
+
+/// A mod package's description: the archive it was made against and the
+/// entries it replaces, each with the SHA-256 of its new and original content.
+///
+/// On disk it's `manifest.json`, with the payloads below `files/`:
+///
+/// ```json
+/// {
+///   "format": "cpk-mod/1",
+///   "tool": "cpk-tool-rs 0.1.0",
+///   "target": { "name": "data.cpk", "size": 123456 },
+///   "entries": [
+///     { "path": "a/b.bin", "payload": "files/a/b.bin", "size": 16,
+///       "sha256": "...", "original_sha256": "..." }
+///   ],
+///   "digest": "..."
+/// }
+/// ```
+///
+/// `digest` is the SHA-256 of the compact JSON of every other field, with
+/// object keys sorted. It has no key, so it only catches accidental damage:
+/// whoever edits a manifest on purpose can recompute it.
+#[derive(Debug, Clone)]
+pub struct ModManifest {
+    /// Tool and version that wrote the package.
+    pub tool: String,
+    pub target: ModTarget,
+    pub entries: Vec<ModEntry>,
+}
+
+/// The archive a package was exported against.
+#[derive(Debug, Clone)]
+pub struct ModTarget {
+    /// File name only; the archive may live anywhere.
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModEntry {
+    /// Archive path of the entry replaced.
+    pub path: String,
+    /// The replacement file, relative to the package.
+    pub payload: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Digest of the entry's content in the target archive.
+    pub original_sha256: String,
+}
+
+/// What `apply` did with a package's entries.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ApplySummary {
+    pub replaced: usize,
+    /// Entries already holding the payload.
+    pub already_applied: usize,
+}
+
+/// Writes a mod package to `package`: the manifest and a copy of each
+/// replacement that differs from its entry in `cpk`.
+///
+/// Replacements are given as archive path -> local file, as
+/// [`crate::delta::dir_replacements`] lists them. Every path must name an
+/// existing entry; packages only replace.
+pub fn export<P: AsRef<Path>>(
+    cpk: &Cpk,
+    replacements: &[(String, PathBuf)],
+    package: P,
+) -> Result<ModManifest> {
+    let package = package.as_ref();
+    let archive = cpk.source_path()?;
+    let target = ModTarget {
+        name: archive
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size: std::fs::metadata(archive)?.len(),
+    };
+
+    // Every path is looked up before anything is written, so a wrong one
+    // leaves no half-written package behind
+    let targets = replacements
+        .iter()
+        .map(|(path, _)| target_entry(cpk, path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut entries = Vec::new();
+    for ((path, local), entry) in replacements.iter().zip(targets) {
+        let original = HashAlgorithm::Sha256.hex_digest(&cpk.read_entry(entry)?);
+        let data = std::fs::read(local)?;
+        let sha256 = HashAlgorithm::Sha256.hex_digest(&data);
+        if sha256 == original {
+            debug!("{}: unchanged, left out", path);
+            continue;
+        }
+
+        let payload = format!("{}/{}", PAYLOAD_DIR, NameEscape::Percent.escape(path));
+        let destination = package.join(&payload);
+        if let Some(dir) = destination.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&destination, &data)?;
+        entries.push(ModEntry {
+            path: entry.full_path(),
+            payload,
+            size: data.len() as u64,
+            sha256,
+            original_sha256: original,
+        });
+    }
+    if entries.is_empty() {
+        return Err(CpkError::InvalidFormat(
+            "No file differs from the archive".to_string(),
+        ));
+    }
+
+    let manifest = ModManifest {
+        tool: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        target,
+        entries,
+    };
+    manifest.write(package)?;
+    Ok(manifest)
+}
+
+/// Applies the package at `package` to `cpk`, writing the result to
+/// `output_path` (the archive itself to modify it in place).
+///
+/// The manifest digest and every payload are checked first. An archive that
+/// isn't the one the package was made against is refused when an entry it
+/// replaces holds neither the original nor the new content, unless `force`
+/// is set. Entries already holding their payload are left alone, and nothing
+/// is written when that's all of them.
+pub fn apply<P: AsRef<Path>, Q: AsRef<Path>>(
+    cpk: &mut Cpk,
+    package: P,
+    output_path: Q,
+    compression: &CompressionPolicy,
+    force: bool,
+) -> Result<ApplySummary> {
+    let package = package.as_ref();
+    let manifest = ModManifest::read(package)?;
+    for entry in &manifest.entries {
+        let data = std::fs::read(package.join(&entry.payload))?;
+        if HashAlgorithm::Sha256.hex_digest(&data) != entry.sha256 {
+            return Err(CpkError::InvalidFormat(format!(
+                "{} doesn't match its digest in the manifest",
+                entry.payload
+            )));
+        }
+    }
+
+    // The entries are checked one by one below, hashing the whole archive
+    // would only make this note more certain
+    let archive = cpk.source_path()?.to_path_buf();
+    if std::fs::metadata(&archive)?.len() != manifest.target.size {
+        warn!(
+            "{} is not the archive the package was made against ({}), checking the entries it replaces",
+            archive.display(),
+            manifest.target.name
+        );
+    }
+
+    let mut summary = ApplySummary::default();
+    let mut replacements = Vec::new();
+    for entry in &manifest.entries {
+        let current =
+            HashAlgorithm::Sha256.hex_digest(&cpk.read_entry(target_entry(cpk, &entry.path)?)?);
+        if current == entry.sha256 {
+            debug!("{}: already applied", entry.path);
+            summary.already_applied += 1;
+            continue;
+        }
+        if current != entry.original_sha256 {
+            let message = format!("{} differs from the one the package replaces", entry.path);
+            if !force {
+                return Err(CpkError::InvalidFormat(message));
+            }
+            warn!("{}, replacing it anyway", message);
+        }
+        replacements.push((entry.path.clone(), package.join(&entry.payload)));
+    }
+
+    if !replacements.is_empty() {
+        info!(
+            "Applying {} replacement(s) to {}",
+            replacements.len(),
+            archive.display()
+        );
+        cpk.replace_files(&archive, &replacements, output_path, compression)?;
+    }
+    summary.replaced = replacements.len();
+    Ok(summary)
+}
+
+impl ModManifest {
+    /// Reads `package`'s manifest, checking its format and digest.
+    pub fn read<P: AsRef<Path>>(package: P) -> Result<Self> {
+        let path = package.as_ref().join(MANIFEST_FILE);
+        let invalid = |what: &str| CpkError::Parse(format!("{}: {}", path.display(), what));
+        let mut value: Value =
+            serde_json::from_slice(&std::fs::read(&path)?).map_err(|e| invalid(&e.to_string()))?;
+
+        if value["format"] != FORMAT {
+            return Err(invalid(&format!(
+                "format {} is not {}",
+                value["format"], FORMAT
+            )));
+        }
+        let digest = value
+            .as_object_mut()
+            .and_then(|fields| fields.remove("digest"))
+            .ok_or_else(|| invalid("no digest"))?;
+        if digest != body_digest(&value) {
+            return Err(invalid("digest doesn't match, the manifest was changed"));
+        }
+
+        let text = |value: &Value, field: &str| {
+            value[field]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| invalid(&format!("'{}' is missing", field)))
+        };
+        let number = |value: &Value, field: &str| {
+            value[field]
+                .as_u64()
+                .ok_or_else(|| invalid(&format!("'{}' is missing", field)))
+        };
+        let target = &value["target"];
+        let entries = value["entries"]
+            .as_array()
+            .ok_or_else(|| invalid("'entries' is missing"))?
+            .iter()
+            .map(|entry| {
+                let payload = text(entry, "payload")?;
+                // Payloads stay inside the package
+                if Path::new(&payload)
+                    .components()
+                    .any(|c| !matches!(c, std::path::Component::Normal(_)))
+                {
+                    return Err(invalid(&format!(
+                        "payload {} is outside the package",
+                        payload
+                    )));
+                }
+                Ok(ModEntry {
+                    path: text(entry, "path")?,
+                    payload,
+                    size: number(entry, "size")?,
+                    sha256: text(entry, "sha256")?,
+                    original_sha256: text(entry, "original_sha256")?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            tool: text(&value, "tool")?,
+            target: ModTarget {
+                name: text(target, "name")?,
+                size: number(target, "size")?,
+            },
+            entries,
+        })
+    }
+
+    fn write(&self, package: &Path) -> Result<()> {
+        let entries: Vec<Value> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "path": entry.path,
+                    "payload": entry.payload,
+                    "size": entry.size,
+                    "sha256": entry.sha256,
+                    "original_sha256": entry.original_sha256,
+                })
+            })
+            .collect();
+        let mut value = json!({
+            "format": FORMAT,
+            "tool": self.tool,
+            "target": {
+                "name": self.target.name,
+                "size": self.target.size,
+            },
+            "entries": entries,
+        });
+        value["digest"] = body_digest(&value);
+
+        std::fs::create_dir_all(package)?;
+        let text = serde_json::to_string_pretty(&value)
+            .map_err(|e| CpkError::Parse(format!("manifest: {}", e)))?;
+        std::fs::write(package.join(MANIFEST_FILE), text + "\n")?;
+        Ok(())
+    }
+}
+
+/// SHA-256 of `value` as compact JSON with sorted keys.
+fn body_digest(value: &Value) -> Value {
+    fn sorted(value: &Value) -> Value {
+        match value {
+            Value::Object(fields) => {
+                let mut keys: Vec<&String> = fields.keys().collect();
+                keys.sort();
+                let mut out = serde_json::Map::new();
+                for key in keys {
+                    out.insert(key.clone(), sorted(&fields[key]));
+                }
+                Value::Object(out)
+            }
+            Value::Array(items) => Value::Array(items.iter().map(sorted).collect()),
+            other => other.clone(),
+        }
+    }
+    let compact = sorted(value).to_string();
+    json!(HashAlgorithm::Sha256.hex_digest(compact.as_bytes()))
+}
+
+/// The entry of `cpk` a package path stands for, preferring the TOC's.
+fn target_entry<'a>(cpk: &'a Cpk, path: &str) -> Result<&'a FileEntry> {
+    let entries = cpk.find_entries(path, &Default::default())?;
+    entries
+        .iter()
+        .find(|e| e.toc_name == "TOC")
+        .or(entries.first())
+        .copied()
+        .ok_or_else(|| CpkError::FileNotFound(path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{TestArchive, read_back};
+
+    const REPLACED: [&str; 2] = ["dir01/file00001.bin", "dir02/file00002.txt"];
+
+    /// An archive, and the local files a package made against it replaces
+    /// two of its entries with.
+    fn setup(dir: &Path) -> (PathBuf, Vec<(String, PathBuf)>) {
+        let archive = dir.join("game.cpk");
+        TestArchive {
+            files: 8,
+            max_size: 0x1000,
+            seed: 12,
+            ..Default::default()
+        }
+        .write_to(&archive);
+
+        let replacements = REPLACED
+            .iter()
+            .map(|path| {
+                let local = dir.join("mod").join(path);
+                std::fs::create_dir_all(local.parent().unwrap()).unwrap();
+                std::fs::write(&local, path.repeat(20)).unwrap();
+                (path.to_string(), local)
+            })
+            .collect();
+        (archive, replacements)
+    }
+
+    fn package(dir: &Path) -> (PathBuf, PathBuf) {
+        let (archive, replacements) = setup(dir);
+        let package = dir.join("package");
+        export(&read_back(&archive), &replacements, &package).unwrap();
+        (archive, package)
+    }
+
+    #[test]
+    fn applying_again_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (archive, package) = package(dir.path());
+        let policy = CompressionPolicy::default();
+
+        let summary = apply(&mut read_back(&archive), &package, &archive, &policy, false).unwrap();
+        assert_eq!((summary.replaced, summary.already_applied), (2, 0));
+        let cpk = read_back(&archive);
+        for path in REPLACED {
+            let entry = cpk.find(path).unwrap();
+            assert_eq!(cpk.read_entry(entry).unwrap(), path.repeat(20).as_bytes());
+        }
+
+        let before = std::fs::read(&archive).unwrap();
+        let summary = apply(&mut read_back(&archive), &package, &archive, &policy, false).unwrap();
+        assert_eq!((summary.replaced, summary.already_applied), (0, 2));
+        assert_eq!(std::fs::read(&archive).unwrap(), before);
+    }
+
+    #[test]
+    fn damaged_packages_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (archive, package) = package(dir.path());
+        let manifest_path = package.join(MANIFEST_FILE);
+        let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+        let before = std::fs::read(&archive).unwrap();
+
+        // A payload that isn't what the manifest lists
+        let payload = package.join(&ModManifest::read(&package).unwrap().entries[0].payload);
+        std::fs::write(&payload, b"changed").unwrap();
+        let policy = CompressionPolicy::default();
+        assert!(apply(&mut read_back(&archive), &package, &archive, &policy, false).is_err());
+        assert_eq!(std::fs::read(&archive).unwrap(), before);
+
+        // A manifest changed without its digest
+        std::fs::write(
+            &manifest_path,
+            manifest.replace("\"size\": 380", "\"size\": 381"),
+        )
+        .unwrap();
+        assert!(matches!(
+            ModManifest::read(&package),
+            Err(CpkError::Parse(_))
+        ));
+
+        // Payloads outside the package, even with a matching digest
+        let mut value: Value = serde_json::from_str(&manifest).unwrap();
+        value["entries"][0]["payload"] = json!("../game.cpk");
+        value.as_object_mut().unwrap().remove("digest");
+        value["digest"] = body_digest(&value);
+        std::fs::write(&manifest_path, value.to_string()).unwrap();
+        assert!(matches!(
+            ModManifest::read(&package),
+            Err(CpkError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn entries_changed_since_are_refused_unless_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let (archive, package) = package(dir.path());
+        let other = dir.path().join("other.bin");
+        std::fs::write(&other, [9u8; 200]).unwrap();
+        let policy = CompressionPolicy::default();
+        read_back(&archive)
+            .replace_file(&archive, REPLACED[0], &other, &archive, &policy)
+            .unwrap();
+
+        assert!(apply(&mut read_back(&archive), &package, &archive, &policy, false).is_err());
+        let summary = apply(&mut read_back(&archive), &package, &archive, &policy, true).unwrap();
+        assert_eq!(summary.replaced, 2);
+    }
+
+    #[test]
+    fn export_with_an_unknown_path_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (archive, mut replacements) = setup(dir.path());
+        replacements.push(("missing.bin".to_string(), replacements[0].1.clone()));
+        let package = dir.path().join("package");
+
+        let result = export(&read_back(&archive), &replacements, &package);
+        assert!(matches!(result, Err(CpkError::FileNotFound(_))));
+        assert!(!package.exists());
+    }
+}
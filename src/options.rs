@@ -0,0 +1,36 @@
+use encoding_rs::Encoding;
+
+/// Limits and behaviour for reading archives and @UTF tables.
+///
+/// The defaults accept every archive seen in the wild; tighten the limits when
+/// reading files from an untrusted source.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Largest table packet `read_cpk` loads, and the largest packet `read_utf` accepts.
+    pub max_utf_size: u64,
+    /// Longest string read from a table; longer ones are cut and noted.
+    pub max_string_len: usize,
+    /// How many @UTF tables may be nested inside each other's data cells
+    /// (an ITOC holding DataL is one level).
+    pub max_depth: usize,
+    /// Skip TOC rows that can't be parsed, noting each as a diagnostic, instead of failing.
+    pub lenient: bool,
+    /// Encoding of table strings; CRI tools write Shift-JIS.
+    pub encoding: &'static Encoding,
+    /// Keep the decrypted table packets after `read_cpk`. Without them an archive
+    /// can be listed, verified and extracted, but not rewritten.
+    pub retain_packets: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            max_utf_size: 100_000_000,
+            max_string_len: 255,
+            max_depth: 8,
+            lenient: false,
+            encoding: encoding_rs::SHIFT_JIS,
+            retain_packets: true,
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::cpk::FileEntry;
+use crate::error::{CpkError, Result};
+use log::debug;
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+/// A step run on each entry's content during extraction, after it's
+/// decompressed and before it's written, such as a game-specific decryption.
+///
+/// Processors run in the order they were added to the archive, each one seeing
+/// the previous one's output. An error fails the entry like a read error would.
+pub trait PostProcessor: Debug + Send + Sync {
+    fn process(&self, entry: &FileEntry, data: &mut Vec<u8>) -> Result<()>;
+}
+
+/// Pipes each entry through a shell command: the content goes to its standard
+/// input and whatever it writes to standard output replaces it.
+///
+/// The entry is described in `CPK_ENTRY_PATH`, `CPK_ENTRY_ID` (when it has one)
+/// and `CPK_ENTRY_SIZE`. A non-zero exit status fails the entry.
+#[derive(Debug, Clone)]
+pub struct CommandProcessor {
+    command: String,
+}
+
+impl CommandProcessor {
+    pub fn new<S: Into<String>>(command: S) -> Self {
+        Self {
+            command: command.into(),
+        }
+    }
+
+    fn shell(&self) -> Command {
+        #[cfg(windows)]
+        {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C").arg(&self.command);
+            shell
+        }
+        #[cfg(not(windows))]
+        {
+            let mut shell = Command::new("sh");
+            shell.arg("-c").arg(&self.command);
+            shell
+        }
+    }
+}
+
+impl PostProcessor for CommandProcessor {
+    fn process(&self, entry: &FileEntry, data: &mut Vec<u8>) -> Result<()> {
+        debug!("{}: running '{}'", entry.full_path(), self.command);
+        let mut shell = self.shell();
+        shell
+            .env("CPK_ENTRY_PATH", entry.full_path())
+            .env("CPK_ENTRY_SIZE", data.len().to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        if let Some(id) = entry.id {
+            shell.env("CPK_ENTRY_ID", id.to_string());
+        }
+        let mut child = shell.spawn()?;
+
+        // Feed the input from another thread so a command writing as it reads
+        // can't fill its output pipe while we're still writing
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        let mut stdout = child.stdout.take().expect("piped stdout");
+        let mut output = Vec::with_capacity(data.len());
+        std::thread::scope(|scope| {
+            let input = &*data;
+            let writer = scope.spawn(move || {
+                // A command that stops reading early closes the pipe; that's its call
+                match stdin.write_all(input) {
+                    Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+                    result => result,
+                }
+            });
+            let read = stdout.read_to_end(&mut output);
+            writer.join().expect("stdin writer panicked")?;
+            read.map(|_| ())
+        })?;
+
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(CpkError::PostProcess(format!(
+                "'{}' exited with {} on {}",
+                self.command,
+                status,
+                entry.full_path()
+            )));
+        }
+        *data = output;
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use crate::cpk::{Cpk, CpkFile, FileEntry};
+use crate::error::Result;
+use crate::filter::EntryFilter;
+use std::fs::File;
+use std::path::Path;
+
+/// A read-only handle on an opened archive that threads can share, e.g. as
+/// `Arc<CpkReader>`, to read different entries at the same time.
+///
+/// The tables are read once up front and never change; entries are read with
+/// positioned reads on one open file, so readers don't share a cursor.
+#[derive(Debug)]
+pub struct CpkReader {
+    cpk: Cpk,
+    file: File,
+}
+
+// Sharing between threads is the point of this type
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CpkReader>();
+};
+
+impl CpkReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut cpk = Cpk::new();
+        cpk.read_cpk(path)?;
+        Self::from_cpk(cpk)
+    }
+
+    /// Wraps an archive that has already been read, keeping its settings such
+    /// as its base offset, codecs and lenient decompression.
+    pub fn from_cpk(cpk: Cpk) -> Result<Self> {
+        let file = File::open(cpk.source_path()?)?;
+        Ok(Self { cpk, file })
+    }
+
+    /// The archive's tables and header, for anything not wrapped here.
+    pub fn cpk(&self) -> &Cpk {
+        &self.cpk
+    }
+
+    /// Each file stored in the archive once, in table order.
+    pub fn entries(&self) -> Vec<CpkFile<'_>> {
+        self.cpk.entries()
+    }
+
+    pub fn find(&self, path: &str) -> Option<&FileEntry> {
+        self.cpk.find(path)
+    }
+
+    pub fn find_by_id(&self, id: u32) -> Option<&FileEntry> {
+        self.cpk.find_by_id(id)
+    }
+
+    pub fn find_entries(&self, target: &str, filter: &EntryFilter) -> Result<Vec<&FileEntry>> {
+        self.cpk.find_entries(target, filter)
+    }
+
+    /// Reads an entry's content, decompressing it if it's stored compressed.
+    pub fn read_entry(&self, entry: &FileEntry) -> Result<Vec<u8>> {
+        self.cpk.read_entry_from(&self.file, entry)
+    }
+
+    /// Reads an entry's data exactly as stored, without decompressing it.
+    pub fn read_entry_raw(&self, entry: &FileEntry) -> Result<Vec<u8>> {
+        self.cpk.read_entry_raw_from(&self.file, entry)
+    }
+
+    pub fn into_inner(self) -> Cpk {
+        self.cpk
+    }
+}
@@ -0,0 +1,193 @@
+use crate::builder::CpkBuilder;
+use crate::cancel::CancellationToken;
+use crate::cpk::{Cpk, CpkMode, FileEntry};
+use crate::error::{CpkError, Result};
+use crate::group;
+use crate::merge::{path_mode, path_split};
+use log::debug;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How `renumber` picks the new IDs.
+#[derive(Debug, Clone)]
+pub enum Numbering {
+    /// Consecutive IDs from the given one, in table order.
+    Sequential(u32),
+    /// Old ID -> new ID; IDs not listed are kept.
+    Mapped(HashMap<u32, u32>),
+}
+
+/// Writes `cpk` to `output_path` with its entries' IDs reassigned, returning how
+/// many changed.
+///
+/// TOC and ITOC are rebuilt together, so a path keeps pointing at the same data
+/// under its new ID, and the groups are relinked by path. Stored data is copied
+/// as is. IDs must stay unique; a GTOC this tool can't read is refused, since
+/// its links would still name the old IDs.
+pub fn renumber<P: AsRef<Path>>(
+    cpk: &Cpk,
+    output_path: P,
+    numbering: &Numbering,
+    cancel: &CancellationToken,
+) -> Result<usize> {
+    let source = cpk.source_path()?;
+    let has_toc = cpk.header_packet("TOC_HDR").is_some();
+    let entries: Vec<&FileEntry> = match has_toc {
+        true => cpk.toc_files()?,
+        false => cpk
+            .file_table
+            .iter()
+            .filter(|e| e.file_type == "FILE" && e.toc_name == "ITOC")
+            .collect(),
+    };
+
+    let mut builder = CpkBuilder::new()
+        .mode(match has_toc {
+            true => path_mode(cpk),
+            false => CpkMode::Id,
+        })
+        .path_split(path_split(cpk))
+        .align(cpk.align())
+        .encrypt_tables(cpk.is_table_encrypted("TOC_HDR"))
+        .cipher(cpk.table_cipher().clone())
+        .crc(cpk.has_crc())
+        .cancellation(cancel.clone());
+
+    let mut changed = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let id = match numbering {
+            Numbering::Sequential(start) => {
+                let id = start.checked_add(i as u32).ok_or_else(|| {
+                    CpkError::InvalidFormat(format!("IDs from {} overflow", start))
+                })?;
+                Some(id)
+            }
+            Numbering::Mapped(ids) => entry.id.map(|id| *ids.get(&id).unwrap_or(&id)),
+        };
+        if id != entry.id {
+            debug!("{}: ID {:?} -> {:?}", entry.full_path(), entry.id, id);
+            changed += 1;
+        }
+        builder = builder.add_copied(entry.full_path(), id, source, entry);
+    }
+
+    if let Some(groups) = group::archive_groups(cpk)? {
+        builder = builder.groups(groups);
+    }
+
+    builder.write(output_path)?;
+    Ok(changed)
+}
+
+/// Reads an `old,new` ID CSV; a header row is skipped.
+pub fn read_id_map<P: AsRef<Path>>(path: P) -> Result<HashMap<u32, u32>> {
+    let path = path.as_ref();
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+
+    let mut ids = HashMap::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| CpkError::Parse(format!("{}: {}", path.display(), e)))?;
+        let old = record.get(0).unwrap_or_default().parse::<u32>();
+        let new = record.get(1).unwrap_or_default().parse::<u32>();
+        match (old, new) {
+            (Ok(old), Ok(new)) => {
+                ids.insert(old, new);
+            }
+            _ if i == 0 => {}
+            _ => {
+                return Err(CpkError::Parse(format!(
+                    "{}: expected 'old,new' on line {}",
+                    path.display(),
+                    record.position().map_or(0, |p| p.line())
+                )));
+            }
+        }
+    }
+
+    debug!("renumber: {} IDs from {}", ids.len(), path.display());
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{TestArchive, read_back};
+
+    fn archive(path: &Path) -> Cpk {
+        TestArchive {
+            files: 10,
+            mode: CpkMode::FileNameAndId,
+            max_size: 0x1000,
+            seed: 9,
+            ..Default::default()
+        }
+        .write_to(path)
+    }
+
+    #[test]
+    fn sequential_ids_follow_table_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let before = archive(&dir.path().join("before.cpk"));
+        let output = dir.path().join("after.cpk");
+
+        let changed = renumber(
+            &before,
+            &output,
+            &Numbering::Sequential(100),
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(changed, 10);
+        let after = read_back(&output);
+        for entry in before.toc_files().unwrap() {
+            let id = entry.id.unwrap() + 100;
+            let by_id = after.find_by_id(id).unwrap();
+            assert_eq!(by_id.full_path(), entry.full_path());
+            assert_eq!(
+                after.read_entry(by_id).unwrap(),
+                before.read_entry(entry).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn mapped_ids_must_stay_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let before = archive(&dir.path().join("before.cpk"));
+        let output = dir.path().join("after.cpk");
+
+        let swap = Numbering::Mapped(HashMap::from([(0, 1), (1, 0)]));
+        assert_eq!(
+            renumber(&before, &output, &swap, &CancellationToken::new()).unwrap(),
+            2
+        );
+        let after = read_back(&output);
+        assert_eq!(
+            after.find_by_id(1).unwrap().full_path(),
+            "dir00/file00000.txt"
+        );
+        assert_eq!(
+            after.find_by_id(0).unwrap().full_path(),
+            "dir01/file00001.bin"
+        );
+
+        let clash = Numbering::Mapped(HashMap::from([(0, 1)]));
+        assert!(renumber(&before, &output, &clash, &CancellationToken::new()).is_err());
+    }
+
+    #[test]
+    fn id_map_skips_a_header_but_no_other_bad_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ids.csv");
+        std::fs::write(&path, "old,new\n3, 7\n8,2\n").unwrap();
+        assert_eq!(read_id_map(&path).unwrap(), HashMap::from([(3, 7), (8, 2)]));
+
+        std::fs::write(&path, "3,7\nfour,5\n").unwrap();
+        assert!(matches!(read_id_map(&path), Err(CpkError::Parse(_))));
+    }
+}
@@ -0,0 +1,77 @@
+use crate::endian::EndianReader;
+use crate::error::{CpkError, Result};
+use log::debug;
+use std::io::{Read, Seek, SeekFrom};
+
+const MAGIC: &[u8; 8] = b"CPKRPL1\0";
+
+/// Length of the list's count and signature.
+const TRAILER_LEN: u64 = 12;
+
+/// The list of data append-style patches left unreferenced, as written after
+/// the appended data at the end of the archive: `(offset, length)` pairs
+/// relative to the archive's start, their count, then the signature, all
+/// little-endian. Each patch lists what earlier ones did, and their list.
+pub(crate) fn encode(ranges: &[(u64, u64)]) -> Vec<u8> {
+    let mut list = Vec::with_capacity(ranges.len() * 16 + TRAILER_LEN as usize);
+    for &(offset, len) in ranges {
+        list.extend_from_slice(&offset.to_le_bytes());
+        list.extend_from_slice(&len.to_le_bytes());
+    }
+    list.extend_from_slice(&(ranges.len() as u32).to_le_bytes());
+    list.extend_from_slice(MAGIC);
+    list
+}
+
+/// A list as read back.
+pub(crate) struct List {
+    /// Bytes the list takes, up to where it was found.
+    pub len: u64,
+    /// `(offset, length)` of the data left behind.
+    pub ranges: Vec<(u64, u64)>,
+}
+
+/// The list ending `end` bytes into the archive at `base`, or `None` when no
+/// list ends there.
+pub(crate) fn read<R: Read + Seek>(
+    reader: &mut EndianReader<R>,
+    base: u64,
+    end: u64,
+) -> Result<Option<List>> {
+    if end < TRAILER_LEN {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(base + end - TRAILER_LEN))?;
+    let trailer = reader.read_bytes(TRAILER_LEN as usize)?;
+    if &trailer[4..] != MAGIC {
+        return Ok(None);
+    }
+
+    let count = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) as u64;
+    let len = count * 16 + TRAILER_LEN;
+    if len > end {
+        return Err(CpkError::InvalidFormat(format!(
+            "List of replaced data holds {} ranges, more than the {} bytes before it",
+            count, end
+        )));
+    }
+    reader.seek(SeekFrom::Start(base + end - len))?;
+    let mut ranges = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let bytes = reader.read_bytes(16)?;
+        let offset = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let size = u64::from_le_bytes(bytes[8..].try_into().unwrap());
+        if offset
+            .checked_add(size)
+            .is_none_or(|range_end| range_end > end)
+        {
+            return Err(CpkError::InvalidFormat(format!(
+                "Replaced data 0x{:X}+{} is past the end of the archive (0x{:X})",
+                offset, size, end
+            )));
+        }
+        ranges.push((offset, size));
+    }
+    debug!("replaced: {} ranges listed at 0x{:X}", count, end - len);
+    Ok(Some(List { len, ranges }))
+}
@@ -0,0 +1,144 @@
+use crate::cpk::{ExtractRecord, FileEntry};
+use crate::error::Result;
+use crate::verify::EntryCheck;
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Machine-readable summary of a command run, written with `--report`.
+pub struct RunReport {
+    command: String,
+    /// Archives in the order they were processed; entries belong to the last one.
+    archives: Vec<PathBuf>,
+    started: Instant,
+    entries: Vec<Value>,
+    issues: Vec<Value>,
+}
+
+impl RunReport {
+    pub fn new<P: AsRef<Path>>(command: &str, archive: P) -> Self {
+        Self {
+            command: command.to_string(),
+            archives: vec![archive.as_ref().to_path_buf()],
+            started: Instant::now(),
+            entries: Vec::new(),
+            issues: Vec::new(),
+        }
+    }
+
+    /// Starts recording entries of another archive processed in the same run.
+    pub fn add_archive<P: AsRef<Path>>(&mut self, archive: P) {
+        self.archives.push(archive.as_ref().to_path_buf());
+    }
+
+    pub fn add_extracted(&mut self, record: &ExtractRecord) {
+        let status = match (&record.error, record.written) {
+            (Some(_), _) => "error",
+            (None, Some(_)) => "ok",
+            (None, None) => "skipped",
+        };
+        let mut entry = entry_json(
+            record.entry,
+            status,
+            record.duration,
+            &record.warnings,
+            record.error.as_deref(),
+        );
+        if let Some(digest) = &record.digest {
+            entry["digest"] = json!(digest);
+        }
+        self.push_entry(entry);
+    }
+
+    pub fn add_checked(&mut self, check: &EntryCheck) {
+        let status = if check.problem.is_some() {
+            "corrupt"
+        } else {
+            "ok"
+        };
+        let mut entry = entry_json(
+            check.entry,
+            status,
+            check.duration,
+            &[],
+            check.problem.as_deref(),
+        );
+        if let Some(digest) = &check.digest {
+            entry["digest"] = json!(digest);
+        }
+        entry["cached"] = json!(check.cached);
+        self.push_entry(entry);
+    }
+
+    fn push_entry(&mut self, mut entry: Value) {
+        if let Some(archive) = self.archives.last() {
+            entry["archive"] = json!(archive.display().to_string());
+        }
+        self.entries.push(entry);
+    }
+
+    /// Records an archive-level problem that isn't tied to a single entry.
+    pub fn add_issue(&mut self, kind: &str, message: String) {
+        self.issues
+            .push(json!({ "kind": kind, "message": message }));
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P, success: bool) -> Result<()> {
+        let count = |status: &str| {
+            self.entries
+                .iter()
+                .filter(|e| e["status"] == status)
+                .count()
+        };
+
+        let report = json!({
+            "command": self.command,
+            "archive": self.archives[0].display().to_string(),
+            "archives": self
+                .archives
+                .iter()
+                .map(|a| a.display().to_string())
+                .collect::<Vec<_>>(),
+            "success": success,
+            "duration_ms": millis(self.started.elapsed()),
+            "summary": {
+                "entries": self.entries.len(),
+                "ok": count("ok"),
+                "skipped": count("skipped"),
+                "failed": self.entries.len() - count("ok") - count("skipped"),
+                "issues": self.issues.len(),
+            },
+            "issues": self.issues,
+            "entries": self.entries,
+        });
+
+        let text = serde_json::to_string_pretty(&report).map_err(std::io::Error::other)?;
+        std::fs::write(path, text + "\n")?;
+        Ok(())
+    }
+}
+
+fn entry_json(
+    entry: &FileEntry,
+    status: &str,
+    duration: Duration,
+    warnings: &[String],
+    error: Option<&str>,
+) -> Value {
+    json!({
+        "path": entry.full_path(),
+        "id": entry.id,
+        "toc": entry.toc_name,
+        "offset": entry.file_offset,
+        "stored_size": entry.file_size,
+        "extract_size": entry.extract_size.unwrap_or(entry.file_size),
+        "status": status,
+        "duration_ms": millis(duration),
+        "warnings": warnings,
+        "error": error,
+    })
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
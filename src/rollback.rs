@@ -0,0 +1,206 @@
+use crate::cancel::StagedFile;
+use crate::error::{CpkError, Result};
+use log::{debug, warn};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"CPKRBK1\0";
+
+/// Undo record for an archive modified in place: its length and the bytes of
+/// every region about to be overwritten, saved next to it as
+/// `<archive>.rollback` before the first write.
+///
+/// The record only appears once it's complete and on disk, so finding one
+/// means the archive may be half patched; `recover` puts it back as it was.
+/// The record stays locked while the modification runs, so `recover` can tell
+/// an interrupted modification from one still going.
+#[derive(Debug)]
+pub(crate) struct Rollback {
+    archive: PathBuf,
+    path: PathBuf,
+    record: File,
+}
+
+impl Rollback {
+    /// Saves the length of `archive` and the `(offset, length)` regions of it
+    /// that are about to be overwritten.
+    pub fn begin(archive: &Path, regions: &[(u64, u64)]) -> Result<Self> {
+        let path = journal_path(archive);
+        if path.exists() {
+            return Err(CpkError::InvalidFormat(format!(
+                "{} holds an interrupted modification of {}, which has to be recovered first",
+                path.display(),
+                archive.display()
+            )));
+        }
+        // Written to <record>.tmp, which is removed if anything fails
+        let partial = StagedFile::new(&path);
+
+        let mut source = File::open(archive)?;
+        let record = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(partial.path())?;
+        record.lock()?;
+        let mut out = BufWriter::new(record);
+        out.write_all(MAGIC)?;
+        out.write_all(&source.metadata()?.len().to_le_bytes())?;
+        out.write_all(&(regions.len() as u32).to_le_bytes())?;
+        for &(offset, len) in regions {
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&len.to_le_bytes())?;
+            source.seek(SeekFrom::Start(offset))?;
+            if std::io::copy(&mut (&mut source).take(len), &mut out)? != len {
+                return Err(CpkError::InvalidFormat(format!(
+                    "Region 0x{:X}+{} is past the end of {}",
+                    offset,
+                    len,
+                    archive.display()
+                )));
+            }
+        }
+        let record = out.into_inner().map_err(|e| e.into_error())?;
+        record.sync_all()?;
+        partial.commit()?;
+        debug!(
+            "rollback: saved {} regions to {}",
+            regions.len(),
+            path.display()
+        );
+
+        Ok(Self {
+            archive: archive.to_path_buf(),
+            path,
+            record,
+        })
+    }
+
+    /// The modification is complete: syncs the archive and drops the record.
+    pub fn commit(self, archive: &File) -> Result<()> {
+        archive.sync_all()?;
+        // Removed before the lock goes with the handle, so `recover` never
+        // takes the lock of a finished record
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+
+    /// Puts the archive back the way it was, after a failed modification.
+    pub fn restore(mut self) -> Result<()> {
+        self.record.seek(SeekFrom::Start(0))?;
+        restore_from(&self.archive, &self.path, &self.record)
+    }
+}
+
+/// Where the rollback record of `archive` is kept.
+pub fn journal_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_os_string();
+    path.push(".rollback");
+    PathBuf::from(path)
+}
+
+/// Restores `archive` from the rollback record an interrupted in-place
+/// modification left behind, returning whether there was one.
+///
+/// Only commands that modify the archive should call this: a record still
+/// locked belongs to a modification in progress, and is refused rather than
+/// undone under it.
+pub fn recover(archive: &Path) -> Result<bool> {
+    let path = journal_path(archive);
+    let journal = match File::open(&path) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    match journal.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(CpkError::InvalidFormat(format!(
+                "{} is being modified by another process",
+                archive.display()
+            )));
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    // The modification may have finished between opening and locking
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    warn!(
+        "{} was being modified in place when it was interrupted, restoring it",
+        archive.display()
+    );
+    restore_from(archive, &path, &journal)?;
+    Ok(true)
+}
+
+/// Whether `archive` has a rollback record, meaning a modification of it in
+/// place is running or was interrupted, and reading it may see it half done.
+pub fn pending(archive: &Path) -> bool {
+    journal_path(archive).exists()
+}
+
+/// Applies the record at `path`, read from `journal`, to `archive`, then
+/// removes it.
+fn restore_from(archive: &Path, path: &Path, journal: &File) -> Result<()> {
+    let invalid = || {
+        CpkError::InvalidFormat(format!(
+            "{} is not a rollback record; remove it if the archive is fine",
+            path.display()
+        ))
+    };
+
+    let mut journal = BufReader::new(journal);
+    let mut magic = [0u8; 8];
+    journal.read_exact(&mut magic).map_err(|_| invalid())?;
+    if &magic != MAGIC {
+        return Err(invalid());
+    }
+    let mut word = [0u8; 8];
+    journal.read_exact(&mut word).map_err(|_| invalid())?;
+    let length = u64::from_le_bytes(word);
+    let mut count = [0u8; 4];
+    journal.read_exact(&mut count).map_err(|_| invalid())?;
+
+    let mut file = OpenOptions::new().write(true).open(archive)?;
+    for _ in 0..u32::from_le_bytes(count) {
+        journal.read_exact(&mut word).map_err(|_| invalid())?;
+        let offset = u64::from_le_bytes(word);
+        journal.read_exact(&mut word).map_err(|_| invalid())?;
+        let len = u64::from_le_bytes(word);
+        file.seek(SeekFrom::Start(offset))?;
+        if std::io::copy(&mut (&mut journal).take(len), &mut file)? != len {
+            return Err(invalid());
+        }
+    }
+    file.set_len(length)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_to_begin_leaves_no_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("a.cpk");
+        std::fs::write(&archive, [0u8; 0x100]).unwrap();
+
+        let result = Rollback::begin(&archive, &[(0, 0x10), (0xF0, 0x20)]);
+        assert!(matches!(result, Err(CpkError::InvalidFormat(_))));
+        assert!(!pending(&archive));
+        let names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["a.cpk"]);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::cpk::Cpk;
+use crate::error::{CpkError, Result};
+use log::debug;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ScanHit {
+    pub offset: u64,
+    pub size: Option<u64>,
+    pub files: usize,
+}
+
+/// Searches a file for `CPK ` signatures and keeps those whose header tables parse.
+pub fn scan_file<P: AsRef<Path>>(path: P, start: u64) -> Result<Vec<ScanHit>> {
+    let path = path.as_ref();
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file);
+
+    let mut candidates = Vec::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE + 3];
+    let mut carried = 0usize;
+    let mut chunk_start = start;
+
+    loop {
+        let read = reader.read(&mut buffer[carried..])?;
+        if read == 0 {
+            break;
+        }
+        let filled = carried + read;
+
+        for (i, window) in buffer[..filled].windows(4).enumerate() {
+            if window == b"CPK " {
+                candidates.push(chunk_start + i as u64);
+            }
+        }
+
+        // Keep the tail so signatures spanning two chunks are still found
+        carried = filled.min(3);
+        buffer.copy_within(filled - carried..filled, 0);
+        chunk_start += (filled - carried) as u64;
+    }
+
+    debug!("scan: {} signature candidates", candidates.len());
+
+    let mut hits = Vec::new();
+    for offset in candidates {
+        let mut cpk = Cpk::with_base_offset(offset);
+        match cpk.read_cpk(path) {
+            Ok(()) => hits.push(ScanHit {
+                offset,
+                size: cpk.archive_size(),
+                files: cpk
+                    .file_table
+                    .iter()
+                    .filter(|e| e.file_type == "FILE")
+                    .count(),
+            }),
+            Err(e) => debug!("scan: rejecting candidate at 0x{:X}: {}", offset, e),
+        }
+    }
+
+    Ok(hits)
+}
+
+/// Finds an archive appended to another file, as some PC releases append theirs
+/// to the game executable: the first one running to the end of the file, or
+/// failing that the first one found at all.
+pub fn find_appended<P: AsRef<Path>>(path: P) -> Result<Option<ScanHit>> {
+    let path = path.as_ref();
+    let file_size = std::fs::metadata(path)?.len();
+    let hits = scan_file(path, 1)?;
+    let trailing = hits
+        .iter()
+        .position(|hit| hit.size.is_some_and(|size| hit.offset + size >= file_size));
+    Ok(match trailing {
+        Some(i) => hits.into_iter().nth(i),
+        None => hits.into_iter().next(),
+    })
+}
+
+/// Copies a scanned archive out of its container into a standalone file.
+pub fn extract_hit<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    hit: &ScanHit,
+    output_path: Q,
+) -> Result<()> {
+    let size = hit.size.ok_or_else(|| {
+        CpkError::InvalidFormat(format!(
+            "Archive at 0x{:X} doesn't declare its size",
+            hit.offset
+        ))
+    })?;
+
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(hit.offset))?;
+
+    let mut output = File::create(output_path)?;
+    let copied = std::io::copy(&mut file.take(size), &mut output)?;
+    output.flush()?;
+
+    debug!("scan: copied {} bytes from 0x{:X}", copied, hit.offset);
+    Ok(())
+}
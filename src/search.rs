@@ -0,0 +1,38 @@
+use memchr::memmem;
+
+/// Returns the offsets of every non-overlapping occurrence of `pattern` in `data`.
+pub fn find_matches(data: &[u8], pattern: &[u8], ignore_case: bool) -> Vec<usize> {
+    if pattern.is_empty() {
+        return Vec::new();
+    }
+
+    if ignore_case {
+        let data = data.to_ascii_lowercase();
+        let pattern = pattern.to_ascii_lowercase();
+        memmem::find_iter(&data, &pattern).collect()
+    } else {
+        memmem::find_iter(data, pattern).collect()
+    }
+}
+
+/// The line around `offset`, for showing matches inside text files.
+///
+/// Returns `None` when the surroundings don't look like text.
+pub fn line_at(data: &[u8], offset: usize, max_len: usize) -> Option<String> {
+    let start = data[..offset]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let end = data[offset..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(data.len(), |i| offset + i);
+
+    let line = std::str::from_utf8(&data[start..end]).ok()?;
+    let line = line.trim_end_matches('\r');
+    if line.chars().any(|c| c.is_control() && c != '\t') {
+        return None;
+    }
+
+    Some(line.chars().take(max_len).collect())
+}
@@ -0,0 +1,202 @@
+use crate::error::Result;
+use log::{debug, info};
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Files smaller than this are written in one go; holes wouldn't save anything meaningful.
+const SPARSE_THRESHOLD: usize = 1024 * 1024;
+
+/// Granularity of hole detection, matching the usual filesystem block size.
+const BLOCK_SIZE: usize = 4096;
+
+/// Writes `data` to `path`, seeking over all-zero blocks so that filesystems
+/// supporting sparse files don't allocate space for them.
+pub fn write_file<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
+    if data.len() < SPARSE_THRESHOLD {
+        std::fs::write(path, data)?;
+        return Ok(());
+    }
+
+    let file = File::create(&path)?;
+    let mut writer = BufWriter::new(file);
+    let mut skipped = 0u64;
+
+    for block in data.chunks(BLOCK_SIZE) {
+        if block.iter().all(|&b| b == 0) {
+            writer.seek(SeekFrom::Current(block.len() as i64))?;
+            skipped += block.len() as u64;
+        } else {
+            writer.write_all(block)?;
+        }
+    }
+
+    // A trailing hole only moved the cursor; extend the file to cover it
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.set_len(data.len() as u64)?;
+
+    if skipped > 0 {
+        debug!(
+            "{}: left {} zero bytes as holes",
+            path.as_ref().display(),
+            skipped
+        );
+    }
+    Ok(())
+}
+
+/// Like `write_file`, writing `data` in chunks of `chunk` bytes and syncing
+/// each one to disk before passing the offset reached to `on_checkpoint`.
+///
+/// With `start` above zero the file should already hold `data[..start]`, and
+/// only the rest is written. It's read back first: when it holds anything
+/// else, having been changed or cut short since, it's written from the start.
+pub fn write_file_checkpointed<P, F>(
+    path: P,
+    data: &[u8],
+    start: u64,
+    chunk: usize,
+    mut on_checkpoint: F,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: FnMut(u64) -> Result<()>,
+{
+    let path = path.as_ref();
+    let start = match data.get(..start as usize) {
+        Some(prefix) if start > 0 && !starts_with(path, prefix)? => {
+            info!(
+                "{} doesn't hold the {} bytes written before, writing it again",
+                path.display(),
+                start
+            );
+            0
+        }
+        Some(_) if start > 0 => {
+            info!("Continuing {} from byte {}", path.display(), start);
+            start
+        }
+        _ => 0,
+    };
+    let file = match start {
+        0 => File::create(path)?,
+        _ => std::fs::OpenOptions::new().write(true).open(path)?,
+    };
+    // Anything past the checkpoint may be half-written
+    file.set_len(start)?;
+    let mut writer = BufWriter::new(file);
+    writer.seek(SeekFrom::Start(start))?;
+
+    let mut offset = start as usize;
+    while offset < data.len() {
+        let end = (offset + chunk).min(data.len());
+        for block in data[offset..end].chunks(BLOCK_SIZE) {
+            if block.iter().all(|&b| b == 0) {
+                writer.seek(SeekFrom::Current(block.len() as i64))?;
+            } else {
+                writer.write_all(block)?;
+            }
+        }
+        writer.flush()?;
+        let file = writer.get_ref();
+        file.set_len(end as u64)?;
+        file.sync_data()?;
+        offset = end;
+        on_checkpoint(offset as u64)?;
+    }
+    Ok(())
+}
+
+/// Whether the file at `path` begins with `prefix`.
+fn starts_with(path: &Path, prefix: &[u8]) -> Result<bool> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let mut buffer = vec![0u8; BLOCK_SIZE * 64];
+    for expected in prefix.chunks(buffer.len()) {
+        let read = &mut buffer[..expected.len()];
+        match file.read_exact(read) {
+            Ok(()) if read == expected => {}
+            Ok(()) => return Ok(false),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holes_read_back_as_zeros_up_to_the_full_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bin");
+        let mut data = vec![0u8; SPARSE_THRESHOLD + 3 * BLOCK_SIZE];
+        // Data around a hole, then a trailing hole
+        data[..100].fill(7);
+        data[SPARSE_THRESHOLD / 2..SPARSE_THRESHOLD / 2 + 10].fill(9);
+        write_file(&path, &data).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+
+    /// Three blocks, the middle one a hole.
+    fn data() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..3 * BLOCK_SIZE as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        data[BLOCK_SIZE..2 * BLOCK_SIZE].fill(0);
+        data
+    }
+
+    /// Writes `data` from `start` in 5000-byte chunks, returning the checkpoints.
+    fn write_from(path: &Path, data: &[u8], start: u64) -> Vec<u64> {
+        let mut checkpoints = Vec::new();
+        write_file_checkpointed(path, data, start, 5000, |offset| {
+            checkpoints.push(offset);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), data);
+        checkpoints
+    }
+
+    #[test]
+    fn checkpoints_follow_the_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = data();
+        let checkpoints = write_from(&dir.path().join("out.bin"), &data, 0);
+        assert_eq!(checkpoints, [5000, 10000, data.len() as u64]);
+    }
+
+    #[test]
+    fn continues_a_file_holding_what_was_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bin");
+        let data = data();
+        // Past the checkpoint the earlier run may have left anything
+        let mut earlier = data[..6000].to_vec();
+        earlier.extend_from_slice(&[0xFF; 100]);
+        std::fs::write(&path, &earlier).unwrap();
+
+        let checkpoints = write_from(&path, &data, 5000);
+        assert_eq!(checkpoints, [10000, data.len() as u64]);
+    }
+
+    #[test]
+    fn rewrites_a_file_not_holding_what_was_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bin");
+        let data = data();
+        let mut changed = data[..5000].to_vec();
+        changed[10] ^= 1;
+        for earlier in [changed, data[..4000].to_vec()] {
+            std::fs::write(&path, &earlier).unwrap();
+            let checkpoints = write_from(&path, &data, 5000);
+            assert_eq!(checkpoints, [5000, 10000, data.len() as u64]);
+        }
+    }
+}
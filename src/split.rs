@@ -0,0 +1,211 @@
+use crate::builder::{ARCHIVE_OVERHEAD, CpkBuilder, ITOC_OVERHEAD, ITOC_ROW_LEN, toc_entry_bound};
+use crate::cancel::CancellationToken;
+use crate::cpk::{Cpk, align_up};
+use crate::error::{CpkError, Result};
+use crate::group::{self, Group};
+use crate::merge::{path_mode, path_split};
+use log::info;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Writes the entries of `cpk` into volumes of at most `max_size` bytes each,
+/// returning their paths.
+///
+/// Volumes are named after `output_stem` (`game` gives `game.000.cpk`,
+/// `game.001.cpk`, ...) and filled in TOC order. Entries keep their paths and
+/// IDs, and their stored data is copied as is, so extracting every volume gives
+/// back the original files. Each volume lists the part of every group it holds.
+pub fn split<P: AsRef<Path>>(
+    cpk: &Cpk,
+    output_stem: P,
+    max_size: u64,
+    cancel: &CancellationToken,
+) -> Result<Vec<PathBuf>> {
+    let source = cpk.source_path()?;
+    let align = cpk.align().max(1) as u64;
+    let mode = path_mode(cpk);
+    let (mut overhead, mut row_len) = (ARCHIVE_OVERHEAD + align, 0);
+    if mode.has_itoc() {
+        overhead += ITOC_OVERHEAD;
+        row_len = ITOC_ROW_LEN;
+    }
+
+    // Group the entries first so nothing is written if one can never fit
+    let mut volumes = vec![Vec::new()];
+    let mut volume_size = overhead;
+    for entry in cpk.toc_files()? {
+        let path = entry.full_path();
+        let size = toc_entry_bound(&path) + row_len + align_up(entry.file_size, align);
+        if overhead + size > max_size {
+            return Err(CpkError::Unsupported(format!(
+                "'{}' ({} bytes) does not fit in a {} byte volume",
+                path, entry.file_size, max_size
+            )));
+        }
+        if volume_size + size > max_size {
+            volumes.push(Vec::new());
+            volume_size = overhead;
+        }
+        volume_size += size;
+        if let Some(volume) = volumes.last_mut() {
+            volume.push((path, entry));
+        }
+    }
+
+    // Each volume gets the part of every group it holds, which needs the GTOC read
+    let groups = group::archive_groups(cpk)?;
+
+    let output_stem = output_stem.as_ref();
+    let mut written = Vec::with_capacity(volumes.len());
+    for (number, entries) in volumes.into_iter().enumerate() {
+        let mut name = output_stem.as_os_str().to_owned();
+        name.push(format!(".{:03}.cpk", number));
+        let path = PathBuf::from(name);
+
+        let mut builder = CpkBuilder::new()
+            .mode(mode)
+            .path_split(path_split(cpk))
+            .align(cpk.align())
+            .encrypt_tables(cpk.is_table_encrypted("TOC_HDR"))
+            .cipher(cpk.table_cipher().clone())
+            .crc(cpk.has_crc())
+            .cancellation(cancel.clone());
+        if let Some(groups) = &groups {
+            let held: HashSet<String> = entries
+                .iter()
+                .map(|(path, _)| path.to_lowercase())
+                .collect();
+            let parts: Vec<Group> = groups
+                .iter()
+                .map(|group| Group {
+                    files: group
+                        .files
+                        .iter()
+                        .filter(|file| held.contains(&file.to_lowercase()))
+                        .cloned()
+                        .collect(),
+                    ..group.clone()
+                })
+                .filter(|group| !group.files.is_empty())
+                .collect();
+            builder = builder.groups(parts);
+        }
+        for (entry_path, entry) in entries {
+            builder = builder.add_copied(entry_path, entry.id, source, entry);
+        }
+        builder.write(&path)?;
+        written.push(path);
+    }
+
+    info!("Split {} into {} volumes", source.display(), written.len());
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::{TestArchive, read_back};
+
+    fn whole(path: &Path, files: usize, max_size: usize) -> Cpk {
+        TestArchive {
+            files,
+            max_size,
+            seed: 5,
+            ..Default::default()
+        }
+        .write_to(path)
+    }
+
+    #[test]
+    fn volumes_stay_under_the_limit_and_hold_every_entry_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let whole = whole(&dir.path().join("whole.cpk"), 20, 0x2000);
+        let volumes = split(
+            &whole,
+            dir.path().join("part"),
+            0x8000,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert!(volumes.len() > 1);
+
+        let mut held = Vec::new();
+        for path in &volumes {
+            assert!(std::fs::metadata(path).unwrap().len() <= 0x8000);
+            let volume = read_back(path);
+            held.extend(volume.toc_files().unwrap().iter().map(|e| e.full_path()));
+        }
+        held.sort();
+        let mut paths: Vec<String> = whole
+            .toc_files()
+            .unwrap()
+            .iter()
+            .map(|e| e.full_path())
+            .collect();
+        paths.sort();
+        assert_eq!(held, paths);
+    }
+
+    #[test]
+    fn entry_larger_than_a_volume_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let whole = whole(&dir.path().join("whole.cpk"), 4, 0x4000);
+        let result = split(
+            &whole,
+            dir.path().join("part"),
+            0x1000,
+            &CancellationToken::new(),
+        );
+        assert!(matches!(result, Err(CpkError::Unsupported(_))));
+        assert!(!dir.path().join("part.000.cpk").exists());
+    }
+
+    #[test]
+    fn groups_are_shared_out_across_volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = TestArchive {
+            files: 20,
+            max_size: 0x2000,
+            seed: 5,
+            ..Default::default()
+        };
+        let members: Vec<String> = (0..20)
+            .step_by(3)
+            .map(|i| {
+                let extension = if i % 2 == 0 { "txt" } else { "bin" };
+                format!("dir{:02}/file{:05}.{}", i % 16, i, extension)
+            })
+            .collect();
+        settings
+            .builder()
+            .groups(vec![Group {
+                name: "every_third".to_string(),
+                attribute: Some("TEST".to_string()),
+                files: members.clone(),
+            }])
+            .write(dir.path().join("whole.cpk"))
+            .unwrap();
+        let whole = read_back(&dir.path().join("whole.cpk"));
+
+        let volumes = split(
+            &whole,
+            dir.path().join("part"),
+            0x8000,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        let mut grouped = Vec::new();
+        for path in &volumes {
+            if let Some(groups) = group::archive_groups(&read_back(path)).unwrap() {
+                for group in groups {
+                    assert_eq!(group.name, "every_third");
+                    grouped.extend(group.files);
+                }
+            }
+        }
+        grouped.sort();
+        let mut members = members;
+        members.sort();
+        assert_eq!(grouped, members);
+    }
+}
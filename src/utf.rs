@@ -1,10 +1,13 @@
-use crate::endian::EndianReader;
+use crate::diagnostic::Diagnostic;
+use crate::endian::{EndianReader, EndianWriter};
 use crate::error::{CpkError, Result};
+use crate::options::ParseOptions;
 use log::{debug, warn};
-use std::io::{Cursor, SeekFrom};
+use std::collections::HashMap;
+use std::io::{Cursor, SeekFrom, Write};
 
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum ColumnFlags {
     StorageNone = 0x00,
     StorageZero = 0x10,
@@ -24,6 +27,7 @@ pub enum ColumnType {
     UInt64 = 0x06,
     Int64 = 0x07,
     Float = 0x08,
+    Double = 0x09,
     String = 0x0A,
     Data = 0x0B,
 }
@@ -32,6 +36,97 @@ pub enum ColumnType {
 pub struct Column {
     pub flags: u8,
     pub name: String,
+    /// Value shared by every row when the column uses STORAGE_CONSTANT
+    pub constant: Option<CellValue>,
+}
+
+impl Column {
+    pub fn storage(&self) -> u8 {
+        self.flags & 0xF0
+    }
+
+    pub fn column_type(&self) -> u8 {
+        self.flags & 0x0F
+    }
+
+    /// Short name of the value type, e.g. `u32` or `string`.
+    pub fn type_name(&self) -> &'static str {
+        match self.column_type() {
+            0x00 => "u8",
+            0x01 => "s8",
+            0x02 => "u16",
+            0x03 => "s16",
+            0x04 => "u32",
+            0x05 => "s32",
+            0x06 => "u64",
+            0x07 => "s64",
+            0x08 => "f32",
+            0x09 => "f64",
+            0x0A => "string",
+            0x0B => "data",
+            _ => "unknown",
+        }
+    }
+
+    /// Bytes a value takes where it is stored; strings are a 4-byte offset and
+    /// data an offset and a size.
+    pub fn value_size(&self) -> usize {
+        value_size(self.column_type())
+    }
+
+    /// Parses `text` as a value of this column's type; data is written in hex.
+    pub fn parse_value(&self, text: &str) -> Result<CellValue> {
+        let invalid = || {
+            CpkError::InvalidFormat(format!(
+                "'{}' is not a valid {} for column '{}'",
+                text,
+                self.type_name(),
+                self.name
+            ))
+        };
+        let value = match self.column_type() {
+            0x00 => CellValue::UInt8(text.parse().map_err(|_| invalid())?),
+            0x01 => CellValue::Int8(text.parse().map_err(|_| invalid())?),
+            0x02 => CellValue::UInt16(text.parse().map_err(|_| invalid())?),
+            0x03 => CellValue::Int16(text.parse().map_err(|_| invalid())?),
+            0x04 => CellValue::UInt32(text.parse().map_err(|_| invalid())?),
+            0x05 => CellValue::Int32(text.parse().map_err(|_| invalid())?),
+            0x06 => CellValue::UInt64(text.parse().map_err(|_| invalid())?),
+            0x07 => CellValue::Int64(text.parse().map_err(|_| invalid())?),
+            0x08 => CellValue::Float(text.parse().map_err(|_| invalid())?),
+            0x09 => CellValue::Double(text.parse().map_err(|_| invalid())?),
+            0x0A => CellValue::String(text.to_string()),
+            0x0B => {
+                let digits = text.trim_start_matches("0x");
+                if !digits.len().is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                let bytes = (0..digits.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+                    .collect::<std::result::Result<Vec<u8>, _>>()
+                    .map_err(|_| invalid())?;
+                CellValue::Data(bytes)
+            }
+            other => {
+                return Err(CpkError::Unsupported(format!(
+                    "Column '{}' has unknown type 0x{:02X}",
+                    self.name, other
+                )));
+            }
+        };
+        Ok(value)
+    }
+
+    /// How the values are stored: absent (`zero`), shared by all rows, or per row.
+    pub fn storage_name(&self) -> &'static str {
+        match self.storage() {
+            0x10 => "zero",
+            0x30 | 0x70 => "constant",
+            0x50 => "per-row",
+            _ => "unknown",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -46,11 +141,32 @@ pub enum CellValue {
     UInt64(u64),
     Int64(i64),
     Float(f32),
+    Double(f64),
     String(String),
     Data(Vec<u8>),
     None,
 }
 
+impl std::fmt::Display for CellValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CellValue::UInt8(v) => write!(f, "{}", v),
+            CellValue::Int8(v) => write!(f, "{}", v),
+            CellValue::UInt16(v) => write!(f, "{}", v),
+            CellValue::Int16(v) => write!(f, "{}", v),
+            CellValue::UInt32(v) => write!(f, "{}", v),
+            CellValue::Int32(v) => write!(f, "{}", v),
+            CellValue::UInt64(v) => write!(f, "{}", v),
+            CellValue::Int64(v) => write!(f, "{}", v),
+            CellValue::Float(v) => write!(f, "{}", v),
+            CellValue::Double(v) => write!(f, "{}", v),
+            CellValue::String(v) => write!(f, "{}", v),
+            CellValue::Data(v) => write!(f, "<{} bytes>", v.len()),
+            CellValue::None => write!(f, "-"),
+        }
+    }
+}
+
 #[allow(dead_code)]
 impl CellValue {
     pub fn as_u8(&self) -> Option<u8> {
@@ -109,6 +225,20 @@ impl CellValue {
         }
     }
 
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            CellValue::UInt8(v) => Some(*v as i64),
+            CellValue::Int8(v) => Some(*v as i64),
+            CellValue::UInt16(v) => Some(*v as i64),
+            CellValue::Int16(v) => Some(*v as i64),
+            CellValue::UInt32(v) => Some(*v as i64),
+            CellValue::Int32(v) => Some(*v as i64),
+            CellValue::UInt64(v) if *v <= i64::MAX as u64 => Some(*v as i64),
+            CellValue::Int64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
     pub fn as_string(&self) -> Option<&str> {
         match self {
             CellValue::String(s) => Some(s),
@@ -132,6 +262,28 @@ pub struct Cell {
 
 pub type Row = Vec<Cell>;
 
+/// A data cell: its row, or `None` for a column's constant, and its column.
+type DataCell = (Option<usize>, usize);
+
+/// What the parser is reading, reported when a packet turns out to be malformed,
+/// and the recoverable problems it ran into so far.
+struct ParseState {
+    what: String,
+    offset: u64,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ParseState {
+    fn note(&mut self, message: String) {
+        warn!("UTF: {}: {}", self.what, message);
+        self.diagnostics.push(Diagnostic {
+            table: String::new(),
+            location: self.what.clone(),
+            message,
+        });
+    }
+}
+
 #[derive(Debug)]
 pub struct Utf {
     pub table_size: u32,
@@ -139,11 +291,29 @@ pub struct Utf {
     pub strings_offset: u64,
     pub data_offset: u64,
     pub table_name: u32,
+    pub name: String,
     pub num_columns: u16,
     pub row_length: u16,
     pub num_rows: u32,
     pub columns: Vec<Column>,
     pub rows: Vec<Row>,
+    /// Recoverable problems found by the last `read_utf`.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Rows left empty because they couldn't be read, in lenient mode.
+    pub skipped_rows: Vec<u32>,
+    // The data pool as read, and the offset and size each data cell had in
+    // it, so `to_bytes` can leave unchanged blobs where they were
+    data_pool: Vec<u8>,
+    data_cells: HashMap<DataCell, (u32, u32)>,
+    options: ParseOptions,
+    // How many tables this one is nested in
+    depth: usize,
+}
+
+impl Default for Utf {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Utf {
@@ -154,19 +324,83 @@ impl Utf {
             strings_offset: 0,
             data_offset: 0,
             table_name: 0,
+            name: String::new(),
             num_columns: 0,
             row_length: 0,
             num_rows: 0,
             columns: Vec::new(),
             rows: Vec::new(),
+            diagnostics: Vec::new(),
+            skipped_rows: Vec::new(),
+            data_pool: Vec::new(),
+            data_cells: HashMap::new(),
+            options: ParseOptions::default(),
+            depth: 0,
         }
     }
 
+    /// A table read with `options`. In lenient mode rows that can't be read are
+    /// left empty and listed in `diagnostics` and `skipped_rows` instead of failing.
+    pub fn with_options(options: ParseOptions) -> Self {
+        Self {
+            options,
+            ..Self::new()
+        }
+    }
+
+    /// Parses a table stored in one of this table's data cells, with the same options.
+    pub fn read_nested(&self, data: &[u8]) -> Result<Utf> {
+        if self.depth >= self.options.max_depth {
+            return Err(CpkError::InvalidFormat(format!(
+                "@UTF tables nested deeper than {}",
+                self.options.max_depth
+            )));
+        }
+        let mut table = Utf::with_options(self.options.clone());
+        table.depth = self.depth + 1;
+        table.read_utf(data)?;
+        Ok(table)
+    }
+
+    /// Parses an @UTF packet. Errors are wrapped in [`CpkError::Table`], naming the
+    /// table, the column or row being read and where it starts in the packet;
+    /// problems worked around are left in `diagnostics`.
     pub fn read_utf(&mut self, data: &[u8]) -> Result<()> {
+        let mut at = ParseState {
+            what: "table header".to_string(),
+            offset: 0,
+            diagnostics: Vec::new(),
+        };
+        let result = self.parse(data, &mut at);
+        let table = match self.name.is_empty() {
+            true => "@UTF".to_string(),
+            false => self.name.clone(),
+        };
+        self.diagnostics = at.diagnostics;
+        for diagnostic in &mut self.diagnostics {
+            diagnostic.table = table.clone();
+        }
+        result.map_err(|error| CpkError::Table {
+            table,
+            location: at.what,
+            offset: at.offset,
+            error: Box::new(error),
+        })
+    }
+
+    fn parse(&mut self, data: &[u8], at: &mut ParseState) -> Result<()> {
         debug!(
             "UTF: Starting to read UTF data, buffer size: {}",
             data.len()
         );
+        self.name.clear();
+        if data.len() as u64 > self.options.max_utf_size {
+            return Err(CpkError::InvalidFormat(format!(
+                "packet of {} bytes is over the {} byte limit",
+                data.len(),
+                self.options.max_utf_size
+            )));
+        }
         let mut reader = EndianReader::new(Cursor::new(data), false); // Big endian
         let offset = reader.position()?;
         debug!("UTF: Initial offset: {}", offset);
@@ -208,6 +442,14 @@ impl Utf {
             self.num_columns, self.row_length, self.num_rows
         );
 
+        // Read early so errors further down can name the table
+        self.name = if self.strings_offset + (self.table_name as u64) < data.len() as u64 {
+            at.what = "table name".to_string();
+            self.read_string_at(&mut reader, self.table_name as u64, at)?
+        } else {
+            String::new()
+        };
+
         // Validate offsets against buffer size
         if self.rows_offset > data.len() as u64 {
             return Err(CpkError::InvalidFormat(format!(
@@ -233,8 +475,12 @@ impl Utf {
 
         // Read columns
         self.columns.clear();
+        self.data_pool.clear();
+        self.data_cells.clear();
         for i in 0..self.num_columns {
             debug!("UTF: Reading column {}", i);
+            at.what = format!("column {}", i);
+            at.offset = reader.position()?;
             let flags = reader.read_u8()?;
             let flags = if flags == 0 {
                 reader.seek(SeekFrom::Current(3))?;
@@ -248,157 +494,236 @@ impl Utf {
 
             // Safe string reading with bounds checking
             let name = if self.strings_offset + name_offset as u64 >= data.len() as u64 {
-                warn!(
-                    "UTF: Column {} name offset {} is beyond buffer, using default name",
-                    i, name_offset
-                );
+                at.note(format!(
+                    "name offset {} is beyond the packet, named Column{}",
+                    name_offset, i
+                ));
                 format!("Column{}", i)
             } else {
-                match self.read_string_at(&mut reader, name_offset as u64) {
+                match self.read_string_at(&mut reader, name_offset as u64, at) {
                     Ok(s) if !s.is_empty() => s,
                     _ => {
-                        warn!("UTF: Failed to read column {} name, using default", i);
+                        at.note(format!("name could not be read, named Column{}", i));
                         format!("Column{}", i)
                     }
                 }
             };
 
+            at.what = format!("column {} ({})", i, name);
+            let constant = if flags & 0xF0 == 0x30 {
+                if flags & 0x0F == 0x0B {
+                    let position = reader.position()? as usize;
+                    self.data_cells
+                        .insert((None, i as usize), data_span(data, position));
+                }
+                let value = self.read_value(&mut reader, flags & 0x0F, at)?;
+                debug!("UTF: Column {} constant value: {:?}", i, value);
+                Some(value)
+            } else {
+                None
+            };
+
             debug!("UTF: Column {} name: '{}', flags: 0x{:02X}", i, name, flags);
-            self.columns.push(Column { flags, name });
+            self.columns.push(Column {
+                flags,
+                name,
+                constant,
+            });
         }
 
         // Read rows
         self.rows.clear();
+        self.skipped_rows.clear();
         for row_idx in 0..self.num_rows {
             debug!("UTF: Reading row {}", row_idx);
-            reader.seek(SeekFrom::Start(
-                self.rows_offset + (row_idx as u64 * self.row_length as u64),
-            ))?;
+            match self.read_row(&mut reader, row_idx, at) {
+                Ok(row) => self.rows.push(row),
+                Err(e) if self.options.lenient => {
+                    at.note(format!(
+                        "row skipped, unreadable at packet offset 0x{:X}: {}",
+                        at.offset, e
+                    ));
+                    self.skipped_rows.push(row_idx);
+                    // Keeps later rows at their index
+                    self.rows.push(Vec::new());
+                }
+                Err(e) => return Err(e),
+            }
+        }
 
-            let mut row = Vec::new();
+        // Remember where the data cells point, see `to_bytes`
+        if self.columns.iter().any(|c| c.column_type() == 0x0B) {
+            let end = (self.table_size as u64 + 8)
+                .min(data.len() as u64)
+                .max(self.data_offset);
+            self.data_pool = data[self.data_offset as usize..end as usize].to_vec();
+            for (row_idx, row) in self.rows.iter().enumerate() {
+                for (col_idx, cell) in row.iter().enumerate() {
+                    if self.columns[col_idx].flags & 0xF0 == 0x50
+                        && self.columns[col_idx].column_type() == 0x0B
+                    {
+                        let span = data_span(data, cell.position as usize);
+                        self.data_cells.insert((Some(row_idx), col_idx), span);
+                    }
+                }
+            }
+        }
 
-            for col_idx in 0..self.num_columns {
-                let column = &self.columns[col_idx as usize];
-                let storage_flag = column.flags & 0xF0;
-                debug!(
-                    "UTF: Row {}, Column {} ({}), storage_flag: 0x{:02X}",
-                    row_idx, col_idx, column.name, storage_flag
-                );
+        debug!("UTF: Successfully parsed UTF data");
+        Ok(())
+    }
 
-                let cell = match storage_flag {
-                    0x00 | 0x10 | 0x30 => {
-                        // STORAGE_NONE, STORAGE_ZERO, STORAGE_CONSTANT
-                        Cell {
-                            value: CellValue::None,
-                            position: reader.position()?,
-                        }
-                    }
-                    0x50 => {
-                        // STORAGE_PERROW
-                        let column_type = column.flags & 0x0F;
-                        let position = reader.position()?;
-                        debug!(
-                            "UTF: Reading PERROW data, type: 0x{:02X}, position: {}",
-                            column_type, position
-                        );
-
-                        let value = match column_type {
-                            0x00 => {
-                                let val = reader.read_u8()?;
-                                debug!("UTF: Read UInt8: {}", val);
-                                CellValue::UInt8(val)
-                            }
-                            0x01 => {
-                                let val = reader.read_i8()?;
-                                debug!("UTF: Read Int8: {}", val);
-                                CellValue::Int8(val)
-                            }
-                            0x02 => {
-                                let val = reader.read_u16()?;
-                                debug!("UTF: Read UInt16: {}", val);
-                                CellValue::UInt16(val)
-                            }
-                            0x03 => {
-                                let val = reader.read_i16()?;
-                                debug!("UTF: Read Int16: {}", val);
-                                CellValue::Int16(val)
-                            }
-                            0x04 => {
-                                let val = reader.read_u32()?;
-                                debug!("UTF: Read UInt32: {}", val);
-                                CellValue::UInt32(val)
-                            }
-                            0x05 => {
-                                let val = reader.read_i32()?;
-                                debug!("UTF: Read Int32: {}", val);
-                                CellValue::Int32(val)
-                            }
-                            0x06 => {
-                                let val = reader.read_u64()?;
-                                debug!("UTF: Read UInt64: {}", val);
-                                CellValue::UInt64(val)
-                            }
-                            0x07 => {
-                                let val = reader.read_i64()?;
-                                debug!("UTF: Read Int64: {}", val);
-                                CellValue::Int64(val)
-                            }
-                            0x08 => {
-                                let val = reader.read_f32()?;
-                                debug!("UTF: Read Float: {}", val);
-                                CellValue::Float(val)
-                            }
-                            0x0A => {
-                                let str_offset = reader.read_u32()?;
-                                debug!("UTF: String offset: {}", str_offset);
-                                let string_value =
-                                    self.read_string_at(&mut reader, str_offset as u64)?;
-                                debug!("UTF: String value: '{}'", string_value);
-                                CellValue::String(string_value)
-                            }
-                            0x0B => {
-                                let data_offset = reader.read_u32()?;
-                                let data_size = reader.read_u32()?;
-                                debug!("UTF: Data offset: {}, size: {}", data_offset, data_size);
-                                let data_value = self.read_data_at(
-                                    &mut reader,
-                                    data_offset as u64,
-                                    data_size as usize,
-                                )?;
-                                CellValue::Data(data_value)
-                            }
-                            _ => {
-                                return Err(CpkError::Parse(format!(
-                                    "Unsupported column type: {}",
-                                    column_type
-                                )));
-                            }
-                        };
-
-                        Cell { value, position }
+    fn read_row(
+        &self,
+        reader: &mut EndianReader<Cursor<&[u8]>>,
+        row_idx: u32,
+        at: &mut ParseState,
+    ) -> Result<Row> {
+        reader.seek(SeekFrom::Start(
+            self.rows_offset + (row_idx as u64 * self.row_length as u64),
+        ))?;
+
+        let mut row = Vec::new();
+
+        for col_idx in 0..self.num_columns {
+            let column = &self.columns[col_idx as usize];
+            let storage_flag = column.flags & 0xF0;
+            at.what = format!("row {}, column {}", row_idx, column.name);
+            at.offset = reader.position()?;
+            debug!(
+                "UTF: Row {}, Column {} ({}), storage_flag: 0x{:02X}",
+                row_idx, col_idx, column.name, storage_flag
+            );
+
+            let cell = match storage_flag {
+                0x00 | 0x10 => {
+                    // STORAGE_NONE, STORAGE_ZERO
+                    Cell {
+                        value: CellValue::None,
+                        position: reader.position()?,
                     }
-                    _ => {
-                        return Err(CpkError::Parse(format!(
-                            "Unknown storage flag: {}",
-                            storage_flag
-                        )));
+                }
+                0x30 => {
+                    // STORAGE_CONSTANT
+                    Cell {
+                        value: column.constant.clone().unwrap_or(CellValue::None),
+                        position: reader.position()?,
                     }
-                };
+                }
+                0x50 => {
+                    // STORAGE_PERROW
+                    let column_type = column.column_type();
+                    let position = reader.position()?;
+                    debug!(
+                        "UTF: Reading PERROW data, type: 0x{:02X}, position: {}",
+                        column_type, position
+                    );
 
-                row.push(cell);
-            }
+                    let value = self.read_value(reader, column_type, at)?;
+                    Cell { value, position }
+                }
+                _ => {
+                    return Err(CpkError::Parse(format!(
+                        "Unknown storage flag: 0x{:02X}",
+                        storage_flag
+                    )));
+                }
+            };
 
-            self.rows.push(row);
+            row.push(cell);
         }
 
-        debug!("UTF: Successfully parsed UTF data");
-        Ok(())
+        Ok(row)
+    }
+
+    fn read_value(
+        &self,
+        reader: &mut EndianReader<Cursor<&[u8]>>,
+        column_type: u8,
+        at: &mut ParseState,
+    ) -> Result<CellValue> {
+        let value = match column_type {
+            0x00 => {
+                let val = reader.read_u8()?;
+                debug!("UTF: Read UInt8: {}", val);
+                CellValue::UInt8(val)
+            }
+            0x01 => {
+                let val = reader.read_i8()?;
+                debug!("UTF: Read Int8: {}", val);
+                CellValue::Int8(val)
+            }
+            0x02 => {
+                let val = reader.read_u16()?;
+                debug!("UTF: Read UInt16: {}", val);
+                CellValue::UInt16(val)
+            }
+            0x03 => {
+                let val = reader.read_i16()?;
+                debug!("UTF: Read Int16: {}", val);
+                CellValue::Int16(val)
+            }
+            0x04 => {
+                let val = reader.read_u32()?;
+                debug!("UTF: Read UInt32: {}", val);
+                CellValue::UInt32(val)
+            }
+            0x05 => {
+                let val = reader.read_i32()?;
+                debug!("UTF: Read Int32: {}", val);
+                CellValue::Int32(val)
+            }
+            0x06 => {
+                let val = reader.read_u64()?;
+                debug!("UTF: Read UInt64: {}", val);
+                CellValue::UInt64(val)
+            }
+            0x07 => {
+                let val = reader.read_i64()?;
+                debug!("UTF: Read Int64: {}", val);
+                CellValue::Int64(val)
+            }
+            0x08 => {
+                let val = reader.read_f32()?;
+                debug!("UTF: Read Float: {}", val);
+                CellValue::Float(val)
+            }
+            0x09 => {
+                let val = reader.read_f64()?;
+                debug!("UTF: Read Double: {}", val);
+                CellValue::Double(val)
+            }
+            0x0A => {
+                let str_offset = reader.read_u32()?;
+                debug!("UTF: String offset: {}", str_offset);
+                let string_value = self.read_string_at(reader, str_offset as u64, at)?;
+                debug!("UTF: String value: '{}'", string_value);
+                CellValue::String(string_value)
+            }
+            0x0B => {
+                let data_offset = reader.read_u32()?;
+                let data_size = reader.read_u32()?;
+                debug!("UTF: Data offset: {}, size: {}", data_offset, data_size);
+                let data_value =
+                    self.read_data_at(reader, data_offset as u64, data_size as usize)?;
+                CellValue::Data(data_value)
+            }
+            _ => {
+                return Err(CpkError::Parse(format!(
+                    "Unsupported column type: 0x{:02X}",
+                    column_type
+                )));
+            }
+        };
+
+        Ok(value)
     }
 
     fn read_string_at(
         &self,
         reader: &mut EndianReader<Cursor<&[u8]>>,
         offset: u64,
+        at: &mut ParseState,
     ) -> Result<String> {
         let current_pos = reader.position()?;
         let target_pos = self.strings_offset + offset;
@@ -408,7 +733,26 @@ impl Utf {
         );
 
         reader.seek(SeekFrom::Start(target_pos))?;
-        let result = reader.read_cstring(None)?;
+        let (result, had_errors) =
+            reader.read_cstring_as(self.options.max_string_len, self.options.encoding)?;
+        if had_errors {
+            at.note(format!(
+                "string at offset {} is not valid {}, read as '{}'; it won't be written back as it was",
+                offset,
+                self.options.encoding.name(),
+                result
+            ));
+        }
+        // Without a terminator the string ran into the end of the packet or the length limit
+        let end = reader.position()?;
+        reader.seek(SeekFrom::Start(end - 1))?;
+        if reader.read_u8()? != 0 {
+            at.note(format!(
+                "string at offset {} is unterminated, cut to {} bytes",
+                offset,
+                end - target_pos
+            ));
+        }
         reader.seek(SeekFrom::Start(current_pos))?;
         Ok(result)
     }
@@ -475,4 +819,413 @@ impl Utf {
             }
         }
     }
+
+    pub fn has_column(&self, column_name: &str) -> bool {
+        self.columns.iter().any(|c| c.name == column_name)
+    }
+
+    pub fn is_per_row(&self, column_name: &str) -> bool {
+        self.columns
+            .iter()
+            .any(|c| c.name == column_name && c.storage() == 0x50)
+    }
+
+    /// Replaces a cell's value, promoting constant/zero columns to per-row storage when needed.
+    pub fn set_cell(&mut self, row: usize, column_name: &str, value: CellValue) -> Result<()> {
+        let col_index = self
+            .columns
+            .iter()
+            .position(|c| c.name == column_name)
+            .ok_or_else(|| CpkError::Parse(format!("Unknown column: {}", column_name)))?;
+        if row >= self.rows.len() {
+            return Err(CpkError::Parse(format!(
+                "Row {} out of range ({} rows)",
+                row,
+                self.rows.len()
+            )));
+        }
+
+        let column = &mut self.columns[col_index];
+        if column.storage() != 0x50 {
+            let shared = column.constant.take().unwrap_or(CellValue::None);
+            column.flags = 0x50 | column.column_type();
+            for cells in &mut self.rows {
+                cells[col_index].value = shared.clone();
+            }
+        }
+
+        self.rows[row][col_index].value = value;
+        Ok(())
+    }
+
+    /// Overwrites a per-row integer cell in the packet this table was parsed from,
+    /// leaving every other byte of the packet untouched.
+    pub fn patch_cell(
+        &self,
+        packet: &mut [u8],
+        row: usize,
+        column_name: &str,
+        value: u64,
+    ) -> Result<()> {
+        let col_index = self
+            .columns
+            .iter()
+            .position(|c| c.name == column_name)
+            .ok_or_else(|| CpkError::Parse(format!("Unknown column: {}", column_name)))?;
+        let column = &self.columns[col_index];
+        if column.storage() != 0x50 {
+            return Err(CpkError::Unsupported(format!(
+                "Column '{}' is not stored per row",
+                column_name
+            )));
+        }
+
+        let width = match column.column_type() {
+            0x00 | 0x01 => 1,
+            0x02 | 0x03 => 2,
+            0x04 | 0x05 => 4,
+            0x06 | 0x07 => 8,
+            other => {
+                return Err(CpkError::Unsupported(format!(
+                    "Column '{}' has non-integer type {}",
+                    column_name, other
+                )));
+            }
+        };
+        let signed = column.column_type() & 1 == 1;
+        let limit = if width == 8 {
+            u64::MAX >> signed as u32
+        } else {
+            (1u64 << (width * 8 - signed as usize)) - 1
+        };
+        if value > limit {
+            return Err(CpkError::InvalidFormat(format!(
+                "Value {} doesn't fit column '{}'",
+                value, column_name
+            )));
+        }
+
+        let position = self
+            .rows
+            .get(row)
+            .and_then(|cells| cells.get(col_index))
+            .map(|cell| cell.position as usize)
+            .ok_or_else(|| CpkError::Parse(format!("Row {} out of range", row)))?;
+        let target = packet.get_mut(position..position + width).ok_or_else(|| {
+            CpkError::InvalidFormat(format!("Cell position {} out of range", position))
+        })?;
+        target.copy_from_slice(&value.to_be_bytes()[8 - width..]);
+        Ok(())
+    }
+
+    /// Serializes the table into an @UTF packet.
+    ///
+    /// For a parsed table, the data pool is written back as read: blobs still
+    /// holding their original bytes keep their place, and changed ones are
+    /// appended after it. The bytes of blobs no cell refers to anymore are
+    /// dropped, moving what follows them back. The pool also keeps its
+    /// alignment in the packet, so an untouched table comes out with the same
+    /// data layout.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut strings = StringPool::new();
+        strings.add("<NULL>");
+        let table_name = strings.add(&self.name);
+        let name_offsets: Vec<u32> = self.columns.iter().map(|c| strings.add(&c.name)).collect();
+        let mut data_pool = DataPool::new(self);
+
+        let mut column_writer = EndianWriter::new(Vec::new(), false);
+        for (col_idx, (column, name_offset)) in self.columns.iter().zip(&name_offsets).enumerate() {
+            column_writer.write_u8(column.flags)?;
+            column_writer.write_u32(*name_offset)?;
+            if column.storage() == 0x30 {
+                let constant = column.constant.as_ref().unwrap_or(&CellValue::None);
+                write_value(
+                    &mut column_writer,
+                    column,
+                    constant,
+                    &mut strings,
+                    &mut data_pool,
+                    (None, col_idx),
+                )?;
+            }
+        }
+
+        let mut row_writer = EndianWriter::new(Vec::new(), false);
+        for (row_idx, row) in self.rows.iter().enumerate() {
+            for (col_idx, (column, cell)) in self.columns.iter().zip(row).enumerate() {
+                if column.storage() == 0x50 {
+                    write_value(
+                        &mut row_writer,
+                        column,
+                        &cell.value,
+                        &mut strings,
+                        &mut data_pool,
+                        (Some(row_idx), col_idx),
+                    )?;
+                }
+            }
+        }
+
+        let row_length: usize = self
+            .columns
+            .iter()
+            .filter(|c| c.storage() == 0x50)
+            .map(|c| value_size(c.column_type()))
+            .sum();
+
+        let column_bytes = column_writer.into_inner();
+        let row_bytes = row_writer.into_inner();
+        let mut string_bytes = strings.into_bytes();
+
+        let data_pool = data_pool.bytes;
+
+        let rows_offset = 0x20 + column_bytes.len();
+        let strings_offset = rows_offset + row_bytes.len();
+        // The data pool starts on an 8-byte boundary, or as aligned as it was
+        // read, up to 32 bytes
+        let align = match self.data_offset {
+            0 => 8,
+            read_at => 1 << read_at.trailing_zeros().min(5),
+        };
+        while !(strings_offset + string_bytes.len()).is_multiple_of(align) {
+            string_bytes.push(0);
+        }
+        let data_offset = strings_offset + string_bytes.len();
+        let table_size = data_offset + data_pool.len();
+
+        let mut writer = EndianWriter::new(Vec::with_capacity(table_size), false);
+        writer.write_bytes(b"@UTF")?;
+        writer.write_u32((table_size - 8) as u32)?;
+        writer.write_u32((rows_offset - 8) as u32)?;
+        writer.write_u32((strings_offset - 8) as u32)?;
+        writer.write_u32((data_offset - 8) as u32)?;
+        writer.write_u32(table_name)?;
+        writer.write_u16(self.columns.len() as u16)?;
+        writer.write_u16(row_length as u16)?;
+        writer.write_u32(self.rows.len() as u32)?;
+        writer.write_bytes(&column_bytes)?;
+        writer.write_bytes(&row_bytes)?;
+        writer.write_bytes(&string_bytes)?;
+        writer.write_bytes(&data_pool)?;
+
+        Ok(writer.into_inner())
+    }
+}
+
+/// Deduplicating Shift-JIS string pool used while serializing tables.
+struct StringPool {
+    bytes: Vec<u8>,
+    offsets: HashMap<String, u32>,
+}
+
+impl StringPool {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            offsets: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, value: &str) -> u32 {
+        if let Some(&offset) = self.offsets.get(value) {
+            return offset;
+        }
+
+        let offset = self.bytes.len() as u32;
+        let (encoded, _, _) = encoding_rs::SHIFT_JIS.encode(value);
+        self.bytes.extend_from_slice(&encoded);
+        self.bytes.push(0);
+        self.offsets.insert(value.to_string(), offset);
+        offset
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Data pool used while serializing tables, starting from the pool the table
+/// was parsed with less the blobs no cell holds anymore.
+struct DataPool {
+    bytes: Vec<u8>,
+    // Where the unchanged blobs went, by cell
+    kept: HashMap<DataCell, u32>,
+}
+
+impl DataPool {
+    fn new(table: &Utf) -> Self {
+        let current = |cell: &DataCell| -> Option<&[u8]> {
+            let value = match cell {
+                (None, col_idx) => table.columns.get(*col_idx)?.constant.as_ref()?,
+                (Some(row_idx), col_idx) => &table.rows.get(*row_idx)?.get(*col_idx)?.value,
+            };
+            match value {
+                CellValue::Data(bytes) => Some(bytes),
+                CellValue::None => Some(&[]),
+                _ => None,
+            }
+        };
+        let span = |&(offset, size): &(u32, u32)| offset as usize..offset as usize + size as usize;
+
+        // Blobs whose cell changed or is gone are dropped, unless an unchanged
+        // cell shares their bytes
+        let mut keep = vec![true; table.data_pool.len()];
+        let mut unchanged = Vec::new();
+        for (cell, original) in &table.data_cells {
+            let range = span(original);
+            match table.data_pool.get(range.clone()) {
+                Some(bytes) if current(cell) == Some(bytes) => unchanged.push((*cell, range)),
+                Some(_) => keep[range].fill(false),
+                None => {}
+            }
+        }
+        for (_, range) in &unchanged {
+            keep[range.clone()].fill(true);
+        }
+
+        // New offset of each kept byte of the pool
+        let mut moved_to = Vec::with_capacity(keep.len() + 1);
+        let mut bytes = Vec::with_capacity(table.data_pool.len());
+        for (byte, kept) in table.data_pool.iter().zip(&keep) {
+            moved_to.push(bytes.len() as u32);
+            if *kept {
+                bytes.push(*byte);
+            }
+        }
+        moved_to.push(bytes.len() as u32);
+        let kept = unchanged
+            .into_iter()
+            .map(|(cell, range)| (cell, moved_to[range.start]))
+            .collect();
+        Self { bytes, kept }
+    }
+
+    fn add(&mut self, cell: DataCell, value: &[u8]) -> u32 {
+        if let Some(&offset) = self.kept.get(&cell) {
+            return offset;
+        }
+
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(value);
+        offset
+    }
+}
+
+/// Reads the big-endian pool offset and size of the data cell at `position`.
+fn data_span(packet: &[u8], position: usize) -> (u32, u32) {
+    let read = |at: usize| {
+        packet
+            .get(at..at + 4)
+            .map_or(0, |bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    (read(position), read(position + 4))
+}
+
+fn value_size(column_type: u8) -> usize {
+    match column_type {
+        0x00 | 0x01 => 1,
+        0x02 | 0x03 => 2,
+        0x04 | 0x05 | 0x08 | 0x0A => 4,
+        _ => 8,
+    }
+}
+
+fn write_value<W: Write>(
+    writer: &mut EndianWriter<W>,
+    column: &Column,
+    value: &CellValue,
+    strings: &mut StringPool,
+    data_pool: &mut DataPool,
+    cell: DataCell,
+) -> Result<()> {
+    let mismatch = || {
+        CpkError::InvalidFormat(format!(
+            "Value {:?} doesn't fit column '{}' (type 0x{:02X})",
+            value,
+            column.name,
+            column.column_type()
+        ))
+    };
+    // Missing values are written as zero / "<NULL>" / empty data
+    let is_none = matches!(value, CellValue::None);
+
+    match column.column_type() {
+        0x00 => writer.write_u8(if is_none {
+            0
+        } else {
+            value.as_u8().ok_or_else(mismatch)?
+        }),
+        0x01 => {
+            let v = if is_none {
+                0
+            } else {
+                value.as_i64().ok_or_else(mismatch)?
+            };
+            writer.write_i8(i8::try_from(v).map_err(|_| mismatch())?)
+        }
+        0x02 => writer.write_u16(if is_none {
+            0
+        } else {
+            value.as_u16().ok_or_else(mismatch)?
+        }),
+        0x03 => {
+            let v = if is_none {
+                0
+            } else {
+                value.as_i64().ok_or_else(mismatch)?
+            };
+            writer.write_i16(i16::try_from(v).map_err(|_| mismatch())?)
+        }
+        0x04 => writer.write_u32(if is_none {
+            0
+        } else {
+            value.as_u32().ok_or_else(mismatch)?
+        }),
+        0x05 => {
+            let v = if is_none {
+                0
+            } else {
+                value.as_i64().ok_or_else(mismatch)?
+            };
+            writer.write_i32(i32::try_from(v).map_err(|_| mismatch())?)
+        }
+        0x06 => writer.write_u64(if is_none {
+            0
+        } else {
+            value.as_u64().ok_or_else(mismatch)?
+        }),
+        0x07 => writer.write_i64(if is_none {
+            0
+        } else {
+            value.as_i64().ok_or_else(mismatch)?
+        }),
+        0x08 => match value {
+            CellValue::Float(v) => writer.write_f32(*v),
+            CellValue::None => writer.write_f32(0.0),
+            _ => Err(mismatch()),
+        },
+        0x09 => match value {
+            CellValue::Double(v) => writer.write_f64(*v),
+            CellValue::None => writer.write_f64(0.0),
+            _ => Err(mismatch()),
+        },
+        0x0A => match value {
+            CellValue::String(v) => writer.write_u32(strings.add(v)),
+            CellValue::None => writer.write_u32(0),
+            _ => Err(mismatch()),
+        },
+        0x0B => {
+            let bytes: &[u8] = match value {
+                CellValue::Data(v) => v,
+                CellValue::None => &[],
+                _ => return Err(mismatch()),
+            };
+            writer.write_u32(data_pool.add(cell, bytes))?;
+            writer.write_u32(bytes.len() as u32)
+        }
+        other => Err(CpkError::Unsupported(format!(
+            "Cannot write column type 0x{:02X}",
+            other
+        ))),
+    }
 }
@@ -0,0 +1,217 @@
+use crate::cache::{CachedCheck, CheckKey, check_key};
+use crate::compression;
+use crate::cpk::{Cpk, FileEntry};
+use crate::digest::HashAlgorithm;
+use crate::error::{CpkError, Result};
+use crate::layout::{self, Region, RegionKind};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+/// Table packets are conventionally placed on 0x800 boundaries regardless of `Align`.
+const TABLE_ALIGN: u64 = 0x800;
+
+/// Structural problems found in an archive's physical layout.
+#[derive(Debug, Default)]
+pub struct LayoutReport {
+    /// Pairs of regions sharing bytes.
+    pub overlaps: Vec<(Region, Region)>,
+    /// Gaps that are not explained by alignment padding.
+    pub gaps: Vec<Region>,
+    /// Regions reaching past the end of the file.
+    pub truncated: Vec<Region>,
+}
+
+impl LayoutReport {
+    pub fn is_clean(&self) -> bool {
+        self.overlaps.is_empty() && self.gaps.is_empty() && self.truncated.is_empty()
+    }
+}
+
+/// Checks that headers, tables and entries don't overlap, are contiguous up to
+/// alignment padding, and fit inside a file of `file_len` bytes.
+pub fn check_layout(cpk: &Cpk, file_len: u64) -> LayoutReport {
+    let mut report = LayoutReport::default();
+    let regions = layout::regions(cpk);
+
+    // Regions are sorted by start, so tracking the one reaching furthest is enough
+    let mut furthest: Option<&Region> = None;
+    for region in &regions {
+        if let Some(prev) = furthest
+            && region.start < prev.end
+            && !region.is_empty()
+        {
+            report.overlaps.push((prev.clone(), region.clone()));
+        }
+        if furthest.is_none_or(|prev| region.end > prev.end) {
+            furthest = Some(region);
+        }
+        if region.end > file_len {
+            report.truncated.push(region.clone());
+        }
+    }
+
+    let end = cpk
+        .archive_size()
+        .and_then(|size| cpk.base_offset().checked_add(size))
+        .into_iter()
+        .chain(regions.iter().map(|r| r.end))
+        .max()
+        .unwrap_or(0);
+
+    let base = cpk.base_offset();
+    let align = (cpk.align() as u64).max(1);
+    report.gaps = layout::with_gaps(regions, end)
+        .into_iter()
+        .filter(|r| r.kind == RegionKind::Gap)
+        .filter(|gap| {
+            let (start, end) = (gap.start - base, gap.end - base);
+            start.div_ceil(align) * align != end && start.div_ceil(TABLE_ALIGN) * TABLE_ALIGN != end
+        })
+        .collect();
+
+    report
+}
+
+/// Result of decoding one entry in a deep check.
+#[derive(Debug, Clone)]
+pub struct EntryCheck<'a> {
+    pub entry: &'a FileEntry,
+    pub duration: Duration,
+    /// Why the stored data doesn't decode to what the tables describe.
+    pub problem: Option<String>,
+    /// SHA-256 of the stored (possibly compressed) bytes, if they could be read.
+    pub digest: Option<String>,
+    /// Whether the result came from the cache instead of the data.
+    pub cached: bool,
+}
+
+/// Reads and fully decompresses every entry, checking the result against
+/// ExtractSize and, where the TOC has one, the CRC column.
+///
+/// Entries listed in both the TOC and the ITOC are only checked once.
+pub fn check_entries(cpk: &Cpk) -> Result<Vec<EntryCheck<'_>>> {
+    check_all(cpk, &HashMap::new())
+}
+
+/// Like `check_entries`, taking the results of the entries in `known` (as
+/// `VerifyCache::lookup` returns them for an unchanged archive) instead of
+/// reading them again, and adding the results of the others to it.
+pub fn check_entries_with<'a>(
+    cpk: &'a Cpk,
+    known: &mut HashMap<CheckKey, CachedCheck>,
+) -> Result<Vec<EntryCheck<'a>>> {
+    let checks = check_all(cpk, known)?;
+    for check in checks.iter().filter(|check| !check.cached) {
+        let result = CachedCheck {
+            sha256: check.digest.clone(),
+            problem: check.problem.clone(),
+        };
+        known.insert(check_key(check.entry), result);
+    }
+    Ok(checks)
+}
+
+fn check_all<'a>(
+    cpk: &'a Cpk,
+    known: &HashMap<CheckKey, CachedCheck>,
+) -> Result<Vec<EntryCheck<'a>>> {
+    let mut checks = Vec::new();
+
+    for entry in cpk.unique_files() {
+        cpk.cancellation().check()?;
+        if let Some(cached) = known.get(&check_key(entry)) {
+            checks.push(EntryCheck {
+                entry,
+                duration: Duration::ZERO,
+                problem: cached.problem.clone(),
+                digest: cached.sha256.clone(),
+                cached: true,
+            });
+            continue;
+        }
+
+        let start = Instant::now();
+        let (problem, digest) = match cpk.read_entry_raw(entry) {
+            Ok(data) => (
+                check_entry(entry, &data).err(),
+                Some(HashAlgorithm::Sha256.hex_digest(&data)),
+            ),
+            Err(CpkError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                (Some("data is truncated".to_string()), None)
+            }
+            Err(e) => return Err(e),
+        };
+        checks.push(EntryCheck {
+            entry,
+            duration: start.elapsed(),
+            problem,
+            digest,
+            cached: false,
+        });
+    }
+
+    Ok(checks)
+}
+
+/// Checks one entry's stored data against what the tables say about it.
+fn check_entry(entry: &FileEntry, data: &[u8]) -> std::result::Result<(), String> {
+    let extract_size = entry.extract_size.unwrap_or(entry.file_size);
+    let is_crilayla = data.starts_with(b"CRILAYLA");
+
+    if extract_size == entry.file_size {
+        return check_crc(entry, data);
+    }
+    if extract_size < entry.file_size {
+        return Err(format!(
+            "ExtractSize {} is smaller than FileSize {}",
+            extract_size, entry.file_size
+        ));
+    }
+    if !is_crilayla {
+        return Err(format!(
+            "ExtractSize {} exceeds FileSize {} but data isn't CRILAYLA-compressed",
+            extract_size, entry.file_size
+        ));
+    }
+
+    // Checking the CRC needs the content itself, the size alone doesn't
+    if entry.crc.is_some() {
+        return match compression::decompress_crilayla(data) {
+            Ok(content) if content.len() as u64 == extract_size => check_crc(entry, &content),
+            Ok(content) => Err(format!(
+                "decompresses to {} bytes, ExtractSize is {}",
+                content.len(),
+                extract_size
+            )),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+
+    match compression::check_crilayla(data) {
+        Ok(size) if size == extract_size => Ok(()),
+        Ok(size) => Err(format!(
+            "decompresses to {} bytes, ExtractSize is {}",
+            size, extract_size
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Compares extracted content against the entry's CRC column, if it has one.
+fn check_crc(entry: &FileEntry, content: &[u8]) -> std::result::Result<(), String> {
+    match entry.crc {
+        Some(expected) => {
+            let actual = compression::crc32(content);
+            if actual == expected {
+                Ok(())
+            } else {
+                Err(format!(
+                    "CRC is {:08X}, the TOC says {:08X}",
+                    actual, expected
+                ))
+            }
+        }
+        None => Ok(()),
+    }
+}